@@ -0,0 +1,59 @@
+#![feature(test)]
+extern crate test;
+
+use libdb::{AllocOptions, Danger, Database, DEFAULT_CACHE_PAGES};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use test::Bencher;
+
+/// How many fragments each run writes and reads back.
+const FRAGMENTS: u64 = 200;
+
+/// Opens an empty file to back a store. The file is removed straight away, and lives on until it is closed.
+fn scratch(name: &str) -> File {
+    let path = std::env::temp_dir().join(format!("libdb-bench-{}-{}", name, std::process::id()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .expect("Failed to create the store");
+
+    std::fs::remove_file(&path).expect("Failed to remove the store");
+
+    file
+}
+
+/// Writes many small fragments and reads each of them back, as a store of small objects would.
+fn small_fragments(b: &mut Bencher, name: &str, cache_pages: usize) {
+    b.iter(|| {
+        let mut file = scratch(name);
+        Database::destructive_reinitialise(&mut file, Danger).unwrap();
+
+        let mut db = Database::new(file).unwrap();
+        db.set_cache_capacity(cache_pages).unwrap();
+
+        for id in 1..=FRAGMENTS {
+            db.write_fragment(AllocOptions::default().fragment(id), &id.to_le_bytes().repeat(16)).unwrap();
+        }
+
+        let mut contents = vec![];
+        for id in 1..=FRAGMENTS {
+            contents.clear();
+            db.open_fragment(id).unwrap().read_to_end(&mut contents).unwrap();
+        }
+
+        db.flush().unwrap();
+    });
+}
+
+#[bench]
+fn small_fragments_uncached(b: &mut Bencher) {
+    small_fragments(b, "uncached", 0);
+}
+
+#[bench]
+fn small_fragments_cached(b: &mut Bencher) {
+    small_fragments(b, "cached", DEFAULT_CACHE_PAGES);
+}
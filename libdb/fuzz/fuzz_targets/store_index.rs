@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    libdb::fuzzing::read_store_index(data);
+    libdb::fuzzing::read_store(data);
+});
@@ -0,0 +1,294 @@
+use crate::error::{ArchiveError, Result};
+use crate::progress::Progress;
+use crate::rw::RWFragmentStore;
+use crate::{AllocOptions, Database, FragmentID};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const ARCHIVE_MAGIC: [u8; 8] = *b"LIBDBARC";
+const END_MAGIC: [u8; 8] = *b"LIBDBEND";
+
+/// The version of the archive format written by [`Database::export`]. Archives of this version or older can be imported.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Describes a store's contents independently of how they are laid out in its backing buffer, so a store can be backed up, moved to another
+/// machine, or carried across changes to the store's own format.
+///
+/// Only the newest sequence of each fragment is kept. Deleted fragments and free space are left out.
+///
+/// # Binary Layout (Little-Endian)
+/// ```text
+/// Offset  Size     Field
+/// -------------------------------
+/// 0       8 B      Magic number ("LIBDBARC")
+/// 8       4 B      Archive version
+/// 12      4 B      Version of the store the archive was taken from
+/// 16      8 B      Number of fragments
+/// 24+              Fragments, each laid out as:
+///         8 B        Fragment ID
+///         8 B        Sequence number
+///         8 B        Length
+///         N B        Fragment data
+/// ...     8 B      End marker ("LIBDBEND")
+/// ```
+///
+/// The end marker tells a complete archive apart from one which was cut short.
+struct ArchiveHeader {
+    version: u32,
+    store_version: u32,
+    fragments: u64,
+}
+
+impl ArchiveHeader {
+    fn read(source: &mut impl Read) -> Result<Self> {
+        let mut buffer = [0u8; 24];
+        read_exact(source, &mut buffer)?;
+
+        if buffer[0..8] != ARCHIVE_MAGIC {
+            return Err(ArchiveError::InvalidMagic.into());
+        }
+
+        let header = Self {
+            version: u32::from_le_bytes(buffer[8..12].try_into()?),
+            store_version: u32::from_le_bytes(buffer[12..16].try_into()?),
+            fragments: u64::from_le_bytes(buffer[16..24].try_into()?),
+        };
+
+        if header.version > ARCHIVE_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(header.version).into());
+        }
+
+        Ok(header)
+    }
+
+    fn write(&self, target: &mut impl Write) -> Result<()> {
+        let mut buffer = [0u8; 24];
+
+        buffer[0..8].copy_from_slice(&ARCHIVE_MAGIC);
+        buffer[8..12].copy_from_slice(&self.version.to_le_bytes());
+        buffer[12..16].copy_from_slice(&self.store_version.to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.fragments.to_le_bytes());
+
+        target.write_all(&buffer)?;
+
+        Ok(())
+    }
+}
+
+/// Reads exactly enough to fill `buffer`, treating running out of input as a truncated archive.
+fn read_exact(source: &mut impl Read, buffer: &mut [u8]) -> Result<()> {
+    match source.read_exact(buffer) {
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Err(ArchiveError::Truncated.into()),
+        result => Ok(result?),
+    }
+}
+
+impl<Backing: Read + Write + Seek> Database<Backing> {
+    /// Writes every fragment in the store to `target` as an archive. See [`ArchiveHeader`] for its layout.
+    ///
+    /// Returns the number of fragments written.
+    pub fn export(&mut self, target: impl Write) -> Result<u64> {
+        self.export_with_progress(target, &Progress::default())
+    }
+
+    /// Like [`Database::export`], but reports each fragment to `progress` before writing it, and stops if it is cancelled. An archive
+    /// which was stopped part-way lacks its end marker, so it can't be imported.
+    pub fn export_with_progress(&mut self, mut target: impl Write, progress: &Progress) -> Result<u64> {
+        let fragments = self.fragments().map(|frag| (frag.id, frag.sequence, frag.length)).collect::<Vec<_>>();
+
+        ArchiveHeader {
+            version: ARCHIVE_VERSION,
+            store_version: self.data_source.header.version,
+            fragments: fragments.len() as u64,
+        }.write(&mut target)?;
+
+        for (done, (id, sequence, length)) in fragments.iter().copied().enumerate() {
+            progress.step(done as u64, fragments.len() as u64)?;
+
+            let mut buffer = [0u8; 24];
+            buffer[0..8].copy_from_slice(&id.to_le_bytes());
+            buffer[8..16].copy_from_slice(&sequence.to_le_bytes());
+            buffer[16..24].copy_from_slice(&length.to_le_bytes());
+            target.write_all(&buffer)?;
+
+            let copied = std::io::copy(&mut self.open_fragment(id)?, &mut target)?;
+
+            if copied != length {
+                return Err(ArchiveError::Truncated.into());
+            }
+        }
+
+        target.write_all(&END_MAGIC)?;
+        target.flush()?;
+
+        Ok(fragments.len() as u64)
+    }
+
+    /// Restores an archive written by [`Database::export`] into `backing`, which must be empty.
+    ///
+    /// Fragments keep their IDs but start their history afresh, so each is restored as the next sequence of an empty store's fragment.
+    pub fn import(backing: Backing, source: impl Read) -> Result<Self> {
+        Self::import_with_progress(backing, source, &Progress::default())
+    }
+
+    /// Like [`Database::import`], but reports each fragment to `progress` before restoring it, and stops if it is cancelled. What was
+    /// restored before then is left in `backing`, but isn't flushed.
+    pub fn import_with_progress(mut backing: Backing, mut source: impl Read, progress: &Progress) -> Result<Self> {
+        if backing.seek(SeekFrom::End(0))? != 0 {
+            return Err(ArchiveError::BackingNotEmpty.into());
+        }
+
+        let header = ArchiveHeader::read(&mut source)?;
+        tracing::debug!("Importing {} fragments from a version {} store", header.fragments, header.store_version);
+
+        let mut db = Self { data_source: RWFragmentStore::blank(backing)? };
+
+        for done in 0..header.fragments {
+            progress.step(done, header.fragments)?;
+
+            let mut buffer = [0u8; 24];
+            read_exact(&mut source, &mut buffer)?;
+
+            let id = FragmentID::from_le_bytes(buffer[0..8].try_into()?);
+            let length = u64::from_le_bytes(buffer[16..24].try_into()?);
+
+            let mut frag = db.new_fragment(AllocOptions::default().fragment(id).size_hint(length))?;
+
+            match std::io::copy(&mut (&mut source).take(length), &mut frag) {
+                Ok(copied) if copied == length => {
+                    frag.commit()?;
+                },
+                Ok(_) => {
+                    frag.abandon();
+                    return Err(ArchiveError::Truncated.into());
+                },
+                Err(err) => {
+                    frag.abandon();
+                    return Err(err.into());
+                },
+            }
+        }
+
+        let mut end = [0u8; 8];
+        read_exact(&mut source, &mut end)?;
+
+        if end != END_MAGIC {
+            return Err(ArchiveError::Truncated.into());
+        }
+
+        db.flush()?;
+
+        Ok(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::global::Inner;
+    use std::io::Cursor;
+
+    fn store() -> Result<Database<Cursor<Vec<u8>>>> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        Database::new(backing)
+    }
+
+    #[test]
+    pub fn test_archive_round_trip() -> Result<()> {
+        let large = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut db = store()?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+        db.write_fragment(AllocOptions::default().fragment(2), &large)?;
+        db.write_fragment(AllocOptions::default().fragment(3), b"Deleted")?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Goodbye")?;
+        db.delete_fragment(3)?;
+
+        let mut archive = vec![];
+        assert_eq!(db.export(&mut archive)?, 3);
+
+        let mut restored = Database::import(Cursor::new(vec![]), archive.as_slice())?;
+
+        for (id, expected) in [(1, b"Goodbye".as_slice()), (2, &large)] {
+            let mut contents = vec![];
+            restored.open_fragment(id)?.read_to_end(&mut contents)?;
+            assert_eq!(contents, expected);
+        }
+
+        assert!(restored.open_fragment(3).is_err());
+
+        // The restored store is a store like any other, and survives being reopened.
+        restored.flush()?;
+        let mut reopened = Database::new(restored.into_inner()?)?;
+        let mut contents = vec![];
+        reopened.open_fragment(1)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"Goodbye");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rejects_damaged_archives() -> Result<()> {
+        let mut db = store()?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+
+        let mut archive = vec![];
+        db.export(&mut archive)?;
+
+        let import = |archive: &[u8]| Database::import(Cursor::new(vec![]), archive).map(|_| ());
+        let refused = |result: Result<()>, expected: ArchiveError| result.is_err_and(|err| matches!(err.inner(), Inner::ArchiveError(err) if format!("{:?}", err) == format!("{:?}", expected)));
+
+        assert!(refused(import(&archive[..archive.len() - 3]), ArchiveError::Truncated));
+        assert!(refused(import(b"not an archive at all..."), ArchiveError::InvalidMagic));
+
+        let mut newer = archive.clone();
+        newer[8..12].copy_from_slice(&(ARCHIVE_VERSION + 1).to_le_bytes());
+        assert!(refused(import(&newer), ArchiveError::UnsupportedVersion(ARCHIVE_VERSION + 1)));
+
+        assert!(refused(Database::import(Cursor::new(vec![0u8; 16]), archive.as_slice()).map(|_| ()), ArchiveError::BackingNotEmpty));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_long_operations_report_progress_and_stop_when_cancelled() -> Result<()> {
+        use crate::progress::{CancellationToken, Progress};
+        use std::sync::{Arc, Mutex};
+
+        let mut db = store()?;
+        for i in 0..10u8 {
+            db.write_fragment(AllocOptions::default(), &[i; 100])?;
+        }
+
+        // The token is cancelled from the callback once a few fragments have been written.
+        let (token, steps) = (CancellationToken::new(), Arc::new(Mutex::new(vec![])));
+        let progress = Progress::new({
+            let (token, steps) = (token.clone(), steps.clone());
+            move |step| {
+                steps.lock().unwrap().push(step.done);
+                if step.done == 3 {
+                    token.cancel();
+                }
+            }
+        }).cancellable(token);
+
+        let mut archive = vec![];
+        let cancelled = db.export_with_progress(&mut archive, &progress).expect_err("The export was cancelled");
+        assert!(matches!(cancelled.inner(), Inner::ManualError(crate::error::ManualError::Cancelled)));
+        assert_eq!(*steps.lock().unwrap(), vec![0, 1, 2, 3]);
+        assert!(Database::import(Cursor::new(vec![]), archive.as_slice()).is_err());
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let progress = Progress::new({
+            let reports = reports.clone();
+            move |step| reports.lock().unwrap().push((step.done, step.total))
+        });
+
+        assert_eq!(db.scrub(usize::MAX, &progress)?, vec![]);
+        assert_eq!(reports.lock().unwrap().len(), 11);
+        assert_eq!(reports.lock().unwrap().last(), Some(&(10, 11)));
+
+        Ok(())
+    }
+}
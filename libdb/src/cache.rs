@@ -0,0 +1,423 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use crate::durability::SyncData;
+
+/// The size of the blocks the cache reads and writes the backing buffer in. It doesn't depend on the store's page size.
+pub(crate) const CACHE_PAGE: u64 = 4096;
+
+/// How many pages a store caches unless told otherwise.
+pub const DEFAULT_CACHE_PAGES: usize = 256;
+
+/// Keeps recently used parts of the backing buffer in memory, and holds on to writes until it is flushed.
+///
+/// Reads and writes are served from whole pages of [`CACHE_PAGE`] bytes, so the many small reads and writes of headers and descriptors
+/// become a few page-sized ones. Pages which have been written to are written back when the cache is flushed, in order of offset, or when
+/// they are evicted to make room for others. At most `capacity` pages are kept, and the least recently used one is evicted first.
+///
+/// Runs of whole pages which aren't cached are read and written directly, so large fragments don't push everything else out of the cache.
+/// A capacity of 0 turns the cache off, passing every read and write straight to the backing buffer.
+pub(crate) struct PageCache<Backing> {
+    inner: Backing,
+
+    /// Where the next read or write begins.
+    position: u64,
+
+    /// The length of the backing buffer, counting writes which haven't been written back yet.
+    len: u64,
+
+    capacity: usize,
+    pages: HashMap<u64, Page>,
+
+    /// The cached pages by when they were last used, least recent first.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+struct Page {
+    data: Vec<u8>,
+
+    /// The part of the page which has been written to since it was last written back.
+    dirty: Option<Range<usize>>,
+    used: u64,
+}
+
+impl Page {
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        self.dirty = Some(match self.dirty.take() {
+            Some(dirty) => dirty.start.min(range.start)..dirty.end.max(range.end),
+            None => range,
+        });
+    }
+
+    /// Writes the dirty part of the page back to the backing buffer, stopping at the end of the buffer.
+    fn write_back(&mut self, inner: &mut (impl Write + Seek), len: u64, number: u64) -> Result<()> {
+        let Some(dirty) = self.dirty.take() else {
+            return Ok(());
+        };
+
+        let start = number * CACHE_PAGE;
+        let end = dirty.end.min(len.saturating_sub(start) as usize);
+
+        if dirty.start < end {
+            inner.seek(SeekFrom::Start(start + dirty.start as u64))?;
+            inner.write_all(&self.data[dirty.start..end])?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<Backing: std::fmt::Debug> std::fmt::Debug for PageCache<Backing> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PageCache")
+            .field("inner", &self.inner)
+            .field("capacity", &self.capacity)
+            .field("cached", &self.pages.len())
+            .field("dirty", &self.pages.values().filter(|page| page.dirty.is_some()).count())
+            .finish()
+    }
+}
+
+impl<Backing: Read + Write + Seek> PageCache<Backing> {
+    pub(crate) fn new(mut inner: Backing) -> Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            inner,
+            position: 0,
+            len,
+            capacity: DEFAULT_CACHE_PAGES,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        })
+    }
+
+    pub(crate) fn get_ref(&self) -> &Backing {
+        &self.inner
+    }
+
+    /// Gives direct access to the backing buffer. Cached pages which haven't been written to are dropped, so later reads see whatever is
+    /// written here. Anything written through the cache should be flushed first, or it may overwrite what is written here when it is.
+    pub(crate) fn get_mut(&mut self) -> &mut Backing {
+        self.pages.retain(|_, page| page.dirty.is_some());
+        self.recency.retain(|_, number| self.pages.contains_key(number));
+
+        &mut self.inner
+    }
+
+    /// Syncs the backing buffer to its disk. Pages waiting in the cache aren't written back first; see [`Write::flush`].
+    pub(crate) fn sync_data(&mut self) -> Result<()> where Backing: SyncData {
+        self.inner.sync_data()
+    }
+
+    /// Writes back every dirty page and returns the backing buffer.
+    pub(crate) fn into_inner(mut self) -> Result<Backing> {
+        self.flush()?;
+
+        Ok(self.inner)
+    }
+
+    /// Changes how many pages may be cached, evicting the least recently used ones if there are too many.
+    pub(crate) fn set_capacity(&mut self, pages: usize) -> Result<()> {
+        self.capacity = pages;
+
+        while self.pages.len() > self.capacity {
+            self.evict()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` at `offset`, and on to the backing buffer straight away rather than when the cache is next flushed.
+    pub(crate) fn write_through(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let position = self.position;
+
+        self.position = offset;
+        self.write_all(data)?;
+        self.position = position;
+
+        let last = (offset + data.len() as u64).div_ceil(CACHE_PAGE);
+
+        for number in offset / CACHE_PAGE..last {
+            if let Some(page) = self.pages.get_mut(&number) {
+                page.write_back(&mut self.inner, self.len, number)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads as much of `buf` as the backing buffer holds from `offset`. Whatever lies past its end reads as zeroes.
+    fn read_direct(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        buf[filled..].fill(0);
+
+        Ok(())
+    }
+
+    /// Returns a cached page, reading it in first if `load` is set. Pages which are about to be overwritten entirely needn't be read.
+    fn page(&mut self, number: u64, load: bool) -> Result<&mut Page> {
+        if !self.pages.contains_key(&number) {
+            while self.pages.len() >= self.capacity {
+                self.evict()?;
+            }
+
+            let mut data = vec![0u8; CACHE_PAGE as usize];
+            if load {
+                self.read_direct(number * CACHE_PAGE, &mut data)?;
+            }
+
+            self.pages.insert(number, Page { data, dirty: None, used: 0 });
+        }
+
+        self.clock += 1;
+
+        let page = self.pages.get_mut(&number).expect("Page was just cached");
+        self.recency.remove(&page.used);
+        page.used = self.clock;
+        self.recency.insert(self.clock, number);
+
+        Ok(page)
+    }
+
+    fn evict(&mut self) -> Result<()> {
+        let Some((_, number)) = self.recency.pop_first() else {
+            return Ok(());
+        };
+
+        if let Some(mut page) = self.pages.remove(&number) {
+            page.write_back(&mut self.inner, self.len, number)?;
+        }
+
+        Ok(())
+    }
+
+    /// The number of whole pages from `first` which aren't cached, up to `limit`.
+    fn uncached_run(&self, first: u64, limit: u64) -> u64 {
+        (first..first + limit).take_while(|number| !self.pages.contains_key(number)).count() as u64
+    }
+}
+
+impl<Backing: Read + Write + Seek> Read for PageCache<Backing> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.capacity == 0 {
+            self.inner.seek(SeekFrom::Start(self.position))?;
+            let read = self.inner.read(buf)?;
+            self.position += read as u64;
+
+            return Ok(read);
+        }
+
+        let end = self.len.min(self.position + buf.len() as u64);
+        let mut done = 0;
+
+        while self.position < end {
+            let (number, offset) = (self.position / CACHE_PAGE, self.position % CACHE_PAGE);
+            let run = match offset {
+                0 => self.uncached_run(number, (end - self.position) / CACHE_PAGE),
+                _ => 0,
+            };
+
+            let read = if run > 0 {
+                let read = (run * CACHE_PAGE) as usize;
+                self.read_direct(self.position, &mut buf[done..done + read])?;
+                read
+            } else {
+                let read = (CACHE_PAGE - offset).min(end - self.position) as usize;
+                let page = self.page(number, true)?;
+                buf[done..done + read].copy_from_slice(&page.data[offset as usize..offset as usize + read]);
+                read
+            };
+
+            done += read;
+            self.position += read as u64;
+        }
+
+        Ok(done)
+    }
+}
+
+impl<Backing: Read + Write + Seek> Write for PageCache<Backing> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.capacity == 0 {
+            self.inner.seek(SeekFrom::Start(self.position))?;
+            let written = self.inner.write(buf)?;
+            self.position += written as u64;
+            self.len = self.len.max(self.position);
+
+            return Ok(written);
+        }
+
+        let end = self.position + buf.len() as u64;
+        let mut done = 0;
+
+        while self.position < end {
+            let (number, offset) = (self.position / CACHE_PAGE, self.position % CACHE_PAGE);
+            let run = match offset {
+                0 => self.uncached_run(number, (end - self.position) / CACHE_PAGE),
+                _ => 0,
+            };
+
+            let written = if run > 0 {
+                let written = (run * CACHE_PAGE) as usize;
+                self.inner.seek(SeekFrom::Start(self.position))?;
+                self.inner.write_all(&buf[done..done + written])?;
+                written
+            } else {
+                let written = (CACHE_PAGE - offset).min(end - self.position) as usize;
+
+                // Pages past the end of the buffer hold nothing worth reading in.
+                let load = number * CACHE_PAGE < self.len;
+                let page = self.page(number, load)?;
+                page.data[offset as usize..offset as usize + written].copy_from_slice(&buf[done..done + written]);
+                page.mark_dirty(offset as usize..offset as usize + written);
+                written
+            };
+
+            done += written;
+            self.position += written as u64;
+            self.len = self.len.max(self.position);
+        }
+
+        Ok(done)
+    }
+
+    /// Writes back the dirty part of every page in order of offset, then flushes the backing buffer.
+    fn flush(&mut self) -> Result<()> {
+        let mut dirty = self.pages.iter()
+            .filter(|(_, page)| page.dirty.is_some())
+            .map(|(number, _)| *number)
+            .collect::<Vec<_>>();
+        dirty.sort_unstable();
+
+        for number in dirty {
+            if let Some(page) = self.pages.get_mut(&number) {
+                page.write_back(&mut self.inner, self.len, number)?;
+            }
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl<Backing: Read + Write + Seek> Seek for PageCache<Backing> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "seek to a negative or overflowing position"))?;
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Counts the reads and writes which reach the backing buffer.
+    #[derive(Default)]
+    struct Counting {
+        inner: Cursor<Vec<u8>>,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.writes += 1;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /// Writes and reads back a table of 32-byte descriptors, one at a time, much as the fragment table is.
+    fn descriptors(cache: &mut PageCache<Counting>) -> Result<()> {
+        for i in 0..256u64 {
+            cache.seek(SeekFrom::Start(i * 32))?;
+            cache.write_all(&[i as u8; 32])?;
+        }
+
+        for i in 0..256u64 {
+            let mut descriptor = [0u8; 32];
+            cache.seek(SeekFrom::Start(i * 32))?;
+            cache.read_exact(&mut descriptor)?;
+            assert_eq!(descriptor, [i as u8; 32]);
+        }
+
+        cache.flush()
+    }
+
+    #[test]
+    pub fn test_small_writes_are_gathered_into_pages() -> Result<()> {
+        let mut uncached = PageCache::new(Counting::default())?;
+        uncached.set_capacity(0)?;
+        descriptors(&mut uncached)?;
+
+        let mut cached = PageCache::new(Counting::default())?;
+        descriptors(&mut cached)?;
+
+        assert_eq!(cached.get_ref().inner.get_ref(), uncached.get_ref().inner.get_ref());
+        assert_eq!((uncached.get_ref().reads, uncached.get_ref().writes), (256, 256));
+        assert_eq!((cached.get_ref().reads, cached.get_ref().writes), (0, 2));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_evicted_pages_are_written_back() -> Result<()> {
+        let mut cache = PageCache::new(Cursor::new(vec![]))?;
+        cache.set_capacity(2)?;
+
+        for page in 0..4u64 {
+            cache.seek(SeekFrom::Start(page * CACHE_PAGE + 10))?;
+            cache.write_all(&[page as u8 + 1; 10])?;
+        }
+
+        // The first two pages were evicted to make room for the last two. Only the parts of them which were written reach the buffer.
+        assert_eq!(cache.get_ref().get_ref().len() as u64, CACHE_PAGE + 20);
+
+        let inner = cache.into_inner()?.into_inner();
+        assert_eq!(inner.len() as u64, 3 * CACHE_PAGE + 20);
+
+        for page in 0..4u64 {
+            let start = (page * CACHE_PAGE + 10) as usize;
+            assert_eq!(inner[start..start + 10], [page as u8 + 1; 10]);
+        }
+
+        Ok(())
+    }
+}
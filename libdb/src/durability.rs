@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::Cursor;
+
+/// How much of the work of making a commit last is done before the commit returns. Whatever isn't done then is done when the store is next
+/// flushed, or when the page cache evicts the pages involved.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Durability {
+    /// Commits are recorded in memory, and reach the backing buffer when the store is flushed. A crash loses every commit since then.
+    #[default]
+    Relaxed,
+
+    /// Every commit writes the header, the fragment table and the cached pages back to the backing buffer and flushes it, so commits survive
+    /// the process crashing. They can still be lost if the machine loses power before the operating system writes them out.
+    FlushOnCommit,
+
+    /// Like [`Durability::FlushOnCommit`], and the backing buffer is then synced to its disk, so commits survive power loss.
+    FsyncOnCommit,
+}
+
+/// Backing buffers which can be made to hold on to what was written to them through a loss of power.
+pub trait SyncData {
+    fn sync_data(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Buffers in memory are lost with the process anyway, so there is nothing to sync.
+impl<T> SyncData for Cursor<T> {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: SyncData + ?Sized> SyncData for &mut T {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        (**self).sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rw::RWFragmentStore;
+    use crate::AllocOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    /// A disk which counts how often it was synced.
+    #[derive(Default)]
+    struct Disk {
+        bytes: Cursor<Vec<u8>>,
+        syncs: usize,
+    }
+
+    impl Read for Disk {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.bytes.read(buf)
+        }
+    }
+
+    impl Write for Disk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.bytes.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Disk {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.bytes.seek(pos)
+        }
+    }
+
+    impl SyncData for Disk {
+        fn sync_data(&mut self) -> std::io::Result<()> {
+            self.syncs += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_commits_reach_the_disk_as_the_durability_asks() -> crate::error::Result<()> {
+        for (durability, syncs, survives) in [(Durability::Relaxed, 0, false), (Durability::FlushOnCommit, 0, true), (Durability::FsyncOnCommit, 1, true)] {
+            let mut disk = Disk::default();
+            RWFragmentStore::blank(&mut disk)?;
+
+            let mut db = crate::Database::new(&mut disk)?;
+            db.set_durability(durability);
+            db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+
+            // The process dies without flushing the store.
+            std::mem::forget(db);
+
+            assert_eq!(disk.syncs, syncs, "{:?}", durability);
+
+            let mut crashed = Cursor::new(disk.bytes.into_inner());
+            let recovered = crate::Database::new(&mut crashed)?.open_fragment(1).is_ok();
+            assert_eq!(recovered, survives, "{:?}", durability);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_sync_reaches_the_disk_whatever_the_durability() -> crate::error::Result<()> {
+        let mut disk = Disk::default();
+        RWFragmentStore::blank(&mut disk)?;
+
+        let mut db = crate::Database::new(&mut disk)?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+        assert!(db.is_dirty());
+
+        db.sync()?;
+        assert!(!db.is_dirty());
+        std::mem::forget(db);
+
+        assert_eq!(disk.syncs, 1);
+        assert!(crate::Database::new(&mut Cursor::new(disk.bytes.into_inner()))?.open_fragment(1).is_ok());
+
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ macro_rules! multi_error {
             use backtrace::Backtrace;
 
             #[derive(Debug)]
+            #[allow(clippy::enum_variant_names)]
             pub enum Inner {
                 $($err($obj),)*
                 $($manual),*
@@ -28,6 +29,12 @@ macro_rules! multi_error {
                 }
             }
 
+            impl Error {
+                pub fn inner(&self) -> &Inner {
+                    &self.inner
+                }
+            }
+
             impl std::error::Error for Error {}
             impl std::fmt::Display for Error {
                 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { std::fmt::Debug::fmt(self, f) }
@@ -51,6 +58,8 @@ multi_error! { global();
     CustomError = String;
     ManualError = crate::error::ManualError;
     FragmentError = crate::error::FragmentError;
+    ArchiveError = crate::error::ArchiveError;
+    LockError = crate::error::LockError;
     IoError = std::io::Error;
     SystemTimeError = std::time::SystemTimeError;
     DecodeError = std::array::TryFromSliceError;
@@ -66,12 +75,17 @@ impl global::Error {
 
 pub type Result<T> = ::std::result::Result<T, global::Error>;
 
-use std::marker::PointeeSized;
 pub use global::Error;
 
 #[derive(Debug, Clone)]
 pub enum ManualError {
     BackingObjectMissing,
+
+    /// The operation was stopped through its [`crate::progress::CancellationToken`] before it finished.
+    Cancelled,
+
+    /// The file was expected to hold a store, but is empty.
+    EmptyStore(std::path::PathBuf),
 }
 
 impl std::error::Error for ManualError {}
@@ -93,6 +107,31 @@ pub enum FragmentError {
     InvalidTable,
     LengthExceedsCapacity,
     FailedToCreateNewFragmentTablePart,
+    InvalidPageSize(u32),
+
+    /// The fragment's sequence reaches past the end of the backing buffer.
+    Truncated(crate::FragmentID),
+
+    /// A migration to another page size has been started and must be finished first.
+    MigrationInProgress(u32),
+
+    /// The fragment recording a page-size migration's progress can't be read.
+    InvalidCheckpoint,
+
+    /// The header's metadata would take up this many bytes, more than [`crate::MAX_METADATA_SIZE`].
+    MetadataTooLarge(usize),
+    InvalidMetadata,
+
+    /// A conditional write found the fragment at a different sequence than the one it expected, so it wrote nothing. A sequence of 0
+    /// stands for a fragment which doesn't exist. See [`crate::Database::write_if_sequence`].
+    SequenceConflict { id: crate::FragmentID, expected: u64, found: u64 },
+
+    /// A fragment couldn't be recorded when its handle was dropped, which may have left the store inconsistent. It can still be read, but
+    /// refuses writes until it is opened again.
+    Poisoned,
+
+    /// The store was closed to writes with [`crate::Database::close`], such as because its file is about to be moved or replaced.
+    Closed,
 }
 
 impl std::error::Error for FragmentError {}
@@ -112,4 +151,38 @@ impl FragmentError {
         Err(Self::InvalidFragmentTable.into())
     }
     
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone)]
+pub enum LockError {
+    /// Another process holds a lock on the store which conflicts with the one asked for. It is described if it recorded itself in the
+    /// store's lock file.
+    Contended(std::path::PathBuf, Option<crate::lock::LockInfo>),
+}
+
+impl std::error::Error for LockError {}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Contended(path, Some(holder)) => write!(f, "{} is locked by {}", path.display(), holder),
+            Self::Contended(path, None) => write!(f, "{} is locked by another process", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ArchiveError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    BackingNotEmpty,
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
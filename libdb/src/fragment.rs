@@ -1,10 +1,11 @@
+use crate::error::FragmentError;
+use crate::metrics::Operation;
 use crate::rw::FragmentDescriptor;
 use crate::rw::Pointer;
+use crate::rw::{INLINE, MAX_INLINE_SIZE};
 use crate::rw::RWFragmentStore;
-use crate::rw::RWFragmentStoreIndex;
-use crate::rw::PAGE_SIZE;
 use crate::FragmentID;
-use std::io::{BufReader, BufWriter, Cursor};
+use std::io::Cursor;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Read;
@@ -16,60 +17,121 @@ pub trait Buffer: Read + Write + Seek {}
 
 impl<T> Buffer for T where T: Read + Write + Seek {}
 
+/// Backing buffers which hold the whole store in memory, so fragments can be borrowed from them instead of copied out.
+/// See [`FragmentHandle::as_slice`].
+pub trait InMemory {
+    fn bytes(&self) -> &[u8];
+}
+
+impl<T: AsRef<[u8]>> InMemory for Cursor<T> {
+    fn bytes(&self) -> &[u8] {
+        self.get_ref().as_ref()
+    }
+}
+
+impl<T: InMemory + ?Sized> InMemory for &mut T {
+    fn bytes(&self) -> &[u8] {
+        (**self).bytes()
+    }
+}
+
 impl<Backing: Read + Write + Seek> RWFragmentStore<Backing> {
-    pub fn new_fragment(&mut self, options: impl Into<AllocOptions>) -> crate::error::Result<FragmentHandle<Backing>> {
-        let opt = options.into();
+    pub fn new_fragment(&mut self, options: impl Into<AllocOptions>) -> crate::error::Result<FragmentHandle<'_, Backing>> {
+        self.mark_dirty()?;
+
+        let (id, sequence, fragment_type) = self.alloc_fragment(options.into())?;
+
+        Ok(FragmentHandle {
+            index: self,
+
+            id,
+            sequence,
+
+            fragment_type,
+        })
+    }
+
+    /// Deletes a fragment by recording a tombstone as its next sequence. Writing to the fragment again brings it back.
+    pub fn delete_fragment(&mut self, id: FragmentID) -> crate::error::Result<()> {
+        if self.header.newest(id).is_none_or(FragmentDescriptor::is_tombstone) {
+            return FragmentError::not_found(id);
+        }
+
+        self.mark_dirty()?;
+
+        let (_, sequence) = self.next_frag_and_seq(Some(id));
+
+        self.header.push_fragment_descriptor(FragmentDescriptor {
+            id,
+            sequence,
+            offset: crate::rw::TOMBSTONE,
+            length: 0,
+            inline: None,
+            written: Some(crate::rw::now()),
+        })?;
 
+        self.committed()
+    }
+
+    /// Writes `data` as the next sequence of fragment `id`, but only if its newest sequence is still `expected`, which lets writers that
+    /// read a fragment and write it back be sure nobody wrote it in between. Expecting sequence 0 only writes the fragment if it doesn't
+    /// exist, or has been deleted. Returns the sequence written, or [`FragmentError::SequenceConflict`] without writing anything.
+    pub fn write_if_sequence(&mut self, id: FragmentID, expected: u64, data: &[u8]) -> crate::error::Result<u64> {
+        let found = self.header.newest(id).filter(|frag| !frag.is_tombstone()).map_or(0, |frag| frag.sequence);
+        if found != expected {
+            return Err(FragmentError::SequenceConflict { id, expected, found }.into());
+        }
+
+        // The whole of the data is buffered, so it's placed in the best-fitting free space like any other small fragment.
+        let size = data.len();
+        let mut fragment = self.new_fragment(AllocOptions::default().fragment(id).buffer_threshold(size as u64).buffer_capacity(size))?;
+        let sequence = fragment.sequence;
+
+        match fragment.write_all(data) {
+            Ok(()) => {
+                fragment.commit()?;
+            },
+            Err(err) => {
+                fragment.abandon();
+                return Err(err.into());
+            },
+        }
+
+        Ok(sequence)
+    }
+
+    /// Reserves the next sequence of a fragment without creating a handle for it.
+    fn alloc_fragment(&mut self, opt: AllocOptions) -> crate::error::Result<(FragmentID, u64, FragmentType)> {
         let (frag, seq) = self.next_frag_and_seq(opt.fragment);
 
-        match opt.size_hint {
+        let fragment_type = match opt.size_hint {
             SizeHint::Sized(size) => {
-                let (ptr, size) = self.header.allocate_fragment(size)?;
-                Ok(FragmentHandle {
-                    index: self,
-
-                    id: frag,
-                    sequence: seq,
-
-                    fragment_type: FragmentType::Sized(SizedFragment {
-                        max_size: Some(size.next_multiple_of(PAGE_SIZE as u64)),
-                        cursor: 0,
-                        ptr,
-                        size,
-                    }),
+                let (ptr, capacity) = self.header.allocate_fragment(size)?;
+
+                FragmentType::Sized(SizedFragment {
+                    max_size: Some(capacity),
+                    cursor: 0,
+                    ptr,
+                    size,
                 })
             }
-            SizeHint::Growable => Ok(FragmentHandle {
-                index: self,
+            SizeHint::Growable => {
+                let buffer_threshold = opt.buffer_threshold.map_or(self.header.page_size, |threshold| threshold.max(self.header.page_size));
+                let capacity = opt.buffer_capacity.unwrap_or(self.header.page_size as usize).min(buffer_threshold as usize);
 
-                id: frag,
-                sequence: seq,
-
-                fragment_type: FragmentType::Dynamic(DynamicFragment {
-                    buffer_threshold: PAGE_SIZE as u64,
-                    buffer: InlineBuffer::Buffered(Cursor::new(vec![0; PAGE_SIZE])),
-                }),
-            }),
-        }
-    }
+                FragmentType::Dynamic(DynamicFragment {
+                    buffer_threshold,
+                    buffer: InlineBuffer::Buffered(Cursor::new(Vec::with_capacity(capacity))),
+                })
+            },
+        };
 
-    fn next_fragment_id(&mut self) -> FragmentID {
-        self.header
-            .fragment_table()
-            .max_by(|a, b| a.id.cmp(&b.id))
-            .map(|frag| frag.id)
-            .unwrap_or(1)
+        Ok((frag, seq, fragment_type))
     }
 
     fn next_frag_and_seq(&mut self, frag: Option<FragmentID>) -> (FragmentID, u64) {
-        let frag = frag.unwrap_or(self.next_fragment_id());
-        let seq = self
-            .header
-            .fragment_table()
-            .filter(|i| i.id == frag)
-            .max_by(|a, b| a.sequence.cmp(&b.sequence))
-            .map(|frag| frag.sequence)
-            .unwrap_or(0);
+        let frag = frag.unwrap_or_else(|| self.header.allocate_id());
+        let seq = self.header.newest(frag).map_or(0, |frag| frag.sequence);
 
         (frag, seq + 1)
     }
@@ -79,6 +141,8 @@ impl<Backing: Read + Write + Seek> RWFragmentStore<Backing> {
 pub struct AllocOptions {
     size_hint: SizeHint,
     fragment: Option<FragmentID>,
+    buffer_threshold: Option<u64>,
+    buffer_capacity: Option<usize>,
 }
 
 #[derive(Default)]
@@ -91,17 +155,31 @@ pub enum SizeHint {
 impl AllocOptions {
     pub fn size_hint(mut self, size: u64) -> Self {
         self.size_hint = SizeHint::Sized(size);
-        return self;
+        self
     }
 
     pub fn growable(mut self) -> Self {
         self.size_hint = SizeHint::Growable;
-        return self;
+        self
     }
 
     pub fn fragment(mut self, fragment: FragmentID) -> Self {
         self.fragment = Some(fragment);
-        return self;
+        self
+    }
+
+    /// How much a growable fragment buffers in memory before it starts writing straight to the end of the store. Buffered fragments are
+    /// placed in the best-fitting free space once they are done, while fragments which outgrow the buffer can't reuse free space at all.
+    /// Defaults to, and is never less than, the store's page size.
+    pub fn buffer_threshold(mut self, bytes: u64) -> Self {
+        self.buffer_threshold = Some(bytes);
+        self
+    }
+
+    /// How much memory a growable fragment's buffer starts with. Defaults to the store's page size, or the buffer threshold if it's smaller.
+    pub fn buffer_capacity(mut self, bytes: usize) -> Self {
+        self.buffer_capacity = Some(bytes);
+        self
     }
 }
 
@@ -118,39 +196,83 @@ pub struct FragmentHandle<'a, Backing: Buffer> {
 #[derive(Debug, Clone)]
 pub(crate) enum FragmentType {
     ReadOnly(SizedFragment),
+
+    /// A fragment whose contents are kept in the fragment table. Like [`Self::ReadOnly`], writing to it starts a new sequence.
+    Inline(Cursor<Vec<u8>>),
     Sized(SizedFragment),
     Dynamic(DynamicFragment),
 }
 
 impl<'a, Backing: Buffer> FragmentHandle<'a, Backing> {
-    pub fn done(self) -> crate::error::Result<()> {
-        std::mem::drop(self);
-        Ok(())
+    /// Finishes writing the fragment and records it, returning its ID. Nothing is recorded if this fails. Dropping the handle records it
+    /// too, but can only log a failure, so writers which need to know whether their fragment was recorded should commit it.
+    pub fn commit(mut self) -> crate::error::Result<FragmentID> {
+        let closed = self.close();
+
+        if closed.is_err() {
+            self.release();
+        }
+
+        closed.map(|()| self.id)
+    }
+
+    /// Throws away whatever has been written to the fragment, leaving its previous sequence as the newest one.
+    pub fn abandon(mut self) {
+        self.release();
     }
 
     pub fn size(&self) -> usize {
         match self.fragment_type {
             FragmentType::ReadOnly(SizedFragment { size, .. }) | FragmentType::Sized(SizedFragment { size, .. }) => size as usize,
-            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref buf), .. }) => buf.get_ref().len(),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref buf), .. }) | FragmentType::Inline(ref buf) => buf.get_ref().len(),
             FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(.., size), .. }) => size as usize,
         }
     }
+
+    /// Reads the rest of the fragment onto the end of `buf`, making room for all of it at once. Reusing one buffer for many fragments saves
+    /// allocating a fresh one for each.
+    pub fn read_into(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        buf.reserve(self.size());
+        self.read_to_end(buf)
+    }
+}
+
+impl<'a, Backing: Buffer + InMemory> FragmentHandle<'a, Backing> {
+    /// Borrows the whole fragment from the backing buffer instead of copying it. Pages waiting in the cache are written back first, so the
+    /// slice holds everything written to the fragment so far.
+    pub fn as_slice(&mut self) -> crate::error::Result<&[u8]> {
+        let (ptr, size) = match self.fragment_type {
+            FragmentType::ReadOnly(SizedFragment { ptr, size, .. }) | FragmentType::Sized(SizedFragment { ptr, size, .. }) => (ptr, size),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(ptr, size), .. }) => (ptr, size),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref buf), .. }) | FragmentType::Inline(ref buf) => return Ok(buf.get_ref()),
+        };
+
+        self.index.backing.flush()?;
+
+        let range = ptr as usize..ptr.saturating_add(size) as usize;
+        self.index.backing.get_ref().bytes().get(range).ok_or_else(|| FragmentError::Truncated(self.id).into())
+    }
 }
 
 impl<'a, Backing: Buffer> Read for FragmentHandle<'a, Backing> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let _timer = self.index.metrics.time(Operation::Read);
+
         match self.fragment_type {
             FragmentType::ReadOnly(ref mut frag) | FragmentType::Sized(ref mut frag) => {
+                // Never read past the end of the fragment into whatever follows it.
+                let remaining = frag.size.saturating_sub(frag.cursor).min(buf.len() as u64) as usize;
+
                 let start = self.index.backing.stream_position()?;
                 self.index.backing.seek(SeekFrom::Start(frag.ptr + frag.cursor))?;
-                let read = self.index.backing.read(buf)?;
+                let read = self.index.backing.read(&mut buf[..remaining])?;
                 self.index.backing.seek(SeekFrom::Start(start))?;
                 frag.cursor += read as u64;
                 Ok(read)
             }
             FragmentType::Dynamic(DynamicFragment {
                 buffer: InlineBuffer::Buffered(ref mut cursor), ..
-            }) => cursor.read(buf),
+            }) | FragmentType::Inline(ref mut cursor) => cursor.read(buf),
             FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(..), .. }) => self.index.backing.read(buf),
         }
     }
@@ -158,25 +280,32 @@ impl<'a, Backing: Buffer> Read for FragmentHandle<'a, Backing> {
 
 impl<'a, Backing: Buffer> Write for FragmentHandle<'a, Backing> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if let FragmentType::ReadOnly(SizedFragment { size, .. }) = self.fragment_type {
+        let _timer = self.index.metrics.time(Operation::Write);
+
+        if let FragmentType::ReadOnly(..) | FragmentType::Inline(..) = self.fragment_type {
+            // Fragments are never modified in place. Writing to one starts its next sequence instead.
             let alloc = AllocOptions::default()
-                .size_hint(size)
                 .growable()
                 .fragment(self.id);
 
-            self.fragment_type = self.index
-                .new_fragment(alloc)
-                .map_err(Error::other)?
-                .fragment_type
-                .clone();
+            let (_, sequence, fragment_type) = self.index
+                .alloc_fragment(alloc)
+                .map_err(Error::other)?;
+
+            self.sequence = sequence;
+            self.fragment_type = fragment_type;
         }
 
         match self.fragment_type {
-            FragmentType::ReadOnly(ref mut frag) => {
-                log::trace!("FragmentType is still ReadOnly after write. There's probably something seriously wrong.");
+            FragmentType::ReadOnly(..) | FragmentType::Inline(..) => {
+                tracing::trace!("FragmentType is still ReadOnly after write. There's probably something seriously wrong.");
                 unreachable!()
             },
             FragmentType::Sized(ref mut frag) => {
+                if frag.cursor + buf.len() as u64 > frag.size {
+                    return Err(Error::new(ErrorKind::WriteZero, "write exceeds fragment bounds"));
+                }
+
                 let start = self.index.backing.stream_position()?;
                 self.index.backing.seek(SeekFrom::Start(frag.ptr + frag.cursor))?;
                 let written = self.index.backing.write(buf)?;
@@ -189,21 +318,33 @@ impl<'a, Backing: Buffer> Write for FragmentHandle<'a, Backing> {
                     && cursor.get_ref().len() + buf.len() > frag.buffer_threshold as usize {
 
                     // Switch to write-through mode.
-                    // 1. Allocate a new fragment
-                    // 2. Copy the buffer over to the fragment
-                    // 3. Switch to write-through mode
+                    // The buffered data is moved to the end of the backing buffer, where the fragment can keep growing until it is closed.
+                    let ptr = self.index.header.end.next_multiple_of(self.index.header.page_size);
+                    let len = cursor.get_ref().len() as u64;
 
-                    // let (ptr, size) = self.index.header
-                    //     .allocate_fragment(cursor.get_ref().len() as u64)
-                    //     .map_err(Error::other)?;
+                    self.index.header.grow_to(ptr + len)?;
 
-                    self.index.backing.write_all(cursor.get_mut())?;
-                    frag.buffer = InlineBuffer::WriteThrough(self.index.header.end.next_multiple_of(PAGE_SIZE as u64), (cursor.get_ref().len() + buf.len()) as u64);
+                    let start = self.index.backing.stream_position()?;
+                    self.index.backing.seek(SeekFrom::Start(ptr))?;
+                    self.index.backing.write_all(cursor.get_ref())?;
+                    self.index.backing.seek(SeekFrom::Start(start))?;
+
+                    frag.buffer = InlineBuffer::WriteThrough(ptr, len);
                 }
 
                 match frag.buffer {
                     InlineBuffer::Buffered(ref mut cursor) => cursor.write(buf),
-                    InlineBuffer::WriteThrough(..) => self.index.backing.write(buf),
+                    InlineBuffer::WriteThrough(ptr, ref mut size) => {
+                        self.index.header.grow_to(ptr + *size + buf.len() as u64)?;
+
+                        let start = self.index.backing.stream_position()?;
+                        self.index.backing.seek(SeekFrom::Start(ptr + *size))?;
+                        let written = self.index.backing.write(buf)?;
+                        self.index.backing.seek(SeekFrom::Start(start))?;
+
+                        *size += written as u64;
+                        Ok(written)
+                    },
                 }
             },
         }
@@ -211,7 +352,7 @@ impl<'a, Backing: Buffer> Write for FragmentHandle<'a, Backing> {
 
     fn flush(&mut self) -> std::io::Result<()> {
         if let FragmentType::ReadOnly(..) = self.fragment_type {
-            log::trace!("Flushing a read-only fragment. This does nothing.");
+            tracing::trace!("Flushing a read-only fragment. This does nothing.");
         }
 
         self.index.backing.flush()
@@ -223,7 +364,7 @@ impl<'a, Backing: Buffer> Seek for FragmentHandle<'a, Backing> {
         match self.fragment_type {
             FragmentType::ReadOnly(ref mut sized) | FragmentType::Sized(ref mut sized) => {
                 // 1) decide the upper‐bound for this fragment
-                let bound = sized.max_size.unwrap_or(sized.size);
+                let bound = sized.max_size.unwrap_or(sized.size).max(sized.size);
 
                 // 2) build a reusable constructor for our InvalidInput error
                 let invalid = || Error::new(ErrorKind::InvalidInput, "seek beyond fragment bounds");
@@ -252,7 +393,7 @@ impl<'a, Backing: Buffer> Seek for FragmentHandle<'a, Backing> {
                 Ok(sized.cursor)
             }
 
-            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref mut cursor), .. }) => cursor.seek(pos),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref mut cursor), .. }) | FragmentType::Inline(ref mut cursor) => cursor.seek(pos),
             FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(..), .. }) => self.index.backing.seek(pos),
         }
     }
@@ -260,46 +401,89 @@ impl<'a, Backing: Buffer> Seek for FragmentHandle<'a, Backing> {
 
 impl<'a, Backing: Buffer> Drop for FragmentHandle<'a, Backing> {
     fn drop(&mut self) {
-        self.flush().expect("Failed to flush");
+        // A handle dropped while the thread unwinds was most likely cut off part-way through being written, so what it holds is thrown
+        // away rather than recorded.
+        if std::thread::panicking() {
+            self.release();
+            return;
+        }
 
-        match self.fragment_type {
+        // A failure can't be reported from here, so the fragment is thrown away instead. The store stops accepting writes in case the
+        // failure left it inconsistent, rather than taking the process down with it.
+        if let Err(err) = self.close() {
+            tracing::error!(fragment = self.id, "Failed to record a fragment when its handle was dropped: {:?}", err);
+            self.release();
+            self.index.poisoned = true;
+        }
+    }
+}
+
+impl<'a, Backing: Buffer> FragmentHandle<'a, Backing> {
+    /// Adds the fragment's sequence to the fragment table, allocating space for buffered data first. Buffered data of at most
+    /// [`MAX_INLINE_SIZE`] bytes is kept in the table instead. Afterwards the handle is read-only.
+    fn close(&mut self) -> crate::error::Result<()> {
+        if let FragmentType::ReadOnly(..) | FragmentType::Inline(..) = self.fragment_type {
+            return Ok(());
+        }
+
+        let _timer = self.index.metrics.time(Operation::Commit);
+
+        let (offset, length, inline) = match self.fragment_type {
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref buf), .. }) if buf.get_ref().len() as u64 <= MAX_INLINE_SIZE => {
+                (INLINE, buf.get_ref().len() as u64, Some(buf.get_ref().clone()))
+            }
             FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(ref mut buf), .. }) => {
-                let (ptr, size) = self
-                    .index
-                    .header
-                    .allocate_fragment(buf.get_ref().len() as u64)
-                    .expect("Closing fragment failed. The fragment was not written.");
-
-                self.index
-                    .backing
-                    .seek(SeekFrom::Start(ptr))
-                    .expect("Failed to flush dynamic fragment");
-                self.index
-                    .backing
-                    .write_all(buf.get_ref())
-                    .expect("Failed to flush dynamic fragment");
-                self.index
-                    .header
-                    .push_fragment_descriptor(FragmentDescriptor {
-                        id: self.id,
-                        sequence: self.sequence,
-                        offset: ptr,
-                        length: size,
-                    })
-                    .expect("Closing fragment failed. The database is in a corrupt state.");
+                let length = buf.get_ref().len() as u64;
+                let (ptr, _) = self.index.header.allocate_fragment(length)?;
+
+                self.index.backing.seek(SeekFrom::Start(ptr))?;
+                self.index.backing.write_all(buf.get_ref())?;
+
+                (ptr, length, None)
             }
-            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(ptr, size), .. }) => self
-                .index
-                .header
-                .push_fragment_descriptor(FragmentDescriptor {
-                    id: self.id,
-                    sequence: self.sequence,
-                    offset: ptr,
-                    length: size,
-                })
-                .expect("Closing fragment failed. The database is in a corrupt state."),
-            _ => {}
+            FragmentType::Sized(SizedFragment { ptr, size, .. }) => (ptr, size, None),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(ptr, size), .. }) => (ptr, size, None),
+            FragmentType::ReadOnly(..) | FragmentType::Inline(..) => unreachable!("Read-only handles have nothing to commit"),
+        };
+
+        self.index.header.push_fragment_descriptor(FragmentDescriptor {
+            id: self.id,
+            sequence: self.sequence,
+            offset,
+            length,
+            inline,
+            written: Some(crate::rw::now()),
+        })?;
+
+        self.discard();
+
+        self.index.committed()
+    }
+
+    /// Gives the space set aside for the fragment back to the store, then leaves the handle read-only without recording anything it has
+    /// written.
+    fn release(&mut self) {
+        let allocation = match self.fragment_type {
+            FragmentType::Sized(SizedFragment { ptr, size, max_size, .. }) => Some((ptr, max_size.unwrap_or(size))),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::WriteThrough(ptr, size), .. }) => Some((ptr, size.next_multiple_of(self.index.header.page_size))),
+            FragmentType::Dynamic(DynamicFragment { buffer: InlineBuffer::Buffered(..), .. }) | FragmentType::ReadOnly(..) | FragmentType::Inline(..) => None,
+        };
+
+        if let Some((ptr, size)) = allocation {
+            self.index.header.free_space.free(ptr, size);
         }
+
+        self.discard();
+    }
+
+    /// Leaves the handle read-only without recording anything it has written.
+    fn discard(&mut self) {
+        self.fragment_type = FragmentType::ReadOnly(SizedFragment {
+            cursor: 0,
+            ptr: crate::rw::TOMBSTONE,
+            size: 0,
+            max_size: Some(0),
+        });
     }
 }
 
@@ -332,9 +516,14 @@ enum InlineBuffer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::assert_matches::assert_matches;
+    use std::assert_matches;
     use std::io::Cursor;
     use std::io::Result;
+    use crate::store::FragmentStore;
+    use crate::rw::Storage;
+    use std::time::{Duration, SystemTime};
+
+    const PAGE_SIZE: usize = crate::rw::DEFAULT_PAGE_SIZE as usize;
 
     #[test]
     pub fn test_reposition_seek() -> Result<()> {
@@ -393,14 +582,47 @@ mod tests {
             fragment.flush()?;
         }
 
-        assert_eq!(backing.backing.get_ref()[256..267], *b"hello world");
+        assert_eq!(backing.backing.get_ref().get_ref()[256..267], *b"hello world");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_read_stops_at_fragment_end() -> Result<()> {
+        let mut backing = Cursor::new(vec![0; 1024]);
+
+        let mut backing = RWFragmentStore::blank(&mut backing).map_err(Error::other)?;
+        backing.backing.get_mut().get_mut()[256..267].copy_from_slice(b"hello world");
+
+        let mut fragment = FragmentHandle {
+            index: &mut backing,
+
+            id: 1,
+            sequence: 1,
+
+            fragment_type: FragmentType::ReadOnly(SizedFragment {
+                cursor: 0,
+                ptr: 256,
+                size: 5,
+                max_size: Some(0),
+            }),
+        };
+
+        let mut contents = vec![];
+        fragment.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"hello");
+
+        assert_matches!(fragment.seek(SeekFrom::Start(1)), Ok(1));
+        contents.clear();
+        fragment.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"ello");
 
         Ok(())
     }
 
     #[test]
     pub fn test_dynamic_fragment() -> crate::error::Result<()> {
-        let mut store = RWFragmentStore::new(Cursor::new(vec![0; 1024]))?;
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![0; 1024]))?;
 
         {
             let mut frag = store.new_fragment(AllocOptions::default().size_hint(100))?;
@@ -411,4 +633,538 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_fragments_survive_reopen() -> crate::error::Result<()> {
+        let small = b"Hello World!".to_vec();
+        let large = (0..3 * PAGE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![]))?;
+
+        store.new_fragment(AllocOptions::default().fragment(1))?.write_all(&small)?;
+        store.new_fragment(AllocOptions::default().fragment(2))?.write_all(&large)?;
+        store.flush()?;
+
+        let mut store = RWFragmentStore::new(store.into_inner()?)?;
+
+        for (id, expected) in [(1, &small), (2, &large)] {
+            let mut contents = vec![];
+            store.open_fragment(id)?.read_to_end(&mut contents)?;
+            assert_eq!(&contents, expected);
+        }
+
+        // Writing to an existing fragment replaces its contents with a new sequence
+        store.open_fragment(1)?.write_all(b"Goodbye")?;
+
+        let mut contents = vec![];
+        let mut fragment = store.open_fragment(1)?;
+        assert_eq!(fragment.sequence, 2);
+        fragment.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"Goodbye");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_conditional_writes_only_land_on_the_expected_sequence() -> crate::error::Result<()> {
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![]))?;
+
+        assert_eq!(store.write_if_sequence(1, 0, b"first")?, 1);
+        assert_eq!(store.write_if_sequence(1, 1, b"second")?, 2);
+
+        // A writer still holding sequence 1 lost the race, and a creator finds the fragment already there.
+        for expected in [1, 0] {
+            let conflict = store.write_if_sequence(1, expected, b"stale").unwrap_err();
+            assert_matches!(conflict.inner(), crate::error::global::Inner::FragmentError(FragmentError::SequenceConflict { id: 1, found: 2, .. }));
+        }
+
+        let mut contents = vec![];
+        store.open_fragment(1)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"second");
+
+        // A deleted fragment can be created again.
+        store.delete_fragment(1)?;
+        assert_eq!(store.write_if_sequence(1, 0, b"third")?, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fragments_can_be_borrowed_from_memory() -> crate::error::Result<()> {
+        let small = b"Hello World!".to_vec();
+        let large = (0..3 * PAGE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![]))?;
+        store.new_fragment(AllocOptions::default().fragment(1))?.write_all(&small)?;
+        store.new_fragment(AllocOptions::default().fragment(2))?.write_all(&large)?;
+
+        // Nothing has been flushed, so the slices can only be right if the cache is written back first.
+        assert_eq!(store.open_fragment(1)?.as_slice()?, small.as_slice());
+        assert_eq!(store.open_fragment(2)?.as_slice()?, large.as_slice());
+
+        // Without borrowing, one buffer can still be reused for every fragment.
+        let mut contents = vec![];
+        store.open_fragment(1)?.read_into(&mut contents)?;
+        store.open_fragment(2)?.read_into(&mut contents)?;
+        assert_eq!(contents, [small, large].concat());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fragments_lists_newest_sequences() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+
+        db.new_fragment(AllocOptions::default().fragment(1))?.write_all(b"Hello")?;
+        db.new_fragment(AllocOptions::default().fragment(2))?.write_all(b"World")?;
+        db.new_fragment(AllocOptions::default().fragment(3))?.write_all(b"!")?;
+        db.open_fragment(1)?.write_all(b"Goodbye")?;
+
+        db.delete_fragment(3)?;
+        assert!(db.delete_fragment(3).is_err());
+
+        let fragments = db.fragments()
+            .map(|frag| (frag.id, frag.sequence, frag.length))
+            .collect::<Vec<_>>();
+
+        assert_eq!(fragments, vec![(0, 0, PAGE_SIZE as u64), (1, 2, 7), (2, 1, 5)]);
+        assert!(db.open_fragment(3).is_err());
+
+        let info = db.fragment_info(1)?;
+        assert_eq!((info.sequence, info.length, info.allocated, info.inline), (2, 7, 0, true));
+        assert!(db.fragment_info(3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_new_fragments_get_fresh_ids() -> crate::error::Result<()> {
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![]))?;
+
+        let mut ids = vec![];
+        for contents in [b"one", b"two", b"six"] {
+            let mut frag = store.new_fragment(AllocOptions::default())?;
+            frag.write_all(contents)?;
+            ids.push(frag.id);
+        }
+
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // Neither a fragment given its own ID nor a deleted one has its ID handed out again, even after the store is reopened.
+        store.new_fragment(AllocOptions::default().fragment(10))?.write_all(b"ten")?;
+        store.delete_fragment(10)?;
+        store.flush()?;
+
+        let mut store = RWFragmentStore::new(store.into_inner()?)?;
+        assert_eq!(store.new_fragment(AllocOptions::default())?.id, 11);
+
+        for (id, expected) in [(1, b"one"), (2, b"two"), (3, b"six")] {
+            let mut contents = vec![];
+            store.open_fragment(id)?.read_to_end(&mut contents)?;
+            assert_eq!(&contents, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_newest_sequences_are_rebuilt_on_open() -> crate::error::Result<()> {
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![]))?;
+
+        // Enough sequences to spill the fragment table into a second part.
+        for round in 0..40u64 {
+            for id in 1..=4 {
+                store.new_fragment(AllocOptions::default().fragment(id))?.write_all(&round.to_le_bytes())?;
+            }
+        }
+
+        store.delete_fragment(2)?;
+        store.delete_fragment(3)?;
+        store.new_fragment(AllocOptions::default().fragment(3))?.write_all(b"back")?;
+        store.flush()?;
+
+        let before = store.header.live_fragments().map(|frag| (frag.id, frag.sequence, frag.offset)).collect::<Vec<_>>();
+        let mut store = RWFragmentStore::new(store.into_inner()?)?;
+        let after = store.header.live_fragments().map(|frag| (frag.id, frag.sequence, frag.offset)).collect::<Vec<_>>();
+
+        assert_eq!(before, after);
+        assert_eq!(after.iter().map(|(id, sequence, _)| (*id, *sequence)).collect::<Vec<_>>(), vec![(0, 0), (1, 40), (3, 42), (4, 40)]);
+
+        assert!(store.open_fragment(2).is_err());
+
+        let mut contents = vec![];
+        store.open_fragment(3)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"back");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_refused_growth_keeps_previous_sequence() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+
+        db.on_grow(|_| Err(Error::new(ErrorKind::StorageFull, "full")));
+
+        assert!(db.write_fragment(AllocOptions::default().fragment(1), &[1u8; 100]).is_err());
+        assert!(db.write_fragment(AllocOptions::default().fragment(1), &vec![0u8; 2 * PAGE_SIZE]).is_err());
+
+        let mut contents = vec![];
+        db.open_fragment(1)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"Hello");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_allocated_size_follows_growth() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        let before = db.allocated_size();
+        assert_eq!(before, 3 * PAGE_SIZE as u64);
+
+        let grown = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        db.on_grow({
+            let grown = grown.clone();
+            move |bytes| {
+                grown.fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+                Ok(())
+            }
+        });
+
+        db.write_fragment(AllocOptions::default().fragment(1), &vec![0u8; 2 * PAGE_SIZE])?;
+
+        assert!(db.allocated_size() > before);
+        assert_eq!(db.allocated_size(), before + grown.load(std::sync::atomic::Ordering::Relaxed));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_stats_skip_deleted_and_superseded_fragments() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        assert_eq!(db.stats().last_modified, None);
+
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Goodbye")?;
+        db.write_fragment(AllocOptions::default().fragment(2), &vec![0u8; 2 * PAGE_SIZE])?;
+        db.write_fragment(AllocOptions::default().fragment(3), b"Gone")?;
+        db.delete_fragment(3)?;
+        let deleted = db.data_source().header.newest(3).and_then(|frag| frag.written);
+
+        let stats = db.stats();
+        let fragments = db.fragments().collect::<Vec<_>>();
+
+        assert_eq!(stats.allocated, db.allocated_size());
+        assert_eq!(stats.fragments, fragments.len() as u64);
+        assert_eq!(stats.live, fragments.iter().map(|frag| frag.length).sum::<u64>());
+        assert_eq!(stats.largest.map(|frag| frag.id), Some(2));
+
+        // The deletion was the last change, even though the deleted fragment isn't counted.
+        assert_eq!(stats.last_modified, deleted.map(|written| SystemTime::UNIX_EPOCH + Duration::from_millis(written)));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_free_space_is_recorded_on_flush() -> crate::error::Result<()> {
+        use crate::rw::{DIRTY, FREE_SPACE_RECORDED};
+
+        let flags = |db: &crate::Database<Cursor<Vec<u8>>>| u32::from_le_bytes(db.backing().get_ref()[24..28].try_into().unwrap());
+        let reopen = |db: crate::Database<Cursor<Vec<u8>>>| crate::Database::new(Cursor::new(db.backing().get_ref().clone()));
+
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        // Growing the store for a fragment which is then abandoned leaves a gap behind the fragments written after it, if they are too
+        // large to fit in it.
+        let mut db = crate::Database::new(backing)?;
+        let mut abandoned = db.new_fragment(AllocOptions::default().fragment(1))?;
+        abandoned.write_all(&vec![1u8; 4 * PAGE_SIZE])?;
+        abandoned.abandon();
+        db.write_fragment(AllocOptions::default().fragment(2), &vec![2u8; 5 * PAGE_SIZE])?;
+        db.flush()?;
+
+        // Stores written before the map was recorded have no flags, and have their map rebuilt.
+        db.backing_mut().get_mut()[24..32].fill(0);
+        let mut db = reopen(db)?;
+        let rebuilt = db.data_source().header.free_space.clone();
+        assert!(rebuilt.total() > 0);
+
+        db.flush()?;
+        assert_eq!(flags(&db), FREE_SPACE_RECORDED);
+
+        let mut db = reopen(db)?;
+        assert_eq!(db.data_source().header.free_space, rebuilt);
+
+        // A store which isn't flushed after it is changed can't trust its recorded map, so it is rebuilt even if the map was lost.
+        db.write_fragment(AllocOptions::default().fragment(3), b"World")?;
+        assert_eq!(flags(&db), DIRTY);
+        db.backing_mut().get_mut()[28..32].fill(0);
+
+        let db = reopen(db)?;
+        assert_eq!(db.data_source().header.free_space, rebuilt);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_forgotten_allocations_are_reclaimed_on_open() -> crate::error::Result<()> {
+        let reopen = |db: crate::Database<Cursor<Vec<u8>>>| crate::Database::new(Cursor::new(db.backing().get_ref().clone()));
+
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        // An abandoned write leaves a gap behind the fragment written after it, which is recorded as free.
+        let mut db = crate::Database::new(backing)?;
+        let mut abandoned = db.new_fragment(AllocOptions::default().fragment(1))?;
+        abandoned.write_all(&vec![1u8; 4 * PAGE_SIZE])?;
+        abandoned.abandon();
+        db.write_fragment(AllocOptions::default().fragment(2), &vec![2u8; 5 * PAGE_SIZE])?;
+        db.flush()?;
+
+        let mut db = reopen(db)?;
+        let free = db.stats().free;
+        assert_eq!(db.reclaimed(), 0);
+
+        // A handle which is forgotten takes its space out of the map without ever giving it back.
+        let mut forgotten = db.new_fragment(AllocOptions::default().fragment(3).size_hint(2 * PAGE_SIZE as u64))?;
+        forgotten.write_all(&vec![3u8; 2 * PAGE_SIZE])?;
+        std::mem::forget(forgotten);
+        db.flush()?;
+        assert!(db.stats().free < free);
+
+        let db = reopen(db)?;
+        assert_eq!(db.reclaimed(), 2 * PAGE_SIZE as u64);
+        assert_eq!(db.stats().free, free);
+        assert!(db.fragment_info(3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_page_size_is_chosen_when_the_store_is_blanked() -> crate::error::Result<()> {
+        let reopen = |db: crate::Database<Cursor<Vec<u8>>>| crate::Database::new(Cursor::new(db.backing().get_ref().clone()));
+
+        assert!(RWFragmentStore::blank_with_page_size(Cursor::new(vec![]), 1000).is_err());
+        assert!(RWFragmentStore::blank_with_page_size(Cursor::new(vec![]), 256).is_err());
+
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank_with_page_size(&mut backing, 512)?;
+
+        let mut db = crate::Database::new(backing)?;
+        assert_eq!(db.page_size(), 512);
+        assert_eq!(db.allocated_size(), 3 * 512);
+
+        db.write_fragment(AllocOptions::default().fragment(1).size_hint(100), &[1u8; 100])?;
+        db.write_fragment(AllocOptions::default().fragment(2), &[2u8; 1000])?;
+
+        for frag in db.fragments() {
+            assert_eq!(frag.offset % 512, 0);
+            assert_eq!(frag.allocated, frag.length.next_multiple_of(512));
+        }
+
+        db.flush()?;
+        let mut db = reopen(db)?;
+        assert_eq!(db.page_size(), 512);
+        assert_eq!(db.open_fragment(2)?.read_to_end(&mut vec![])?, 1000);
+
+        // Stores written before page sizes were recorded always have pages of the default size.
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+        backing.get_mut()[4..8].fill(0);
+        backing.get_mut()[24..28].fill(0);
+
+        assert_eq!(crate::Database::new(backing)?.page_size(), crate::rw::DEFAULT_PAGE_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_root_and_metadata_are_kept_in_the_header() -> crate::error::Result<()> {
+        let reopen = |db: crate::Database<Cursor<Vec<u8>>>| crate::Database::new(Cursor::new(db.backing().get_ref().clone()));
+
+        // Stores of older versions have no metadata, and are upgraded once some is recorded.
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+        backing.get_mut()[4..8].copy_from_slice(&2u32.to_le_bytes());
+        backing.get_mut()[24..28].fill(0);
+
+        let mut db = crate::Database::new(backing)?;
+        assert_eq!(db.root(), 0);
+        assert!(db.set_root(5).is_err());
+
+        db.write_fragment(AllocOptions::default().fragment(5), b"entry point")?;
+        db.set_root(5)?;
+        db.set_metadata("app", "notes")?;
+        db.set_metadata("schema", "3")?;
+        assert!(db.set_metadata("blob", "x".repeat(crate::MAX_METADATA_SIZE)).is_err());
+        db.flush()?;
+
+        let mut db = reopen(db)?;
+        assert_eq!(db.data_source().header.version, 5);
+        assert_eq!(db.root(), 5);
+        assert_eq!(db.metadata_entries().collect::<Vec<_>>(), vec![("app", "notes"), ("schema", "3")]);
+        assert_eq!(db.open_fragment(db.root())?.read_to_end(&mut vec![])?, 11);
+
+        // The free-space map recorded after the metadata is still found.
+        let free_space = db.data_source().header.free_space.clone();
+        assert_eq!(db.remove_metadata("app")?.as_deref(), Some("notes"));
+        db.flush()?;
+
+        let db = reopen(db)?;
+        assert_eq!(db.metadata("schema"), Some("3"));
+        assert_eq!(db.metadata("app"), None);
+        assert_eq!(db.data_source().header.free_space, free_space);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_tiny_fragments_are_kept_in_the_table() -> crate::error::Result<()> {
+        let reopen = |db: crate::Database<Cursor<Vec<u8>>>| crate::Database::new(Cursor::new(db.backing().get_ref().clone()));
+
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        let before = db.allocated_size();
+
+        let ids = (0..=MAX_INLINE_SIZE as u8).map(|i| db.write_fragment(AllocOptions::default(), &vec![i; i as usize])).collect::<crate::error::Result<Vec<_>>>()?;
+        let large = db.write_fragment(AllocOptions::default(), &[1u8; MAX_INLINE_SIZE as usize + 1])?;
+
+        // The tiny fragments only take up room in the table, which spills into two more parts of a page each.
+        assert_eq!(db.allocated_size(), before + 3 * PAGE_SIZE as u64);
+        assert!(!db.fragment_info(large)?.inline);
+
+        db.flush()?;
+        let mut db = reopen(db)?;
+
+        for (i, id) in ids.iter().enumerate() {
+            let info = db.fragment_info(*id)?;
+            assert_eq!((info.length, info.allocated, info.inline), (i as u64, 0, true));
+
+            let mut contents = vec![];
+            db.open_fragment(*id)?.read_to_end(&mut contents)?;
+            assert_eq!(contents, vec![i as u8; i]);
+        }
+
+        // Writing to an inline fragment starts its next sequence, which is only inlined if it is still small enough.
+        db.open_fragment(ids[3])?.write_all(&[9u8; 2 * MAX_INLINE_SIZE as usize])?;
+        assert!(!db.fragment_info(ids[3])?.inline);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fragments_record_when_they_were_written() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        let before = SystemTime::now() - Duration::from_millis(1);
+        let small = db.write_fragment(AllocOptions::default(), b"tiny")?;
+        let large = db.write_fragment(AllocOptions::default(), &[1u8; 2 * PAGE_SIZE])?;
+        db.delete_fragment(small)?;
+        let after = SystemTime::now();
+
+        db.flush()?;
+        let db = crate::Database::new(Cursor::new(db.backing().get_ref().clone()))?;
+
+        let written = db.fragment_info(large)?.timestamp.expect("the write time is recorded");
+        assert!(before <= written && written <= after);
+        assert!(db.data_source().header.fragment_table().filter(|frag| frag.id == small).all(|frag| frag.written.is_some()));
+
+        // Descriptors written before the time was recorded are read back without one, taking up a single slot.
+        let mut table = Cursor::new(vec![]);
+        FragmentDescriptor { id: 1, sequence: 1, offset: 4096, length: 10, inline: None, written: None }.write(&mut table)?;
+        table.set_position(0);
+
+        let frag = FragmentDescriptor::read(&mut table)?;
+        assert_eq!((frag.length, frag.written, frag.slots()), (10, None, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_fragments_cut_off_by_a_panic_are_discarded() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut frag = db.new_fragment(AllocOptions::default().fragment(1)).unwrap();
+            frag.write_all(&vec![1u8; 2 * PAGE_SIZE]).unwrap();
+            panic!("The upload was cut off");
+        }));
+        assert!(interrupted.is_err());
+
+        let mut contents = vec![];
+        db.open_fragment(1)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"Hello");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_failing_to_record_a_dropped_fragment_poisons_the_store() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+
+        db.on_grow(|_| Err(Error::new(ErrorKind::StorageFull, "full")));
+
+        // Committing reports the failure, and leaves the store as it was.
+        let mut frag = db.new_fragment(AllocOptions::default().fragment(1))?;
+        frag.write_all(&[1u8; 100])?;
+        assert!(frag.commit().is_err());
+        assert!(!db.is_poisoned());
+
+        // Dropping can't, so the store turns writes away instead of panicking.
+        db.new_fragment(AllocOptions::default().fragment(1))?.write_all(&[1u8; 100])?;
+        assert!(db.is_poisoned());
+        let refused = db.write_fragment(AllocOptions::default().fragment(2), b"World").unwrap_err();
+        assert_matches!(refused.inner(), crate::error::global::Inner::FragmentError(FragmentError::Poisoned));
+
+        let mut contents = vec![];
+        db.open_fragment(1)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"Hello");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_closed_stores_refuse_writes_but_not_reads() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+        db.close();
+
+        for refused in [db.write_fragment(AllocOptions::default().fragment(2), b"World").map(|_| ()), db.delete_fragment(1)] {
+            assert_matches!(refused.unwrap_err().inner(), crate::error::global::Inner::FragmentError(FragmentError::Closed));
+        }
+
+        let mut contents = vec![];
+        db.open_fragment(1)?.read_to_end(&mut contents)?;
+        assert_eq!(contents, b"Hello");
+
+        Ok(())
+    }
 }
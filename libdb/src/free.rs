@@ -0,0 +1,161 @@
+use crate::rw::Pointer;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The regions of the backing buffer which new fragments may be allocated in.
+///
+/// Extents are kept by offset, so that an extent can be merged with its neighbours when it is freed, and by size, so that allocations
+/// can pick the smallest extent which fits them. Neighbouring extents are always merged, so no two extents ever touch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FreeSpace {
+    by_offset: BTreeMap<Pointer, u64>,
+    by_size: BTreeSet<(u64, Pointer)>,
+}
+
+impl FreeSpace {
+    /// Returns a region to the free space, merging it with the extents either side of it if they touch.
+    pub(crate) fn free(&mut self, mut offset: Pointer, mut size: u64) {
+        if size == 0 {
+            return;
+        }
+
+        if let Some((&before, &before_size)) = self.by_offset.range(..offset).next_back()
+            && before + before_size == offset {
+
+            self.remove(before, before_size);
+            offset = before;
+            size += before_size;
+        }
+
+        if let Some(&after_size) = self.by_offset.get(&(offset + size)) {
+            self.remove(offset + size, after_size);
+            size += after_size;
+        }
+
+        self.by_offset.insert(offset, size);
+        self.by_size.insert((size, offset));
+    }
+
+    /// Takes `size` bytes from the smallest extent which can hold them. Whatever is left of the extent stays free.
+    pub(crate) fn allocate(&mut self, size: u64) -> Option<Pointer> {
+        if size == 0 {
+            return None;
+        }
+
+        let &(extent, offset) = self.by_size.range((size, 0)..).next()?;
+
+        self.remove(offset, extent);
+
+        if extent > size {
+            self.by_offset.insert(offset + size, extent - size);
+            self.by_size.insert((extent - size, offset + size));
+        }
+
+        Some(offset)
+    }
+
+    fn remove(&mut self, offset: Pointer, size: u64) {
+        self.by_offset.remove(&offset);
+        self.by_size.remove(&(size, offset));
+    }
+
+    /// The parts of this map's extents which `other` leaves uncovered.
+    pub(crate) fn without(&self, other: &FreeSpace) -> FreeSpace {
+        let mut uncovered = FreeSpace::default();
+
+        for (offset, size) in self.extents() {
+            let (mut start, end) = (offset, offset + size);
+
+            // The extent before `offset` may still reach into this one.
+            let before = other.by_offset.range(..offset).next_back();
+            for (&other_offset, &other_size) in before.into_iter().chain(other.by_offset.range(offset..end)) {
+                if other_offset > start {
+                    uncovered.free(start, other_offset - start);
+                }
+
+                start = start.max(other_offset + other_size);
+            }
+
+            if start < end {
+                uncovered.free(start, end - start);
+            }
+        }
+
+        uncovered
+    }
+
+    /// Every free extent as (offset, size), in order of offset.
+    pub(crate) fn extents(&self) -> impl Iterator<Item = (Pointer, u64)> + '_ {
+        self.by_offset.iter().map(|(offset, size)| (*offset, *size))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.by_offset.len()
+    }
+
+    /// The number of free bytes across every extent.
+    pub(crate) fn total(&self) -> u64 {
+        self.by_offset.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = 4096;
+
+    #[test]
+    pub fn test_freed_neighbours_are_merged() {
+        let mut free = FreeSpace::default();
+
+        // Freeing every other page, then the pages between them, leaves a single extent.
+        for page in [0, 2, 4] {
+            free.free(page * PAGE, PAGE);
+        }
+        assert_eq!(free.len(), 3);
+
+        free.free(PAGE, PAGE);
+        free.free(3 * PAGE, PAGE);
+
+        assert_eq!(free.extents().collect::<Vec<_>>(), vec![(0, 5 * PAGE)]);
+        assert_eq!(free.total(), 5 * PAGE);
+    }
+
+    #[test]
+    pub fn test_allocations_take_the_best_fit_and_split_it() {
+        let mut free = FreeSpace::default();
+        free.free(0, 8 * PAGE);
+        free.free(10 * PAGE, 2 * PAGE);
+        free.free(20 * PAGE, 3 * PAGE);
+
+        // The smallest extent which fits is used, even if a larger one comes first.
+        assert_eq!(free.allocate(2 * PAGE), Some(10 * PAGE));
+
+        // An oversized extent is split, and the rest of it stays free.
+        assert_eq!(free.allocate(PAGE), Some(20 * PAGE));
+        assert_eq!(free.allocate(2 * PAGE), Some(21 * PAGE));
+        assert_eq!(free.allocate(5 * PAGE), Some(0));
+        assert_eq!(free.extents().collect::<Vec<_>>(), vec![(5 * PAGE, 3 * PAGE)]);
+
+        assert_eq!(free.allocate(4 * PAGE), None);
+
+        // Giving back what was split off rejoins the extent it came from.
+        free.free(0, 5 * PAGE);
+        assert_eq!(free.extents().collect::<Vec<_>>(), vec![(0, 8 * PAGE)]);
+    }
+
+    #[test]
+    pub fn test_without_keeps_only_what_is_uncovered() {
+        let mut free = FreeSpace::default();
+        free.free(0, 4 * PAGE);
+        free.free(10 * PAGE, 4 * PAGE);
+
+        let mut other = FreeSpace::default();
+        other.free(0, PAGE);
+        other.free(2 * PAGE, PAGE);
+        other.free(12 * PAGE, 8 * PAGE);
+
+        assert_eq!(free.without(&other).extents().collect::<Vec<_>>(), vec![(PAGE, PAGE), (3 * PAGE, PAGE), (10 * PAGE, 2 * PAGE)]);
+        assert_eq!(free.without(&free).len(), 0);
+    }
+}
@@ -0,0 +1,30 @@
+//! Entry points for the fuzz targets in `libdb/fuzz`, which can't reach the parsers they feed otherwise. Each takes whatever bytes it is
+//! given, and must return rather than panic however corrupt they are.
+
+use crate::rw::{RWFragmentStoreIndex, Storage};
+use crate::{Database, Fragment};
+use std::io::{Cursor, Read};
+
+/// Parses `bytes` as the header and fragment table at the start of a store.
+pub fn read_store_index(bytes: &[u8]) {
+    let _ = <RWFragmentStoreIndex as Storage<_>>::read(Cursor::new(bytes.to_vec()));
+}
+
+/// Parses `bytes` as a fragment and the contents which follow it.
+pub fn read_fragment(bytes: &[u8]) {
+    let _ = <Fragment as Storage<_>>::read(Cursor::new(bytes.to_vec()));
+}
+
+/// Opens `bytes` as a store and reads back every fragment in it, as far as it can be read. Fragments are read up to a mebibyte at most, so
+/// a corrupt length can't make this run for as long as it names.
+pub fn read_store(bytes: &[u8]) {
+    let Ok(mut db) = Database::new(Cursor::new(bytes.to_vec())) else {
+        return;
+    };
+
+    for id in db.fragments().map(|info| info.id).collect::<Vec<_>>() {
+        if let Ok(fragment) = db.open_fragment(id) {
+            let _ = fragment.take(1 << 20).read_to_end(&mut Vec::new());
+        }
+    }
+}
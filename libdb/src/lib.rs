@@ -1,18 +1,28 @@
-#![feature(btree_cursors)]
-#![feature(exact_size_is_empty)]
-#![feature(sized_hierarchy)]
-#![feature(assert_matches)]
 extern crate core;
 
 use crate::rw::RWFragmentStore;
+use crate::progress::Progress;
 use error::*;
-use std::io::{Read, Seek, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::time::SystemTime;
 
 pub mod error;
 mod rw;
 pub mod store;
+pub mod lock;
+pub mod metrics;
+pub mod archive;
 mod fragment;
+mod free;
+mod cache;
+mod migrate;
+pub mod sizing;
+pub mod progress;
+mod durability;
+mod salvage;
+mod savepoint;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzzing;
 
 #[derive(Debug)]
 pub struct Database<Backing: Read + Write + Seek> {
@@ -29,16 +39,101 @@ impl<Backing: Read + Write + Seek> Database<Backing> {
     ///
     /// **Please use this function extremely carefully.**
     ///
-    pub fn destructive_reinitialise(mut backing: Backing, _danger: Danger) -> Result<()> {
-        log::warn!("Destructively reinitialising database.");
-        RWFragmentStore::blank(&mut backing)?;
+    pub fn destructive_reinitialise(backing: Backing, danger: Danger) -> Result<()> {
+        Self::destructive_reinitialise_with_page_size(backing, DEFAULT_PAGE_SIZE, danger)
+    }
+
+    /// Like [`Database::destructive_reinitialise`], but the store allocates its space in pages of `page_size` bytes. See
+    /// [`valid_page_size`].
+    pub fn destructive_reinitialise_with_page_size(mut backing: Backing, page_size: u32, _danger: Danger) -> Result<()> {
+        tracing::warn!("Destructively reinitialising database.");
+        RWFragmentStore::blank_with_page_size(&mut backing, page_size)?;
 
         Ok(())
     }
     
-    // pub fn new_fragment(&'_ mut self) -> Result<FragmentHandle<'_, Backing>> {
-    //     self.backing.new_fragment(AllocOptions::default())
-    // }
+    /// Starts writing a new fragment, or the next sequence of an existing one if `options` names it.
+    pub fn new_fragment(&mut self, options: impl Into<AllocOptions>) -> Result<FragmentHandle<'_, Backing>> {
+        self.data_source.new_fragment(options)
+    }
+
+    /// Writes `data` as a whole fragment. If any of it can't be written, nothing is recorded and the fragment keeps its previous contents.
+    pub fn write_fragment(&mut self, options: impl Into<AllocOptions>, data: &[u8]) -> Result<FragmentID> {
+        let mut fragment = self.new_fragment(options)?;
+
+        match fragment.write_all(data) {
+            Ok(()) => fragment.commit(),
+            Err(err) => {
+                fragment.abandon();
+                Err(err.into())
+            },
+        }
+    }
+
+    /// Writes `data` as the next sequence of fragment `id` only if its newest sequence is still `expected`. See
+    /// [`RWFragmentStore::write_if_sequence`].
+    pub fn write_if_sequence(&mut self, id: FragmentID, expected: u64, data: &[u8]) -> Result<u64> {
+        self.data_source.write_if_sequence(id, expected, data)
+    }
+
+    /// Installs a hook which is asked before the store's backing buffer grows. See [`RWFragmentStore::on_grow`].
+    pub fn on_grow(&mut self, hook: impl Fn(u64) -> std::io::Result<()> + Send + Sync + 'static) {
+        self.data_source.on_grow(hook)
+    }
+
+    /// How many bytes of the backing buffer the store has allocated. Space held by deleted or superseded fragments is included, since it
+    /// stays allocated until it is reused.
+    pub fn allocated_size(&self) -> u64 {
+        self.data_source.header.end
+    }
+
+    /// The number of bytes the store allocates its space in multiples of. It is chosen when the store is initialised.
+    pub fn page_size(&self) -> u32 {
+        self.data_source.header.page_size as u32
+    }
+
+    /// Moves the store over to pages of `page_size` bytes, copying at most `batch` fragments at a time. See
+    /// [`RWFragmentStore::migrate_page_size`].
+    pub fn migrate_page_size(&mut self, page_size: u32, batch: usize) -> Result<MigrationProgress> {
+        self.data_source.migrate_page_size(page_size, batch)
+    }
+
+    /// The fragment the application treats as the entry point to its data. It is fragment 0 unless another is chosen with
+    /// [`Database::set_root`].
+    pub fn root(&self) -> FragmentID {
+        self.data_source.root()
+    }
+
+    /// Makes `id` the root fragment, once the store is next flushed.
+    pub fn set_root(&mut self, id: FragmentID) -> Result<()> {
+        self.data_source.set_root(id)
+    }
+
+    /// Looks up a metadata entry recorded in the store's header.
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.data_source.metadata().find(|(name, _)| *name == key).map(|(_, value)| value)
+    }
+
+    /// Lists the metadata entries recorded in the store's header, in order of key.
+    pub fn metadata_entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.data_source.metadata()
+    }
+
+    /// Records a metadata entry in the store's header, such as the application's name or the version of its schema. See
+    /// [`RWFragmentStore::set_metadata`].
+    pub fn set_metadata(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        self.data_source.set_metadata(key, value)
+    }
+
+    /// Removes a metadata entry from the store's header, returning its value if it had one.
+    pub fn remove_metadata(&mut self, key: &str) -> Result<Option<String>> {
+        self.data_source.remove_metadata(key)
+    }
+
+    /// Reports the duration of every operation on the store to `metrics`. See [`metrics::StoreMetrics`].
+    pub fn set_metrics(&mut self, metrics: std::sync::Arc<dyn metrics::StoreMetrics>) {
+        self.data_source.set_metrics(metrics)
+    }
 
     /// Provides low-level access to the underlying backing object. **Not recommended for daily use**.
     pub fn data_source(&self) -> &RWFragmentStore<Backing> {
@@ -50,27 +145,225 @@ impl<Backing: Read + Write + Seek> Database<Backing> {
         &mut self.data_source
     }
 
+    /// The backing buffer beneath the page cache. Changes which haven't been flushed may not have reached it yet.
     pub fn backing(&self) -> &Backing {
-        &self.data_source.backing
+        self.data_source.backing.get_ref()
     }
 
+    /// The backing buffer beneath the page cache. Flush the store first, or cached changes may overwrite whatever is written here.
     pub fn backing_mut(&mut self) -> &mut Backing {
-        &mut self.data_source.backing
+        self.data_source.backing.get_mut()
+    }
+
+    /// Changes how many pages of the backing buffer are kept in memory. Defaults to [`DEFAULT_CACHE_PAGES`]. A capacity of 0 turns the cache
+    /// off, so every read and write goes straight to the backing buffer.
+    pub fn set_cache_capacity(&mut self, pages: usize) -> Result<()> {
+        self.data_source.set_cache_capacity(pages)
+    }
+
+    /// Whether the store refuses writes because a fragment couldn't be recorded when its handle was dropped. See
+    /// [`FragmentError::Poisoned`].
+    pub fn is_poisoned(&self) -> bool {
+        self.data_source.poisoned
+    }
+
+    /// Closes the store to writes, so every change from now on fails with [`FragmentError::Closed`]. Reads still work.
+    ///
+    /// Meant for when the file behind the store is about to be moved, replaced or deleted while others may still hold the store, so a write
+    /// which reaches it afterwards fails rather than landing in a file nobody will open again.
+    pub fn close(&mut self) {
+        self.data_source.closed = true;
+    }
+
+    /// Whether the store has changes which haven't been flushed. See [`Database::flush`].
+    pub fn is_dirty(&self) -> bool {
+        self.data_source.header.dirty
+    }
+
+    /// Flushes the store, then syncs its backing buffer to its disk, so everything written so far survives power loss whatever the store's
+    /// [`Durability`].
+    pub fn sync(&mut self) -> Result<()> where Backing: SyncData {
+        self.data_source.flush()?;
+        Ok(self.data_source.backing.sync_data()?)
+    }
+
+    /// Chooses how durable each commit is before it returns. Stores are [`Durability::Relaxed`] unless told otherwise.
+    pub fn set_durability(&mut self, durability: Durability) where Backing: SyncData {
+        self.data_source.set_durability(durability)
     }
 
+    /// Flushes the store and returns its backing buffer.
+    pub fn into_inner(mut self) -> Result<Backing> {
+        self.data_source.flush()?;
+        self.data_source.into_inner()
+    }
+
+    /// Writes any pending changes to the header and fragment table back into the backing buffer and flushes it.
     pub fn flush(&mut self) -> Result<()> {
-        Ok(self.data_source.backing.flush()?)
+        self.data_source.flush()
     }
 
     pub fn open_fragment(&mut self, id: FragmentID) -> Result<FragmentHandle<'_, Backing>> {
         self.data_source.open_fragment(id)
     }
+
+    /// Deletes a fragment, so it can no longer be opened and is left out of [`Database::fragments`].
+    pub fn delete_fragment(&mut self, id: FragmentID) -> Result<()> {
+        self.data_source.delete_fragment(id)
+    }
+
+    /// Lists the newest sequence of every fragment in the store, in order of ID. Deleted fragments are skipped.
+    pub fn fragments(&self) -> impl Iterator<Item = FragmentInfo> + '_ {
+        let page_size = self.data_source.header.page_size;
+
+        self.data_source.header.live_fragments().map(move |frag| FragmentInfo::describe(frag, page_size))
+    }
+
+    /// Describes the newest sequence of a fragment without opening it.
+    pub fn fragment_info(&self, id: FragmentID) -> Result<FragmentInfo> {
+        match self.data_source.header.newest(id).filter(|frag| !frag.is_tombstone()) {
+            Some(frag) => Ok(FragmentInfo::describe(frag, self.data_source.header.page_size)),
+            None => FragmentError::not_found(id),
+        }
+    }
+
+    /// The bytes which were allocated to no fragment and missing from the recorded free-space map when the store was opened, and which were
+    /// returned to the free space then. They are left behind if a [`FragmentHandle`] is forgotten rather than committed or dropped, and the
+    /// store is flushed before it is closed.
+    pub fn reclaimed(&self) -> u64 {
+        self.data_source.header.reclaimed
+    }
+
+    /// Summarises how the store uses its backing buffer. Only the header and fragment table are consulted, never the fragments themselves.
+    pub fn stats(&self) -> StoreStats {
+        let header = &self.data_source.header;
+
+        let (fragments, live, largest) = header.live_fragments().fold((0, 0, None::<FragmentInfo>), |(count, live, largest), frag| {
+            let largest = match largest {
+                Some(largest) if largest.length >= frag.length => Some(largest),
+                _ => Some(FragmentInfo::describe(frag, header.page_size)),
+            };
+
+            (count + 1, live + frag.length, largest)
+        });
+
+        let last_modified = header.last_written().map(|written| SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(written));
+
+        StoreStats {
+            allocated: header.end,
+            live,
+            free: header.free_space.total(),
+            fragments,
+            largest,
+            last_modified,
+        }
+    }
+
+    /// Reads back up to `sample` of the store's fragments, spread evenly across it, and returns the IDs of those which reach past the end
+    /// of the backing buffer or can't be read in full. Fragment hashes aren't recorded yet, so their contents can't be checked. Each
+    /// fragment is reported to `progress` before it is read.
+    pub fn scrub(&mut self, sample: usize, progress: &Progress) -> Result<Vec<FragmentID>> {
+        // Pages waiting in the cache count towards the end of the backing buffer too.
+        self.data_source.backing.flush()?;
+        let backing = self.backing_mut();
+        let start = backing.stream_position()?;
+        let end = backing.seek(SeekFrom::End(0))?;
+        backing.seek(SeekFrom::Start(start))?;
+
+        let fragments = self.fragments().collect::<Vec<_>>();
+        let sampled = fragments.iter().step_by((fragments.len() / sample.max(1)).max(1)).take(sample).collect::<Vec<_>>();
+
+        let mut damaged = vec![];
+        let mut contents = vec![];
+
+        for (done, frag) in sampled.iter().enumerate() {
+            progress.step(done as u64, sampled.len() as u64)?;
+
+            if frag.offset.saturating_add(frag.length) > end {
+                damaged.push(frag.id);
+                continue;
+            }
+
+            contents.clear();
+            match self.open_fragment(frag.id)?.read_into(&mut contents) {
+                Ok(read) if read as u64 == frag.length => (),
+                _ => damaged.push(frag.id),
+            }
+        }
+
+        Ok(damaged)
+    }
+}
+
+/// How a store uses its backing buffer. See [`Database::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreStats {
+    /// The bytes of the backing buffer the store has allocated. See [`Database::allocated_size`].
+    pub allocated: u64,
+
+    /// The bytes held by the newest sequence of every fragment which hasn't been deleted.
+    pub live: u64,
+
+    /// The bytes between fragments which new fragments may be allocated in.
+    pub free: u64,
+
+    /// How many fragments haven't been deleted.
+    pub fragments: u64,
+
+    pub largest: Option<FragmentInfo>,
+
+    /// When a fragment was last written or deleted, as far as the fragment table records. `None` if nothing has been written since the
+    /// store began recording write times.
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Describes a fragment without opening it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentInfo {
+    pub id: FragmentID,
+    pub sequence: u64,
+
+    /// Where the fragment's data begins in the backing buffer, or 0 if it is inline.
+    pub offset: u64,
+
+    /// The number of bytes in the fragment.
+    pub length: u64,
+
+    /// The space set aside for the fragment. Space is allocated in whole pages, so this is `length` rounded up to the next page. Inline
+    /// fragments have none set aside.
+    pub allocated: u64,
+
+    /// Whether the fragment's contents are kept in the fragment table. See [`MAX_INLINE_SIZE`].
+    pub inline: bool,
+
+    /// The hash of the fragment's contents. The fragment table doesn't record this yet, so it is always `None` for now.
+    pub hash: Option<FragmentHash>,
+
+    /// When the fragment's newest sequence was written, or `None` for sequences written by stores before version 5, which didn't record
+    /// it.
+    pub timestamp: Option<SystemTime>,
+}
+
+impl FragmentInfo {
+    fn describe(frag: &rw::FragmentDescriptor, page_size: u64) -> Self {
+        Self {
+            id: frag.id,
+            sequence: frag.sequence,
+            offset: if frag.is_inline() { 0 } else { frag.offset },
+            length: frag.length,
+            allocated: if frag.is_inline() { 0 } else { frag.length.next_multiple_of(page_size) },
+            inline: frag.is_inline(),
+            hash: None,
+            timestamp: frag.written.map(|written| SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(written)),
+        }
+    }
 }
 
 pub type FragmentID = u64;
 
 pub struct Fragment {
     id: FragmentID,
+    #[allow(dead_code)]
     hash: FragmentHash,
     timestamp: SystemTime,
     sequence: u64,
@@ -79,12 +372,12 @@ pub struct Fragment {
 
 impl Fragment {
     pub fn validate_hash(self) -> Result<Self> {
-        log::warn!("hash not verified - not implemented");
+        tracing::warn!("hash not verified - not implemented");
         Ok(self)
     }
 
     pub(crate) fn compute_hash(&self) -> Result<[u8; 32]> {
-        log::warn!("hash not verified - not implemented");
+        tracing::warn!("hash not verified - not implemented");
         Ok([0u8; 32])
     }
 }
@@ -110,5 +403,12 @@ pub enum Value {
 pub struct Danger;
 
 pub use fragment::AllocOptions;
+pub use cache::DEFAULT_CACHE_PAGES;
+pub use migrate::MigrationProgress;
+pub use durability::{Durability, SyncData};
+pub use salvage::SalvageReport;
+pub use savepoint::Savepoint;
+pub use rw::{valid_page_size, DEFAULT_PAGE_SIZE, MAX_INLINE_SIZE, MAX_METADATA_SIZE, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
 pub use crate::fragment::FragmentHandle;
+pub use crate::fragment::InMemory;
 use crate::store::FragmentStore;
\ No newline at end of file
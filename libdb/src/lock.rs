@@ -0,0 +1,300 @@
+use crate::error::{LockError, ManualError, Result};
+use crate::{Danger, Database, DEFAULT_PAGE_SIZE};
+use fs2::FileExt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Describes the process which has a store open for writing.
+///
+/// Only one process may write to a store at a time. It holds an exclusive advisory lock on the store file for as long as it has it open, and
+/// records itself in a lock file next to the store (`store.db.lock` for `store.db`). The lock on the store file is what keeps writers apart.
+/// The lock file only tells others who holds it, so a lock file left behind by a process which has since exited is ignored.
+///
+/// A process which can't take the lock may still attach to the store read-only, for instance to inspect it. It then sees the store as it was
+/// when it attached, and must not write to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub pid: u32,
+
+    /// What kind of process holds the lock, such as `server` or `repl`.
+    pub holder: String,
+
+    pub since: SystemTime,
+}
+
+impl LockInfo {
+    /// Describes the current process.
+    pub fn current(holder: impl Into<String>) -> Self {
+        Self {
+            pid: std::process::id(),
+            holder: holder.into(),
+            since: SystemTime::now(),
+        }
+    }
+
+    /// The lock file belonging to the store at `store`.
+    pub fn path(store: &Path) -> PathBuf {
+        let mut path = store.as_os_str().to_owned();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+
+    /// Records this process as the one holding the store's lock. Call this only once the lock on the store file has been taken.
+    pub fn write(&self, store: &Path) -> std::io::Result<()> {
+        let since = self.since.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis();
+        fs::write(Self::path(store), format!("{}\n{}\n{}\n", self.pid, self.holder, since))
+    }
+
+    /// Reads the store's lock file, if there is one and it can be understood.
+    pub fn read(store: &Path) -> std::io::Result<Option<Self>> {
+        let contents = match fs::read_to_string(Self::path(store)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = contents.lines();
+
+        Ok((|| Some(Self {
+            pid: lines.next()?.parse().ok()?,
+            holder: lines.next()?.to_owned(),
+            since: SystemTime::UNIX_EPOCH + Duration::from_millis(lines.next()?.parse().ok()?),
+        }))())
+    }
+
+    /// Removes the store's lock file. Call this before releasing the lock on the store file.
+    pub fn remove(store: &Path) -> std::io::Result<()> {
+        match fs::remove_file(Self::path(store)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let held = self.since.elapsed().unwrap_or_default().as_secs();
+        write!(f, "{} (pid {}) for {}s", self.holder, self.pid, held)
+    }
+}
+
+/// How a process opening a store with [`Database::open_path`] shares it with other processes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// For a process which writes to the store. No other process may hold a lock on it at the same time.
+    Exclusive,
+
+    /// For processes which only read the store. Any number of them may share it, but not while a writer holds it.
+    Shared,
+
+    /// Takes no lock at all, to attach read-only to a store another process is writing to. Only what had been flushed when the store was
+    /// opened can be relied upon.
+    Unlocked,
+}
+
+impl Database<File> {
+    /// Opens the store at `path`, locking it as `mode` asks. See [`LockInfo`] for how processes share stores.
+    ///
+    /// Stores opened exclusively are opened for writing, and are created with pages of [`DEFAULT_PAGE_SIZE`] if the file doesn't exist or
+    /// is empty. The process records itself in the store's lock file, by the name of its executable, until [`Database::unlock`] is called.
+    /// Stores opened any other way must already exist, and are only read.
+    ///
+    /// Fails with [`LockError::Contended`] if another process holds a lock which conflicts with `mode`.
+    pub fn open_path(path: impl AsRef<Path>, mode: LockMode) -> Result<Self> {
+        Self::open_path_with_page_size(path, mode, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Like [`Database::open_path`], but new stores allocate their space in pages of `page_size` bytes.
+    pub fn open_path_with_page_size(path: impl AsRef<Path>, mode: LockMode, page_size: u32) -> Result<Self> {
+        let path = path.as_ref();
+
+        let file = match mode {
+            LockMode::Exclusive => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?,
+            LockMode::Shared | LockMode::Unlocked => File::open(path)?,
+        };
+
+        Self::lock(file, path, mode, Some(page_size))
+    }
+
+    /// Creates a store at `path` with pages of [`DEFAULT_PAGE_SIZE`], and opens it exclusively. Fails if a file is already there, so an
+    /// existing store is never overwritten.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+
+        Self::lock(file, path, LockMode::Exclusive, Some(DEFAULT_PAGE_SIZE))
+    }
+
+    /// Opens the existing store at `path` exclusively. Fails if there is no file there, or with [`ManualError::EmptyStore`] if the file is
+    /// empty, rather than initialising a store in its place.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Self::lock(file, path, LockMode::Exclusive, None)
+    }
+
+    /// Opens the store at `path` exclusively, creating it first if there is no file there or the file is empty. The same as
+    /// [`Database::open_path`] with [`LockMode::Exclusive`].
+    pub fn open_or_create(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_path(path, LockMode::Exclusive)
+    }
+
+    /// Locks `file`, which holds the store at `path`, as `mode` asks, and opens the store. An empty file is only initialised if it is
+    /// locked exclusively and a page size is given for it, so no other process can be initialising it at the same time.
+    fn lock(mut file: File, path: &Path, mode: LockMode, page_size: Option<u32>) -> Result<Self> {
+        let locked = match mode {
+            LockMode::Exclusive => file.try_lock_exclusive(),
+            LockMode::Shared => FileExt::try_lock_shared(&file),
+            LockMode::Unlocked => Ok(()),
+        };
+
+        match locked {
+            Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                return Err(LockError::Contended(path.to_owned(), LockInfo::read(path).ok().flatten()).into());
+            },
+            locked => locked?,
+        }
+
+        if mode != LockMode::Exclusive {
+            return Self::new(file);
+        }
+
+        LockInfo::current(process_name()).write(path)?;
+
+        let store = (|| {
+            if file.metadata()?.len() == 0 {
+                let Some(page_size) = page_size else {
+                    return Err(ManualError::EmptyStore(path.to_owned()).into());
+                };
+
+                Database::destructive_reinitialise_with_page_size(&mut file, page_size, Danger)?;
+            }
+
+            Self::new(file)
+        })();
+
+        if store.is_err() {
+            // The store's lock is released as the file is dropped, so the lock file shouldn't outlive it either.
+            LockInfo::remove(path)?;
+        }
+
+        store
+    }
+
+    /// Releases the lock taken by [`Database::open_path`] on the store at `path`, removing its lock file if this process wrote it. Other
+    /// processes may open the store for writing afterwards, so flush it first.
+    pub fn unlock(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+
+        if LockInfo::read(path)?.is_some_and(|holder| holder.pid == std::process::id()) {
+            LockInfo::remove(path)?;
+        }
+
+        FileExt::unlock(self.backing())?;
+
+        Ok(())
+    }
+}
+
+/// The name the current process records in lock files.
+fn process_name() -> String {
+    std::env::current_exe().ok()
+        .and_then(|exe| exe.file_stem().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "libdb".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    pub fn test_lock_info_round_trip() -> std::io::Result<()> {
+        let store = std::env::temp_dir().join(format!("libdb-lock-test-{}.db", std::process::id()));
+        let info = LockInfo::current("test");
+
+        assert_eq!(LockInfo::read(&store)?, None);
+
+        info.write(&store)?;
+        let read = LockInfo::read(&store)?.expect("Lock file was just written");
+        assert_eq!((read.pid, read.holder.as_str()), (info.pid, "test"));
+
+        LockInfo::remove(&store)?;
+        assert_eq!(LockInfo::read(&store)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_writers_exclude_everyone_else() -> Result<()> {
+        let store = std::env::temp_dir().join(format!("libdb-open-test-{}.db", std::process::id()));
+        let _ = fs::remove_file(&store);
+
+        assert!(Database::open_path(&store, LockMode::Shared).is_err());
+
+        let db = Database::open_path(&store, LockMode::Exclusive)?;
+        assert_eq!(LockInfo::read(&store)?.map(|holder| holder.pid), Some(std::process::id()));
+
+        let contended = Database::open_path(&store, LockMode::Shared).expect_err("The store is locked exclusively");
+        assert!(matches!(contended.inner(), crate::error::global::Inner::LockError(LockError::Contended(_, Some(_)))));
+        assert!(Database::open_path(&store, LockMode::Unlocked).is_ok());
+
+        db.unlock(&store)?;
+        drop(db);
+        assert_eq!(LockInfo::read(&store)?, None);
+
+        // Readers share the store, but keep writers out.
+        let reader = Database::open_path(&store, LockMode::Shared)?;
+        assert!(Database::open_path(&store, LockMode::Shared).is_ok());
+        assert!(Database::open_path(&store, LockMode::Exclusive).is_err());
+
+        drop(reader);
+        fs::remove_file(&store)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_path_constructors_only_initialise_when_asked() -> Result<()> {
+        let store = std::env::temp_dir().join(format!("libdb-create-test-{}.db", std::process::id()));
+        let _ = fs::remove_file(&store);
+
+        assert!(Database::open(&store).is_err());
+
+        let mut db = Database::create(&store)?;
+        let id = db.write_fragment(crate::AllocOptions::default(), b"kept")?;
+        db.flush()?;
+        db.unlock(&store)?;
+        drop(db);
+
+        // An existing store is never overwritten.
+        assert!(Database::create(&store).is_err());
+        let mut db = Database::open(&store)?;
+        assert_eq!(db.open_fragment(id)?.read_to_end(&mut vec![])?, 4);
+        db.unlock(&store)?;
+        drop(db);
+
+        // An empty file is only turned into a store by `open_or_create`.
+        fs::write(&store, b"")?;
+        let empty = Database::open(&store).expect_err("The file is empty");
+        assert!(matches!(empty.inner(), crate::error::global::Inner::ManualError(ManualError::EmptyStore(_))));
+        assert_eq!(fs::metadata(&store)?.len(), 0);
+        assert_eq!(LockInfo::read(&store)?, None);
+
+        let db = Database::open_or_create(&store)?;
+        assert_eq!(db.fragments().count(), 1);
+
+        db.unlock(&store)?;
+        drop(db);
+        fs::remove_file(&store)?;
+
+        Ok(())
+    }
+}
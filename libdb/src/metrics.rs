@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// An operation on a store whose duration is reported to [`StoreMetrics`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Operation {
+    OpenFragment,
+    Read,
+    Write,
+
+    /// Recording a finished fragment in the fragment table, including writing out any data it still had buffered.
+    Commit,
+
+    /// Writing the header and fragment table back to the backing buffer.
+    PersistHeader,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 5] = [Operation::OpenFragment, Operation::Read, Operation::Write, Operation::Commit, Operation::PersistHeader];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::OpenFragment => "open_fragment",
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Commit => "commit",
+            Operation::PersistHeader => "persist_header",
+        }
+    }
+}
+
+/// Receives the time taken by every operation on a store.
+///
+/// It is called on the thread performing the operation, while the store is borrowed, so it should return quickly. Reads and writes are
+/// reported per call rather than per fragment.
+pub trait StoreMetrics: Send + Sync {
+    fn observe(&self, operation: Operation, elapsed: Duration);
+}
+
+#[derive(Default)]
+pub(crate) struct MetricsHook(Option<Arc<dyn StoreMetrics>>);
+
+impl std::fmt::Debug for MetricsHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "MetricsHook(Some(..))" } else { "MetricsHook(None)" })
+    }
+}
+
+impl MetricsHook {
+    pub(crate) fn new(metrics: Arc<dyn StoreMetrics>) -> Self {
+        Self(Some(metrics))
+    }
+
+    /// Starts timing an operation. It is reported once the returned timer is dropped.
+    pub(crate) fn time(&self, operation: Operation) -> Timer {
+        Timer {
+            metrics: self.0.clone(),
+            operation,
+            start: Instant::now(),
+        }
+    }
+}
+
+pub(crate) struct Timer {
+    metrics: Option<Arc<dyn StoreMetrics>>,
+    operation: Operation,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        tracing::trace!(operation = self.operation.name(), elapsed_us = elapsed.as_micros() as u64, "store operation");
+
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe(self.operation, elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rw::RWFragmentStore;
+    use crate::AllocOptions;
+    use std::io::{Cursor, Read};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Operation>>);
+
+    impl StoreMetrics for Recorder {
+        fn observe(&self, operation: Operation, _: Duration) {
+            self.0.lock().unwrap().push(operation);
+        }
+    }
+
+    #[test]
+    pub fn test_operations_are_reported() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let recorder = Arc::new(Recorder::default());
+        let mut db = crate::Database::new(backing)?;
+        db.set_metrics(recorder.clone());
+
+        db.write_fragment(AllocOptions::default().fragment(1), b"Hello")?;
+        db.open_fragment(1)?.read_to_end(&mut vec![])?;
+        db.flush()?;
+
+        let observed = recorder.0.lock().unwrap().clone();
+
+        for operation in Operation::ALL {
+            assert!(observed.contains(&operation), "{:?} wasn't reported", operation);
+        }
+
+        // Dropping a handle which was only read from doesn't count as a commit.
+        assert_eq!(observed.iter().filter(|operation| **operation == Operation::Commit).count(), 1);
+
+        Ok(())
+    }
+}
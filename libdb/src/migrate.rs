@@ -0,0 +1,235 @@
+use crate::error::{FragmentError, Result};
+use crate::fragment::AllocOptions;
+use crate::rw::{valid_page_size, FragmentDescriptor, KnownSize, RWFragmentStore, Storage};
+use crate::FragmentID;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+/// The fragment an unfinished page-size migration records its progress in. Its ID is never handed out to other fragments.
+pub(crate) const MIGRATION_FRAGMENT: FragmentID = FragmentID::MAX;
+
+const MIGRATION_MAGIC: [u8; 4] = *b"MIGR";
+
+/// How many bytes are copied at a time while a fragment is moved.
+const COPY_CHUNK: usize = 64 * 1024;
+
+/// How far a page-size migration has come. See [`RWFragmentStore::migrate_page_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// The page size the store is being migrated to.
+    pub page_size: u32,
+
+    /// How many fragments have been copied into pages of the new size.
+    pub migrated: usize,
+
+    /// How many fragments are still to be copied.
+    pub remaining: usize,
+}
+
+impl MigrationProgress {
+    /// Whether the store now uses the new page size.
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// The contents of the migration fragment: the page size being migrated to, and where each fragment's copy was placed so far.
+///
+/// # Binary Layout (Little-Endian)
+/// ```text
+/// Offset  Size     Field
+/// -------------------------------
+/// 0       4 B      Magic "MIGR"
+/// 4       4 B      Page size being migrated to
+/// 8..     N × 32 B Fragment descriptors of the copies (see `FragmentDescriptor`), which inline fragments are carried over in
+/// ```
+#[derive(Debug, Default)]
+pub(crate) struct Checkpoint {
+    pub(crate) page_size: u32,
+    pub(crate) copies: BTreeMap<FragmentID, FragmentDescriptor>,
+}
+
+impl Checkpoint {
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 8 || bytes[0..4] != MIGRATION_MAGIC || !(bytes.len() - 8).is_multiple_of(FragmentDescriptor::size()) {
+            return Err(FragmentError::InvalidCheckpoint.into());
+        }
+
+        let mut copies = BTreeMap::new();
+        let mut descriptors = Cursor::new(bytes[8..].to_vec());
+        while descriptors.position() < descriptors.get_ref().len() as u64 {
+            let copy = FragmentDescriptor::read(&mut descriptors)?;
+            copies.insert(copy.id, copy);
+        }
+
+        Ok(Self { page_size: u32::from_le_bytes(bytes[4..8].try_into()?), copies })
+    }
+
+    pub(crate) fn encode(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Cursor::new(Vec::with_capacity(8 + self.copies.len() * FragmentDescriptor::size()));
+        bytes.write_all(&MIGRATION_MAGIC)?;
+        bytes.write_all(&self.page_size.to_le_bytes())?;
+
+        for copy in self.copies.values_mut() {
+            copy.write(&mut bytes)?;
+        }
+
+        Ok(bytes.into_inner())
+    }
+}
+
+impl<Backing: Read + Write + Seek> RWFragmentStore<Backing> {
+    /// Moves the store over to pages of `page_size` bytes, copying at most `batch` fragments each call. Call it again until the returned
+    /// progress is complete.
+    ///
+    /// Fragments are copied past the end of the store, so the old layout stays intact until every fragment has been copied. Progress is
+    /// kept in a fragment of its own after each batch, so a migration which is interrupted resumes where it left off. Fragments written
+    /// between batches are copied again. Once every fragment has been copied, the new fragment table is written and only then the header,
+    /// so the store is never left half-way between page sizes. Space held by the old layout becomes free afterwards.
+    pub fn migrate_page_size(&mut self, page_size: u32, batch: usize) -> Result<MigrationProgress> {
+        if !valid_page_size(page_size) {
+            return Err(FragmentError::InvalidPageSize(page_size).into());
+        }
+
+        let mut checkpoint = match self.header.newest(MIGRATION_FRAGMENT).filter(|frag| !frag.is_tombstone()).cloned() {
+            Some(frag) => Checkpoint::decode(&self.read_region(frag.offset, frag.length)?)?,
+            None if page_size as u64 == self.header.page_size => return Ok(MigrationProgress { page_size, migrated: 0, remaining: 0 }),
+            None => Checkpoint { page_size, copies: BTreeMap::new() },
+        };
+
+        if checkpoint.page_size != page_size {
+            return Err(FragmentError::MigrationInProgress(checkpoint.page_size).into());
+        }
+
+        self.mark_dirty()?;
+
+        // Copies of fragments which have since been deleted or rewritten are stale. Their space is reclaimed once the migration completes.
+        let live = self.header.live_fragments().map(|frag| (frag.id, frag.sequence)).collect::<BTreeMap<_, _>>();
+        checkpoint.copies.retain(|id, copy| live.get(id) == Some(&copy.sequence));
+
+        let pending = self.header.live_fragments()
+            .filter(|frag| !checkpoint.copies.contains_key(&frag.id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let page = page_size as u64;
+        for frag in pending.iter().take(batch) {
+            // Inline fragments have no space of their own to move.
+            if frag.is_inline() {
+                checkpoint.copies.insert(frag.id, frag.clone());
+                continue;
+            }
+
+            let offset = self.header.end.next_multiple_of(page).max(page);
+            self.header.grow_to(offset + frag.length.next_multiple_of(page).max(page))?;
+            self.copy_region(frag.offset, offset, frag.length)?;
+
+            checkpoint.copies.insert(frag.id, FragmentDescriptor { offset, ..frag.clone() });
+        }
+
+        let remaining = pending.len().saturating_sub(batch);
+        let progress = MigrationProgress { page_size, migrated: checkpoint.copies.len(), remaining };
+
+        if remaining > 0 {
+            let bytes = checkpoint.encode()?;
+            let mut fragment = self.new_fragment(AllocOptions::default().fragment(MIGRATION_FRAGMENT).size_hint(bytes.len() as u64))?;
+            fragment.write_all(&bytes)?;
+            fragment.commit()?;
+
+            self.flush()?;
+
+            return Ok(progress);
+        }
+
+        // Everything the copies were read from has to have reached the backing buffer before the table describing them does.
+        self.backing.flush()?;
+
+        self.header.relayout(page, checkpoint.copies.into_values().collect())?;
+        self.header.write_table(&mut self.backing)?;
+        self.backing.flush()?;
+
+        self.flush()?;
+
+        Ok(progress)
+    }
+
+    fn read_region(&mut self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; length as usize];
+        self.backing.seek(SeekFrom::Start(offset))?;
+        self.backing.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    fn copy_region(&mut self, from: u64, to: u64, length: u64) -> Result<()> {
+        let mut chunk = vec![0u8; COPY_CHUNK];
+        let mut copied = 0;
+
+        while copied < length {
+            let size = (length - copied).min(COPY_CHUNK as u64) as usize;
+
+            self.backing.seek(SeekFrom::Start(from + copied))?;
+            self.backing.read_exact(&mut chunk[..size])?;
+            self.backing.seek(SeekFrom::Start(to + copied))?;
+            self.backing.write_all(&chunk[..size])?;
+
+            copied += size as u64;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AllocOptions, Danger, Database};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    pub fn test_migration_resumes_and_flips_the_page_size() {
+        let mut backing = Cursor::new(Vec::new());
+        Database::destructive_reinitialise(&mut backing, Danger).unwrap();
+
+        let mut db = Database::new(&mut backing).unwrap();
+        let ids = (0..5u8).map(|i| db.write_fragment(AllocOptions::default(), &vec![i; 100 + 1000 * i as usize]).unwrap()).collect::<Vec<_>>();
+        let tiny = db.write_fragment(AllocOptions::default(), b"tiny").unwrap();
+
+        let progress = db.migrate_page_size(512, 2).unwrap();
+        assert_eq!((progress.migrated, progress.remaining), (2, 5));
+        assert_eq!(db.page_size(), 4096);
+
+        // An interrupted migration picks up from its checkpoint, and copies fragments written since again.
+        drop(db);
+        let mut db = Database::new(&mut backing).unwrap();
+        db.write_fragment(AllocOptions::default().fragment(ids[0]), b"rewritten").unwrap();
+        assert!(db.migrate_page_size(8192, 2).is_err());
+
+        let mut progress = db.migrate_page_size(512, 2).unwrap();
+        while !progress.is_complete() {
+            progress = db.migrate_page_size(512, 2).unwrap();
+        }
+
+        db.flush().unwrap();
+        drop(db);
+
+        let mut db = Database::new(&mut backing).unwrap();
+        assert_eq!(db.page_size(), 512);
+        assert_eq!(db.fragments().count(), 7);
+        assert!(db.fragments().all(|frag| frag.offset % 512 == 0));
+
+        let mut contents = vec![];
+        db.open_fragment(ids[0]).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"rewritten");
+
+        contents.clear();
+        db.open_fragment(ids[4]).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![4; 4100]);
+
+        contents.clear();
+        db.open_fragment(tiny).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"tiny");
+
+        let id = db.write_fragment(AllocOptions::default(), b"after").unwrap();
+        assert_eq!(id, tiny + 1);
+    }
+}
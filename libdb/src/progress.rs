@@ -0,0 +1,78 @@
+use crate::error::{ManualError, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Asks a long operation to stop. Clones share the same token, so one can be handed to the operation while another is kept to cancel it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops the operation the token was handed to once it finishes the chunk of work it is on.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a long operation has come, counted in fragments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub done: u64,
+    pub total: u64,
+}
+
+/// Watches over a long operation, such as [`crate::Database::export_with_progress`].
+///
+/// The operation works through the store a fragment at a time. Before each fragment it fails with [`ManualError::Cancelled`] if the token
+/// has been cancelled, and otherwise reports how far it has come to the callback. The callback runs on the operation's own thread, so it
+/// may also hold the operation up to pause it. Work done before the operation was cancelled isn't undone.
+#[derive(Clone, Default)]
+pub struct Progress {
+    callback: Option<Arc<dyn Fn(Step) + Send + Sync>>,
+    token: CancellationToken,
+}
+
+impl Progress {
+    pub fn new(callback: impl Fn(Step) + Send + Sync + 'static) -> Self {
+        Self { callback: Some(Arc::new(callback)), token: CancellationToken::default() }
+    }
+
+    /// Stops the operation once `token` is cancelled.
+    pub fn cancellable(mut self, token: CancellationToken) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Reports that `done` of `total` fragments have been dealt with, unless the operation has been cancelled.
+    pub(crate) fn step(&self, done: u64, total: u64) -> Result<()> {
+        if self.token.is_cancelled() {
+            return Err(ManualError::Cancelled.into());
+        }
+
+        if let Some(ref callback) = self.callback {
+            callback(Step { done, total });
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("callback", &self.callback.is_some())
+            .field("token", &self.token)
+            .finish()
+    }
+}
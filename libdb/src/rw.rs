@@ -1,17 +1,37 @@
+use crate::cache::PageCache;
 use crate::error::FragmentError;
 use crate::error::Result;
-use crate::store::FragmentStore;
 use crate::Fragment;
+use crate::free::FreeSpace;
+use crate::migrate::{Checkpoint, MIGRATION_FRAGMENT};
 use crate::FragmentID;
-use std::collections::BTreeMap;
+use crate::UnixTimeMs;
+use crate::durability::{Durability, SyncData};
+use crate::metrics::{MetricsHook, Operation, StoreMetrics};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
 
-pub (crate) const PAGE_SIZE: usize = 4096;
+/// The page size of stores which aren't given one, and of every store written before page sizes were recorded.
+pub const DEFAULT_PAGE_SIZE: u32 = 4096;
+
+pub const MIN_PAGE_SIZE: u32 = 512;
+pub const MAX_PAGE_SIZE: u32 = 64 * 1024;
+
+/// Whether a store may allocate its space in pages of `page_size` bytes. Page sizes are powers of two from [`MIN_PAGE_SIZE`] to
+/// [`MAX_PAGE_SIZE`].
+pub fn valid_page_size(page_size: u32) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
+
+/// The version of the store format [`RWFragmentStore::blank`] writes. Version 0 stores always have pages of [`DEFAULT_PAGE_SIZE`], and
+/// don't record it.
+const STORE_VERSION: u32 = 5;
 
 pub trait Storage<Backing>
 where
@@ -74,8 +94,7 @@ impl<Backing: Read + Write + Seek> Storage<Backing> for Fragment {
             return Err(FragmentError::LengthExceedsCapacity.into());
         }
 
-        let mut data = vec![0u8; cap as usize];
-        source.read_exact(&mut data)?;
+        let mut data = read_bounded(&mut source, cap)?;
         data.truncate(len as usize);
 
         Fragment {
@@ -117,37 +136,91 @@ impl KnownSize for Fragment {
 
 #[derive(Debug)]
 pub struct RWFragmentStore<Backing: Read + Write + Seek> {
-    pub(crate) backing: Backing,
+    pub(crate) backing: PageCache<Backing>,
     pub(crate) header: RWFragmentStoreIndex,
+    pub(crate) metrics: MetricsHook,
+    durability: Durability,
+
+    /// Set once a fragment fails to be recorded as its handle is dropped. See [`FragmentError::Poisoned`].
+    pub(crate) poisoned: bool,
+
+    /// Set once the store is closed to writes. See [`FragmentError::Closed`].
+    pub(crate) closed: bool,
+
+    /// Syncs the backing buffer to its disk. Only set once a durability is chosen, since only then is the backing known to be [`SyncData`].
+    sync_data: Option<fn(&mut Backing) -> std::io::Result<()>>,
 }
 
 impl<Backing: Read + Write + Seek> RWFragmentStore<Backing> {
-    pub fn new(mut backing: Backing) -> Result<Self> {
-        Ok(Self {
-            header: RWFragmentStoreIndex::read(&mut backing)?,
+    pub fn new(backing: Backing) -> Result<Self> {
+        let mut backing = PageCache::new(backing)?;
+        let header = RWFragmentStoreIndex::read(&mut backing)?;
+
+        Ok(Self::with_header(backing, header))
+    }
+
+    /// Opens a store whose header and fragment table were already read from `backing`.
+    pub(crate) fn with_header(backing: PageCache<Backing>, header: RWFragmentStoreIndex) -> Self {
+        Self {
+            header,
             backing,
-        })
+            metrics: MetricsHook::default(),
+            durability: Durability::default(),
+            poisoned: false,
+            closed: false,
+            sync_data: None,
+        }
     }
 
     pub fn blank(backing: Backing) -> Result<Self> {
+        Self::blank_with_page_size(backing, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Initialises a store which allocates its space in pages of `page_size` bytes. The page size is recorded in the header, and only
+    /// changes if the store is migrated; see [`Self::migrate_page_size`].
+    ///
+    /// Small pages waste less space on small fragments, while large pages keep stores of large fragments from being split into many pieces.
+    pub fn blank_with_page_size(backing: Backing, page_size: u32) -> Result<Self> {
+        if !valid_page_size(page_size) {
+            return Err(FragmentError::InvalidPageSize(page_size).into());
+        }
+
+        let page_size = page_size as u64;
+        let table = FragmentDescriptor {
+            id: 0,
+            sequence: 0,
+            offset: 2 * page_size,
+            length: page_size,
+            inline: None,
+            written: None,
+        };
+
         Self {
             header: RWFragmentStoreIndex {
-                version: 0,
+                version: STORE_VERSION,
+                page_size,
                 root_fragment: 0,
+                metadata: BTreeMap::new(),
+                next_id: 1,
                 free_space: Default::default(),
-                fragment_table_offset: PAGE_SIZE as Pointer,
+                fragment_table_offset: page_size,
+                newest: BTreeMap::from([(table.id, table.clone())]),
                 fragment_table_parts: vec![FragmentTablePart {
                     continuation: 0,
-                    fragments: vec![FragmentDescriptor {
-                        id: 0,
-                        sequence: 0,
-                        offset: 2 * PAGE_SIZE as Pointer,
-                        length: PAGE_SIZE as u64,
-                    }],
+                    capacity: 1,
+                    fragments: vec![table],
                 }],
-                end: 3 * PAGE_SIZE as Pointer,
+                end: 3 * page_size,
+                dirty: false,
+                reclaimed: 0,
+                grow_hook: GrowHook::default(),
             },
-            backing,
+            backing: PageCache::new(backing)?,
+            metrics: MetricsHook::default(),
+            durability: Durability::default(),
+            poisoned: false,
+            closed: false,
+            sync_data: None,
         }
         .save()
     }
@@ -155,8 +228,154 @@ impl<Backing: Read + Write + Seek> RWFragmentStore<Backing> {
     fn save(mut self) -> Result<Self> {
         self.header.write(&mut self.backing)?;
 
+        // The first fragment is zeroed so that it can be read back in full before anything has been written past it.
+        let page_size = self.header.page_size;
+        let start = self.backing.stream_position()?;
+        self.backing.seek(SeekFrom::Start(2 * page_size))?;
+        self.backing.write_all(&vec![0u8; page_size as usize])?;
+        self.backing.seek(SeekFrom::Start(start))?;
+
+        self.backing.flush()?;
+
         Ok(self)
     }
+
+    /// Installs a hook which is asked before the backing buffer grows, replacing any previous one.
+    /// Space which is reused from deleted or superseded fragments doesn't count as growth.
+    pub fn on_grow(&mut self, hook: impl Fn(u64) -> std::io::Result<()> + Send + Sync + 'static) {
+        self.header.grow_hook = GrowHook(Some(Box::new(hook)));
+    }
+
+    /// Records in the header that the store has changes which haven't been flushed yet. If the store isn't flushed before it is next
+    /// opened, the recorded free-space map can't be trusted and is rebuilt instead.
+    pub(crate) fn mark_dirty(&mut self) -> Result<()> {
+        // Every change marks the store dirty first, so this is where poisoned stores turn writes away.
+        if self.poisoned {
+            return Err(FragmentError::Poisoned.into());
+        }
+
+        if self.closed {
+            return Err(FragmentError::Closed.into());
+        }
+
+        if self.header.dirty {
+            return Ok(());
+        }
+
+        // The flag has to reach the backing buffer before anything else does, so it isn't left waiting in the cache.
+        self.backing.write_through(FLAGS_OFFSET, &DIRTY.to_le_bytes())?;
+
+        self.header.dirty = true;
+
+        Ok(())
+    }
+
+    /// The fragment the application treats as the entry point to its data. It is fragment 0 unless another is chosen.
+    pub fn root(&self) -> FragmentID {
+        self.header.root_fragment
+    }
+
+    /// Makes `id` the root fragment. The fragment must exist. The change is persisted when the store is next flushed.
+    pub fn set_root(&mut self, id: FragmentID) -> Result<()> {
+        if self.header.newest(id).is_none_or(FragmentDescriptor::is_tombstone) {
+            return FragmentError::not_found(id);
+        }
+
+        self.mark_dirty()?;
+        self.header.root_fragment = id;
+
+        Ok(())
+    }
+
+    /// The metadata entries recorded in the header, in order of key.
+    pub fn metadata(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.header.metadata.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Records `value` under `key` in the header, replacing any previous value. Together, the entries may take up at most
+    /// [`MAX_METADATA_SIZE`] bytes. Stores of older versions are upgraded to the current header layout. The change is persisted when the
+    /// store is next flushed.
+    pub fn set_metadata(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        let mut metadata = self.header.metadata.clone();
+        metadata.insert(key.into(), value.into());
+
+        self.replace_metadata(metadata)
+    }
+
+    /// Removes `key` from the header's metadata, returning its value if it had one.
+    pub fn remove_metadata(&mut self, key: &str) -> Result<Option<String>> {
+        let mut metadata = self.header.metadata.clone();
+        let value = metadata.remove(key);
+
+        if value.is_some() {
+            self.replace_metadata(metadata)?;
+        }
+
+        Ok(value)
+    }
+
+    fn replace_metadata(&mut self, metadata: BTreeMap<String, String>) -> Result<()> {
+        if let Some(string) = metadata.iter().flat_map(|(key, value)| [key, value]).find(|string| string.len() > u16::MAX as usize) {
+            return Err(FragmentError::MetadataTooLarge(string.len()).into());
+        }
+
+        let size = encode_metadata(&metadata).len();
+        if size > MAX_METADATA_SIZE {
+            return Err(FragmentError::MetadataTooLarge(size).into());
+        }
+
+        self.mark_dirty()?;
+        self.header.version = STORE_VERSION;
+        self.header.metadata = metadata;
+
+        Ok(())
+    }
+
+    /// Reports the duration of every operation on the store to `metrics`, replacing anything installed before.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn StoreMetrics>) {
+        self.metrics = MetricsHook::new(metrics);
+    }
+
+    /// Changes how many pages of the backing buffer are cached. A capacity of 0 turns the cache off.
+    pub fn set_cache_capacity(&mut self, pages: usize) -> Result<()> {
+        Ok(self.backing.set_capacity(pages)?)
+    }
+
+    /// Returns the backing buffer once every cached change has been written back to it. The header isn't persisted; see [`Self::flush`].
+    pub fn into_inner(self) -> Result<Backing> {
+        Ok(self.backing.into_inner()?)
+    }
+
+    /// Chooses how durable each commit is before it returns. See [`Durability`].
+    pub fn set_durability(&mut self, durability: Durability) where Backing: SyncData {
+        self.durability = durability;
+        self.sync_data = Some(Backing::sync_data);
+    }
+
+    /// Does as much to make a commit last as the store's [`Durability`] asks for. Called once a commit is recorded in the fragment table.
+    pub(crate) fn committed(&mut self) -> Result<()> {
+        if self.durability == Durability::Relaxed {
+            return Ok(());
+        }
+
+        self.flush()?;
+
+        if self.durability == Durability::FsyncOnCommit && let Some(sync_data) = self.sync_data {
+            sync_data(self.backing.get_mut())?;
+        }
+
+        Ok(())
+    }
+
+    /// Persists the header and fragment table, then writes back every cached page and flushes the backing buffer.
+    pub fn flush(&mut self) -> Result<()> {
+        let _timer = self.metrics.time(Operation::PersistHeader);
+
+        self.header.write(&mut self.backing)?;
+        self.backing.flush()?;
+
+        Ok(())
+    }
 }
 
 const RWFS_MAGIC: [u8; 4] = *b"RWFS";
@@ -174,146 +393,295 @@ const RWFS_MAGIC: [u8; 4] = *b"RWFS";
 /// 4       4 B     Version
 /// 8       8 B     Root fragment ID
 /// 16      8 B     Fragment table pointer (start of first chunk)
+/// 24      4 B     Flags (see below)
+/// 28      4 B     Number of recorded free extents
+/// 32      4 B     Page size
+/// 36      4 B     Size of the metadata
+/// 40      8 B     Next fragment ID
+/// 48..    M B     Metadata entries, each a 2-byte length and a key, then a 2-byte length and a value
+/// 48+M..  N × 16 B Free extents, each a size followed by an offset
 /// ```
 ///
-/// All values are encoded in little-endian format.
+/// All values are encoded in little-endian format. The header owns the whole first page, and as many free extents are recorded as fit in
+/// what is left of it. Stores before version 3 have no metadata, and their free extents begin at offset 48. Version 0 stores don't record their page size, which is always [`DEFAULT_PAGE_SIZE`], so their free extents begin at
+/// offset 32 instead. Stores before version 2 don't record the next fragment ID either, and their free extents begin at offset 40. Their
+/// next ID is worked out from the fragment table. Only stores since version 4 may keep fragments inline in the fragment table, and only
+/// version 5 stores record when fragments were written; the header of version 3 is otherwise the same.
+///
+/// The free extents are only trusted if the flags are exactly [`FREE_SPACE_RECORDED`]. Otherwise, as with stores written before extents
+/// were recorded, or stores which weren't flushed after they were last changed, the free-space map is rebuilt from the gaps between
+/// fragments.
 #[derive(Debug)]
 pub(crate) struct RWFragmentStoreIndex {
-    version: u32,
+    pub(crate) version: u32,
+
+    /// Space is allocated in multiples of this many bytes, and fragments always begin on a page boundary.
+    pub(crate) page_size: u64,
+
     root_fragment: FragmentID,
-    pub(crate) free_space: BTreeMap<u64, Vec<Pointer>>,
-    fragment_table_offset: Pointer,
-    fragment_table_parts: Vec<FragmentTablePart>,
 
-    /// Keeps a reference to the end of the backing buffer. Is useful when appending a new chunk.
-    pub(crate) end: Pointer,
-}
+    /// Strings the application keeps alongside the store, such as its name or the version of its schema. See [`MAX_METADATA_SIZE`].
+    metadata: BTreeMap<String, String>,
 
-impl<Backing: Read + Write + Seek> Storage<Backing> for RWFragmentStoreIndex {
-    fn read(mut source: Backing) -> Result<Self> {
-        source.seek(SeekFrom::Start(0))?;
-        let mut buffer = vec![0u8; Self::size()];
-        source.read_exact(&mut buffer)?;
+    /// The ID given to the next fragment created without one. It only ever grows, so IDs aren't handed out twice.
+    pub(crate) next_id: FragmentID,
 
-        if buffer[0..4] != RWFS_MAGIC {
-            return Err(FragmentError::InvalidMagic.into());
-        }
+    pub(crate) free_space: FreeSpace,
+    pub(crate) fragment_table_offset: Pointer,
+    pub(crate) fragment_table_parts: Vec<FragmentTablePart>,
 
-        let fragment_table_offset = u64::from_le_bytes(buffer[16..24].try_into()?);
-        source.seek(SeekFrom::Start(fragment_table_offset))?;
+    /// The newest descriptor of every fragment in the table, tombstones included, so fragments can be looked up without scanning the table.
+    newest: BTreeMap<FragmentID, FragmentDescriptor>,
 
-        let root_fragment = FragmentID::from_le_bytes(buffer[8..16].try_into()?);
-        let mut prev_offset = fragment_table_offset;
-        let mut end = fragment_table_offset;
-        let mut fragment_table_parts = vec![];
+    /// Keeps a reference to the end of the backing buffer. Is useful when appending a new chunk.
+    pub(crate) end: Pointer,
 
-        loop {
-            let mut chunk = FragmentTablePart::read(&mut source)?;
+    /// Whether the header on disk says the store has unflushed changes.
+    pub(crate) dirty: bool,
 
-            end = end.max(prev_offset + chunk.cap() as Pointer);
+    /// The bytes found allocated to no fragment when the store was opened, and returned to the free space. See [`Database::reclaimed`].
+    ///
+    /// [`Database::reclaimed`]: crate::Database::reclaimed
+    pub(crate) reclaimed: u64,
 
-            source.seek(SeekFrom::Start(chunk.continuation))?;
-            prev_offset = chunk.continuation;
+    grow_hook: GrowHook,
+}
 
-            let continuation = chunk.continuation;
-            fragment_table_parts.push(chunk);
+/// Decides whether the backing buffer may grow. It is given the number of bytes the buffer is about to grow by, and refuses by returning an error.
+type OnGrow = Box<dyn Fn(u64) -> std::io::Result<()> + Send + Sync>;
 
-            if continuation == 0 {
-                break;
-            }
-        }
+#[derive(Default)]
+struct GrowHook(Option<OnGrow>);
 
-        let mut free_space = BTreeMap::new();
+impl std::fmt::Debug for GrowHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "GrowHook(Some(..))" } else { "GrowHook(None)" })
+    }
+}
 
-        /*
-        # Algorithm:
-        1. The end of the root fragment is manually inserted into the vector
-        2. The fragment list is iterated. The previous fragment is determined by
-         */
+impl<Backing: Read + Write + Seek> Storage<Backing> for RWFragmentStoreIndex {
+    fn read(mut source: Backing) -> Result<Self> {
+        let (mut index, recorded_free_space) = Self::read_header(&mut source)?;
 
-        let mut slots = fragment_table_parts.iter().flat_map(|i| i.fragments.iter()).collect::<Vec<_>>();
-        slots.sort_by_key(|slot| slot.offset);
+        let mut offset = index.fragment_table_offset;
+        let mut parts = vec![];
 
-        // Iterate over the slots pair-wise. Their gaps are appended to the `free_space` map.
+        // The offsets of the parts read so far. A table whose continuations lead back to one of them is corrupt, and would be read forever.
+        let mut visited = BTreeSet::from([offset]);
 
-        for i in slots.windows(2) {
-            if let [a, b] = *i {
-                end = end.max(a.offset + a.length).max(b.offset + b.length);
+        loop {
+            source.seek(SeekFrom::Start(offset))?;
+            let part = FragmentTablePart::read(&mut source)?;
 
-                let offset = (a.offset + a.length).next_multiple_of(PAGE_SIZE as u64);
+            let continuation = part.continuation;
+            parts.push((offset, part));
 
-                if b.offset > offset {
-                    let size = (b.offset - offset).next_multiple_of(PAGE_SIZE as u64) - PAGE_SIZE as u64;
+            if continuation == 0 {
+                break;
+            }
 
-                    if size > 0 {
-                        free_space.entry(size).or_insert_with(Vec::new).push(offset);
-                    }
-                }
+            if !visited.insert(continuation) {
+                return FragmentError::invalid_fragment_table();
             }
+
+            offset = continuation;
         }
 
-        Ok(Self {
-            version: u32::from_le_bytes(buffer[4..8].try_into()?),
-            root_fragment,
-            free_space,
-            fragment_table_offset,
-            fragment_table_parts,
-            end,
-        })
+        index.assemble(&mut source, parts, recorded_free_space)?;
+
+        Ok(index)
     }
 
     fn write(&mut self, mut source: Backing) -> Result<()> {
-        source.seek(SeekFrom::Start(0))?;
+        let metadata = encode_metadata(&self.metadata);
+
+        // A map too large for the header page is left unrecorded, and rebuilt when the store is next opened.
+        let recorded = self.free_space.len() <= max_recorded_extents(self.version, metadata.len(), self.page_size);
 
-        let mut buf = vec![0u8; Self::size()];
+        let mut buf = vec![0u8; extents_offset(self.version, 0)];
 
         buf[0..4].copy_from_slice(&RWFS_MAGIC);
         buf[4..8].copy_from_slice(&self.version.to_le_bytes());
         buf[8..16].copy_from_slice(&self.root_fragment.to_le_bytes());
         buf[16..24].copy_from_slice(&self.fragment_table_offset.to_le_bytes());
 
-        source.write_all(&buf)?;
+        if self.version > 0 {
+            buf[32..36].copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        }
 
-        // TODO: Write fragment table
-        source.seek(SeekFrom::Start(self.fragment_table_offset))?;
+        if self.version > 1 {
+            buf[40..48].copy_from_slice(&self.next_id.to_le_bytes());
+        }
 
-        for part in &mut self.fragment_table_parts {
-            part.write(&mut source)?;
-            source.seek(SeekFrom::Start(part.continuation))?;
+        if self.version > 2 {
+            buf[36..40].copy_from_slice(&(metadata.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&metadata);
         }
 
+        if recorded {
+            buf[24..28].copy_from_slice(&FREE_SPACE_RECORDED.to_le_bytes());
+            buf[28..32].copy_from_slice(&(self.free_space.len() as u32).to_le_bytes());
+
+            for (offset, size) in self.free_space.extents() {
+                buf.extend_from_slice(&size.to_le_bytes());
+                buf.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+
+        source.seek(SeekFrom::Start(0))?;
+        source.write_all(&buf)?;
+
+        // Space is never returned to the free-space map while the store is open, so if writing the table below is cut short, the map
+        // recorded above still only names space which the old table didn't use either.
+        self.write_table(&mut source)?;
+
+        self.dirty = false;
+
         // Let the OS coalesce adjacent writes - no .flush()
         Ok(())
     }
 }
 
+/// Reads `length` bytes from `source`. Unlike reading into a buffer of that size, a corrupt length larger than what's left of `source` fails
+/// once `source` runs out, rather than first setting aside however much memory it names.
+fn read_bounded(source: impl Read, length: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    source.take(length).read_to_end(&mut bytes)?;
+
+    if (bytes.len() as u64) < length {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(bytes)
+}
+
+/// Finds the gaps between the regions of the backing buffer which are in use, given as (offset, length) pairs.
+fn find_free_space(mut slots: Vec<(Pointer, u64)>, page_size: u64) -> FreeSpace {
+    let mut free_space = FreeSpace::default();
+
+    slots.sort_by_key(|(offset, _)| *offset);
+
+    // Iterate over the slots pair-wise. Their gaps are appended to the `free_space` map.
+    for i in slots.windows(2) {
+        if let [(a_offset, a_length), (b_offset, _)] = *i {
+            let offset = (a_offset + a_length).next_multiple_of(page_size);
+
+            if b_offset > offset {
+                let size = (b_offset - offset).next_multiple_of(page_size) - page_size;
+
+                free_space.free(offset, size);
+            }
+        }
+    }
+
+    free_space
+}
+
+/// Where the header's flags are stored.
+const FLAGS_OFFSET: u64 = 24;
+
+/// Set while the store has changes which haven't been flushed.
+pub(crate) const DIRTY: u32 = 1;
+
+/// Set once the free-space map has been recorded after the header.
+pub(crate) const FREE_SPACE_RECORDED: u32 = 2;
+
+/// Where the free extents begin in a header of the given version, after `metadata` bytes of metadata.
+fn extents_offset(version: u32, metadata: usize) -> usize {
+    match version {
+        0 => 32,
+        1 => 40,
+        2 => 48,
+        _ => 48 + metadata,
+    }
+}
+
+/// How many free extents fit in the first page after the header.
+fn max_recorded_extents(version: u32, metadata: usize, page_size: u64) -> usize {
+    (page_size as usize - extents_offset(version, metadata)) / 16
+}
+
+/// The most bytes the metadata may take up in the header, which leaves room for free extents even in the smallest pages.
+pub const MAX_METADATA_SIZE: usize = 256;
+
+fn encode_metadata(metadata: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    for string in metadata.iter().flat_map(|(key, value)| [key, value]) {
+        bytes.extend_from_slice(&(string.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(string.as_bytes());
+    }
+
+    bytes
+}
+
+fn decode_metadata(mut bytes: &[u8]) -> Result<BTreeMap<String, String>> {
+    fn next(bytes: &mut &[u8]) -> Result<String> {
+        let (len, rest) = bytes.split_at_checked(2).ok_or(FragmentError::InvalidMetadata)?;
+        let (string, rest) = rest.split_at_checked(u16::from_le_bytes(len.try_into()?) as usize).ok_or(FragmentError::InvalidMetadata)?;
+        *bytes = rest;
+
+        String::from_utf8(string.to_vec()).map_err(|_| FragmentError::InvalidMetadata.into())
+    }
+
+    let mut metadata = BTreeMap::new();
+    while !bytes.is_empty() {
+        metadata.insert(next(&mut bytes)?, next(&mut bytes)?);
+    }
+
+    Ok(metadata)
+}
+
 impl KnownSize for RWFragmentStoreIndex {
     fn size() -> usize {
-        24
+        48
     }
 }
 
 impl RWFragmentStoreIndex {
+    /// Finds room for a fragment of at least `min_size` bytes, preferring the smallest free extent which fits before growing the backing
+    /// buffer. Returns where the room begins and how large it is.
     pub fn allocate_fragment(&mut self, min_size: u64) -> Result<(Pointer, u64)> {
-        let size = min_size.next_multiple_of(PAGE_SIZE as u64);
+        let size = min_size.next_multiple_of(self.page_size);
 
-        let ptr = self
-            .free_space
-            .range_mut(size..)
-            .next()
-            .and_then(|(_, i)| i.pop())
-            .unwrap_or(self.end)
-            .next_multiple_of(PAGE_SIZE as u64);
+        let (ptr, reused) = match self.free_space.allocate(size) {
+            Some(ptr) => (ptr, true),
+            None => (self.end.next_multiple_of(self.page_size), false),
+        };
 
-        self.end = self.end.max(ptr + size);
+        if let Err(err) = self.grow_to(ptr + size) {
+            if reused {
+                self.free_space.free(ptr, size);
+            }
+
+            return Err(err.into());
+        }
+
+        tracing::trace!(offset = ptr, size, reused, "allocated room for a fragment");
 
         Ok((ptr, size))
     }
+
+    /// Extends the end of the backing buffer to `end`, provided the grow hook allows it. Does nothing if the buffer is already that large.
+    pub(crate) fn grow_to(&mut self, end: Pointer) -> std::io::Result<()> {
+        if end <= self.end {
+            return Ok(());
+        }
+
+        if let GrowHook(Some(ref hook)) = self.grow_hook {
+            hook(end - self.end)?;
+        }
+
+        tracing::trace!(from = self.end, to = end, "grew the store");
+        self.end = end;
+
+        Ok(())
+    }
  
     fn mk_fragment_table_part(&mut self) -> Result<&mut FragmentTablePart> {
-        let consumed = self.fragment_table().count() * FragmentDescriptor::size();
+        let consumed = (self.fragment_table().count() * FragmentDescriptor::size()) as u64;
 
-        let to_allocate = (consumed.next_multiple_of(PAGE_SIZE) as f64).sqrt().ceil().powi(2) as u64;
+        let to_allocate = (consumed.next_multiple_of(self.page_size) as f64).sqrt().ceil().powi(2) as u64;
         let (ptr, size) = self.allocate_fragment(to_allocate)?;
 
         if let Some(last) = self.fragment_table_parts.last_mut() {
@@ -323,25 +691,60 @@ impl RWFragmentStoreIndex {
         }
 
         assert!(size >= to_allocate);
-        self.fragment_table_parts
-            .push(FragmentTablePart { continuation: 0, fragments: vec![] });
+        self.fragment_table_parts.push(FragmentTablePart {
+            continuation: 0,
+            capacity: (size as usize - FragmentTablePart::size()) / FragmentDescriptor::size(),
+            fragments: vec![],
+        });
 
         self.fragment_table_parts
             .last_mut()
-            .ok_or(FragmentError::FailedToCreateNewFragmentTablePart.into())
+            .ok_or_else(|| FragmentError::FailedToCreateNewFragmentTablePart.into())
     }
 
     pub fn fragment_table(&self) -> impl Iterator<Item = &FragmentDescriptor> {
         self.fragment_table_parts.iter().flat_map(|part| part.fragments.iter())
     }
 
-    pub fn fragment_table_mut(&mut self) -> impl Iterator<Item = &mut FragmentDescriptor> {
-        self.fragment_table_parts.iter_mut().flat_map(|part| part.fragments.iter_mut())
+    /// The newest sequence of every fragment which hasn't been deleted, in order of ID. The fragment recording a page-size migration is
+    /// left out.
+    pub fn live_fragments(&self) -> impl Iterator<Item = &FragmentDescriptor> {
+        self.newest.values().filter(|frag| !frag.is_tombstone() && frag.id != MIGRATION_FRAGMENT)
+    }
+
+    /// When the newest sequence of any fragment was written. Deleting a fragment changes the store too, so tombstones count as well.
+    pub(crate) fn last_written(&self) -> Option<UnixTimeMs> {
+        self.newest.values().filter_map(|frag| frag.written).max()
+    }
+
+    /// The newest sequence of a fragment, which is a tombstone if the fragment was deleted.
+    pub(crate) fn newest(&self, id: FragmentID) -> Option<&FragmentDescriptor> {
+        self.newest.get(&id)
+    }
+
+    /// Hands out a fragment ID which no fragment has had before.
+    pub(crate) fn allocate_id(&mut self) -> FragmentID {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        id
     }
 
     pub fn push_fragment_descriptor(&mut self, fragment: FragmentDescriptor) -> Result<()> {
+        // Fragments may be given IDs of their own, which mustn't be handed out again either.
+        if fragment.id != MIGRATION_FRAGMENT {
+            self.next_id = self.next_id.max(fragment.id + 1);
+        }
+
+        // Older versions can't read inline descriptors, or the time a fragment was written.
+        if fragment.is_inline() || fragment.written.is_some() {
+            self.version = STORE_VERSION;
+        }
+
+        record_newest(&mut self.newest, &fragment);
+
         match self.fragment_table_parts.last_mut() {
-            Some(last) if last.len() < last.cap() => last.fragments.push(fragment),
+            Some(last) if last.len() + fragment.slots() <= last.cap() => last.fragments.push(fragment),
             _ => self.mk_fragment_table_part()?.fragments.push(fragment),
         }
 
@@ -349,6 +752,192 @@ impl RWFragmentStoreIndex {
     }
 }
 
+impl RWFragmentStoreIndex {
+    /// Reads the header at the start of `source`, giving an index with no fragment table yet, and the free-space map recorded in the header if
+    /// it can be trusted. See [`Self::assemble`].
+    pub(crate) fn read_header(mut source: impl Read + Seek) -> Result<(Self, Option<FreeSpace>)> {
+        source.seek(SeekFrom::Start(0))?;
+        let mut buffer = vec![0u8; Self::size()];
+        source.read_exact(&mut buffer)?;
+
+        if buffer[0..4] != RWFS_MAGIC {
+            return Err(FragmentError::InvalidMagic.into());
+        }
+
+        let version = u32::from_le_bytes(buffer[4..8].try_into()?);
+        let page_size = match version {
+            0 => DEFAULT_PAGE_SIZE,
+            _ => u32::from_le_bytes(buffer[32..36].try_into()?),
+        };
+
+        if !valid_page_size(page_size) {
+            return Err(FragmentError::InvalidPageSize(page_size).into());
+        }
+
+        let page_size = page_size as u64;
+        let flags = u32::from_le_bytes(buffer[24..28].try_into()?);
+        let recorded = u32::from_le_bytes(buffer[28..32].try_into()?) as usize;
+
+        let metadata_size = match version {
+            0..=2 => 0,
+            _ => u32::from_le_bytes(buffer[36..40].try_into()?) as usize,
+        };
+
+        if metadata_size > MAX_METADATA_SIZE {
+            return Err(FragmentError::MetadataTooLarge(metadata_size).into());
+        }
+
+        let mut metadata = vec![0u8; metadata_size];
+        source.seek(SeekFrom::Start(Self::size() as u64))?;
+        source.read_exact(&mut metadata)?;
+        let metadata = decode_metadata(&metadata)?;
+
+        let recorded_free_space = match flags == FREE_SPACE_RECORDED && recorded <= max_recorded_extents(version, metadata_size, page_size) {
+            true => {
+                source.seek(SeekFrom::Start(extents_offset(version, metadata_size) as u64))?;
+
+                let mut extents = vec![0u8; recorded * 16];
+                source.read_exact(&mut extents)?;
+
+                let mut free_space = FreeSpace::default();
+                let mut valid = true;
+                for extent in extents.chunks_exact(16) {
+                    let (offset, size) = (u64::from_le_bytes(extent[8..16].try_into()?), u64::from_le_bytes(extent[0..8].try_into()?));
+                    valid &= offset.checked_add(size).is_some();
+
+                    if valid {
+                        free_space.free(offset, size);
+                    }
+                }
+
+                // A map naming space past the end of what the store can address was corrupted, so it is rebuilt like an unrecorded one.
+                valid.then_some(free_space)
+            },
+            false => None,
+        };
+
+        let fragment_table_offset = u64::from_le_bytes(buffer[16..24].try_into()?);
+
+        // The table is consulted too once it is read, in case the store wasn't flushed after fragments were added with IDs of their own.
+        let next_id = match version {
+            0 | 1 => 1,
+            _ => FragmentID::from_le_bytes(buffer[40..48].try_into()?),
+        };
+
+        let index = Self {
+            version,
+            page_size,
+            root_fragment: FragmentID::from_le_bytes(buffer[8..16].try_into()?),
+            metadata,
+            next_id,
+            free_space: FreeSpace::default(),
+            fragment_table_offset,
+            fragment_table_parts: vec![],
+            newest: BTreeMap::new(),
+            end: fragment_table_offset,
+            dirty: false,
+            reclaimed: 0,
+            grow_hook: GrowHook::default(),
+        };
+
+        Ok((index, recorded_free_space))
+    }
+
+    /// Takes the parts of the fragment table, each with the offset it was read from, in the order they are chained in. The free-space map is
+    /// rebuilt from the gaps between the parts and fragments, unless one recorded in the header is given.
+    pub(crate) fn assemble(&mut self, mut source: impl Read + Seek, parts: Vec<(Pointer, FragmentTablePart)>, recorded_free_space: Option<FreeSpace>) -> Result<()> {
+        // The regions of the backing buffer which are in use, as (offset, length) pairs.
+        let mut slots = parts.iter()
+            .map(|(offset, part)| (*offset, (FragmentTablePart::size() + part.cap() * FragmentDescriptor::size()) as u64))
+            .collect::<Vec<_>>();
+
+        self.fragment_table_parts = parts.into_iter().map(|(_, part)| part).collect();
+
+        for frag in self.fragment_table_parts.iter().flat_map(|i| i.fragments.iter()) {
+            record_newest(&mut self.newest, frag);
+        }
+
+        slots.extend(self.fragment_table().filter(|frag| !frag.is_inline()).map(|frag| (frag.offset, frag.length)));
+
+        // The copies made by an unfinished page-size migration aren't in the table, but mustn't be allocated over.
+        if let Some(checkpoint) = self.newest.get(&MIGRATION_FRAGMENT).filter(|frag| !frag.is_tombstone()) {
+            source.seek(SeekFrom::Start(checkpoint.offset))?;
+            let bytes = read_bounded(&mut source, checkpoint.length)?;
+
+            slots.extend(Checkpoint::decode(&bytes)?.copies.values().filter(|copy| !copy.is_inline()).map(|copy| (copy.offset, copy.length)));
+        }
+        self.end = slots.iter().fold(self.end, |end, (offset, length)| end.max(offset + length));
+
+        // Fragments carry no header of their own, so space which was allocated but never committed or released, such as for a handle which
+        // was forgotten before the store was flushed, can only be told apart by belonging to no slot and being missing from the recorded map.
+        (self.free_space, self.reclaimed) = match recorded_free_space {
+            Some(mut free_space) => {
+                let orphaned = find_free_space(slots, self.page_size).without(&free_space);
+                for (offset, size) in orphaned.extents() {
+                    free_space.free(offset, size);
+                }
+
+                (free_space, orphaned.total())
+            },
+            None => (find_free_space(slots, self.page_size), 0),
+        };
+
+        self.next_id = self.newest.keys().rfind(|id| **id != MIGRATION_FRAGMENT).map_or(1, |id| id + 1).max(self.next_id);
+
+        Ok(())
+    }
+
+    /// Writes every part of the fragment table, but not the header which points at it.
+    pub(crate) fn write_table(&mut self, mut source: impl Read + Write + Seek) -> Result<()> {
+        source.seek(SeekFrom::Start(self.fragment_table_offset))?;
+
+        for part in &mut self.fragment_table_parts {
+            part.write(&mut source)?;
+            source.seek(SeekFrom::Start(part.continuation))?;
+        }
+
+        Ok(())
+    }
+
+    /// Lays the store out afresh in pages of `page_size` bytes, with `fragments` as the only sequence of each fragment. Each fragment must
+    /// already begin on a boundary of the new pages, past the first page. The fragment table moves past the end of the store, and any space
+    /// before it which no fragment uses becomes free.
+    pub(crate) fn relayout(&mut self, page_size: u64, fragments: Vec<FragmentDescriptor>) -> Result<()> {
+        let mut slots = vec![(0, page_size)];
+        slots.extend(fragments.iter().filter(|frag| !frag.is_inline()).map(|frag| (frag.offset, frag.length)));
+
+        let mut newest = BTreeMap::new();
+        let capacity = fragments.iter().map(FragmentDescriptor::slots).sum::<usize>().max(1);
+        let mut table = Vec::with_capacity(fragments.len());
+        for frag in fragments {
+            record_newest(&mut newest, &frag);
+            table.push(frag);
+        }
+
+        self.version = STORE_VERSION;
+        self.page_size = page_size;
+        self.free_space = FreeSpace::default();
+
+        let (offset, size) = self.allocate_fragment((FragmentTablePart::size() + capacity * FragmentDescriptor::size()) as u64)?;
+        slots.push((offset, size));
+
+        self.fragment_table_offset = offset;
+        self.fragment_table_parts = vec![FragmentTablePart { continuation: 0, capacity, fragments: table }];
+        self.newest = newest;
+        self.free_space = find_free_space(slots, page_size);
+
+        Ok(())
+    }
+}
+
+/// Remembers `frag` as its fragment's newest sequence, unless a later one is already known.
+pub(crate) fn record_newest(newest: &mut BTreeMap<FragmentID, FragmentDescriptor>, frag: &FragmentDescriptor) {
+    match newest.get(&frag.id) {
+        Some(current) if current.sequence >= frag.sequence => (),
+        _ => { newest.insert(frag.id, frag.clone()); },
+    }
+}
+
 pub type Pointer = u64;
 
 /// Implements binary serialization for a `FragmentDescriptor`.
@@ -362,15 +951,20 @@ pub type Pointer = u64;
 /// -------------------------------
 /// 0       8 B      Fragment ID
 /// 8       8 B      Sequence number
-/// 16      8 B      Offset in backing store (e.g. file or block device)
-/// 24      8 B      Number of bytes the fragment contains
+/// 16      8 B      Offset in backing store (e.g. file or block device), or `INLINE`
+/// 24      8 B      Number of bytes the fragment contains, with the top bit set if the write time follows
+/// 32      8 B      When the fragment was written, in milliseconds since the UNIX epoch, followed by 24 reserved bytes (v5+)
+/// 32..    N × 32 B Contents of an inline fragment, padded to whole slots, after the write time if there is one (v4+)
 /// ```
 ///
 /// Notes:
 /// - The actual length of the fragment is determined during fragment deserialization,
 ///   as it is embedded within the fragment structure itself.
 /// - This compact format is useful for constructing fragment tables or indexes,
-///   and is intentionally fixed-size (24 bytes) for predictable seeking and storage.
+///   and is fixed-size (32 bytes) for every fragment which isn't inline and doesn't record when it was written.
+/// - Descriptors written before version 5 don't record when they were written, and are read back without a write time.
+/// - Fragments of at most [`MAX_INLINE_SIZE`] bytes are kept in the table itself rather than
+///   in space of their own. Their contents take up the slots following the descriptor.
 ///
 /// Ensure that the backing buffer is already seeked correctly prior to invoking `read`.
 #[derive(Debug, Clone)]
@@ -379,6 +973,12 @@ pub(crate) struct FragmentDescriptor {
     pub(crate) sequence: u64,
     pub(crate) offset: u64,
     pub(crate) length: u64,
+
+    /// The contents of an inline fragment, whose offset is [`INLINE`].
+    pub(crate) inline: Option<Vec<u8>>,
+
+    /// When the sequence was written, if the store recorded it.
+    pub(crate) written: Option<UnixTimeMs>,
 }
 
 impl<Backing: Read + Write + Seek> Storage<Backing> for FragmentDescriptor {
@@ -386,24 +986,111 @@ impl<Backing: Read + Write + Seek> Storage<Backing> for FragmentDescriptor {
         let mut buffer = vec![0u8; Self::size()];
         source.read_exact(&mut buffer)?;
 
-        Ok(Self {
+        let mut descriptor = Self {
             id: FragmentID::from_le_bytes(buffer[0..8].try_into()?),
             sequence: u64::from_le_bytes(buffer[8..16].try_into()?),
             offset: u64::from_le_bytes(buffer[16..24].try_into()?),
             length: u64::from_le_bytes(buffer[24..32].try_into()?),
-        })
+            inline: None,
+            written: None,
+        };
+
+        if descriptor.length & WRITTEN != 0 {
+            descriptor.length &= !WRITTEN;
+
+            source.read_exact(&mut buffer)?;
+            descriptor.written = Some(UnixTimeMs::from_le_bytes(buffer[0..8].try_into()?));
+        }
+
+        if descriptor.offset == INLINE {
+            if descriptor.length > MAX_INLINE_SIZE {
+                return FragmentError::invalid_fragment_table();
+            }
+
+            let mut contents = vec![0u8; descriptor.inline_slots() * Self::size()];
+            source.read_exact(&mut contents)?;
+            contents.truncate(descriptor.length as usize);
+
+            descriptor.inline = Some(contents);
+        }
+
+        // Fragments which would end past the last page the store can address can only come from a corrupt table.
+        let end = descriptor.offset.checked_add(descriptor.length).and_then(|end| end.checked_next_multiple_of(MAX_PAGE_SIZE as u64));
+        if !descriptor.is_inline() && end.is_none() {
+            return FragmentError::invalid_fragment_table();
+        }
+
+        Ok(descriptor)
     }
 
     fn write(&mut self, mut source: Backing) -> Result<()> {
         source.write_all(&self.id.to_le_bytes())?;
         source.write_all(&self.sequence.to_le_bytes())?;
         source.write_all(&self.offset.to_le_bytes())?;
-        source.write_all(&self.length.to_le_bytes())?;
+
+        match self.written {
+            Some(written) => {
+                source.write_all(&(self.length | WRITTEN).to_le_bytes())?;
+
+                let mut slot = vec![0u8; Self::size()];
+                slot[0..8].copy_from_slice(&written.to_le_bytes());
+                source.write_all(&slot)?;
+            }
+            None => source.write_all(&self.length.to_le_bytes())?,
+        }
+
+        if let Some(ref contents) = self.inline {
+            let mut padded = contents.clone();
+            padded.resize(self.inline_slots() * Self::size(), 0);
+            source.write_all(&padded)?;
+        }
 
         Ok(())
     }
 }
 
+impl FragmentDescriptor {
+    /// Deleted fragments are marked by a sequence pointing at the start of the backing buffer, where no fragment can ever be stored.
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.offset == TOMBSTONE
+    }
+
+    pub(crate) fn is_inline(&self) -> bool {
+        self.offset == INLINE
+    }
+
+    /// How many descriptor-sized slots of the fragment table the descriptor takes up, counting its write time and the contents of inline
+    /// fragments.
+    pub(crate) fn slots(&self) -> usize {
+        1 + self.written.is_some() as usize + self.inline_slots()
+    }
+
+    fn inline_slots(&self) -> usize {
+        match self.is_inline() {
+            true => (self.length as usize).div_ceil(Self::size()),
+            false => 0,
+        }
+    }
+}
+
+/// Set in the length of descriptors which are followed by the time they were written. No fragment can be this long.
+const WRITTEN: u64 = 1 << 63;
+
+/// The current time, as it is recorded in fragment descriptors.
+pub(crate) fn now() -> UnixTimeMs {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_millis() as UnixTimeMs)
+}
+
+/// The offset given to descriptors of deleted fragments. The start of the backing buffer always holds the store's header.
+pub(crate) const TOMBSTONE: Pointer = 0;
+
+/// The offset given to descriptors of fragments whose contents are kept in the fragment table.
+pub(crate) const INLINE: Pointer = Pointer::MAX;
+
+/// The largest fragment which is kept in the fragment table instead of being allocated space of its own. Fragments are only inlined if
+/// they were buffered until they were closed.
+pub const MAX_INLINE_SIZE: u64 = 64;
+
 impl KnownSize for FragmentDescriptor {
     fn size() -> usize {
         32
@@ -421,24 +1108,26 @@ impl KnownSize for FragmentDescriptor {
 /// Offset  Size     Field
 /// -------------------------------
 /// 0       8 B      Continuation pointer (0 if terminal)
-/// 8       8 B      Capacity (max number of descriptors in this chunk)
-/// 16      8 B      Length (actual number of descriptors stored)
-/// 24..    N × 24 B Fragment descriptors (see `FragmentDescriptor`)
+/// 8       8 B      Capacity (max number of descriptor slots in this chunk)
+/// 16      8 B      Length (number of descriptor slots in use)
+/// 24..    N × 32 B Fragment descriptors (see `FragmentDescriptor`)
 /// ```
 ///
 /// Notes:
 /// - `continuation` is a pointer to the next `FragmentTablePart`, or 0 to denote the end.
 /// - The layout is fixed-size up to offset 24, after which a variable number of
 ///   `FragmentDescriptor`s are stored.
-/// - `capacity` defines how many descriptors the buffer can hold; `length` is how many
-///   are actually present. This allows for preallocation and incremental expansion.
+/// - `capacity` defines how many descriptor slots the buffer can hold; `length` is how many
+///   are actually in use. This allows for preallocation and incremental expansion. Inline
+///   fragments take up more than one slot.
 /// - Null (zero) pointers are permitted for `continuation` to denote list termination.
 ///
 /// Ensure the backing buffer is already seeked to the start of a valid table chunk.
 #[derive(Debug)]
-struct FragmentTablePart {
-    continuation: Pointer, // We'll accept the use of null-pointers here because they're space efficient.
-    fragments: Vec<FragmentDescriptor>,
+pub(crate) struct FragmentTablePart {
+    pub(crate) continuation: Pointer, // We'll accept the use of null-pointers here because they're space efficient.
+    pub(crate) capacity: usize,
+    pub(crate) fragments: Vec<FragmentDescriptor>,
 }
 
 impl<Backing: Read + Write + Seek> Storage<Backing> for FragmentTablePart {
@@ -452,23 +1141,32 @@ impl<Backing: Read + Write + Seek> Storage<Backing> for FragmentTablePart {
             return Err(FragmentError::LengthExceedsCapacity.into());
         }
 
-        let mut fragments = Vec::with_capacity(len as usize);
+        // The length is only trusted once that many descriptors have been read, so nothing is set aside for them up front.
+        let mut fragments = Vec::new();
+        let mut slots = 0;
 
-        for _ in 0..len {
-            fragments.push(FragmentDescriptor::read(&mut source)?);
-            // source.seek(SeekFrom::Current(FragmentDescriptor::size() as i64))?;
+        while slots < len as usize {
+            let frag = FragmentDescriptor::read(&mut source)?;
+            slots += frag.slots();
+            fragments.push(frag);
+        }
+
+        if slots != len as usize {
+            return Err(FragmentError::LengthExceedsCapacity.into());
         }
 
+        // Only the slots in use are known to be set aside for the table, so nothing more is added to a part which was read back.
         Ok(Self {
             continuation: u64::from_le_bytes(buffer[0..8].try_into()?),
+            capacity: slots,
             fragments,
         })
     }
 
     fn write(&mut self, mut source: Backing) -> Result<()> {
         source.write_all(self.continuation.to_le_bytes().as_slice())?;
-        source.write_all(self.fragments.capacity().to_le_bytes().as_slice())?;
-        source.write_all(self.fragments.len().to_le_bytes().as_slice())?;
+        source.write_all(self.capacity.to_le_bytes().as_slice())?;
+        source.write_all(self.len().to_le_bytes().as_slice())?;
 
         for frag in &mut self.fragments {
             frag.write(&mut source)?;
@@ -485,11 +1183,107 @@ impl KnownSize for FragmentTablePart {
 }
 
 impl FragmentTablePart {
+    /// How many descriptor slots are in use.
     pub fn len(&self) -> usize {
-        self.fragments.len()
+        self.fragments.iter().map(FragmentDescriptor::slots).sum()
     }
 
     pub fn cap(&self) -> usize {
-        self.fragments.capacity()
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fuzzing, AllocOptions, Danger, Database};
+    use proptest::prelude::*;
+    use std::io::Cursor;
+
+    /// A store of a few fragments, inline and not, spread over more than one part of the fragment table.
+    fn sample_store() -> Vec<u8> {
+        let mut backing = Cursor::new(Vec::new());
+        Database::destructive_reinitialise_with_page_size(&mut backing, MIN_PAGE_SIZE, Danger).unwrap();
+
+        let mut db = Database::new(&mut backing).unwrap();
+        for id in 1..=12u8 {
+            db.write_fragment(AllocOptions::default().fragment(id as FragmentID), &vec![id; 20 * id as usize]).unwrap();
+        }
+
+        db.delete_fragment(3).unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        backing.into_inner()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_written_fragments_read_back_identically(
+            page_size in prop_oneof![Just(MIN_PAGE_SIZE), Just(DEFAULT_PAGE_SIZE)],
+            writes in prop::collection::vec((1..16 as FragmentID, prop::option::weighted(0.8, prop::collection::vec(any::<u8>(), 0..3 * DEFAULT_PAGE_SIZE as usize)), any::<bool>()), 1..40),
+        ) {
+            let mut backing = Cursor::new(Vec::new());
+            Database::destructive_reinitialise_with_page_size(&mut backing, page_size, Danger).unwrap();
+
+            // What each fragment should hold. Writes of `None` delete the fragment instead.
+            let mut expected = BTreeMap::new();
+            let mut db = Database::new(&mut backing).unwrap();
+
+            for (id, data, flush) in writes {
+                match data {
+                    Some(data) => {
+                        db.write_fragment(AllocOptions::default().fragment(id), &data).unwrap();
+                        expected.insert(id, data);
+                    },
+                    None if expected.remove(&id).is_some() => db.delete_fragment(id).unwrap(),
+                    None => {},
+                }
+
+                if flush {
+                    db.flush().unwrap();
+                }
+            }
+
+            db.flush().unwrap();
+            drop(db);
+
+            // Fragment 0 is where the first part of the fragment table is kept.
+            let mut db = Database::new(&mut backing).unwrap();
+            prop_assert_eq!(db.fragments().map(|info| info.id).filter(|id| *id != 0).collect::<Vec<_>>(), expected.keys().copied().collect::<Vec<_>>());
+
+            for (id, data) in expected {
+                let mut contents = Vec::new();
+                db.open_fragment(id).unwrap().read_to_end(&mut contents).unwrap();
+                prop_assert_eq!(contents, data);
+            }
+        }
+
+        #[test]
+        fn test_corrupt_stores_are_rejected_without_panicking(
+            damage in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..16),
+            truncate in prop::option::of(any::<prop::sample::Index>()),
+        ) {
+            let mut bytes = sample_store();
+            for (at, byte) in damage {
+                let at = at.index(bytes.len());
+                bytes[at] = byte;
+            }
+
+            if let Some(at) = truncate {
+                bytes.truncate(at.index(bytes.len()));
+            }
+
+            fuzzing::read_store(&bytes);
+        }
+
+        #[test]
+        fn test_arbitrary_bytes_are_rejected_without_panicking(bytes in prop::collection::vec(any::<u8>(), 0..1024)) {
+            fuzzing::read_fragment(&bytes);
+            fuzzing::read_store_index(&bytes);
+            fuzzing::read_store(&bytes);
+        }
     }
 }
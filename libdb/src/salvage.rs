@@ -0,0 +1,258 @@
+use crate::cache::PageCache;
+use crate::error::{FragmentError, Result};
+use crate::migrate::MIGRATION_FRAGMENT;
+use crate::rw::{FragmentDescriptor, FragmentTablePart, KnownSize, Pointer, RWFragmentStore, RWFragmentStoreIndex, Storage};
+use crate::{Database, FragmentID};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+
+/// What [`Database::open_salvage`] made of a damaged store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Every fragment which can still be opened, in order of ID. Where a fragment's newest sequence was lost, an older one may have been
+    /// recovered in its place.
+    pub recovered: Vec<FragmentID>,
+
+    /// The IDs which were described by what couldn't be read, as ranges in order. The IDs in an unreadable part of the table can't be known,
+    /// so once a part is lost, every ID below the store's next ID which nothing else describes is counted, including any which were never
+    /// used.
+    pub lost: Vec<RangeInclusive<FragmentID>>,
+
+    /// Where the parts of the fragment table which couldn't be read begin.
+    pub unreadable_parts: Vec<Pointer>,
+
+    /// Where the parts of the fragment table which could only be found by scanning the store begin, because the part before them was lost.
+    pub found_parts: Vec<Pointer>,
+}
+
+impl SalvageReport {
+    /// Whether the store was opened without losing anything.
+    pub fn is_intact(&self) -> bool {
+        self.lost.is_empty() && self.unreadable_parts.is_empty()
+    }
+}
+
+/// Whether a descriptor could have been written by the store: it is inline, a tombstone, or names whole pages within the backing buffer.
+/// Descriptors which fail this can't be read, and are taken as signs of a part of the table being corrupt.
+fn plausible(frag: &FragmentDescriptor, page_size: u64, len: u64) -> bool {
+    let sequenced = frag.sequence > 0 || frag.id == 0;
+    let placed = frag.is_inline()
+        || (frag.is_tombstone() && frag.length == 0)
+        || (frag.offset.is_multiple_of(page_size) && frag.offset >= page_size && frag.offset.checked_add(frag.length).is_some_and(|end| end <= len));
+
+    sequenced && placed
+}
+
+/// Reads the part of the fragment table at `offset`, provided it fits in the backing buffer.
+fn read_part(source: &mut (impl Read + Write + Seek), offset: Pointer, len: u64) -> Result<FragmentTablePart> {
+    source.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = [0u8; 24];
+    source.read_exact(&mut buffer)?;
+
+    // Checked before the part is read, so scanning a page which merely looks like a part doesn't read the rest of the store as descriptors.
+    let slots = u64::from_le_bytes(buffer[16..24].try_into()?);
+    let size = slots.checked_mul(FragmentDescriptor::size() as u64).and_then(|size| size.checked_add(FragmentTablePart::size() as u64));
+    if size.is_none_or(|size| size > len.saturating_sub(offset)) {
+        return FragmentError::invalid_fragment_table();
+    }
+
+    source.seek(SeekFrom::Start(offset))?;
+    FragmentTablePart::read(source)
+}
+
+impl<Backing: Read + Write + Seek> Database<Backing> {
+    /// Opens a store even if parts of its fragment table are corrupt, and reports what was lost.
+    ///
+    /// Parts of the table which can't be read are skipped. Since each part is only found through the part before it, the store is then
+    /// scanned page by page for parts the table no longer leads to. Fragments don't record their IDs alongside their contents, so the table
+    /// is all there is to recover them from. Descriptors which point outside the store are dropped. The recovered table is written back
+    /// straight away, so the store opens normally from then on. Only a corrupt header, or a table of which nothing can be read, still stops
+    /// the store opening.
+    pub fn open_salvage(backing: Backing) -> Result<(Self, SalvageReport)> {
+        let mut backing = PageCache::new(backing)?;
+        let len = backing.seek(SeekFrom::End(0))?;
+
+        let (mut header, _) = RWFragmentStoreIndex::read_header(&mut backing)?;
+        let page_size = header.page_size;
+
+        let mut report = SalvageReport::default();
+        let mut parts = BTreeMap::new();
+
+        // The parts the table still leads to, in order.
+        let mut chain = vec![];
+        let mut offset = header.fragment_table_offset;
+        loop {
+            match read_part(&mut backing, offset, len) {
+                Ok(part) => {
+                    let continuation = part.continuation;
+                    parts.insert(offset, part);
+                    chain.push(offset);
+
+                    if continuation == 0 || parts.contains_key(&continuation) {
+                        break;
+                    }
+
+                    offset = continuation;
+                },
+                Err(_) => {
+                    report.unreadable_parts.push(offset);
+                    break;
+                },
+            }
+        }
+
+        if !report.unreadable_parts.is_empty() {
+            // The regions known to hold fragments or parts of the table, by where they start. They never overlap, and no other part of the
+            // table can start in one.
+            let mut used = BTreeMap::new();
+            let mark_used = |offset: Pointer, part: &FragmentTablePart, used: &mut BTreeMap<Pointer, Pointer>| {
+                used.insert(offset, offset + (FragmentTablePart::size() + part.cap() * FragmentDescriptor::size()) as u64);
+                for frag in part.fragments.iter().filter(|frag| plausible(frag, page_size, len) && !frag.is_inline() && !frag.is_tombstone()) {
+                    used.insert(frag.offset, frag.offset + frag.length);
+                }
+            };
+
+            for (offset, part) in &parts {
+                mark_used(*offset, part, &mut used);
+            }
+
+            for offset in (page_size..len).step_by(page_size as usize) {
+                if used.range(..=offset).next_back().is_some_and(|(_, end)| *end > offset) {
+                    continue;
+                }
+
+                let Ok(part) = read_part(&mut backing, offset, len) else {
+                    continue;
+                };
+
+                let continues = part.continuation == 0 || (part.continuation.is_multiple_of(page_size) && part.continuation < len);
+                if !part.fragments.is_empty() && continues && part.fragments.iter().all(|frag| plausible(frag, page_size, len)) {
+                    mark_used(offset, &part, &mut used);
+                    parts.insert(offset, part);
+                    report.found_parts.push(offset);
+                }
+            }
+        }
+
+        // The parts are chained in the order they were found in, beginning with those the table still led to.
+        let order = chain.into_iter().chain(report.found_parts.iter().copied()).collect::<Vec<_>>();
+        let mut dropped = BTreeSet::new();
+        let mut salvaged = vec![];
+
+        for (i, offset) in order.iter().enumerate() {
+            let Some(mut part) = parts.remove(offset) else {
+                continue;
+            };
+
+            part.fragments.retain(|frag| {
+                let keep = plausible(frag, page_size, len);
+                if !keep {
+                    dropped.insert(frag.id);
+                }
+
+                keep
+            });
+            part.capacity = part.len();
+            part.continuation = order.get(i + 1).copied().unwrap_or(0);
+
+            salvaged.push((*offset, part));
+        }
+
+        let Some((first, _)) = salvaged.first() else {
+            return FragmentError::invalid_fragment_table();
+        };
+
+        header.fragment_table_offset = *first;
+        header.end = *first;
+        header.assemble(&mut backing, salvaged, None)?;
+
+        let described = header.fragment_table().map(|frag| frag.id).collect::<BTreeSet<_>>();
+        report.recovered = header.live_fragments().map(|frag| frag.id).collect();
+
+        if !report.unreadable_parts.is_empty() {
+            let mut next = 1;
+            for id in described.range(1..MIGRATION_FRAGMENT).copied().chain([header.next_id]) {
+                if id > next {
+                    report.lost.push(next..=id - 1);
+                }
+
+                next = next.max(id + 1);
+            }
+        }
+
+        for id in dropped.difference(&described) {
+            if !report.lost.iter().any(|lost| lost.contains(id)) {
+                report.lost.push(*id..=*id);
+            }
+        }
+        report.lost.sort_by_key(|lost| *lost.start());
+
+        let mut db = Self { data_source: RWFragmentStore::with_header(backing, header) };
+        db.flush()?;
+
+        Ok((db, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllocOptions, Danger};
+    use std::io::Cursor;
+
+    #[test]
+    pub fn test_fragments_outside_a_lost_table_part_are_salvaged() -> Result<()> {
+        let mut backing = Cursor::new(Vec::new());
+        Database::destructive_reinitialise_with_page_size(&mut backing, crate::MIN_PAGE_SIZE, Danger)?;
+
+        // Enough fragments that the table spills into several parts.
+        let mut db = Database::new(&mut backing)?;
+        for id in 1..=60u64 {
+            db.write_fragment(AllocOptions::default().fragment(id), &[id as u8; 100])?;
+        }
+        db.flush()?;
+
+        let second = db.data_source.header.fragment_table_parts[0].continuation;
+        let in_second = db.data_source.header.fragment_table_parts[1].fragments.iter().map(|frag| frag.id).collect::<BTreeSet<_>>();
+        assert_ne!(second, 0, "the table should have more than one part");
+        drop(db);
+
+        // An intact store is opened as it is.
+        let (_, report) = Database::open_salvage(&mut backing)?;
+        assert!(report.is_intact());
+        assert_eq!(report.recovered.len(), 61);
+
+        // The second part of the table is overwritten, so it claims more descriptors than the store could hold.
+        backing.get_mut()[second as usize + 16..second as usize + 24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Database::new(&mut backing).is_err());
+
+        let (mut db, report) = Database::open_salvage(&mut backing)?;
+        assert_eq!(report.unreadable_parts, vec![second]);
+        assert!(!report.found_parts.is_empty());
+
+        for id in 1..=60u64 {
+            let lost = report.lost.iter().any(|lost| lost.contains(&id));
+            assert_eq!(lost, in_second.contains(&id), "fragment {id}");
+            assert_eq!(report.recovered.contains(&id), !lost, "fragment {id}");
+
+            if !lost {
+                let mut contents = Vec::new();
+                db.open_fragment(id)?.read_to_end(&mut contents)?;
+                assert_eq!(contents, vec![id as u8; 100]);
+            }
+        }
+
+        // The recovered table was written back, and takes new fragments.
+        db.write_fragment(AllocOptions::default().fragment(100), b"after")?;
+        db.flush()?;
+        drop(db);
+
+        let mut db = Database::new(&mut backing)?;
+        assert!(db.open_fragment(100).is_ok());
+        assert!(db.open_fragment(*in_second.first().unwrap()).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::Result;
+use crate::rw::{now, record_newest, FragmentDescriptor, TOMBSTONE};
+use crate::{Database, FragmentID};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek, Write};
+
+/// A point in a store's history which it can be rolled back to. See [`Database::savepoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    /// How many descriptors the fragment table held when the savepoint was taken. The table is only ever appended to, so everything written
+    /// since lies after them.
+    descriptors: usize,
+}
+
+impl<Backing: Read + Write + Seek> Database<Backing> {
+    /// Marks the current state of the store, so that every fragment written or deleted from here on can be undone with
+    /// [`Database::rollback`]. Taking a savepoint costs nothing, and savepoints which aren't rolled back to needn't be released.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            descriptors: self.data_source.header.fragment_table().count(),
+        }
+    }
+
+    /// Returns every fragment written or deleted since `savepoint` to the sequence it had then, and deletes the fragments created since.
+    /// Returns how many fragments were touched.
+    ///
+    /// Nothing is removed from the fragment table. Each fragment is given a new sequence instead, pointing at the contents it had at the
+    /// savepoint, so sequences only ever grow and nobody who read a sequence in between is misled into taking it for the restored one. IDs
+    /// handed out since the savepoint stay used. The savepoint no longer applies once the store's page size has been migrated.
+    pub fn rollback(&mut self, savepoint: Savepoint) -> Result<usize> {
+        let header = &self.data_source.header;
+
+        let touched = header.fragment_table().skip(savepoint.descriptors).map(|frag| frag.id).collect::<BTreeSet<FragmentID>>();
+        if touched.is_empty() {
+            return Ok(0);
+        }
+
+        let mut restored = BTreeMap::new();
+        for frag in header.fragment_table().take(savepoint.descriptors).filter(|frag| touched.contains(&frag.id)) {
+            record_newest(&mut restored, frag);
+        }
+
+        self.data_source.mark_dirty()?;
+
+        for &id in &touched {
+            let sequence = self.data_source.header.newest(id).map_or(0, |frag| frag.sequence) + 1;
+            let written = Some(now());
+
+            let descriptor = match restored.remove(&id) {
+                Some(frag) => FragmentDescriptor { sequence, written, ..frag },
+                None => FragmentDescriptor { id, sequence, offset: TOMBSTONE, length: 0, inline: None, written },
+            };
+
+            self.data_source.header.push_fragment_descriptor(descriptor)?;
+        }
+
+        self.data_source.committed()?;
+
+        Ok(touched.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rw::RWFragmentStore;
+    use crate::AllocOptions;
+    use std::io::{Cursor, Read};
+
+    fn contents(db: &mut crate::Database<Cursor<Vec<u8>>>, id: u64) -> Option<Vec<u8>> {
+        let mut data = vec![];
+        db.open_fragment(id).ok()?.read_to_end(&mut data).ok()?;
+        Some(data)
+    }
+
+    #[test]
+    pub fn test_rollback_restores_every_fragment_touched_since_the_savepoint() -> crate::error::Result<()> {
+        let mut backing = Cursor::new(vec![]);
+        RWFragmentStore::blank(&mut backing)?;
+
+        let mut db = crate::Database::new(backing)?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"one")?;
+        db.write_fragment(AllocOptions::default().fragment(2), &vec![2u8; 10_000])?;
+        db.write_fragment(AllocOptions::default().fragment(3), b"three")?;
+
+        let savepoint = db.savepoint();
+        assert_eq!(db.rollback(savepoint)?, 0);
+
+        db.write_fragment(AllocOptions::default().fragment(1), b"uno")?;
+        db.write_fragment(AllocOptions::default().fragment(1), b"eins")?;
+        db.write_fragment(AllocOptions::default().fragment(2), b"two")?;
+        db.delete_fragment(3)?;
+        db.write_fragment(AllocOptions::default().fragment(4), b"four")?;
+        let sequence = db.fragment_info(1)?.sequence;
+
+        assert_eq!(db.rollback(savepoint)?, 4);
+
+        assert_eq!(contents(&mut db, 1).as_deref(), Some(&b"one"[..]));
+        assert_eq!(contents(&mut db, 2), Some(vec![2u8; 10_000]));
+        assert_eq!(contents(&mut db, 3).as_deref(), Some(&b"three"[..]));
+        assert_eq!(contents(&mut db, 4), None);
+
+        // Restored fragments get new sequences rather than going back to their old ones.
+        assert!(db.fragment_info(1)?.sequence > sequence);
+
+        // The rollback survives the store being reopened.
+        db.flush()?;
+        let mut db = crate::Database::new(Cursor::new(db.backing().get_ref().clone()))?;
+        assert_eq!(contents(&mut db, 1).as_deref(), Some(&b"one"[..]));
+        assert_eq!(contents(&mut db, 4), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use crate::fragment::AllocOptions;
+
+/// The fewest sizes a histogram must have seen before it suggests anything other than the defaults.
+pub const MIN_OBSERVATIONS: u64 = 16;
+
+/// The most a histogram lets growable fragments buffer in memory.
+pub const MAX_BUFFER_THRESHOLD: u64 = 1024 * 1024;
+
+/// Counts how large the fragments written for one purpose tend to be, in power-of-two buckets, so that later fragments can be buffered to
+/// suit them. See [`SizeHistogram::alloc_options`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Bucket `n` counts sizes up to `2^n` bytes which didn't fit in the bucket before it.
+    buckets: [u64; 65],
+    count: u64,
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self { buckets: [0; 65], count: 0 }
+    }
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, size: u64) {
+        let bucket = match size {
+            0 | 1 => 0,
+            size => (u64::BITS - (size - 1).leading_zeros()) as usize,
+        };
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+    }
+
+    /// How many sizes have been recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// A size which at least `quantile` of the recorded sizes don't exceed, rounded up to a power of two. `None` if nothing was recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        let wanted = ((self.count as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        self.buckets.iter().enumerate().find_map(|(bucket, count)| {
+            seen += count;
+            (seen >= wanted).then(|| 1u64.checked_shl(bucket as u32).unwrap_or(u64::MAX))
+        })
+    }
+
+    /// Options for writing another fragment like the ones recorded. Growable fragments buffer enough to hold nearly all of them, so they
+    /// can be placed in free space once they are done rather than spilling to the end of the store, while the buffer starts out only as
+    /// large as a typical one, so tiny fragments don't each hold a page of memory. Until enough sizes are recorded, the defaults are used.
+    pub fn alloc_options(&self) -> AllocOptions {
+        let (Some(typical), Some(largest)) = (self.quantile(0.5), self.quantile(0.95)) else {
+            return AllocOptions::default();
+        };
+
+        if self.count < MIN_OBSERVATIONS {
+            return AllocOptions::default();
+        }
+
+        AllocOptions::default()
+            .growable()
+            .buffer_threshold(largest.min(MAX_BUFFER_THRESHOLD))
+            .buffer_capacity(typical.min(MAX_BUFFER_THRESHOLD) as usize)
+    }
+}
+
+impl FromIterator<u64> for SizeHistogram {
+    fn from_iter<T: IntoIterator<Item = u64>>(sizes: T) -> Self {
+        let mut histogram = Self::default();
+        sizes.into_iter().for_each(|size| histogram.record(size));
+
+        histogram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rw::RWFragmentStore;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    pub fn test_medium_fragments_stay_buffered() -> crate::error::Result<()> {
+        let histogram = (0..100u64).map(|i| 5000 + i * 50).collect::<SizeHistogram>();
+        assert_eq!(histogram.quantile(0.5), Some(8192));
+        assert_eq!(SizeHistogram::default().quantile(0.5), None);
+
+        let mut store = RWFragmentStore::blank(Cursor::new(vec![]))?;
+
+        // Free space left behind by an abandoned fragment is only reused by a fragment which stays buffered until it is done.
+        let mut abandoned = store.new_fragment(AllocOptions::default().fragment(1).size_hint(12 * 1024))?;
+        abandoned.write_all(&[1; 12 * 1024])?;
+        abandoned.abandon();
+        let freed = store.header.free_space.total();
+
+        let end = store.header.end;
+        store.new_fragment(AllocOptions::default().fragment(3))?.write_all(&[3; 6000])?;
+        assert!(store.header.end > end);
+
+        let end = store.header.end;
+        store.new_fragment(histogram.alloc_options().fragment(4))?.write_all(&[4; 6000])?;
+        assert_eq!(store.header.end, end);
+        assert!(store.header.free_space.total() < freed);
+
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use crate::{Fragment, FragmentID};
+use std::io::{Cursor, Read, Seek, Write};
+use crate::FragmentID;
 use crate::error::{FragmentError, Result};
 use crate::fragment::{FragmentHandle, FragmentType, SizedFragment};
+use crate::metrics::Operation;
 use crate::rw::RWFragmentStore;
 
 pub trait FragmentStore<Backing: Read + Write + Seek> {
@@ -11,24 +11,28 @@ pub trait FragmentStore<Backing: Read + Write + Seek> {
 
 impl<Backing: Read + Write + Seek> FragmentStore<Backing> for RWFragmentStore<Backing> {
     fn open_fragment(&'_ mut self, fragment: FragmentID) -> Result<FragmentHandle<'_, Backing>> {
-        if let Some(frag) = self.header.fragment_table()
-            .filter(|i| i.id == fragment)
-            .max_by_key(|i| i.sequence)
-            .cloned() {
+        let _timer = self.metrics.time(Operation::OpenFragment);
 
-            Ok(FragmentHandle {
-                index: self,
-
-                id: frag.id,
-                sequence: frag.sequence,
+        if let Some(frag) = self.header.newest(fragment).filter(|i| !i.is_tombstone()).cloned() {
 
-                fragment_type: FragmentType::ReadOnly(SizedFragment {
+            let fragment_type = match frag.inline {
+                Some(contents) => FragmentType::Inline(Cursor::new(contents)),
+                None => FragmentType::ReadOnly(SizedFragment {
                     cursor: 0,
                     ptr: frag.offset,
                     size: frag.length,
 
                     max_size: Some(0), // Disable writes
-                })
+                }),
+            };
+
+            Ok(FragmentHandle {
+                index: self,
+
+                id: frag.id,
+                sequence: frag.sequence,
+
+                fragment_type,
             })
         } else {
             Err(FragmentError::NoFound(fragment).into())
@@ -0,0 +1,236 @@
+//! `#[derive(DbModel)]`, which binds a struct to a collection of JSON documents through the client SDK in `src/bin/sdk`.
+//!
+//! The SDK isn't a crate of its own, so the generated code names it as `crate::sdk`, where every binary including it declares it.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Group, TokenStream as Tokens};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, GenericArgument, LitStr, PathArguments, Token, Type};
+
+/// Implements `DbModel` for a struct with named fields.
+///
+/// - `#[db(collection = "users")]` on the struct names its collection. It defaults to the struct's name in lower case.
+/// - `#[db(key)]` marks the field holding each document's ID, which its key is made of. Exactly one field must have it.
+/// - `#[db(index)]` declares a secondary index over a field, named `by-<field>`. `#[db(index = "name")]` names it.
+///
+/// Fields are named as serde names them, so `#[serde(rename = "...")]` is followed, and fields serde skips are left out of the schema.
+#[proc_macro_derive(DbModel, attributes(db))]
+pub fn derive_db_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match model(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// What the derive reads from a field.
+struct ModelField<'a> {
+    field: &'a Field,
+    name: String,
+    key: bool,
+    index: Option<String>,
+    skipped: bool,
+}
+
+fn model(input: &DeriveInput) -> syn::Result<Tokens> {
+    let Data::Struct(ref data) = input.data else {
+        return Err(syn::Error::new_spanned(input, "DbModel can only be derived for structs"));
+    };
+
+    let Fields::Named(ref fields) = data.fields else {
+        return Err(syn::Error::new_spanned(input, "DbModel needs a struct with named fields"));
+    };
+
+    let mut collection = input.ident.to_string().to_lowercase();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("db")) {
+        attr.parse_nested_meta(|meta| match meta.path.is_ident("collection") {
+            true => {
+                collection = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            },
+            false => Err(meta.error("Expected `collection = \"...\"`")),
+        })?;
+    }
+
+    let fields = fields.named.iter().map(model_field).collect::<syn::Result<Vec<_>>>()?;
+
+    let mut keys = fields.iter().filter(|field| field.key);
+    let Some(key) = keys.next() else {
+        return Err(syn::Error::new_spanned(input, "One field must be marked #[db(key)]"));
+    };
+
+    if let Some(other) = keys.next() {
+        return Err(syn::Error::new_spanned(other.field, "Only one field may be marked #[db(key)]"));
+    }
+
+    let key = key.field.ident.as_ref();
+
+    let indexes = fields.iter().filter_map(|field| {
+        let index = field.index.as_ref()?;
+        let pointer = format!("/{}", field.name.replace('~', "~0").replace('/', "~1"));
+
+        Some(quote! { crate::sdk::model::Index { name: #index, field: #pointer } })
+    });
+
+    let properties = fields.iter().filter(|field| !field.skipped).map(|field| {
+        let name = &field.name;
+        let schema = schema(&field.field.ty);
+
+        quote! { #name: #schema }
+    });
+
+    let required = fields.iter()
+        .filter(|field| !field.skipped && optional(&field.field.ty).is_none())
+        .map(|field| &field.name);
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics crate::sdk::model::DbModel for #ident #ty_generics #where_clause {
+            const COLLECTION: &'static str = #collection;
+            const INDEXES: &'static [crate::sdk::model::Index] = &[#(#indexes),*];
+
+            fn id(&self) -> String {
+                self.#key.to_string()
+            }
+
+            fn schema() -> serde_json::Value {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": { #(#properties),* },
+                    "required": [#(#required),*],
+                })
+            }
+        }
+    })
+}
+
+fn model_field(field: &Field) -> syn::Result<ModelField<'_>> {
+    let mut model = ModelField {
+        field,
+        name: field.ident.as_ref().map(ToString::to_string).unwrap_or_default(),
+        key: false,
+        index: None,
+        skipped: false,
+    };
+
+    for attr in &field.attrs {
+        if attr.path().is_ident("db") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("key") {
+                    model.key = true;
+                } else if meta.path.is_ident("index") {
+                    model.index = Some(match meta.input.peek(Token![=]) {
+                        true => meta.value()?.parse::<LitStr>()?.value(),
+                        false => String::new(),
+                    });
+                } else {
+                    return Err(meta.error("Expected `key`, `index` or `index = \"...\"`"));
+                }
+
+                Ok(())
+            })?;
+        } else if attr.path().is_ident("serde") {
+            // Only renames and skips matter here. Anything else is read past, whether it has a value or arguments.
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") && meta.input.peek(Token![=]) {
+                    model.name = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") {
+                    model.skipped = true;
+                } else if meta.input.peek(Token![=]) {
+                    meta.value()?.parse::<syn::Expr>()?;
+                } else if meta.input.peek(syn::token::Paren) {
+                    meta.input.parse::<Group>()?;
+                }
+
+                Ok(())
+            })?;
+        }
+    }
+
+    if let Some(ref mut index) = model.index
+        && index.is_empty()
+    {
+        *index = format!("by-{}", model.name);
+    }
+
+    Ok(model)
+}
+
+/// The type inside `Option<...>`, if `ty` is one.
+fn optional(ty: &Type) -> Option<&Type> {
+    match generic(ty)? {
+        ("Option", inner) => Some(inner),
+        _ => None,
+    }
+}
+
+/// The name of a type and its first type argument, such as `Vec` and `u8` for `Vec<u8>`.
+fn generic(ty: &Type) -> Option<(&'static str, &Type)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+
+    let segment = path.path.segments.last()?;
+    let PathArguments::AngleBracketed(ref arguments) = segment.arguments else {
+        return None;
+    };
+
+    let inner = arguments.args.iter().find_map(|argument| match argument {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })?;
+
+    let name = match segment.ident.to_string().as_str() {
+        "Option" => "Option",
+        "Vec" | "VecDeque" | "HashSet" | "BTreeSet" => "Vec",
+        "HashMap" | "BTreeMap" => "Map",
+        "Box" | "Arc" | "Rc" => "Box",
+        _ => return None,
+    };
+
+    Some((name, inner))
+}
+
+/// The JSON Schema of the values serde writes for `ty`. Types it doesn't know, such as other structs, accept any value.
+fn schema(ty: &Type) -> Tokens {
+    match ty {
+        Type::Reference(reference) => return schema(&reference.elem),
+        Type::Slice(slice) => {
+            let items = schema(&slice.elem);
+            return quote! { { "type": "array", "items": #items } };
+        },
+        _ => (),
+    }
+
+    match generic(ty) {
+        Some(("Option", inner)) => {
+            let inner = schema(inner);
+            return quote! { { "anyOf": [#inner, { "type": "null" }] } };
+        },
+        Some(("Vec", inner)) => {
+            let items = schema(inner);
+            return quote! { { "type": "array", "items": #items } };
+        },
+        Some(("Map", _)) => return quote! { { "type": "object" } },
+        Some((_, inner)) => return schema(inner),
+        None => (),
+    }
+
+    let Type::Path(path) = ty else {
+        return quote! { {} };
+    };
+
+    let name = path.path.segments.last().map(|segment| segment.ident.to_string()).unwrap_or_default();
+    let kind = match name.as_str() {
+        "String" | "str" | "char" => "string",
+        "bool" => "boolean",
+        "f32" | "f64" => "number",
+        "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => "integer",
+        _ => return quote! { {} },
+    };
+
+    quote! { { "type": #kind } }
+}
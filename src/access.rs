@@ -0,0 +1,316 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::USER_AGENT;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use crate::config::AccessLogConfig;
+use crate::request_id::RequestId;
+use crate::error::*;
+
+/// How many entries may wait to be written before new ones are dropped. Requests never wait for the access log.
+const QUEUE_LENGTH: usize = 4096;
+
+/// A part of an access log entry which may be left out for privacy. Tokens are never logged, so they don't need to be listed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessField {
+    Ip,
+    UserAgent,
+
+    /// The requested path, which may contain object keys. The route it matched is logged either way.
+    Path,
+    Query,
+    Database,
+}
+
+/// One line of the access log.
+#[derive(Debug, Serialize)]
+pub struct AccessEntry {
+    time: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    method: String,
+
+    /// The route pattern the request matched, such as `/objects/{key:.+}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<String>,
+
+    status: u16,
+    duration_ms: f64,
+
+    /// The size of the response body, if it was known up front.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+}
+
+impl AccessEntry {
+    /// Removes the fields the configuration asks to leave out, and truncates the client's address if asked to.
+    fn redact(&mut self, config: &AccessLogConfig) {
+        for field in &config.redact {
+            match field {
+                AccessField::Ip => self.ip = None,
+                AccessField::UserAgent => self.user_agent = None,
+                AccessField::Path => self.path = None,
+                AccessField::Query => self.query = None,
+                AccessField::Database => self.database = None,
+            }
+        }
+    }
+}
+
+/// Reduces an address to the network it belongs to: the first three octets of an IPv4 address, or the first 48 bits of an IPv6 address.
+fn truncate_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        },
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::from([a, b, c, 0, 0, 0, 0, 0])
+        },
+    }
+}
+
+/// Queues entries for the access log, which is written by a background task. Add it as app data to enable [`log_access`].
+#[derive(Clone)]
+pub struct AccessLog {
+    config: AccessLogConfig,
+    sender: Sender<AccessEntry>,
+
+    /// Entries dropped because the queue was full.
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    /// Opens the access log and starts writing to it. The task finishes once every [`AccessLog`] has been dropped and the queue is empty.
+    pub fn start(config: AccessLogConfig) -> Result<(Self, JoinHandle<()>)> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(QUEUE_LENGTH);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let writer = Writer::open(config.clone())?;
+        let task = {
+            let dropped = dropped.clone();
+            tokio::task::spawn_blocking(move || writer.run(receiver, dropped))
+        };
+
+        Ok((Self { config, sender, dropped }, task))
+    }
+
+    fn push(&self, mut entry: AccessEntry) {
+        entry.redact(&self.config);
+
+        if self.sender.try_send(entry).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Records every request in the access log, if there is one.
+pub async fn log_access(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let Some(log) = req.app_data::<web::Data<AccessLog>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let started = Instant::now();
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok()).map(ToOwned::to_owned);
+
+    let mut entry = AccessEntry {
+        time: Utc::now(),
+        request_id: req.extensions().get::<RequestId>().map(|id| id.as_str().to_owned()),
+        method: req.method().to_string(),
+        route: req.match_pattern(),
+        path: Some(req.path().to_owned()),
+        query: Some(req.query_string().to_owned()).filter(|query| !query.is_empty()),
+        ip: req.peer_addr()
+            .map(|addr| if log.config.truncate_ip { truncate_ip(addr.ip()) } else { addr.ip() })
+            .map(|ip| ip.to_string()),
+        user_agent: header(USER_AGENT.as_str()),
+        database: header("db"),
+        status: 0,
+        duration_ms: 0.0,
+        bytes: None,
+    };
+
+    let res = next.call(req).await;
+
+    entry.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match res {
+        Ok(ref res) => {
+            entry.status = res.status().as_u16();
+            entry.bytes = match res.response().body().size() {
+                BodySize::Sized(bytes) => Some(bytes),
+                BodySize::None | BodySize::Stream => None,
+            };
+        },
+        Err(ref err) => entry.status = err.as_response_error().status_code().as_u16(),
+    }
+
+    log.push(entry);
+
+    res
+}
+
+struct Writer {
+    config: AccessLogConfig,
+    file: BufWriter<File>,
+
+    /// How many bytes the current file holds.
+    size: u64,
+}
+
+impl Writer {
+    fn open(config: AccessLogConfig) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        if let Err(err) = prune(&config) {
+            log::warn!("Failed to remove old access logs: {}", err);
+        }
+
+        Ok(Self { file: BufWriter::new(file), size, config })
+    }
+
+    fn run(mut self, mut receiver: Receiver<AccessEntry>, dropped: Arc<AtomicU64>) {
+        let mut reported = 0;
+
+        while let Some(entry) = receiver.blocking_recv() {
+            if let Err(err) = self.write(&entry) {
+                log::error!("Failed to write to the access log: {}", err);
+            }
+
+            // Flushing only once the queue is empty lets a burst of requests be written together.
+            if receiver.is_empty() {
+                if let Err(err) = self.file.flush() {
+                    log::error!("Failed to write to the access log: {}", err);
+                }
+
+                let dropped = dropped.load(Ordering::Relaxed);
+                if dropped > reported {
+                    log::warn!("Dropped {} access log entries because the log couldn't keep up", dropped - reported);
+                    reported = dropped;
+                }
+            }
+        }
+
+        if let Err(err) = self.file.flush() {
+            log::error!("Failed to write to the access log: {}", err);
+        }
+    }
+
+    fn write(&mut self, entry: &AccessEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Moves the current file aside, named after the time it was rotated, and starts a new one.
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+
+        let mut rotated = self.config.path.clone().into_os_string();
+        rotated.push(Utc::now().format(".%Y%m%dT%H%M%S%.3f").to_string());
+        std::fs::rename(&self.config.path, rotated)?;
+
+        self.file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.config.path)?);
+        self.size = 0;
+
+        if let Err(err) = prune(&self.config) {
+            log::warn!("Failed to remove old access logs: {}", err);
+        }
+
+        Ok(())
+    }
+}
+
+/// Deletes rotated logs which are older than the retention period.
+fn prune(config: &AccessLogConfig) -> std::io::Result<()> {
+    let Some(name) = config.path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
+    };
+
+    let dir = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let cutoff = SystemTime::now() - Duration::from_secs(config.retention_days * 24 * 60 * 60);
+    let prefix = format!("{}.", name);
+
+    for file in std::fs::read_dir(dir)? {
+        let file = file?;
+
+        if file.file_name().to_str().is_some_and(|file| file.starts_with(&prefix)) && file.metadata()?.modified()? < cutoff {
+            std::fs::remove_file(file.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_truncates_addresses_to_their_network() {
+        assert_eq!(truncate_ip("192.0.2.123".parse().unwrap()).to_string(), "192.0.2.0");
+        assert_eq!(truncate_ip("2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap()).to_string(), "2001:db8:85a3::");
+    }
+
+    #[test]
+    pub fn test_redacts_configured_fields() -> serde_json::Result<()> {
+        let config = AccessLogConfig {
+            redact: vec![AccessField::Path, AccessField::UserAgent],
+            ..AccessLogConfig::default()
+        };
+
+        let mut entry = AccessEntry {
+            time: Utc::now(),
+            request_id: Some("9b6fdeca6d9bce26".to_owned()),
+            method: "GET".to_owned(),
+            route: Some("/objects/{key:.+}".to_owned()),
+            path: Some("/objects/people/alice".to_owned()),
+            query: None,
+            ip: Some("192.0.2.0".to_owned()),
+            user_agent: Some("curl/8.0".to_owned()),
+            database: Some("db1".to_owned()),
+            status: 200,
+            duration_ms: 1.0,
+            bytes: Some(5),
+        };
+
+        entry.redact(&config);
+        let line = serde_json::to_string(&entry)?;
+
+        assert!(!line.contains("alice") && !line.contains("curl"));
+        assert!(line.contains("/objects/{key:.+}") && line.contains("192.0.2.0"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeSet;
+use actix_web::{put, web, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::auth::AuthenticatedUser;
+use crate::index::{commit_change, DBIndexChange};
+use crate::resources::Access;
+use crate::response::{ApiError, ApiResponse};
+use crate::{AppID, DBIndex, Database, DatabaseID, UserID};
+
+/// Everyone who may use a database, besides its owner.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct Acl {
+    pub rw: BTreeSet<UserID>,
+    pub ro: BTreeSet<UserID>,
+    pub apps: BTreeSet<AppID>,
+}
+
+/// One difference between a database's access list and the one asked for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AclChange {
+    Member { user: UserID, from: Option<Access>, to: Option<Access> },
+    App { app: AppID, granted: bool },
+}
+
+impl AclChange {
+    pub(crate) fn into_index_change(self, database: &DatabaseID) -> DBIndexChange {
+        match self {
+            AclChange::Member { user, to, .. } => DBIndexChange::SetMember { database: database.clone(), user, access: to },
+            AclChange::App { app, granted } => DBIndexChange::SetAppAccess { database: database.clone(), app, granted },
+        }
+    }
+}
+
+impl Acl {
+    /// Whatever must change for the database to have exactly this access list.
+    pub fn diff(&self, db: &Database) -> Vec<AclChange> {
+        let access = |rw: bool, ro: bool| match (rw, ro) {
+            (true, _) => Some(Access::ReadWrite),
+            (false, true) => Some(Access::ReadOnly),
+            (false, false) => None,
+        };
+
+        let users = db.rw.iter().chain(db.ro.iter()).chain(self.rw.iter()).chain(self.ro.iter()).collect::<BTreeSet<_>>();
+
+        let members = users.into_iter().filter_map(|user| {
+            let from = access(db.rw.contains(user), db.ro.contains(user));
+            let to = access(self.rw.contains(user), self.ro.contains(user));
+
+            (from != to).then(|| AclChange::Member { user: user.clone(), from, to })
+        });
+
+        let granted = self.apps.iter()
+            .filter(|app| !db.apps.contains(app))
+            .map(|app| AclChange::App { app: app.clone(), granted: true });
+
+        let revoked = db.apps.iter()
+            .filter(|app| !self.apps.contains(*app))
+            .map(|app| AclChange::App { app: app.clone(), granted: false });
+
+        members.chain(granted).chain(revoked).collect()
+    }
+}
+
+fn bad_request(message: String) -> actix_web::Error {
+    ApiError::bad_request("invalid_acl", message).into()
+}
+
+#[derive(Serialize)]
+struct Changes {
+    changes: Vec<AclChange>,
+}
+
+/// Replaces a database's read-write members, read-only members and apps with the lists given, and reports what changed.
+///
+/// The changes are applied together, so nothing ever sees the database with only some of them. Only the database's owner may change its
+/// access list.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    request_body = Acl,
+    responses((status = 200, description = "The changes made to the access list"), (status = 400, description = "The access list can't be applied"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[put("/databases/{id}/acl")]
+pub async fn set_acl(id: web::Path<DatabaseID>, acl: web::Json<Acl>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let changes = {
+        let index = index.lock().await;
+
+        let Some(db) = index.databases.iter().find(|db| db.id == id && db.owner == user.id) else {
+            return Err(ApiError::no_such_database().into());
+        };
+
+        if let Some(member) = acl.rw.intersection(&acl.ro).next() {
+            return Err(bad_request(format!("{} can't be both a read-write and a read-only member", member)));
+        }
+
+        if acl.rw.contains(&db.owner) || acl.ro.contains(&db.owner) {
+            return Err(bad_request("The owner of a database always has access to it".to_owned()));
+        }
+
+        if let Some(member) = acl.rw.iter().chain(acl.ro.iter()).find(|member| !index.users.iter().any(|user| user.id == **member)) {
+            return Err(bad_request(format!("No such user: {}", member)));
+        }
+
+        if let Some(app) = acl.apps.iter().find(|app| !index.apps.iter().any(|existing| existing.id == **app)) {
+            return Err(bad_request(format!("No such app: {}", app)));
+        }
+
+        acl.diff(db)
+    };
+
+    if !changes.is_empty() {
+        commit_change(changes.iter().cloned().map(|change| change.into_index_change(&id)).collect::<Vec<_>>()).await
+            .map_err(ApiError::internal)?;
+
+        log::warn!(target: "audit", "{} changed the access list of database {}: {} changes", user, id, changes.len());
+    }
+
+    Ok(ApiResponse::ok(Changes { changes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::IndexFixture;
+
+    #[test]
+    pub fn test_diff_reports_only_what_changes() {
+        let index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .app("a1")
+            .rw("u2")
+            .ro("u3")
+            .ro("u4")
+            .build();
+
+        let acl = Acl {
+            rw: ["u3".to_owned()].into(),
+            ro: ["u4".to_owned(), "u5".to_owned()].into(),
+            apps: ["a2".to_owned()].into(),
+        };
+
+        assert_eq!(acl.diff(&index.databases[0]), vec![
+            AclChange::Member { user: "u2".to_owned(), from: Some(Access::ReadWrite), to: None },
+            AclChange::Member { user: "u3".to_owned(), from: Some(Access::ReadOnly), to: Some(Access::ReadWrite) },
+            AclChange::Member { user: "u5".to_owned(), from: None, to: Some(Access::ReadOnly) },
+            AclChange::App { app: "a2".to_owned(), granted: true },
+            AclChange::App { app: "a1".to_owned(), granted: false },
+        ]);
+    }
+}
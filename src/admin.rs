@@ -0,0 +1,136 @@
+use std::path::Path;
+use std::path::PathBuf;
+use actix_web::{post, web, Responder};
+use libdb::AllocOptions;
+use libdb::lock::LockMode;
+use serde::Serialize;
+use crate::auth::AuthenticatedUser;
+use crate::error::*;
+use crate::index::{commit_change, DBIndexChange};
+use crate::keys::{KeyDirectory, ObjectKey};
+use crate::pool::{open_store, unlock_store, DbPool, STORE_FILE};
+use crate::response::{ApiError, ApiResponse};
+use crate::{DBIndex, DatabaseID};
+use crate::telemetry;
+
+#[derive(Debug, Serialize)]
+pub struct SalvageReport {
+    /// The number of objects copied into the fresh store.
+    pub recovered: usize,
+
+    /// The keys of objects which couldn't be read from the damaged store.
+    pub lost: Vec<String>,
+
+    /// Where the damaged store was moved to. It is kept in case anything more can be recovered by hand.
+    pub preserved: Option<PathBuf>,
+}
+
+/// Copies every readable object from a damaged store into a fresh one with pages of `page_size` bytes. The damaged store is moved aside
+/// first.
+fn salvage(root: &Path, page_size: u32) -> Result<SalvageReport> {
+    let path = root.join(STORE_FILE);
+    let preserved = root.join(format!("{}.corrupt-{}", STORE_FILE, chrono::Utc::now().timestamp()));
+
+    let preserved = match std::fs::rename(&path, &preserved) {
+        Ok(()) => Some(preserved),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut fresh = open_store(&path, page_size)?;
+
+    // If even the store's header can't be read, there is nothing to copy and the fresh store is left empty.
+    let mut damaged = preserved.as_ref().and_then(|preserved| match libdb::Database::open_path(preserved, LockMode::Unlocked) {
+        Ok(damaged) => Some(damaged),
+        Err(err) => {
+            log::error!("Failed to open {}: {:?}", preserved.display(), err);
+            None
+        },
+    });
+
+    let directory = match damaged.as_mut().map(KeyDirectory::load) {
+        Some(Ok(directory)) => directory,
+        Some(Err(err)) => {
+            log::error!("Failed to read the key directory of the damaged store: {:?}", err);
+            KeyDirectory::default()
+        },
+        None => KeyDirectory::default(),
+    };
+
+    let mut recovered = KeyDirectory::default();
+    let mut lost = vec![];
+
+    for key in directory.keys() {
+        let (Some(damaged), Ok(key)) = (damaged.as_mut(), ObjectKey::parse(key)) else {
+            lost.push(key.to_owned());
+            continue;
+        };
+
+        // Compressed objects are recovered decompressed, since their dictionaries aren't carried over.
+        match directory.get(&key).map(|meta| (meta, crate::dictionary::read_contents(damaged, meta))) {
+            Some((meta, Ok(data))) => {
+                let (id, _) = recovered.get_or_insert(&key, &meta.content_type);
+                fresh.write_fragment(AllocOptions::default().fragment(id), &data)?;
+            },
+            Some((_, Err(err))) => {
+                log::warn!("Failed to recover {}: {:?}", key, err);
+                lost.push(key.to_string());
+            },
+            None => lost.push(key.to_string()),
+        }
+    }
+
+    recovered.save(&mut fresh)?;
+    fresh.flush()?;
+    unlock_store(&path, &fresh)?;
+
+    Ok(SalvageReport {
+        recovered: recovered.keys().count(),
+        lost,
+        preserved,
+    })
+}
+
+#[derive(Serialize)]
+struct Repaired {
+    report: SalvageReport,
+}
+
+/// Rebuilds a database's store from whatever can still be read from it, and lifts its quarantine.
+///
+/// The damaged store is moved aside rather than deleted. Only the database's owner may repair it.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "The repair report"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[post("/admin/databases/{id}/repair")]
+pub async fn repair_database(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let Some((root, page_size)) = index.lock().await.databases.iter()
+        .find(|db| db.id == id && db.owner == user.id)
+        .map(|db| (db.root.clone(), db.page_size.unwrap_or(libdb::DEFAULT_PAGE_SIZE))) else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    // Quarantining the database first keeps other requests from reopening the store while it is being rebuilt.
+    commit_change(DBIndexChange::QuarantineDatabase { database: id.clone(), reason: "Being repaired".to_owned() }).await
+        .map_err(ApiError::internal)?;
+
+    pool.evict(&id).await;
+
+    log::warn!(target: "audit", "{} started repairing database {}", user, id);
+
+    let report = telemetry::block(move || salvage(&root, page_size))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    log::warn!(target: "audit", "Repaired database {}: recovered {} objects, lost {}", id, report.recovered, report.lost.len());
+
+    commit_change(DBIndexChange::ReleaseDatabase { database: id }).await
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Repaired { report }))
+}
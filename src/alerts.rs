@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use crate::config::{AlertChannel, AlertConfig, EmailConfig};
+use crate::error::*;
+use crate::DatabaseID;
+
+/// How many alerts may wait to be sent before new ones are dropped. Raising an alert never waits.
+const QUEUE_LENGTH: usize = 256;
+
+/// Something an operator should know about, even without watching the logs or metrics.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Alert {
+    /// A store failed to open too many times in a row, and its database won't be opened until it is repaired.
+    StoreQuarantined { database: DatabaseID, reason: String },
+
+    /// A backup couldn't be completed. The client received an incomplete archive.
+    BackupFailed { database: DatabaseID, error: String },
+
+    /// A write was refused because the database would have outgrown its quota.
+    QuotaExceeded { database: DatabaseID, quota: u64 },
+
+    /// A write was refused because the disk holding the database is nearly full.
+    DiskFull { database: DatabaseID },
+}
+
+impl Alert {
+    fn database(&self) -> &DatabaseID {
+        match self {
+            Alert::StoreQuarantined { database, .. }
+            | Alert::BackupFailed { database, .. }
+            | Alert::QuotaExceeded { database, .. }
+            | Alert::DiskFull { database } => database,
+        }
+    }
+
+    /// Alerts with the same key are about the same problem, so repeats of it can be held back.
+    fn key(&self) -> (std::mem::Discriminant<Self>, DatabaseID) {
+        (std::mem::discriminant(self), self.database().clone())
+    }
+
+    fn summary(&self) -> String {
+        match self {
+            Alert::StoreQuarantined { database, reason } => format!("Database {} was quarantined: {}", database, reason),
+            Alert::BackupFailed { database, error } => format!("Backing up database {} failed: {}", database, error),
+            Alert::QuotaExceeded { database, quota } => format!("Database {} has used up its quota of {} bytes", database, quota),
+            Alert::DiskFull { database } => format!("The disk holding database {} is full", database),
+        }
+    }
+}
+
+/// An alert as it is sent to every channel.
+#[derive(Debug, Serialize)]
+struct AlertEvent {
+    time: DateTime<Utc>,
+    summary: String,
+    #[serde(flatten)]
+    alert: Alert,
+}
+
+enum Dispatch {
+    Alert(Alert),
+    /// Sends every alert raised before it, then stops.
+    Shutdown,
+}
+
+static ALERTS: OnceLock<Sender<Dispatch>> = OnceLock::new();
+
+/// Sends the alert to every configured channel in the background. Does nothing if no channels are configured.
+pub fn raise(alert: Alert) {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+
+    log::warn!("{}", alert.summary());
+
+    if alerts.try_send(Dispatch::Alert(alert)).is_err() {
+        log::error!("Dropped an alert because too many are waiting to be sent");
+    }
+}
+
+/// Starts sending alerts to the configured channels, unless there are none.
+pub fn start(config: AlertConfig) -> Option<JoinHandle<()>> {
+    if config.channels.is_empty() {
+        return None;
+    }
+
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(QUEUE_LENGTH);
+    ALERTS.set(sender).ok()?;
+
+    Some(tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut throttle = Throttle::new(config.repeat_interval());
+
+        while let Some(dispatch) = receiver.recv().await {
+            let alert = match dispatch {
+                Dispatch::Alert(alert) => alert,
+                Dispatch::Shutdown => {
+                    receiver.close();
+                    continue;
+                },
+            };
+
+            if !throttle.allow(&alert, Instant::now()) {
+                continue;
+            }
+
+            let event = AlertEvent {
+                time: Utc::now(),
+                summary: alert.summary(),
+                alert,
+            };
+
+            for channel in config.channels.iter() {
+                if let Err(err) = send(&client, channel, &event).await {
+                    log::error!("Failed to send an alert to {}: {:?}", channel.name(), err);
+                }
+            }
+        }
+    }))
+}
+
+/// Waits for every alert raised so far to be sent.
+pub async fn shutdown(handler: JoinHandle<()>) {
+    if let Some(alerts) = ALERTS.get() {
+        let _ = alerts.send(Dispatch::Shutdown).await;
+    }
+
+    if let Err(err) = handler.await {
+        log::error!("Alert handler failed: {}", err);
+    }
+}
+
+async fn send(client: &reqwest::Client, channel: &AlertChannel, event: &AlertEvent) -> Result<()> {
+    match channel {
+        AlertChannel::Webhook { url } => {
+            client.post(url).json(event).send().await?.error_for_status()?;
+        },
+        AlertChannel::Email(email) => {
+            let message = email_message(email, event)?;
+            let email = email.clone();
+
+            tokio::task::spawn_blocking(move || send_email(&email, &message))
+                .await
+                .map_err(std::io::Error::other)??;
+        },
+        AlertChannel::Stdout => println!("{}", serde_json::to_string(event)?),
+    }
+
+    Ok(())
+}
+
+fn email_message(config: &EmailConfig, event: &AlertEvent) -> Result<Message> {
+    let mut message = Message::builder()
+        .from(config.from.parse()?)
+        .subject(format!("[database-server] {}", event.summary));
+
+    for to in config.to.iter() {
+        message = message.to(to.parse()?);
+    }
+
+    Ok(message.body(format!("{}\n\n{}\n", event.summary, serde_json::to_string_pretty(event)?))?)
+}
+
+fn send_email(config: &EmailConfig, message: &Message) -> Result<()> {
+    let mut transport = match config.starttls {
+        true => SmtpTransport::starttls_relay(&config.server)?,
+        false => SmtpTransport::builder_dangerous(&config.server),
+    };
+
+    if let Some(port) = config.port {
+        transport = transport.port(port);
+    }
+
+    if let (Some(username), Some(password)) = (config.username.clone(), config.password.clone()) {
+        transport = transport.credentials(Credentials::new(username, password));
+    }
+
+    transport.build().send(message)?;
+
+    Ok(())
+}
+
+/// Holds back repeats of an alert until `interval` has passed since it was last sent, so a database which keeps refusing writes doesn't
+/// raise an alert for every one of them.
+struct Throttle {
+    interval: Duration,
+    sent: HashMap<(std::mem::Discriminant<Alert>, DatabaseID), Instant>,
+}
+
+impl Throttle {
+    fn new(interval: Duration) -> Self {
+        Self { interval, sent: HashMap::new() }
+    }
+
+    fn allow(&mut self, alert: &Alert, now: Instant) -> bool {
+        let key = alert.key();
+
+        if self.sent.get(&key).is_some_and(|sent| now.duration_since(*sent) < self.interval) {
+            return false;
+        }
+
+        self.sent.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_throttle_holds_back_repeats() {
+        let mut throttle = Throttle::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        let full = |database: &str| Alert::DiskFull { database: database.to_owned() };
+
+        assert!(throttle.allow(&full("db1"), start));
+        assert!(!throttle.allow(&full("db1"), start + Duration::from_secs(30)));
+        assert!(throttle.allow(&full("db2"), start + Duration::from_secs(30)));
+        assert!(throttle.allow(&Alert::QuotaExceeded { database: "db1".to_owned(), quota: 1 }, start + Duration::from_secs(30)));
+        assert!(throttle.allow(&full("db1"), start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    pub fn test_events_name_their_kind() -> serde_json::Result<()> {
+        let alert = Alert::QuotaExceeded { database: "db1".to_owned(), quota: 1024 };
+        let event = serde_json::to_value(AlertEvent { time: Utc::now(), summary: alert.summary(), alert })?;
+
+        assert_eq!(event["event"], "quota_exceeded");
+        assert_eq!(event["database"], "db1");
+        assert_eq!(event["quota"], 1024);
+
+        Ok(())
+    }
+}
@@ -1,11 +1,44 @@
+use std::collections::BTreeSet;
 use std::ops::Deref;
 use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use serde_json::json;
-use crate::{Application, DBIndex, User};
-use crate::auth::AuthenticatedUser;
-use crate::error::{AppError, TokenError};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::{Application, DBIndex};
+use crate::error::AppError;
+use crate::hashing;
+use crate::telemetry;
+use crate::response::ApiError;
+
+/// What an app's token may be used for. Each scope is separate, so an app which may write can't necessarily read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Reading objects, their metadata and history, and querying indexes.
+    Read,
+
+    /// Writing and deleting objects.
+    Write,
+
+    /// Changing how collections are stored: dictionaries, indexes and versioning.
+    Admin,
+}
+
+impl Scope {
+    /// Every scope, which apps have unless they are given fewer.
+    pub fn all() -> BTreeSet<Scope> {
+        BTreeSet::from([Scope::Read, Scope::Write, Scope::Admin])
+    }
+}
+
+impl Application {
+    /// Whether the app's token may be used for `scope` on the database. The app must still be attached to the database.
+    pub fn permits(&self, db: &str, scope: Scope) -> bool {
+        self.scopes.contains(&scope) && self.databases.as_ref().is_none_or(|databases| databases.contains(db))
+    }
+}
 
 pub struct ValidatedApp(Application);
 
@@ -17,17 +50,33 @@ impl Deref for ValidatedApp {
     }
 }
 
+impl ValidatedApp {
+    /// How the app is named in the changelog.
+    pub fn actor(&self) -> String {
+        format!("app:{}", self.id)
+    }
+
+    /// The app with ID `id`, for callers who proved they act for it without its token, such as by signing an S3 request.
+    pub async fn find(index: &DBIndex, id: &str) -> Option<Self> {
+        let app = index.lock().await.apps.iter().find(|app| app.id == id).cloned()?;
+        telemetry::record_app(&app.id);
+
+        Some(ValidatedApp(app))
+    }
+}
+
 impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::Unauthorized().json(json! {{
-            "success": false,
-            "message": match self {
-                AppError::MissingToken => "Missing token",
-                AppError::InvalidToken => "Invalid token",
-                AppError::ExpiredToken => "Expired token",
-                AppError::NoApp => "Token does not belong to an application",
-            }
-        }})
+        match self {
+            AppError::MissingToken => ApiError::new(self.status_code(), "missing_token", "Missing token"),
+            AppError::InvalidToken => ApiError::new(self.status_code(), "invalid_token", "Invalid token"),
+            AppError::ExpiredToken => ApiError::new(self.status_code(), "expired_token", "Expired token"),
+            AppError::NoApp => ApiError::new(self.status_code(), "no_app", "Token does not belong to an application"),
+        }.error_response()
     }
 }
 
@@ -52,14 +101,11 @@ impl FromRequest for ValidatedApp {
                 panic!("No index");
             };
             
+            let hash = hashing::hash(token);
             for app in index.lock().await.apps.iter() {
-                if app.token.token.eq(&token) {
-                    return Ok(ValidatedApp(Application {
-                        name: app.name.clone(),
-                        id: app.id.clone(),
-                        owner: app.owner.clone(),
-                        token: app.token.clone(),
-                    }));
+                if app.token.token.eq(&hash) {
+                    telemetry::record_app(&app.id);
+                    return Ok(ValidatedApp(app.clone()));
                 }
             }
             
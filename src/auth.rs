@@ -1,11 +1,18 @@
 use std::ops::Deref;
-use actix_web::{web, Error, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
 use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use serde_json::json;
 use crate::error::TokenError;
+use crate::index::{push_change, DBIndexChange};
+use crate::hashing;
+use crate::telemetry;
 use crate::{DBIndex, User};
+use crate::response::ApiError;
+
+/// How stale a user's recorded activity may get before using a token records it again.
+pub const ACTIVITY_RESOLUTION: chrono::Duration = chrono::Duration::minutes(5);
 
 pub struct AuthenticatedUser(User);
 
@@ -17,17 +24,47 @@ impl Deref for AuthenticatedUser {
     }
 }
 
+impl AuthenticatedUser {
+    /// How the user is named in the changelog.
+    pub fn actor(&self) -> String {
+        match self.service {
+            Some(_) => format!("service:{}", self.id),
+            None => format!("user:{}", self.id),
+        }
+    }
+}
+
+/// Names the user at the start of an audit log entry, telling service accounts apart from people.
+impl std::fmt::Display for AuthenticatedUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.service {
+            Some(_) => write!(f, "Service account {}", self.id),
+            None => write!(f, "User {}", self.id),
+        }
+    }
+}
+
 impl ResponseError for TokenError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::Unauthorized().json(json! {{
-            "success": false,
-            "message": match self {
-                TokenError::MissingToken => "Missing token", 
-                TokenError::InvalidToken => "Invalid token",
-                TokenError::ExpiredToken => "Expired token",
-                TokenError::NoUser => "Token does not belong to a user",
-            }
-        }})
+        match self {
+            TokenError::MissingToken => ApiError::new(self.status_code(), "missing_token", "Missing token"),
+            TokenError::InvalidToken => ApiError::new(self.status_code(), "invalid_token", "Invalid token"),
+            TokenError::ExpiredToken => ApiError::new(self.status_code(), "expired_token", "Expired token"),
+            TokenError::NoUser => ApiError::new(self.status_code(), "no_user", "Token does not belong to a user"),
+        }.error_response()
+    }
+}
+
+/// Whether a service account's stored token hash belongs to `token`, whose [`hashing::hash`] is `hash`. Returns `Some(true)` if it matched
+/// the hash service account tokens were stored as before they were keyed, which should then be replaced with `hash`.
+fn service_token_matches(stored: &str, token: &str, hash: &str) -> Option<bool> {
+    match hashing::is_hashed(stored) {
+        true => (stored == hash).then_some(false),
+        false => (stored == hashing::legacy_hash(token)).then_some(true),
     }
 }
 
@@ -35,7 +72,7 @@ impl FromRequest for AuthenticatedUser {
     type Error = TokenError;
     type Future = BoxFuture<'static, actix_web::Result<Self, Self::Error>>;
 
-    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
         let token = req.headers().get("Authorization").cloned();
         let index = req.app_data::<web::Data<DBIndex>>().cloned();
         
@@ -52,21 +89,58 @@ impl FromRequest for AuthenticatedUser {
                 panic!("No index");
             };
             
-            for user in index.lock().await.users.iter() {
-                if let Some(token) = user.api.iter().find(|u| u.token == token) {
-                    return if token.expiry < chrono::Utc::now() {
-                        Err(TokenError::ExpiredToken)
-                    } else {
-                        Ok(AuthenticatedUser(User {
-                            oauth: user.oauth.clone(),
-                            api: user.api.clone(),
-                            id: user.id.clone(),
-                        }))
-                    }
-                }
+            // Tokens are only stored hashed, so they are looked up by their hash.
+            let hash = hashing::hash(token);
+
+            let now = chrono::Utc::now();
+            let user = index.lock().await.users.iter().find_map(|user| match user.service {
+                Some(ref service) => service.token_hash.as_deref()
+                    .and_then(|stored| service_token_matches(stored, token, &hash))
+                    .map(|legacy| (user.clone(), service.expiry, legacy)),
+                None => user.api.iter().find(|u| u.token == hash).map(|token| (user.clone(), Some(token.expiry), false)),
+            });
+
+            let Some((user, expiry, legacy)) = user else {
+                return Err(TokenError::NoUser);
+            };
+
+            if expiry.is_some_and(|expiry| expiry < now) {
+                return Err(TokenError::ExpiredToken);
             }
-            
-            Err(TokenError::NoUser)
+
+            if legacy {
+                log::info!("Rehashed the token of service account {} with the token key", user.id);
+                push_change(DBIndexChange::RehashServiceToken { user: user.id.clone(), token_hash: hash }).await;
+            }
+
+            // Activity is only written to the index every so often, so using a token doesn't cost a write every time.
+            if user.last_active.is_none_or(|last| now - last >= ACTIVITY_RESOLUTION) {
+                push_change(DBIndexChange::RecordActivity { user: user.id.clone(), at: now }).await;
+            }
+
+            telemetry::record_user(&user.id);
+
+            Ok(AuthenticatedUser(user))
         }.boxed()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_service_tokens_stored_before_keying_are_upgraded() {
+        let hash = "h1:keyed".to_owned();
+
+        // Tokens from before the key was introduced were stored as their bare SHA-256.
+        let legacy = hashing::legacy_hash("sa.token");
+        assert_eq!(hashing::legacy_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(service_token_matches(&legacy, "sa.token", &hash), Some(true));
+        assert_eq!(service_token_matches(&legacy, "sa.other", &hash), None);
+
+        // Once rehashed, only the keyed hash is accepted.
+        assert_eq!(service_token_matches(&hash, "sa.token", &hash), Some(false));
+        assert_eq!(service_token_matches(&hash, "sa.token", "h1:other"), None);
+        assert_eq!(service_token_matches(&hash, "h1:keyed", "h1:other"), None);
+    }
+}
@@ -0,0 +1,252 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use actix_web::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use futures::StreamExt;
+use libdb::error::ArchiveError;
+use libdb::progress::{CancellationToken, Progress};
+use serde::Serialize;
+use tokio::sync::mpsc::{Receiver, Sender};
+use crate::alerts::{self, Alert};
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::error::*;
+use crate::index::{commit_change, DBIndexChange};
+use crate::pool::{DbPool, STORE_FILE};
+use crate::response::{ApiError, ApiResponse};
+use crate::{DBIndex, DatabaseID};
+use crate::telemetry;
+
+/// How much of an archive is sent to the client at a time.
+const CHUNK: usize = 256 * 1024;
+
+/// How many chunks may wait between the store and the client, in either direction.
+const QUEUE_LENGTH: usize = 4;
+
+type Chunk = std::io::Result<web::Bytes>;
+
+/// Hands whatever is written to it to a response body as it streams.
+struct BodyWriter(Sender<Chunk>);
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(Ok(web::Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "The client went away"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Cancels an operation once the response it streams to is dropped, as happens when the client goes away.
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Reads a request body as it arrives.
+struct BodyReader {
+    receiver: Receiver<Chunk>,
+    chunk: web::Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+
+        Ok(len)
+    }
+}
+
+/// Finds a database belonging to the user. Backups and restores are only for the database's owner.
+async fn owned_database(id: &DatabaseID, user: &AuthenticatedUser, index: &DBIndex) -> Option<crate::Database> {
+    index.lock().await.databases.iter()
+        .find(|db| db.id == *id && db.owner == user.id)
+        .cloned()
+}
+
+/// Streams an archive of the database's store, as written by [`libdb::Database::export`].
+///
+/// Nothing can write to the store until the whole archive has been sent, so the archive captures a single moment. Only the database's owner
+/// may back it up. Outside the database's maintenance windows the request is refused, with `Retry-After` saying when the next one opens.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "A backup of the database's store"), (status = 404, description = "No such database, or the caller may not use it"), (status = 503, description = "The database is outside its maintenance windows")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/backup")]
+pub async fn backup_database(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let Some(db) = owned_database(&id, &user, &index).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    if let Some(wait) = config.maintenance.until_window(&db.id, chrono::Utc::now()) {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, wait.as_secs().to_string()))
+            .json(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "outside_maintenance_window", format!("Backups of this database wait for its maintenance window, which opens in {} seconds", wait.as_secs()))));
+    }
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Chunk>(QUEUE_LENGTH);
+    let cancel = CancellationToken::new();
+    let progress = Progress::default().cancellable(cancel.clone());
+
+    log::info!(target: "audit", "{} is backing up database {}", user, id);
+
+    tokio::task::spawn_blocking(move || {
+        let errors = sender.clone();
+
+        // The store stays locked until the export has finished or the client has gone away.
+        if let Err(err) = store.blocking_lock().export_with_progress(BufWriter::with_capacity(CHUNK, BodyWriter(sender)), &progress) {
+            if matches!(err.inner(), libdb::error::global::Inner::IoError(err) if err.kind() == ErrorKind::BrokenPipe)
+                || matches!(err.inner(), libdb::error::global::Inner::ManualError(libdb::error::ManualError::Cancelled)) {
+                log::info!("Stopped backing up database {} because the client went away", id);
+                return;
+            }
+
+            log::error!("Failed to back up database {}: {:?}", id, err);
+            alerts::raise(Alert::BackupFailed { database: id.clone(), error: err.inner().to_string() });
+
+            // Failing the body cuts the archive short, and importing it is refused for lack of an end marker.
+            let _ = errors.blocking_send(Err(std::io::Error::other(err.to_string())));
+        }
+    });
+
+    // The export is cancelled as soon as the body is dropped, rather than once it next fails to hand the client a chunk.
+    let body = futures::stream::unfold((receiver, CancelOnDrop(cancel)), |(mut receiver, cancel)| async move {
+        receiver.recv().await.map(|chunk| (chunk, (receiver, cancel)))
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "application/octet-stream"))
+        .insert_header((CONTENT_DISPOSITION, format!("attachment; filename=\"{}.libdb\"", db.id)))
+        .streaming(body))
+}
+
+#[derive(Serialize)]
+struct Restored {
+    fragments: usize,
+}
+
+/// Replaces the database's store with one restored from an archive made by [`backup_database`].
+///
+/// The database is quarantined while it is being restored, so nothing can use it. The archive is restored into a new file which only
+/// replaces the store once it is complete, so a failed restore leaves the store as it was. Only the database's owner may restore it.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    request_body(content = Vec<u8>, description = "A backup made by `GET /databases/{id}/backup`", content_type = "application/octet-stream"),
+    responses((status = 200, description = "The store was replaced"), (status = 400, description = "The backup is invalid"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/restore")]
+pub async fn restore_database(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, mut payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let Some(db) = owned_database(&id, &user, &index).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    commit_change(DBIndexChange::QuarantineDatabase { database: id.clone(), reason: "Being restored".to_owned() }).await
+        .map_err(ApiError::internal)?;
+
+    pool.evict(&id).await;
+
+    log::warn!(target: "audit", "{} started restoring database {}", user, id);
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Chunk>(QUEUE_LENGTH);
+    let restore = telemetry::block(move || restore(&db, BodyReader { receiver, chunk: web::Bytes::new() }));
+
+    while let Some(chunk) = payload.next().await {
+        // The restore stops reading once it fails, and whatever is left of the body doesn't matter then.
+        if sender.send(chunk.map_err(std::io::Error::other)).await.is_err() {
+            break;
+        }
+    }
+
+    drop(sender);
+    let restored = restore.await?;
+
+    commit_change(DBIndexChange::ReleaseDatabase { database: id.clone() }).await
+        .map_err(ApiError::internal)?;
+
+    let fragments = match restored {
+        Ok(fragments) => fragments,
+        Err(err) if err.is_quota_exceeded() => return Err(DatabaseError::QuotaExceeded.into()),
+        Err(err) if err.is_out_of_space() => return Err(DatabaseError::OutOfSpace.into()),
+        Err(err) => return match archive_error(&err) {
+            Some(archive) => Err(ApiError::bad_request("invalid_archive", match archive {
+                ArchiveError::InvalidMagic => "The body is not an archive".to_owned(),
+                ArchiveError::UnsupportedVersion(version) => format!("Archives of version {} are not supported", version),
+                ArchiveError::Truncated | ArchiveError::BackingNotEmpty => "The archive is incomplete".to_owned(),
+            }).into()),
+            None => Err(ApiError::internal(err).into()),
+        },
+    };
+
+    log::warn!(target: "audit", "Restored database {}: {} fragments", id, fragments);
+
+    Ok(ApiResponse::ok(Restored { fragments }))
+}
+
+/// Whether restoring failed because of what was sent, rather than on the server's side.
+fn archive_error(err: &Error) -> Option<&ArchiveError> {
+    match err.inner() {
+        global::Inner::LibDbError(err) => match err.inner() {
+            libdb::error::global::Inner::ArchiveError(err) => Some(err),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Restores an archive into a new file beside the database's store, then moves it over the store. Returns the number of fragments restored.
+fn restore(db: &crate::Database, archive: impl Read) -> Result<usize> {
+    let path = db.root.join(STORE_FILE);
+    let restoring = db.root.join(format!("{}.restoring", STORE_FILE));
+
+    let result = (|| {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&restoring)?;
+
+        let store = libdb::Database::<File>::import(file, archive)?;
+        let fragments = store.fragments().count();
+
+        if db.quota.is_some_and(|quota| store.allocated_size() > quota) {
+            return Err(std::io::Error::new(ErrorKind::QuotaExceeded, "database quota exceeded").into());
+        }
+
+        store.backing().sync_all()?;
+        std::fs::rename(&restoring, &path)?;
+
+        Ok(fragments)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&restoring);
+    }
+
+    result
+}
@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use libdb::error::{Error, Result};
+use libdb::lock::LockMode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+
+/// The key the server hashes tokens with, kept beside the index. Without it, none of the tokens in a restored index would work.
+const KEY_FILE: &str = "token.key";
+
+/// The file the server locks while it replaces the index, beside it.
+const INDEX_LOCK_FILE: &str = "index.lock";
+
+/// A file of a backup set, along with what it should hold.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BackupFile {
+    /// The file's path within the backup set.
+    path: String,
+    size: u64,
+
+    /// The SHA-256 of the file's contents, in hex.
+    sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackedUpDatabase {
+    id: String,
+
+    /// The directory the database's store lived in when it was backed up. Restores put it in the data directory under the same name.
+    root: PathBuf,
+    archive: BackupFile,
+    fragments: u64,
+}
+
+/// Describes a backup set, and lets a restore check that nothing in it has changed since.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    created: DateTime<Utc>,
+    index: BackupFile,
+
+    /// Backups of servers which hadn't yet hashed their tokens have no key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<BackupFile>,
+    databases: Vec<BackedUpDatabase>,
+}
+
+fn json_error(err: serde_json::Error) -> Error {
+    Error::custom(err.to_string())
+}
+
+fn sha256(path: &Path) -> Result<(u64, String)> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => {
+                context.update(&buffer[..read]);
+                size += read as u64;
+            },
+        }
+    }
+
+    let hex = context.finish().as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    Ok((size, hex))
+}
+
+fn describe(set: &Path, path: &str) -> Result<BackupFile> {
+    let (size, sha256) = sha256(&set.join(path))?;
+
+    Ok(BackupFile { path: path.to_owned(), size, sha256 })
+}
+
+/// Checks every file of the backup set against the manifest, naming the first which doesn't match.
+fn verify(set: &Path, manifest: &Manifest) -> Result<()> {
+    for expected in std::iter::once(&manifest.index).chain(manifest.key.as_ref()).chain(manifest.databases.iter().map(|db| &db.archive)) {
+        if describe(set, &expected.path)? != *expected {
+            return Err(Error::custom(format!("{} doesn't match the manifest, so the backup set is damaged", expected.path)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Backs up the index, the token key and every store in `dir` into a new, timestamped backup set in `dest`, and returns the set's path.
+///
+/// Changes to the index are held back for the whole backup, and every store is locked against writers before any is copied, so the set
+/// captures a single moment across the whole deployment. Fails, without writing a set, if a store is open for writing, as it is while a
+/// running server uses it.
+pub fn backup_all(dir: &Path, dest: &Path) -> Result<PathBuf> {
+    // The server only replaces the index while holding an exclusive lock beside it, so this holds its changes back until the backup is done.
+    let index_path = dir.join("index.json");
+    let index_lock = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(INDEX_LOCK_FILE))?;
+    FileExt::lock_shared(&index_lock)?;
+
+    let index = std::fs::read(&index_path)?;
+    let parsed = serde_json::from_slice::<Value>(&index).map_err(json_error)?;
+
+    let databases = parsed["databases"].as_array().into_iter().flatten()
+        .map(|db| (db["id"].as_str().unwrap_or_default().to_owned(), PathBuf::from(db["root"].as_str().unwrap_or_default())))
+        .collect::<Vec<_>>();
+
+    let stores = databases.iter()
+        .map(|(_, root)| libdb::Database::open_path(root.join("store.db"), LockMode::Shared))
+        .collect::<Result<Vec<_>>>()?;
+
+    let created = Utc::now();
+    let set = dest.join(format!("backup-{}", created.format("%Y%m%dT%H%M%SZ")));
+    std::fs::create_dir_all(set.join("stores"))?;
+    std::fs::write(set.join("index.json"), &index)?;
+
+    let key = match dir.join(KEY_FILE).exists() {
+        true => {
+            std::fs::copy(dir.join(KEY_FILE), set.join(KEY_FILE))?;
+            Some(describe(&set, KEY_FILE)?)
+        },
+        false => None,
+    };
+
+    let mut backed_up = vec![];
+    for ((id, root), mut store) in databases.into_iter().zip(stores) {
+        let path = format!("stores/{}.dbx", id);
+        let mut archive = BufWriter::new(File::create_new(set.join(&path))?);
+        let fragments = store.export(&mut archive)?;
+        archive.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+
+        log::info!("Backed up {} fragments of database {}", fragments, id);
+        backed_up.push(BackedUpDatabase { id, root, archive: describe(&set, &path)?, fragments });
+    }
+
+    let manifest = Manifest { version: MANIFEST_VERSION, created, index: describe(&set, "index.json")?, key, databases: backed_up };
+    std::fs::write(set.join(MANIFEST_FILE), serde_json::to_vec_pretty(&manifest).map_err(json_error)?)?;
+
+    Ok(set)
+}
+
+/// Restores a backup set made by [`backup_all`] into `dir`, which must not hold an index yet. Every file is checked against the manifest
+/// before anything is written. The server's token key is restored along with the index, so tokens keep working. Each store is put in a directory of the same name as it had before, within `dir`, and the index is written
+/// last and pointed at them, so a restore which fails part-way leaves no index behind.
+pub fn restore_all(set: &Path, dir: &Path) -> Result<()> {
+    let manifest = serde_json::from_slice::<Manifest>(&std::fs::read(set.join(MANIFEST_FILE))?).map_err(json_error)?;
+    if manifest.version > MANIFEST_VERSION {
+        return Err(Error::custom(format!("The backup set was made by a newer version (manifest version {})", manifest.version)));
+    }
+
+    verify(set, &manifest)?;
+
+    let index_path = dir.join("index.json");
+    if index_path.exists() {
+        return Err(Error::custom(format!("{} already exists. Restore into an empty data directory", index_path.display())));
+    }
+
+    let mut index = serde_json::from_slice::<Value>(&std::fs::read(set.join(&manifest.index.path))?).map_err(json_error)?;
+
+    for db in manifest.databases.iter() {
+        let root = dir.join(db.root.file_name().unwrap_or(db.id.as_ref()));
+        std::fs::create_dir_all(&root)?;
+
+        let backing = File::create_new(root.join("store.db"))?;
+        let archive = BufReader::new(File::open(set.join(&db.archive.path))?);
+        libdb::Database::import(backing, archive)?.into_inner()?.sync_all()?;
+
+        let entry = index["databases"].as_array_mut().into_iter().flatten().find(|entry| entry["id"] == db.id.as_str());
+        if let Some(entry) = entry {
+            entry["root"] = Value::String(root.to_string_lossy().into_owned());
+        }
+
+        log::info!("Restored {} fragments of database {}", db.fragments, db.id);
+    }
+
+    if let Some(ref key) = manifest.key {
+        std::fs::copy(set.join(&key.path), dir.join(KEY_FILE))?;
+    }
+
+    let partial = index_path.with_extension("json.partial");
+    std::fs::write(&partial, serde_json::to_vec_pretty(&index).map_err(json_error)?)?;
+    std::fs::rename(&partial, &index_path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_backup_sets_restore_into_another_directory() -> Result<()> {
+        let base = std::env::temp_dir().join(format!("dbadmin-backup-test-{}", std::process::id()));
+        let (dir, dest, restored) = (base.join("data"), base.join("backups"), base.join("restored"));
+        std::fs::create_dir_all(dir.join("db1"))?;
+        std::fs::create_dir_all(&restored)?;
+
+        let mut store = libdb::Database::create(dir.join("db1/store.db"))?;
+        store.write_fragment(libdb::AllocOptions::default().fragment(7), b"Hello")?;
+        store.flush()?;
+        store.unlock(dir.join("db1/store.db"))?;
+        drop(store);
+
+        let index = json!({"version": 2, "databases": [{"id": "db1", "root": dir.join("db1")}], "apps": [], "users": []});
+        std::fs::write(dir.join("index.json"), index.to_string())?;
+        std::fs::write(dir.join(KEY_FILE), b"key")?;
+
+        let set = backup_all(&dir, &dest)?;
+        restore_all(&set, &restored)?;
+
+        let index = serde_json::from_slice::<Value>(&std::fs::read(restored.join("index.json"))?).map_err(json_error)?;
+        assert_eq!(index["databases"][0]["root"], json!(restored.join("db1")));
+        assert_eq!(std::fs::read(restored.join(KEY_FILE))?, b"key");
+
+        let mut store = libdb::Database::open_path(restored.join("db1/store.db"), LockMode::Shared)?;
+        let mut contents = vec![];
+        store.open_fragment(7)?.read_into(&mut contents)?;
+        assert_eq!(contents, b"Hello");
+
+        // Restoring over an existing index, or from a damaged set, is refused.
+        assert!(restore_all(&set, &restored).is_err());
+        std::fs::write(set.join("stores/db1.dbx"), b"damaged")?;
+        assert!(restore_all(&set, &base.join("elsewhere")).is_err());
+
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+}
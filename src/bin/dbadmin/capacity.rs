@@ -0,0 +1,239 @@
+use chrono::{DateTime, Duration, Utc};
+use libdb::error::{Error, Result};
+use libdb::lock::LockMode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// How long samples are kept for. Growth is projected from the samples taken within this long of the newest.
+const HISTORY_DAYS: i64 = 90;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+/// How much space each store had allocated at one moment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sample {
+    at: DateTime<Utc>,
+    allocated: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct DatabaseReport {
+    id: String,
+    name: String,
+    allocated: u64,
+    live: u64,
+
+    /// The share of the allocated space which no live fragment uses.
+    dead_ratio: f64,
+
+    /// How many bytes a day the store has grown by across the recorded samples.
+    growth_per_day: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    databases: Vec<DatabaseReport>,
+    disk_available: u64,
+    growth_per_day: Option<f64>,
+
+    /// How long until the disk is full if every store keeps growing as it has. Unknown until there are two samples, or if nothing grows.
+    days_until_full: Option<f64>,
+}
+
+/// The least-squares slope of size against time, in bytes a day. Needs samples spread over some time.
+fn growth_per_day(points: &[(DateTime<Utc>, u64)]) -> Option<f64> {
+    let (first, _) = points.first()?;
+    let days = points.iter()
+        .map(|(at, size)| ((*at - *first).num_seconds() as f64 / 86400.0, *size as f64))
+        .collect::<Vec<_>>();
+
+    let n = days.len() as f64;
+    let mean_x = days.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = days.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let variance = days.iter().map(|(x, _)| (x - mean_x).powi(2)).sum::<f64>();
+    let covariance = days.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum::<f64>();
+
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+fn read_history(path: &Path) -> Result<Vec<Sample>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut samples = vec![];
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(sample) => samples.push(sample),
+            Err(err) => log::warn!("Skipping an unreadable capacity sample: {}", err),
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Rewrites the history with `samples`, leaving out any older than [`HISTORY_DAYS`].
+fn write_history(path: &Path, samples: &[Sample]) -> Result<()> {
+    let cutoff = Utc::now() - Duration::days(HISTORY_DAYS);
+    let mut out = String::new();
+
+    for sample in samples.iter().filter(|sample| sample.at >= cutoff) {
+        out.push_str(&serde_json::to_string(sample).map_err(|err| Error::custom(err.to_string()))?);
+        out.push('\n');
+    }
+
+    let partial = path.with_extension("partial");
+    File::create(&partial)?.write_all(out.as_bytes())?;
+    std::fs::rename(&partial, path)?;
+
+    Ok(())
+}
+
+/// Opens a store without locking it, so it can be inspected while the server has it open. Only its header and fragment table are read.
+fn inspect(root: &Path) -> Result<libdb::StoreStats> {
+    let store = libdb::Database::open_path(root.join("store.db"), LockMode::Unlocked)?;
+
+    Ok(store.stats())
+}
+
+fn build_report(index: &Value, history: &[Sample], disk_available: u64, mut inspect: impl FnMut(&Path) -> Result<libdb::StoreStats>) -> (Report, Sample) {
+    let mut sample = Sample { at: Utc::now(), allocated: BTreeMap::new() };
+    let mut databases = vec![];
+
+    for db in index["databases"].as_array().into_iter().flatten() {
+        let id = db["id"].as_str().unwrap_or_default().to_owned();
+        let name = db["name"].as_str().unwrap_or_default().to_owned();
+        let root = Path::new(db["root"].as_str().unwrap_or_default());
+
+        let mut report = DatabaseReport { id: id.clone(), name, allocated: 0, live: 0, dead_ratio: 0.0, growth_per_day: None, error: None };
+
+        match inspect(root) {
+            Ok(stats) => {
+                report.allocated = stats.allocated;
+                report.live = stats.live;
+                report.dead_ratio = match stats.allocated {
+                    0 => 0.0,
+                    allocated => allocated.saturating_sub(stats.live) as f64 / allocated as f64,
+                };
+
+                sample.allocated.insert(id.clone(), stats.allocated);
+
+                let points = history.iter()
+                    .chain(std::iter::once(&sample))
+                    .filter_map(|sample| sample.allocated.get(&id).map(|size| (sample.at, *size)))
+                    .collect::<Vec<_>>();
+
+                report.growth_per_day = growth_per_day(&points);
+            },
+            Err(err) => report.error = Some(err.inner().to_string()),
+        }
+
+        databases.push(report);
+    }
+
+    let total = history.iter()
+        .chain(std::iter::once(&sample))
+        .map(|sample| (sample.at, sample.allocated.values().sum::<u64>()))
+        .collect::<Vec<_>>();
+
+    let growth = growth_per_day(&total);
+
+    let report = Report {
+        databases,
+        disk_available,
+        growth_per_day: growth,
+        days_until_full: growth.filter(|growth| *growth > 0.0).map(|growth| disk_available as f64 / growth),
+    };
+
+    (report, sample)
+}
+
+fn render(report: &Report) -> String {
+    let mut out = String::new();
+    let size = |bytes: f64| crate::top::human_size(bytes.max(0.0) as u64);
+
+    let _ = writeln!(out, "{:<40} {:<20} {:>10} {:>10} {:>6} {:>12}", "DATABASE", "NAME", "SIZE", "LIVE", "DEAD", "GROWTH/DAY");
+
+    for db in report.databases.iter() {
+        match db.error {
+            Some(ref error) => { let _ = writeln!(out, "{:<40} {:<20} failed to open: {}", db.id, db.name, error.trim()); },
+            None => {
+                let _ = writeln!(out, "{:<40} {:<20} {:>10} {:>10} {:>5.0}% {:>12}", db.id, db.name, size(db.allocated as f64), size(db.live as f64),
+                    db.dead_ratio * 100.0, db.growth_per_day.map_or("-".to_owned(), size));
+            },
+        }
+    }
+
+    let _ = writeln!(out, "\n{} free on disk.", size(report.disk_available as f64));
+
+    match (report.growth_per_day, report.days_until_full) {
+        (Some(growth), Some(days)) => { let _ = writeln!(out, "Stores grow by {} a day, so the disk fills in about {:.0} days.", size(growth), days); },
+        (Some(_), None) => { let _ = writeln!(out, "Stores aren't growing."); },
+        _ => { let _ = writeln!(out, "Run again later to project growth. Each run records a sample."); },
+    }
+
+    out
+}
+
+/// Reports how much space every store in `dir` uses and how quickly it grows, then records a sample for later runs to project growth from.
+pub fn run(dir: &Path, format: Format, history: Option<&Path>) -> Result<()> {
+    let index = std::fs::read_to_string(dir.join("index.json"))?;
+    let index = serde_json::from_str::<Value>(&index).map_err(|err| Error::custom(err.to_string()))?;
+
+    let history_path = history.map_or_else(|| dir.join("capacity.jsonl"), Path::to_path_buf);
+    let mut history = read_history(&history_path)?;
+
+    let (report, sample) = build_report(&index, &history, fs2::available_space(dir)?, inspect);
+
+    match format {
+        Format::Table => print!("{}", render(&report)),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report).map_err(|err| Error::custom(err.to_string()))?),
+    }
+
+    history.push(sample);
+    write_history(&history_path, &history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_growth_is_projected_from_samples() {
+        let start = Utc::now() - Duration::days(10);
+        let sample = |days: i64, size: u64| Sample { at: start + Duration::days(days), allocated: BTreeMap::from([("db1".to_owned(), size)]) };
+        let history = vec![sample(0, 1000), sample(5, 6000)];
+
+        let index = json! {{ "databases": [{ "id": "db1", "name": "first", "root": "/db1" }, { "id": "db2", "name": "second", "root": "/db2" }] }};
+        let stats = |root: &Path| match root.to_str() {
+            Some("/db1") => Ok(libdb::StoreStats { allocated: 11000, live: 8250, free: 0, fragments: 3, largest: None, last_modified: None }),
+            _ => Err(Error::custom("No such store")),
+        };
+
+        let (report, sample) = build_report(&index, &history, 100_000, stats);
+
+        let db1 = &report.databases[0];
+        assert_eq!((db1.allocated, db1.dead_ratio), (11000, 0.25));
+        assert!((db1.growth_per_day.unwrap() - 1000.0).abs() < 1.0);
+        assert!(report.databases[1].error.is_some());
+
+        assert!((report.days_until_full.unwrap() - 100.0).abs() < 0.1);
+        assert_eq!(sample.allocated, BTreeMap::from([("db1".to_owned(), 11000)]));
+    }
+}
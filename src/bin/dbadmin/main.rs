@@ -0,0 +1,99 @@
+use clap::{Parser, Subcommand};
+
+mod backup;
+mod capacity;
+mod seed;
+mod top;
+
+/// Tools for looking after a running server.
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Shows what each database is doing, refreshed in place until interrupted.
+    Top {
+        /// The server's address, such as `http://localhost:2003`.
+        #[clap(long = "url")]
+        url: String,
+
+        /// The bearer token the server's metrics are guarded by, if any.
+        #[clap(long = "token")]
+        token: Option<String>,
+
+        /// How often to refresh, in seconds.
+        #[clap(long = "interval", default_value_t = 2)]
+        interval: u64,
+    },
+
+    /// Provisions a demo app and database for a user, and fills the database with sample documents.
+    SeedDemo {
+        /// The server's address, such as `http://localhost:2003`.
+        #[clap(long = "url")]
+        url: String,
+
+        /// An API token of the user the demo is provisioned for.
+        #[clap(long = "token")]
+        token: String,
+
+        /// What to name the demo's app and database.
+        #[clap(long = "name", default_value = "demo")]
+        name: String,
+    },
+
+    /// Reports how much space each store uses, how fast it grows and how long until the disk fills. Each run records a sample of store
+    /// sizes, which later runs project growth from.
+    Capacity {
+        /// The server's data directory, holding `index.json`.
+        dir: std::path::PathBuf,
+
+        #[clap(long = "format", value_enum, default_value = "table")]
+        format: capacity::Format,
+
+        /// Where samples are kept. Defaults to `capacity.jsonl` in the data directory.
+        #[clap(long = "history")]
+        history: Option<std::path::PathBuf>,
+    },
+
+    /// Backs up the index and every store of a data directory into one consistent, timestamped backup set with a manifest of checksums.
+    /// Stores open for writing can't be backed up, so stop the server first.
+    BackupAll {
+        /// The server's data directory, holding `index.json`.
+        dir: std::path::PathBuf,
+
+        /// Where the backup set is created.
+        dest: std::path::PathBuf,
+    },
+
+    /// Restores a backup set made by `backup-all` into an empty data directory, after checking it against its manifest.
+    RestoreAll {
+        /// The backup set, as printed by `backup-all`.
+        set: std::path::PathBuf,
+
+        /// The data directory to restore into. It must not hold an `index.json` yet.
+        dir: std::path::PathBuf,
+    },
+}
+
+pub fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let result = match Args::parse().command {
+        Command::Top { url, token, interval } => top::run(&url, token.as_deref(), std::time::Duration::from_secs(interval.max(1))),
+        Command::SeedDemo { url, token, name } => seed::run(&url, &token, &name),
+        Command::Capacity { dir, format, history } => capacity::run(&dir, format, history.as_deref()),
+        Command::BackupAll { dir, dest } => backup::backup_all(&dir, &dest).map(|set| println!("{}", set.display())),
+        Command::RestoreAll { set, dir } => backup::restore_all(&set, &dir),
+    };
+
+    if let Err(err) = result {
+        log::error!("{err:?}");
+        std::process::exit(1);
+    }
+}
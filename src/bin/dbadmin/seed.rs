@@ -0,0 +1,102 @@
+use libdb::error::{Error, Result};
+use reqwest::blocking::{Client, RequestBuilder};
+use serde_json::{json, Value};
+
+/// Sample documents, grouped into collections by the first part of their key.
+fn samples() -> Vec<(&'static str, Value)> {
+    vec![
+        ("users/ada", json! {{ "name": "Ada Lovelace", "email": "ada@example.com", "joined": "1843-07-10" }}),
+        ("users/alan", json! {{ "name": "Alan Turing", "email": "alan@example.com", "joined": "1936-05-28" }}),
+        ("users/grace", json! {{ "name": "Grace Hopper", "email": "grace@example.com", "joined": "1952-05-01" }}),
+        ("posts/1", json! {{ "author": "ada", "title": "Notes on the Analytical Engine", "tags": ["engines", "notes"] }}),
+        ("posts/2", json! {{ "author": "alan", "title": "On Computable Numbers", "tags": ["logic"] }}),
+        ("posts/3", json! {{ "author": "grace", "title": "The Education of a Computer", "tags": ["compilers"] }}),
+        ("settings/site", json! {{ "title": "Demo", "theme": "light", "posts_per_page": 10 }}),
+    ]
+}
+
+/// The manifest provisioning the demo's app and database, both named after `name`.
+fn manifest(name: &str) -> Value {
+    json! {{
+        "apps": [{ "name": name }],
+        "databases": [{ "name": name, "apps": [name] }],
+    }}
+}
+
+/// Picks the app's token and the database's ID out of the response to the demo's manifest.
+fn provisioned(response: &Value, name: &str) -> Result<(String, String)> {
+    let resources = response["resources"].as_array().ok_or_else(|| Error::custom("The server didn't list what it provisioned"))?;
+    let find = |kind: &str| resources.iter().find(|resource| resource["kind"] == kind && resource["name"] == name);
+
+    let token = find("app")
+        .and_then(|app| app["token"].as_str())
+        .ok_or_else(|| Error::custom(format!("The app {} already exists, so its token can't be looked up. Pick another --name", name)))?;
+
+    let database = find("database")
+        .and_then(|db| db["id"].as_str())
+        .ok_or_else(|| Error::custom(format!("The server didn't provision the database {}", name)))?;
+
+    Ok((token.to_owned(), database.to_owned()))
+}
+
+fn send(request: RequestBuilder) -> Result<Value> {
+    let response = request.send().map_err(|err| Error::custom(err.to_string()))?;
+    let status = response.status();
+    let body = response.text().map_err(|err| Error::custom(err.to_string()))?;
+
+    if !status.is_success() {
+        return Err(Error::custom(format!("The server responded with {}: {}", status, body)));
+    }
+
+    serde_json::from_str(&body).map_err(|err| Error::custom(err.to_string()))
+}
+
+/// Provisions a demo app and database for the user `token` belongs to, fills the database with sample documents, then reads them back.
+pub fn run(url: &str, token: &str, name: &str) -> Result<()> {
+    let client = Client::new();
+    let url = url.trim_end_matches('/');
+
+    let response = send(client.post(format!("{}/provision", url)).bearer_auth(token).json(&manifest(name)))?;
+    let (app_token, database) = provisioned(&response, name)?;
+    println!("Provisioned the app and database {} ({})", name, database);
+
+    let samples = samples();
+    for (key, document) in samples.iter() {
+        send(client.put(format!("{}/objects/{}", url, key)).bearer_auth(&app_token).header("db", &database).json(document))?;
+    }
+
+    let listing = send(client.get(format!("{}/objects", url)).bearer_auth(&app_token).header("db", &database).query(&[("delimiter", "/")]))?;
+    let collections = listing["common_prefixes"].as_array().map_or(0, Vec::len);
+    println!("Wrote {} documents in {} collections", samples.len(), collections);
+
+    let post = send(client.get(format!("{}/objects/posts/1", url)).bearer_auth(&app_token).header("db", &database))?;
+    if post != samples.iter().find(|(key, _)| *key == "posts/1").map_or(Value::Null, |(_, document)| document.clone()) {
+        return Err(Error::custom("A document read back differently from how it was written"));
+    }
+
+    println!("\nUse the database by sending these headers:\n  Authorization: Bearer {}\n  db: {}", app_token, database);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_finds_the_new_app_token_and_database() {
+        let response = json! {{
+            "success": true,
+            "resources": [
+                { "kind": "app", "name": "demo", "id": "a1", "outcome": "created", "token": "secret" },
+                { "kind": "database", "name": "demo", "id": "db1", "outcome": "created", "changes": [] },
+            ]
+        }};
+
+        assert_eq!(provisioned(&response, "demo").unwrap(), ("secret".to_owned(), "db1".to_owned()));
+
+        // An app which already existed comes back without its token.
+        let existing = json! {{ "resources": [{ "kind": "app", "name": "demo", "id": "a1", "outcome": "unchanged" }] }};
+        assert!(provisioned(&existing, "demo").is_err());
+    }
+}
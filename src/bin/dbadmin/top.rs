@@ -0,0 +1,217 @@
+use libdb::error::{Error, Result};
+use reqwest::blocking::Client;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Clears the terminal and moves the cursor to its top left, so each refresh replaces the last.
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// The operations shown as rates, with the column each is shown in.
+const COLUMNS: [(&str, &str); 4] = [("read", "READ/S"), ("write", "WRITE/S"), ("commit", "COMMIT/S"), ("open_fragment", "OPENS/S")];
+
+/// What the metrics say about one database at one moment.
+#[derive(Debug, Default, PartialEq)]
+struct DatabaseSample {
+    /// How many of each operation its store has performed since the server started.
+    operations: BTreeMap<String, u64>,
+    size: Option<u64>,
+    open: bool,
+}
+
+struct Sample {
+    at: Instant,
+    databases: BTreeMap<String, DatabaseSample>,
+}
+
+pub fn run(url: &str, token: Option<&str>, interval: Duration) -> Result<()> {
+    let client = Client::new();
+    let url = format!("{}/metrics", url.trim_end_matches('/'));
+    let mut previous = None::<Sample>;
+
+    loop {
+        let mut screen = String::from(CLEAR);
+        let _ = writeln!(screen, "{}  (every {}s, Ctrl-C to quit)\n", url, interval.as_secs());
+
+        match fetch(&client, &url, token) {
+            Ok(sample) => {
+                screen.push_str(&render(previous.as_ref(), &sample));
+                previous = Some(sample);
+            },
+            Err(err) => {
+                let _ = writeln!(screen, "Failed to fetch metrics: {}", err.inner());
+            },
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()?;
+        drop(stdout);
+
+        std::thread::sleep(interval);
+    }
+}
+
+fn fetch(client: &Client, url: &str, token: Option<&str>) -> Result<Sample> {
+    let mut request = client.get(url);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().map_err(|err| Error::custom(err.to_string()))?;
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(Error::custom(format!("The server responded with {}", status)));
+    }
+
+    let body = response.text().map_err(|err| Error::custom(err.to_string()))?;
+
+    Ok(Sample {
+        at: Instant::now(),
+        databases: parse(&body),
+    })
+}
+
+/// Picks the per-database series `top` shows out of the server's metrics, ignoring anything else.
+fn parse(metrics: &str) -> BTreeMap<String, DatabaseSample> {
+    let mut databases = BTreeMap::<String, DatabaseSample>::new();
+
+    for line in metrics.lines().filter(|line| !line.starts_with('#')) {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+
+        let (name, labels) = match series.split_once('{') {
+            Some((name, labels)) => (name, parse_labels(labels.trim_end_matches('}'))),
+            None => (series, BTreeMap::new()),
+        };
+
+        let (Some(database), Ok(value)) = (labels.get("database"), value.parse::<f64>()) else {
+            continue;
+        };
+
+        let sample = databases.entry(database.clone()).or_default();
+
+        match name {
+            "libdb_operation_duration_seconds_count" => if let Some(operation) = labels.get("operation") {
+                sample.operations.insert(operation.clone(), value as u64);
+            },
+            "libdb_store_size_bytes" => sample.size = Some(value as u64),
+            "libdb_store_open" => sample.open = value > 0.0,
+            _ => (),
+        }
+    }
+
+    databases
+}
+
+/// Parses `a="1",b="2"`, undoing the escaping of `\`, `"` and newlines.
+fn parse_labels(labels: &str) -> BTreeMap<String, String> {
+    let mut parsed = BTreeMap::new();
+    let mut chars = labels.chars();
+
+    loop {
+        let name = chars.by_ref().take_while(|c| *c != '=').collect::<String>();
+        if name.is_empty() || chars.next() != Some('"') {
+            return parsed;
+        }
+
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c) => value.push(c),
+                    None => (),
+                },
+                c => value.push(c),
+            }
+        }
+
+        parsed.insert(name.trim_start_matches(',').to_owned(), value);
+    }
+}
+
+/// Lays the sample out as a table. Rates are worked out against the previous sample, so none are shown until there is one.
+fn render(previous: Option<&Sample>, current: &Sample) -> String {
+    let mut out = String::new();
+
+    let _ = write!(out, "{:<40} {:>5} {:>10}", "DATABASE", "OPEN", "SIZE");
+    for (_, column) in COLUMNS {
+        let _ = write!(out, " {:>10}", column);
+    }
+    out.push('\n');
+
+    for (id, sample) in current.databases.iter() {
+        let _ = write!(out, "{:<40} {:>5} {:>10}", id, if sample.open { "yes" } else { "no" }, sample.size.map_or("-".to_owned(), human_size));
+
+        for (operation, _) in COLUMNS {
+            let rate = previous.and_then(|previous| {
+                let elapsed = current.at.duration_since(previous.at).as_secs_f64();
+                let before = previous.databases.get(id).and_then(|sample| sample.operations.get(operation)).copied().unwrap_or_default();
+                let now = sample.operations.get(operation).copied()?;
+
+                (elapsed > 0.0).then(|| now.saturating_sub(before) as f64 / elapsed)
+            });
+
+            let _ = write!(out, " {:>10}", rate.map_or("-".to_owned(), |rate| format!("{:.1}", rate)));
+        }
+
+        out.push('\n');
+    }
+
+    if current.databases.is_empty() {
+        out.push_str("No databases reported. Is `metrics.enabled` set?\n");
+    }
+
+    out
+}
+
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_reads_rates_from_metrics() {
+        let metrics = |reads: u64| format!(concat!(
+            "# TYPE libdb_operation_duration_seconds histogram\n",
+            "libdb_operation_duration_seconds_bucket{{database=\"db\\\"1\",operation=\"read\",le=\"+Inf\"}} {0}\n",
+            "libdb_operation_duration_seconds_count{{database=\"db\\\"1\",operation=\"read\"}} {0}\n",
+            "libdb_store_size_bytes{{database=\"db\\\"1\"}} 1572864\n",
+            "libdb_store_open{{database=\"db\\\"1\"}} 1\n",
+        ), reads);
+
+        let start = Instant::now();
+        let before = Sample { at: start, databases: parse(&metrics(10)) };
+        let after = Sample { at: start + Duration::from_secs(2), databases: parse(&metrics(30)) };
+
+        let db = &after.databases["db\"1"];
+        assert_eq!(db.operations["read"], 30);
+        assert_eq!(db.size, Some(1572864));
+        assert!(db.open);
+
+        let table = render(Some(&before), &after);
+        let row = table.lines().find(|line| line.starts_with("db\"1")).unwrap();
+        assert!(row.contains("1.5 MiB") && row.contains("10.0"));
+    }
+}
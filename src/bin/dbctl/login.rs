@@ -0,0 +1,61 @@
+use libdb::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The file in the user's home directory which keeps the server and tokens `login` saved.
+const CREDENTIALS_FILE: &str = ".dbctl.toml";
+
+/// What `login` remembers, so later commands don't need to be told where the server is or how to authenticate.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub url: String,
+
+    /// A user's API token, or a service account's token, for managing databases and tokens.
+    pub token: String,
+
+    /// An app's token, for uploading and downloading objects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_token: Option<String>,
+}
+
+fn credentials_file() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(CREDENTIALS_FILE))
+        .ok_or_else(|| Error::custom("HOME isn't set, so there is nowhere to keep credentials"))
+}
+
+impl Credentials {
+    /// The credentials saved by the last `login`, if there was one.
+    pub fn load() -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(credentials_file()?) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        toml::from_str(&contents).map(Some).map_err(|err| Error::custom(format!("Invalid credentials file: {err}")))
+    }
+
+    /// Saves the credentials where only the user can read them.
+    pub fn save(&self) -> Result<PathBuf> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = credentials_file()?;
+        let contents = toml::to_string(self).map_err(|err| Error::custom(err.to_string()))?;
+
+        let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path)?;
+        file.write_all(contents.as_bytes())?;
+
+        Ok(path)
+    }
+
+    /// Forgets the saved credentials. Returns whether there were any.
+    pub fn remove() -> Result<bool> {
+        match std::fs::remove_file(credentials_file()?) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
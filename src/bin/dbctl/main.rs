@@ -0,0 +1,246 @@
+use clap::{Parser, Subcommand};
+use libdb::error::{Error, Result};
+use login::Credentials;
+use output::Output;
+use sdk::retry::RetryPolicy;
+use sdk::Client;
+use std::path::{Path, PathBuf};
+
+mod login;
+mod output;
+
+#[path = "../sdk/mod.rs"]
+mod sdk;
+
+/// Manages a running server from the command line: its databases, objects and tokens.
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+
+    /// How results are printed.
+    #[clap(long = "output", value_enum, default_value = "table", global = true)]
+    output: Output,
+
+    /// The server's address, in place of the one saved by `login`.
+    #[clap(long = "url", global = true)]
+    url: Option<String>,
+
+    /// An API token, in place of the one saved by `login`.
+    #[clap(long = "token", global = true)]
+    token: Option<String>,
+
+    /// An app's token, in place of the one saved by `login`.
+    #[clap(long = "app-token", global = true)]
+    app_token: Option<String>,
+
+    /// How many times requests are retried when the server is busy, unavailable or times out. 0 turns retries off.
+    #[clap(long = "retries", default_value_t = 3, global = true)]
+    retries: u32,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks a token against the server and saves both, so later commands don't need them. Pass the server and token with `--url` and
+    /// `--token`, or trade a refresh token for a new token with `--refresh`.
+    Login {
+        /// A refresh token to trade for a new API token.
+        #[clap(long = "refresh", conflicts_with = "token")]
+        refresh: Option<String>,
+    },
+
+    /// Forgets the saved server and tokens.
+    Logout,
+
+    /// Lists the databases the user belongs to.
+    Databases,
+
+    /// Creates a database owned by the user.
+    CreateDatabase {
+        name: String,
+
+        /// The page size of the database's store, in bytes.
+        #[clap(long = "page-size")]
+        page_size: Option<u32>,
+    },
+
+    /// Deletes a database and everything in it.
+    DeleteDatabase {
+        id: String,
+    },
+
+    /// Uploads a file as an object. Without a key, the server picks one.
+    Upload {
+        /// The database to upload into.
+        #[clap(long = "db")]
+        db: String,
+
+        file: PathBuf,
+
+        key: Option<String>,
+
+        #[clap(long = "content-type")]
+        content_type: Option<String>,
+    },
+
+    /// Downloads an object into a file, or to stdout if no file is given.
+    Download {
+        /// The database to download from.
+        #[clap(long = "db")]
+        db: String,
+
+        key: String,
+
+        file: Option<PathBuf>,
+    },
+
+    /// Lists the user's API tokens.
+    Tokens,
+
+    /// Revokes every token of a user. Only user admins may do this.
+    RevokeTokens {
+        user: String,
+    },
+
+    /// Creates a service account and prints its token, which can't be shown again. Only user admins may do this.
+    CreateServiceAccount {
+        name: String,
+
+        /// How long the account's token lasts, in seconds. It never expires otherwise.
+        #[clap(long = "expires-in")]
+        expires_in: Option<u64>,
+    },
+}
+
+impl Args {
+    /// The saved credentials, with whatever was passed on the command line in their place.
+    fn credentials(&self) -> Result<Credentials> {
+        let saved = Credentials::load()?.unwrap_or_default();
+
+        let credentials = Credentials {
+            url: self.url.clone().unwrap_or(saved.url),
+            token: self.token.clone().unwrap_or(saved.token),
+            app_token: self.app_token.clone().or(saved.app_token),
+        };
+
+        if credentials.url.is_empty() {
+            return Err(Error::custom("Not logged in. Run `dbctl login --url <url> --token <token>` or pass `--url`"));
+        }
+
+        Ok(credentials)
+    }
+
+    fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            ..Default::default()
+        }
+    }
+
+    /// A client acting as the user.
+    fn user(&self) -> Result<Client> {
+        let credentials = self.credentials()?;
+        Client::connect(&credentials.url, &credentials.token, self.retry())
+    }
+
+    /// A client acting as the app, for reading and writing objects in `db`.
+    fn app(&self, db: &str) -> Result<Client> {
+        let credentials = self.credentials()?;
+        let token = credentials.app_token.ok_or_else(|| Error::custom("Objects can only be reached with an app's token. Pass `--app-token`"))?;
+
+        let mut client = Client::connect(&credentials.url, &token, self.retry())?;
+        client.use_database(Some(db.to_owned()));
+
+        Ok(client)
+    }
+}
+
+fn login(args: &Args, refresh: Option<&str>) -> Result<()> {
+    let mut credentials = Credentials {
+        url: args.url.clone().ok_or_else(|| Error::custom("Pass the server's address with `--url`"))?,
+        token: args.token.clone().unwrap_or_default(),
+        app_token: args.app_token.clone(),
+    };
+
+    if let Some(refresh) = refresh {
+        let issued = Client::refresh(&credentials.url, refresh, args.retry())?;
+        credentials.token = issued.get("token").and_then(serde_json::Value::as_str).unwrap_or_default().to_owned();
+
+        // The refresh token just used is spent, so the new one is the only way to get another token later.
+        if let Some(refresh) = issued.get("refresh").and_then(serde_json::Value::as_str) {
+            eprintln!("New refresh token: {refresh}");
+        }
+    }
+
+    if credentials.token.is_empty() {
+        return Err(Error::custom("Pass an API token with `--token`, or a refresh token with `--refresh`"));
+    }
+
+    // Listing tokens is the cheapest request which proves the token works.
+    Client::connect(&credentials.url, &credentials.token, args.retry())?.tokens()?;
+
+    let path = credentials.save()?;
+    eprintln!("Logged in to {}. Credentials saved to {}", credentials.url, path.display());
+
+    Ok(())
+}
+
+fn upload(args: &Args, db: &str, file: &Path, key: Option<&str>, content_type: Option<&str>) -> Result<()> {
+    let body = reqwest::blocking::Body::from(std::fs::File::open(file)?);
+    let key = args.app(db)?.upload(key, content_type, body)?;
+
+    args.output.print(&serde_json::json! {{ "object": key }}, &["object"])
+}
+
+fn download(args: &Args, db: &str, key: &str, file: Option<&Path>) -> Result<()> {
+    let mut response = args.app(db)?.download(key)?;
+
+    let copied = match file {
+        Some(file) => response.copy_to(&mut std::fs::File::create(file)?),
+        None => response.copy_to(&mut std::io::stdout().lock()),
+    };
+
+    copied.map(|_| ()).map_err(|err| Error::custom(err.to_string()))
+}
+
+pub fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let args = Args::parse();
+    let output = args.output;
+
+    let result = match args.command {
+        Command::Login { ref refresh } => login(&args, refresh.as_deref()),
+        Command::Logout => Credentials::remove().map(|removed| if !removed {
+            eprintln!("Not logged in");
+        }),
+        Command::Databases => args.user()
+            .and_then(|client| client.databases())
+            .and_then(|databases| output.print(&databases.into(), &["id", "name", "owner", "rw", "ro"])),
+        Command::CreateDatabase { ref name, page_size } => args.user()
+            .and_then(|client| client.create_database(name, page_size))
+            .and_then(|database| output.print(&database, &["id", "name"])),
+        Command::DeleteDatabase { ref id } => args.user()
+            .and_then(|client| client.delete_database(id))
+            .and_then(|deleted| output.print(&deleted, &["success"])),
+        Command::Upload { ref db, ref file, ref key, ref content_type } => upload(&args, db, file, key.as_deref(), content_type.as_deref()),
+        Command::Download { ref db, ref key, ref file } => download(&args, db, key, file.as_deref()),
+        Command::Tokens => args.user()
+            .and_then(|client| client.tokens())
+            .and_then(|tokens| output.print(&tokens.into(), &["token", "expiry"])),
+        Command::RevokeTokens { ref user } => args.user()
+            .and_then(|client| client.revoke_tokens(user))
+            .and_then(|revoked| output.print(&revoked, &["revoked"])),
+        Command::CreateServiceAccount { ref name, expires_in } => args.user()
+            .and_then(|client| client.create_service_account(name, expires_in))
+            .and_then(|account| output.print(&account, &["id", "token", "expiry"])),
+    };
+
+    if let Err(err) = result {
+        log::error!("{err:?}");
+        std::process::exit(1);
+    }
+}
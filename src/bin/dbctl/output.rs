@@ -0,0 +1,82 @@
+use libdb::error::{Error, Result};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+/// Formats a field for a table. Strings are shown without their quotes, missing fields as `-`, and redacted tokens by their prefix.
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => "-".to_owned(),
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Object(token)) if token.contains_key("prefix") => format!("{}...", cell(token.get("prefix"))),
+        Some(Value::Array(values)) => values.iter().map(|value| cell(Some(value))).collect::<Vec<_>>().join(","),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Lays `rows` out as a table of `columns`, each as wide as its widest cell.
+pub fn render(rows: &[Value], columns: &[&str]) -> String {
+    let cells = rows.iter()
+        .map(|row| columns.iter().map(|column| cell(row.get(column))).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let widths = columns.iter().enumerate()
+        .map(|(i, column)| cells.iter().map(|row| row[i].chars().count()).chain([column.len()]).max().unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    let header = columns.iter().map(|column| column.to_uppercase()).collect::<Vec<_>>();
+
+    let mut table = String::new();
+    for row in [header].iter().chain(cells.iter()) {
+        let line = row.iter().zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+
+    table
+}
+
+impl Output {
+    /// Prints a listing, or a single object as a table of one row.
+    pub fn print(self, value: &Value, columns: &[&str]) -> Result<()> {
+        match self {
+            Output::Json => println!("{}", serde_json::to_string_pretty(value).map_err(|err| Error::custom(err.to_string()))?),
+            Output::Table => match value {
+                Value::Array(rows) => print!("{}", render(rows, columns)),
+                row => print!("{}", render(std::slice::from_ref(row), columns)),
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_tables_line_up_their_columns() {
+        let rows = [
+            json! {{ "id": "db1", "name": "photos", "rw": ["u2", "u3"] }},
+            json! {{ "id": "database2", "name": null }},
+        ];
+
+        assert_eq!(render(&[json! {{ "token": { "prefix": "dbt_Zq" } }}], &["token"]), "TOKEN\ndbt_Zq...\n");
+
+        assert_eq!(render(&rows, &["id", "name", "rw"]), "\
+ID         NAME    RW
+db1        photos  u2,u3
+database2  -       -
+");
+    }
+}
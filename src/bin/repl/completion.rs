@@ -0,0 +1,53 @@
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+/// Completes the first word of a line from the commands available at the current prompt. Later words complete from `names`, such as the
+/// open store's fragment IDs, or as file names if none of those match.
+#[derive(Default)]
+pub struct Completions {
+    commands: &'static [&'static str],
+    names: Vec<String>,
+    files: FilenameCompleter,
+}
+
+impl Completions {
+    pub fn new(commands: &'static [&'static str], names: Vec<String>) -> Self {
+        Self { commands, names, files: FilenameCompleter::new() }
+    }
+}
+
+impl Completer for Completions {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |space| space + 1);
+        let word = &line[start..pos];
+
+        let matching = |candidates: &mut dyn Iterator<Item = &str>| candidates
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| Pair { display: candidate.to_owned(), replacement: format!("{} ", candidate) })
+            .collect::<Vec<_>>();
+
+        if line[..start].trim().is_empty() {
+            return Ok((start, matching(&mut self.commands.iter().copied())));
+        }
+
+        match matching(&mut self.names.iter().map(String::as_str)) {
+            names if names.is_empty() => self.files.complete_path(line, pos),
+            names => Ok((start, names)),
+        }
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
@@ -0,0 +1,492 @@
+use libdb::error::Result;
+use libdb::lock::LockMode;
+use libdb::{AllocOptions, Database, FragmentID};
+use std::fs::File;
+use clap::Parser;
+use completion::Completions;
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::Editor;
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Seek};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+mod completion;
+mod remote;
+#[path = "../sdk/mod.rs"]
+mod sdk;
+mod transfer;
+
+/// The number of bytes `inspect` dumps unless asked for more.
+const INSPECT_LIMIT: u64 = 512;
+
+/// How many fragments `page-size` copies between checkpoints.
+const MIGRATION_BATCH: usize = 64;
+
+#[derive(clap::Parser)]
+struct Args {
+    /// Read commands from this file instead of standard input.
+    #[clap(long = "script")]
+    script: Option<PathBuf>,
+
+    /// Stop at the first command which fails. Only applies when commands come from a script or a pipe.
+    #[clap(long = "fail-fast")]
+    fail_fast: bool,
+
+    /// How many times requests to a server are retried when it is busy, unavailable or times out. 0 turns retries off.
+    #[clap(long = "retries", default_value_t = 3)]
+    retries: u32,
+}
+
+/// The file in the user's home directory which keeps the history of commands typed into the REPL.
+const HISTORY_FILE: &str = ".libdb_repl_history";
+
+const COMMANDS: &[&str] = &["open-db", "connect", "exit"];
+const DATABASE_COMMANDS: &[&str] = &["open", "ls", "new", "rm", "import", "export", "inspect", "page-size", "rusty-dump", "exit"];
+const FRAGMENT_COMMANDS: &[&str] = &["print", "write", "commit"];
+
+enum Input {
+    /// A script or a pipe.
+    Script(Box<dyn BufRead + Send>),
+
+    /// Someone typing at a terminal, who gets line editing, history and completion.
+    Terminal(Box<Editor<Completions, FileHistory>>),
+}
+
+/// Where commands come from, and what happens when they fail.
+struct Session {
+    input: Input,
+
+    /// Set when commands are read from a script or a pipe rather than typed in. No prompts are shown then.
+    scripted: bool,
+
+    fail_fast: bool,
+
+    /// Whether any command has failed. The REPL exits with a non-zero status if so.
+    failed: bool,
+
+    /// Set once the input runs out, or a command fails with `--fail-fast`. Every prompt is then answered as though the user asked to leave.
+    finished: bool,
+}
+
+static SESSION: OnceLock<Mutex<Session>> = OnceLock::new();
+
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+fn session() -> MutexGuard<'static, Session> {
+    SESSION.get()
+        .expect("Session not started")
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn main() {
+    let args = Args::parse();
+    let mut db = None;
+
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let scripted = args.script.is_some() || !std::io::stdin().is_terminal();
+
+    let input = match args.script {
+        Some(ref script) => match File::open(script) {
+            Ok(script) => Input::Script(Box::new(BufReader::new(script))),
+            Err(err) => {
+                log::error!("Failed to open {}: {err}", script.display());
+                std::process::exit(1);
+            },
+        },
+        None if scripted => Input::Script(Box::new(BufReader::new(std::io::stdin()))),
+        None => match Editor::new() {
+            Ok(mut editor) => {
+                if let Some(history) = history_file() {
+                    let _ = editor.load_history(&history);
+                }
+
+                Input::Terminal(Box::new(editor))
+            },
+            Err(err) => {
+                log::warn!("Line editing is unavailable: {err}");
+                Input::Script(Box::new(BufReader::new(std::io::stdin())))
+            },
+        },
+    };
+
+    let _ = SESSION.set(Mutex::new(Session {
+        input,
+        scripted,
+        fail_fast: args.fail_fast,
+        failed: false,
+        finished: false,
+    }));
+
+    let retry = sdk::retry::RetryPolicy {
+        retries: args.retries,
+        ..Default::default()
+    };
+
+    print_errors(|exit| {
+        complete(COMMANDS, vec![]);
+
+        match prompt("> ").unwrap_or_else(|| "exit".to_owned()) {
+            cmd if cmd.starts_with("open-db ") => {
+                let path = PathBuf::from(&cmd[8..].trim());
+
+                let empty = !std::fs::metadata(&path).is_ok_and(|meta| meta.size() > 0);
+
+                if empty && prompt("Database is empty. Initialise? (y/n) ").is_none_or(|answer| answer.trim() != "y") {
+                    log::warn!("Database is empty - not opening.");
+                    return Ok(());
+                }
+
+                let opened = match empty {
+                    true => Database::open_or_create(&path),
+                    false => Database::open(&path),
+                };
+
+                let handle = match opened {
+                    // Another process, usually the server, has the store open for writing. It can still be inspected, but not changed.
+                    Err(ref err) if let libdb::error::global::Inner::LockError(locked) = err.inner() => {
+                        eprintln!("{}.", locked);
+
+                        if prompt("Attach read-only? (y/n) ").is_none_or(|answer| answer.trim() != "y") {
+                            return Ok(());
+                        }
+
+                        DBHandle::read_only(Database::open_path(&path, LockMode::Unlocked)?, path.clone())
+                    },
+                    store => DBHandle::new(store?, path.clone()),
+                };
+
+                let handle = db.insert(handle);
+                with_database(&mut handle.db, &path, handle.read_only);
+            }
+            cmd if cmd.starts_with("connect ") => {
+                let mut args = cmd[8..].split_whitespace();
+                let (Some(url), Some(token)) = (args.next(), args.next()) else {
+                    return Err(libdb::error::Error::custom("Usage: connect <url> <token>"));
+                };
+
+                remote::with_remote(&mut remote::Remote::connect(url, token, retry)?);
+            },
+            cmd if cmd.starts_with("exit") => {
+                drop(db.take());
+                *exit = true;
+            },
+            cmd if cmd.trim().is_empty() => (),
+            cmd => return Err(libdb::error::Error::custom(format!("'{}' is not a recognised command", cmd.split_whitespace().next().unwrap_or_default()))),
+        };
+
+        Ok(())
+    });
+
+    // Exiting skips destructors, so the store must be closed first.
+    drop(db);
+
+    if let Input::Terminal(ref mut editor) = session().input && let Some(history) = history_file()
+        && let Err(err) = editor.save_history(&history) {
+        log::warn!("Failed to save history to {}: {err}", history.display());
+    }
+
+    if session().failed {
+        std::process::exit(1);
+    }
+}
+
+/// A store the REPL has open. Stores opened for writing are flushed and unlocked once they are closed.
+#[derive(Debug)]
+struct DBHandle {
+    db: Database<File>,
+    path: PathBuf,
+
+    /// Whether another process has the store locked, so it was attached to without being written to.
+    read_only: bool,
+}
+
+impl DBHandle {
+    pub fn new(db: Database<File>, path: PathBuf) -> Self {
+        Self { db, path, read_only: false }
+    }
+
+    /// Wraps a store which another process has locked. It is never written to.
+    pub fn read_only(db: Database<File>, path: PathBuf) -> Self {
+        Self { db, path, read_only: true }
+    }
+}
+
+impl Drop for DBHandle {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+
+        self.db.flush()
+            .expect("Failed to flush database");
+
+        if let Err(err) = self.db.unlock(&self.path) {
+            log::error!("Failed to unlock database: {err}");
+        }
+    }
+}
+
+fn print_errors(mut handler: impl FnMut(&mut bool) -> Result<()>) {
+    let mut r#break = false;
+    while !r#break {
+        if let Err(e) = handler(&mut r#break) {
+            log::error!("{e:?}");
+
+            let mut session = session();
+            session.failed = true;
+            session.finished |= session.scripted && session.fail_fast;
+        }
+    }
+}
+
+/// Reads the next command, or the answer to a question. Returns `None` once there is nothing more to read.
+///
+/// Scripts may contain blank lines and comments starting with `#`, which are skipped.
+fn prompt(prompt: impl AsRef<str>) -> Option<String> {
+    let mut session = session();
+
+    while !session.finished {
+        let line = match session.input {
+            Input::Script(ref mut input) => {
+                let mut buf = String::new();
+                input.read_line(&mut buf).ok().filter(|len| *len > 0).map(|_| buf)
+            },
+            Input::Terminal(ref mut editor) => match editor.readline(prompt.as_ref()) {
+                Ok(line) => {
+                    let _ = editor.add_history_entry(line.as_str());
+                    Some(line)
+                },
+                // Ctrl-C abandons the line being typed, rather than the whole REPL.
+                Err(ReadlineError::Interrupted) => Some(String::new()),
+                Err(_) => None,
+            },
+        };
+
+        match line {
+            None => session.finished = true,
+            Some(line) if session.scripted && (line.trim().is_empty() || line.trim_start().starts_with('#')) => (),
+            Some(line) => return Some(line),
+        }
+    }
+
+    None
+}
+
+/// Sets what can be completed at the prompts which follow: `commands` as the first word, and `names` after it.
+fn complete(commands: &'static [&'static str], names: Vec<String>) {
+    if let Input::Terminal(ref mut editor) = session().input {
+        editor.set_helper(Some(Completions::new(commands, names)));
+    }
+}
+
+fn with_database(db: &mut Database<File>, path: impl AsRef<std::path::Path>, read_only: bool) {
+    print_errors(|exit| {
+        complete(DATABASE_COMMANDS, db.fragments().map(|frag| frag.id.to_string()).collect());
+
+        let cmd = prompt(format!("- ({}{}) > ", path.as_ref().display(), if read_only { ", read-only" } else { "" }))
+            .unwrap_or_else(|| "exit".to_owned());
+        let mut cmd = cmd
+            .split_whitespace()
+            .peekable();
+
+        match cmd.next() {
+            Some("new" | "rm" | "import" | "page-size") if read_only => return Err(libdb::error::Error::custom("The database is attached read-only")),
+            Some("open") => {
+                let Some(id) = cmd.next().map(str::parse::<FragmentID>)
+                    .transpose()
+                    .map_err(libdb::error::Error::from)? else {
+                    return Err(libdb::error::Error::custom("No fragment ID specified"));
+                };
+
+                let frag = db.open_fragment(id)?;
+
+                with_fragment(frag, read_only);
+            },
+            Some("ls") => {
+                println!("{:>10} {:>10} {:>12}  timestamp", "id", "sequence", "size");
+
+                for frag in db.fragments() {
+                    let timestamp = frag.timestamp
+                        .map(|timestamp| chrono::DateTime::<chrono::Utc>::from(timestamp).to_rfc3339())
+                        .unwrap_or_else(|| "-".to_owned());
+
+                    println!("{:>10} {:>10} {:>12}  {}", frag.id, frag.sequence, frag.length, timestamp);
+                }
+            },
+            Some("new") => {
+                let options = match cmd.next().map(str::parse::<u64>).transpose().map_err(libdb::error::Error::from)? {
+                    Some(size) => AllocOptions::default().size_hint(size),
+                    None => AllocOptions::default().growable(),
+                };
+
+                let frag = db.new_fragment(options)?;
+                eprintln!("Created fragment {}", frag.id);
+
+                with_fragment(frag, false);
+            },
+            Some("rm") => {
+                let Some(id) = cmd.next().map(str::parse::<FragmentID>)
+                    .transpose()
+                    .map_err(libdb::error::Error::from)? else {
+                    return Err(libdb::error::Error::custom("No fragment ID specified"));
+                };
+
+                db.delete_fragment(id)?;
+                db.flush()?;
+                eprintln!("Deleted fragment {}", id);
+            },
+            Some("import") => transfer::import(db, cmd)?,
+            Some("export") => transfer::export(db, cmd)?,
+            Some("inspect") => {
+                let Some(id) = cmd.next().map(str::parse::<FragmentID>)
+                    .transpose()
+                    .map_err(libdb::error::Error::from)? else {
+                    return Err(libdb::error::Error::custom("Usage: inspect <id> [bytes]"));
+                };
+
+                let limit = cmd.next().map(str::parse::<u64>).transpose().map_err(libdb::error::Error::from)?.unwrap_or(INSPECT_LIMIT);
+                let info = db.fragment_info(id)?;
+
+                let hash = info.hash
+                    .map(|hash| hash.iter().map(|byte| format!("{:02x}", byte)).collect())
+                    .unwrap_or_else(|| "-".to_owned());
+                let timestamp = info.timestamp
+                    .map(|timestamp| chrono::DateTime::<chrono::Utc>::from(timestamp).to_rfc3339())
+                    .unwrap_or_else(|| "-".to_owned());
+
+                println!("fragment   {}", info.id);
+                println!("sequence   {}", info.sequence);
+                match info.inline {
+                    true => println!("offset     inline"),
+                    false => println!("offset     {:#x}", info.offset),
+                }
+                println!("length     {} bytes", info.length);
+                println!("allocated  {} bytes ({} unused)", info.allocated, info.allocated.saturating_sub(info.length));
+                println!("hash       {}", hash);
+                println!("timestamp  {}", timestamp);
+                println!();
+
+                let mut contents = vec![];
+                db.open_fragment(id)?.take(limit).read_to_end(&mut contents)?;
+
+                let mut stdout = BufWriter::new(std::io::stdout());
+                hexdump(&contents, &mut stdout)?;
+
+                if info.length > limit {
+                    writeln!(stdout, "... {} more bytes", info.length - limit)?;
+                }
+
+                stdout.flush()?;
+            },
+            Some("page-size") => {
+                let Some(page_size) = cmd.next().map(str::parse::<u32>)
+                    .transpose()
+                    .map_err(libdb::error::Error::from)? else {
+                    println!("{}", db.page_size());
+                    return Ok(());
+                };
+
+                loop {
+                    let progress = db.migrate_page_size(page_size, MIGRATION_BATCH)?;
+
+                    if progress.is_complete() {
+                        break;
+                    }
+
+                    eprintln!("Copied {} of {} fragments", progress.migrated, progress.migrated + progress.remaining);
+                }
+
+                eprintln!("The store now uses {}-byte pages", db.page_size());
+            },
+            Some("rusty-dump") => log::debug!("{db:#?}"),
+            Some("exit") => *exit = true,
+            Some(cmd) => return Err(libdb::error::Error::custom(format!("'{cmd}' is not a recognised command"))),
+            None => ()
+        };
+
+        Ok(())
+    })
+}
+
+fn with_fragment(mut frag: libdb::FragmentHandle<impl Read + Write + Seek>, read_only: bool) {
+    print_errors(|exit| {
+        complete(FRAGMENT_COMMANDS, vec![]);
+
+        // Running out of input keeps whatever was written, just as dropping the fragment would.
+        let cmd = prompt(format!("--- [{}{}] > ", frag.id, 'i'))
+            .unwrap_or_else(|| "commit".to_owned());
+        let mut cmd = cmd
+            .split_whitespace()
+            .peekable();
+
+        match cmd.next() {
+            Some("write") if read_only => return Err(libdb::error::Error::custom("The database is attached read-only")),
+            Some("print") => {
+                let mut buf = vec![0u8; frag.size().min(1024 * 1024)];
+                log::debug!("Reading fragment: {} bytes", frag.size());
+                let mut stdout = BufWriter::new(std::io::stdout());
+
+                while let Ok(len) = frag.read(&mut buf) && len > 0 {
+                    log::debug!("Read {len} bytes");
+                    stdout.write_all(&buf[..len])?;
+                }
+
+                stdout.write_all(b"\n")?;
+
+                stdout.flush()?;
+            },
+            Some("write") => {
+                let args = cmd
+                    .fold(String::new(), |a, b| a + b + " ")
+                    .trim()
+                    .to_owned();
+
+                frag.write_all(args.as_bytes())?;
+            }
+            Some("commit") => {
+                frag.flush()?;
+                *exit = true;
+            },
+            Some(cmd) => return Err(libdb::error::Error::custom(format!("'{cmd}' is not a recognised command"))),
+            None => ()
+        }
+
+        Ok(())
+    })
+}
+
+/// Writes `data` as lines of sixteen bytes, each showing its offset, the bytes in hex and then as ASCII.
+fn hexdump(data: &[u8], out: &mut impl Write) -> std::io::Result<()> {
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let hex = (0..16).fold(String::new(), |mut hex, i| {
+            if i == 8 {
+                hex.push(' ');
+            }
+
+            match chunk.get(i) {
+                Some(byte) => hex += &format!("{:02x} ", byte),
+                None => hex += "   ",
+            }
+
+            hex
+        });
+
+        let ascii = chunk.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect::<String>();
+
+        writeln!(out, "{:08x}  {} |{}|", line * 16, hex, ascii)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::sdk::retry::RetryPolicy;
+use crate::sdk::Client;
+use crate::transfer::{self, Progress, Transfer};
+use crate::{complete, print_errors, prompt};
+use libdb::error::{Error, Result};
+use reqwest::blocking::Body;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// A connection to a running server, driven through its HTTP API.
+///
+/// Listing databases needs a user's API token, while reading and writing objects needs an app's token. The server decides what the token
+/// may do, and any request it refuses fails with the server's own error message.
+pub struct Remote {
+    client: Client,
+}
+
+impl Remote {
+    pub fn connect(url: &str, token: &str, retry: RetryPolicy) -> Result<Self> {
+        Ok(Self { client: Client::connect(url, token, retry)? })
+    }
+}
+
+/// Remote databases name their contents by object key.
+impl Transfer for Remote {
+    fn import(&mut self, name: Option<&str>, source: File, size: u64) -> Result<String> {
+        self.client.upload(name, None, Body::sized(Progress::new(source, size), size))
+    }
+
+    fn export(&mut self, name: &str, target: &mut dyn Write) -> Result<u64> {
+        let response = self.client.download(name)?;
+        let size = response.content_length().unwrap_or_default();
+
+        Ok(std::io::copy(&mut Progress::new(response, size), target)?)
+    }
+}
+
+const REMOTE_COMMANDS: &[&str] = &["databases", "use", "ls", "cat", "import", "export", "exit"];
+
+pub fn with_remote(remote: &mut Remote) {
+    print_errors(|exit| {
+        complete(REMOTE_COMMANDS, vec![]);
+
+        let cmd = prompt(format!("- ({}{}) > ", remote.client.url(), remote.client.database().map(|db| format!(", {db}")).unwrap_or_default()))
+            .unwrap_or_else(|| "exit".to_owned());
+        let mut cmd = cmd.split_whitespace();
+
+        match cmd.next() {
+            Some("databases") => {
+                println!("{:<40} name", "id");
+
+                for db in remote.client.databases()? {
+                    let field = |name: &str| db.get(name).and_then(Value::as_str).unwrap_or("-").to_owned();
+                    println!("{:<40} {}", field("id"), field("name"));
+                }
+            },
+            Some("use") => {
+                let Some(database) = cmd.next() else {
+                    return Err(Error::custom("Usage: use <database>"));
+                };
+
+                remote.client.use_database(Some(database.to_owned()));
+            },
+            Some("ls") => {
+                let prefix = cmd.next().unwrap_or_default();
+
+                remote.client.list_objects(prefix, |page| {
+                    let entries = ["common_prefixes", "keys"].into_iter()
+                        .flat_map(|field| page.get(field).and_then(Value::as_array).into_iter().flatten())
+                        .filter_map(Value::as_str);
+
+                    for entry in entries {
+                        println!("{}", entry);
+                    }
+                })?;
+            },
+            Some("cat") => {
+                let Some(key) = cmd.next() else {
+                    return Err(Error::custom("Usage: cat <key>"));
+                };
+
+                let mut stdout = BufWriter::new(std::io::stdout());
+                remote.export(key, &mut stdout)?;
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
+            },
+            Some("import") => transfer::import(remote, cmd)?,
+            Some("export") => transfer::export(remote, cmd)?,
+            Some("exit") => *exit = true,
+            Some(cmd) => return Err(Error::custom(format!("'{cmd}' is not a recognised command"))),
+            None => (),
+        }
+
+        Ok(())
+    })
+}
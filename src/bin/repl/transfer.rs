@@ -0,0 +1,134 @@
+use libdb::error::{Error, Result};
+use libdb::{AllocOptions, Database, FragmentID};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+
+/// The amount of data moved at a time by `import` and `export`.
+const COPY_CHUNK: usize = 1024 * 1024;
+
+/// Transfers larger than this report their progress.
+const PROGRESS_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// Somewhere `import` and `export` can move data to and from, so both commands work the same on a local store and against a server.
+pub trait Transfer {
+    /// Stores the `size` bytes of `source` as `name`, or under a new name if none is given. Returns the name the data was stored as.
+    fn import(&mut self, name: Option<&str>, source: File, size: u64) -> Result<String>;
+
+    /// Writes the contents of `name` to `target`, returning how many bytes were written.
+    fn export(&mut self, name: &str, target: &mut dyn Write) -> Result<u64>;
+}
+
+/// Local stores name their contents by fragment ID.
+impl<Backing: Read + Write + Seek> Transfer for Database<Backing> {
+    fn import(&mut self, name: Option<&str>, source: File, size: u64) -> Result<String> {
+        let mut options = AllocOptions::default().size_hint(size);
+        if let Some(id) = name.map(str::parse::<FragmentID>).transpose().map_err(Error::from)? {
+            options = options.fragment(id);
+        }
+
+        let mut frag = self.new_fragment(options)?;
+
+        let id = match copy(&mut Progress::new(source, size), &mut frag) {
+            Ok(_) => frag.commit()?,
+            Err(err) => {
+                frag.abandon();
+                return Err(err.into());
+            },
+        };
+
+        self.flush()?;
+
+        Ok(id.to_string())
+    }
+
+    fn export(&mut self, name: &str, target: &mut dyn Write) -> Result<u64> {
+        let frag = self.open_fragment(name.parse::<FragmentID>().map_err(Error::from)?)?;
+        let size = frag.size() as u64;
+
+        Ok(copy(&mut Progress::new(frag, size), target)?)
+    }
+}
+
+/// `import <path> [name]`
+pub fn import<'a>(target: &mut impl Transfer, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+    let Some(source) = args.next() else {
+        return Err(Error::custom("Usage: import <path> [name]"));
+    };
+
+    let source = File::open(source)?;
+    let size = source.metadata()?.len();
+
+    let name = target.import(args.next(), source, size)?;
+    eprintln!("Imported {} bytes as {}", size, name);
+
+    Ok(())
+}
+
+/// `export <name> <path>`
+pub fn export<'a>(target: &mut impl Transfer, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+    let (Some(name), Some(path)) = (args.next(), args.next()) else {
+        return Err(Error::custom("Usage: export <name> <path>"));
+    };
+
+    let mut out = BufWriter::new(File::create(path)?);
+    let size = target.export(name, &mut out)?;
+    out.flush()?;
+
+    eprintln!("Exported {} bytes from {}", size, name);
+
+    Ok(())
+}
+
+fn copy(from: &mut impl Read, to: &mut (impl Write + ?Sized)) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut copied = 0u64;
+
+    loop {
+        let len = from.read(&mut buf)?;
+        if len == 0 {
+            return Ok(copied);
+        }
+
+        to.write_all(&buf[..len])?;
+        copied += len as u64;
+    }
+}
+
+/// Reports how much of a transfer of more than [`PROGRESS_THRESHOLD`] bytes has been read so far.
+pub struct Progress<R> {
+    inner: R,
+    read: u64,
+    total: u64,
+
+    /// The percentage last reported, so it is only reported again once it changes.
+    shown: Option<u64>,
+}
+
+impl<R> Progress<R> {
+    pub fn new(inner: R, total: u64) -> Self {
+        Self { inner, read: 0, total, shown: None }
+    }
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.read += len as u64;
+
+        if self.total > PROGRESS_THRESHOLD {
+            let percent = (self.read * 100 / self.total).min(100);
+
+            if self.shown != Some(percent) {
+                eprint!("\r{} / {} bytes ({}%)", self.read, self.total, percent);
+
+                if percent == 100 {
+                    eprintln!();
+                }
+
+                self.shown = Some(percent);
+            }
+        }
+
+        Ok(len)
+    }
+}
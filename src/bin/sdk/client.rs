@@ -0,0 +1,274 @@
+use super::retry::{self, RetryPolicy};
+use libdb::error::{Error, Result};
+use reqwest::blocking::{Body, RequestBuilder, Response};
+use reqwest::header::HeaderValue;
+use reqwest::{Method, StatusCode, Url};
+use serde_json::Value;
+
+/// A connection to a running server, driven through its HTTP API.
+///
+/// Managing databases and tokens needs a user's API token, while reading and writing objects needs an app's token. The server decides what
+/// the token may do, and any request it refuses fails with the server's own error message.
+pub struct Client {
+    http: reqwest::blocking::Client,
+    url: Url,
+    token: String,
+    retry: RetryPolicy,
+
+    /// The database objects are read from and written to.
+    database: Option<String>,
+}
+
+impl Client {
+    pub fn connect(url: &str, token: &str, retry: RetryPolicy) -> Result<Self> {
+        let mut url = Url::parse(url).map_err(|err| Error::custom(format!("Invalid URL: {err}")))?;
+
+        // Paths are joined onto the URL, which only keeps its last segment if it ends in a slash.
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+
+        Ok(Self {
+            http: reqwest::blocking::Client::new(),
+            url,
+            token: token.to_owned(),
+            retry,
+            database: None,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn database(&self) -> Option<&str> {
+        self.database.as_deref()
+    }
+
+    /// Chooses the database objects are read from and written to.
+    pub fn use_database(&mut self, database: Option<String>) {
+        self.database = database;
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let url = self.url.join(path).map_err(|err| Error::custom(format!("Invalid path {path}: {err}")))?;
+        let request = self.http.request(method, url).bearer_auth(&self.token);
+
+        Ok(match self.database {
+            Some(ref database) => request.header("db", database),
+            None => request,
+        })
+    }
+
+    /// Sends the request, retrying it as the retry policy allows.
+    ///
+    /// Requests which aren't safe to repeat are given an `Idempotency-Key`, which stays the same across retries so a retry can be told apart
+    /// from a new request. Requests whose bodies are streamed can't be repeated, so they are only ever sent once.
+    fn execute(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.build().map_err(|err| Error::custom(err.to_string()))?;
+
+        if self.retry.retries > 0 && !request.method().is_idempotent() && request.try_clone().is_some() {
+            let key = HeaderValue::from_str(&retry::idempotency_key()).map_err(|err| Error::custom(err.to_string()))?;
+            request.headers_mut().insert("Idempotency-Key", key);
+        }
+
+        let mut attempt = 0;
+        let response = loop {
+            let next = request.try_clone().filter(|_| attempt < self.retry.retries);
+            let result = self.http.execute(request);
+
+            let Some(next) = next else {
+                break result;
+            };
+
+            let delay = match result {
+                Ok(ref response) if retry::retryable(response.status()) => self.retry.delay(attempt, retry::retry_after(response)),
+                Err(ref err) if err.is_timeout() => self.retry.delay(attempt, None),
+                _ => None,
+            };
+
+            let Some(delay) = delay else {
+                break result;
+            };
+
+            log::info!("Retrying {} {} in {:?}", next.method(), next.url(), delay);
+            std::thread::sleep(delay);
+
+            request = next;
+            attempt += 1;
+        };
+
+        response.map_err(|err| Error::custom(err.to_string()))
+    }
+
+    /// Sends the request as [`Client::execute`] does, and turns error responses into errors carrying whatever the server said was wrong.
+    fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let response = self.execute(request)?;
+        let status = response.status();
+
+        match status.is_success() {
+            true => Ok(response),
+            false => Err(Self::failure(status, &response.json::<Value>().unwrap_or_default())),
+        }
+    }
+
+    fn failure(status: StatusCode, body: &Value) -> Error {
+        let message = body.get("message").or_else(|| body.get("error"))
+            .and_then(Value::as_str)
+            .unwrap_or_else(|| status.canonical_reason().unwrap_or("Request failed"));
+
+        Error::custom(format!("{} ({})", message, status))
+    }
+
+    /// Sends the request, but answers `None` rather than failing if the server says there is no such object.
+    fn send_object(&self, request: RequestBuilder) -> Result<Option<Response>> {
+        let response = self.execute(request)?;
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(Some(response));
+        }
+
+        let body = response.json::<Value>().unwrap_or_default();
+        match status == StatusCode::NOT_FOUND && body.get("error").and_then(Value::as_str) == Some("no_such_object") {
+            true => Ok(None),
+            false => Err(Self::failure(status, &body)),
+        }
+    }
+
+    fn json(&self, request: RequestBuilder) -> Result<Value> {
+        self.send(request)?
+            .json()
+            .map_err(|err| Error::custom(err.to_string()))
+    }
+
+    fn get_json(&self, path: &str, query: &[(&str, &str)]) -> Result<Value> {
+        self.json(self.request(Method::GET, path)?.query(query))
+    }
+
+    /// Fetches every page of a listing, calling `page` with each one.
+    pub fn paged(&self, path: &str, query: &[(&str, &str)], mut page: impl FnMut(&Value)) -> Result<()> {
+        let mut cursor = None::<String>;
+
+        loop {
+            let mut query = query.to_vec();
+            if let Some(ref cursor) = cursor {
+                query.push(("cursor", cursor));
+            }
+
+            let body = self.get_json(path, &query)?;
+            page(&body);
+
+            match body.get("next_cursor").and_then(Value::as_str) {
+                Some(next) => cursor = Some(next.to_owned()),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Fetches every page of a listing, and collects the entries listed under `field`.
+    fn collect(&self, path: &str, query: &[(&str, &str)], field: &str) -> Result<Vec<Value>> {
+        let mut entries = vec![];
+        self.paged(path, query, |page| entries.extend(page.get(field).and_then(Value::as_array).into_iter().flatten().cloned()))?;
+
+        Ok(entries)
+    }
+
+    /// Trades a refresh token for a new API token and refresh token. The refresh token can't be used again afterwards.
+    pub fn refresh(url: &str, refresh: &str, retry: RetryPolicy) -> Result<Value> {
+        let client = Self::connect(url, "", retry)?;
+        client.json(client.request(Method::POST, "refresh")?.json(&serde_json::json! {{ "refresh": refresh }}))
+    }
+
+    /// The databases the user belongs to.
+    pub fn databases(&self) -> Result<Vec<Value>> {
+        self.collect("databases", &[], "databases")
+    }
+
+    pub fn create_database(&self, name: &str, page_size: Option<u32>) -> Result<Value> {
+        let page_size = page_size.map(|page_size| page_size.to_string());
+        let mut query = vec![("name", name)];
+        query.extend(page_size.as_deref().map(|page_size| ("page_size", page_size)));
+
+        self.json(self.request(Method::PUT, "databases")?.query(&query))
+    }
+
+    pub fn delete_database(&self, id: &str) -> Result<Value> {
+        self.json(self.request(Method::DELETE, &format!("databases/{id}"))?)
+    }
+
+    /// The user's API tokens. Only their prefixes are shown.
+    pub fn tokens(&self) -> Result<Vec<Value>> {
+        self.collect("tokens", &[], "tokens")
+    }
+
+    /// Signs a user out everywhere. Only user admins may do this.
+    pub fn revoke_tokens(&self, user: &str) -> Result<Value> {
+        self.json(self.request(Method::POST, &format!("admin/users/{user}/revoke-tokens"))?)
+    }
+
+    /// Creates a service account, whose token is only ever shown in the response. Only user admins may do this.
+    pub fn create_service_account(&self, name: &str, expires_in: Option<u64>) -> Result<Value> {
+        self.json(self.request(Method::POST, "admin/service-accounts")?.json(&serde_json::json! {{ "name": name, "expires_in": expires_in }}))
+    }
+
+    fn object_path(key: &str) -> String {
+        format!("objects/{}", key.trim_start_matches('/'))
+    }
+
+    /// The objects and common prefixes directly under `prefix`, calling `page` with each page of them.
+    pub fn list_objects(&self, prefix: &str, page: impl FnMut(&Value)) -> Result<()> {
+        self.paged("objects", &[("prefix", prefix), ("delimiter", "/")], page)
+    }
+
+    /// Stores `body` as the object at `key`, or under a new key if none is given, and returns the key it was stored at.
+    pub fn upload(&self, key: Option<&str>, content_type: Option<&str>, body: Body) -> Result<String> {
+        let mut request = match key {
+            Some(key) => self.request(Method::PUT, &Self::object_path(key))?,
+            None => self.request(Method::POST, "objects")?,
+        };
+
+        if let Some(content_type) = content_type {
+            request = request.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+
+        let body = self.json(request.body(body))?;
+        Ok(body.get("object").and_then(Value::as_str).unwrap_or_default().to_owned())
+    }
+
+    /// Starts downloading the object at `key`. Its contents are the body of the response.
+    pub fn download(&self, key: &str) -> Result<Response> {
+        self.send(self.request(Method::GET, &Self::object_path(key))?)
+    }
+
+    /// The contents of the object at `key`, or `None` if there is no such object.
+    pub fn read_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(response) = self.send_object(self.request(Method::GET, &Self::object_path(key))?)? else {
+            return Ok(None);
+        };
+
+        let body = response.bytes().map_err(|err| Error::custom(err.to_string()))?;
+        Ok(Some(body.to_vec()))
+    }
+
+    /// Deletes the object at `key`, answering whether there was one.
+    pub fn delete_object(&self, key: &str) -> Result<bool> {
+        Ok(self.send_object(self.request(Method::DELETE, &Self::object_path(key))?)?.is_some())
+    }
+
+    /// Declares the secondary index `name` over the field of `collection`'s documents which the JSON pointer `field` names, and builds it.
+    pub fn create_index(&self, collection: &str, name: &str, field: &str) -> Result<Value> {
+        let path = format!("collections/{collection}/indexes/{name}");
+        self.json(self.request(Method::PUT, &path)?.json(&serde_json::json! {{ "field": field }}))
+    }
+
+    /// The keys of `collection`'s objects whose value in the index `name` is `value`, which is given as JSON.
+    pub fn lookup(&self, collection: &str, name: &str, value: &str) -> Result<Vec<String>> {
+        let results = self.collect(&format!("collections/{collection}/indexes/{name}"), &[("value", value)], "results")?;
+
+        Ok(results.iter()
+            .filter_map(|result| result.get("key").and_then(Value::as_str))
+            .map(str::to_owned)
+            .collect())
+    }
+}
@@ -0,0 +1,493 @@
+use super::client::Client;
+use super::retry::RetryPolicy;
+use actix_web::dev::ServerHandle;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, App, HttpRequest, HttpResponse, HttpServer, ResponseError};
+use libdb::error::{Error, Result};
+use libdb::{AllocOptions, Danger, Database, FragmentID};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+/// An in-process stand-in for the server, so code using [`Client`] can be tested without running the server binary.
+///
+/// It listens on a free port of the loopback interface and serves the routes the client uses for databases, objects and secondary
+/// indexes, with the same response bodies and error codes as the server. Every database is an in-memory libdb store, so nothing touches
+/// the disk and everything is gone once the mock is dropped.
+///
+/// There are no users or apps. [`MockServer::USER_TOKEN`] may manage databases and [`MockServer::APP_TOKEN`] may use the objects of any of
+/// them. The database [`MockServer::DATABASE`] exists from the start.
+pub struct MockServer {
+    address: SocketAddr,
+    handle: ServerHandle,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl MockServer {
+    pub const USER_TOKEN: &'static str = "mock-user";
+    pub const APP_TOKEN: &'static str = "mock-app";
+    pub const DATABASE: &'static str = "mock";
+
+    pub fn start() -> Result<Self> {
+        let mut databases = BTreeMap::new();
+        databases.insert(Self::DATABASE.to_owned(), MockDatabase::new(Self::DATABASE)?);
+
+        let state = web::Data::new(Mutex::new(MockState { databases, created: 0 }));
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let thread = std::thread::spawn(move || actix_web::rt::System::new().block_on(async move {
+            let server = HttpServer::new(move || App::new()
+                .app_data(state.clone())
+                .service(list_databases)
+                .service(create_database)
+                .service(delete_database)
+                .service(list_objects)
+                .service(post_object)
+                .service(get_object)
+                .service(put_object)
+                .service(delete_object)
+                .service(create_index)
+                .service(query_index)
+                .default_service(web::to(|| async { MockError::new(StatusCode::NOT_FOUND, "not_supported", "The mock server doesn't serve this route") })))
+                .workers(1)
+                .bind(("127.0.0.1", 0));
+
+            let server = match server {
+                Ok(server) => server,
+                Err(err) => {
+                    let _ = sender.send(Err(err.to_string()));
+                    return Ok(());
+                },
+            };
+
+            let address = server.addrs()[0];
+            let server = server.run();
+            let _ = sender.send(Ok((address, server.handle())));
+
+            server.await
+        }));
+
+        let (address, handle) = receiver.recv()
+            .map_err(|_| Error::custom("The mock server stopped before it started listening"))?
+            .map_err(|err| Error::custom(format!("The mock server couldn't listen: {err}")))?;
+
+        Ok(Self { address, handle, thread: Some(thread) })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.address)
+    }
+
+    /// A client using [`MockServer::APP_TOKEN`], with [`MockServer::DATABASE`] chosen. Requests aren't retried.
+    pub fn app_client(&self) -> Result<Client> {
+        let mut client = Client::connect(&self.url(), Self::APP_TOKEN, RetryPolicy { retries: 0, ..RetryPolicy::default() })?;
+        client.use_database(Some(Self::DATABASE.to_owned()));
+
+        Ok(client)
+    }
+
+    /// A client using [`MockServer::USER_TOKEN`]. Requests aren't retried.
+    pub fn user_client(&self) -> Result<Client> {
+        Client::connect(&self.url(), Self::USER_TOKEN, RetryPolicy { retries: 0, ..RetryPolicy::default() })
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        futures::executor::block_on(self.handle.stop(false));
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct MockState {
+    databases: BTreeMap<String, MockDatabase>,
+
+    /// How many databases have been created, which their IDs are numbered by.
+    created: usize,
+}
+
+type Mock = web::Data<Mutex<MockState>>;
+
+struct MockDatabase {
+    name: String,
+    store: Database<Cursor<Vec<u8>>>,
+
+    /// The fragment holding each object, and its content type.
+    objects: BTreeMap<String, (FragmentID, String)>,
+
+    /// The field each index of each collection is over, keyed by collection and then by name.
+    indexes: BTreeMap<(String, String), String>,
+}
+
+impl MockDatabase {
+    fn new(name: &str) -> Result<Self> {
+        let mut backing = Cursor::new(vec![]);
+        Database::destructive_reinitialise(&mut backing, Danger)?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            store: Database::new(backing)?,
+            objects: BTreeMap::new(),
+            indexes: BTreeMap::new(),
+        })
+    }
+
+    fn write(&mut self, key: &str, content_type: &str, data: &[u8]) -> Result<()> {
+        let options = match self.objects.get(key) {
+            Some(&(id, _)) => AllocOptions::default().fragment(id),
+            None => AllocOptions::default(),
+        };
+
+        let id = self.store.write_fragment(options, data)?;
+        self.objects.insert(key.to_owned(), (id, content_type.to_owned()));
+
+        Ok(())
+    }
+
+    fn read(&mut self, key: &str) -> Result<Option<(Vec<u8>, String)>> {
+        let Some((id, content_type)) = self.objects.get(key).cloned() else {
+            return Ok(None);
+        };
+
+        let mut data = vec![];
+        self.store.open_fragment(id)?.read_to_end(&mut data)?;
+
+        Ok(Some((data, content_type)))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool> {
+        let Some((id, _)) = self.objects.remove(key) else {
+            return Ok(false);
+        };
+
+        self.store.delete_fragment(id)?;
+        Ok(true)
+    }
+
+    /// The keys of the collection's JSON documents, along with the value of `field` in each, where it has one.
+    fn values(&mut self, collection: &str, field: &str) -> Result<Vec<(String, Value)>> {
+        let prefix = format!("{collection}/");
+        let keys = self.objects.keys().filter(|key| key.starts_with(&prefix)).cloned().collect::<Vec<_>>();
+        let mut values = vec![];
+
+        for key in keys {
+            let Some((data, _)) = self.read(&key)? else {
+                continue;
+            };
+
+            let value = serde_json::from_slice::<Value>(&data).ok().and_then(|document| document.pointer(field).cloned());
+            values.extend(value.filter(|value| !value.is_array() && !value.is_object()).map(|value| (key, value)));
+        }
+
+        Ok(values)
+    }
+}
+
+/// A failed response, with the same body as the server's.
+#[derive(Debug)]
+struct MockError {
+    status: StatusCode,
+    error: &'static str,
+    message: String,
+}
+
+impl MockError {
+    fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Self { status, error, message: message.into() }
+    }
+
+    fn internal(err: impl Display) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", err.to_string())
+    }
+}
+
+impl Display for MockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.error, self.message)
+    }
+}
+
+impl ResponseError for MockError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(json! {{ "success": false, "error": self.error, "message": self.message }})
+    }
+}
+
+impl actix_web::Responder for MockError {
+    type Body = actix_web::body::BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse {
+        self.error_response()
+    }
+}
+
+type MockResult = std::result::Result<HttpResponse, MockError>;
+
+fn ok(body: Value) -> HttpResponse {
+    success(StatusCode::OK, body)
+}
+
+fn success(status: StatusCode, mut body: Value) -> HttpResponse {
+    body["success"] = json!(true);
+    HttpResponse::build(status).json(body)
+}
+
+/// Checks the request carries `token`.
+fn authorise(req: &HttpRequest, token: &str) -> std::result::Result<(), MockError> {
+    let given = req.headers().get("Authorization").and_then(|header| header.to_str().ok());
+
+    match given.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(given) if given == token => Ok(()),
+        Some(_) => Err(MockError::new(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token")),
+        None => Err(MockError::new(StatusCode::UNAUTHORIZED, "missing_token", "Missing token")),
+    }
+}
+
+/// Checks the request carries the app token, then runs `f` on the database its `db` header names.
+fn with_database(req: &HttpRequest, mock: &Mock, f: impl FnOnce(&mut MockDatabase) -> MockResult) -> MockResult {
+    authorise(req, MockServer::APP_TOKEN)?;
+
+    let Some(db) = req.headers().get("db").and_then(|db| db.to_str().ok()) else {
+        return Err(MockError::new(StatusCode::BAD_REQUEST, "missing_header", "No db header"));
+    };
+
+    let mut state = mock.lock().map_err(MockError::internal)?;
+    match state.databases.get_mut(db) {
+        Some(db) => f(db),
+        None => Err(MockError::new(StatusCode::NOT_FOUND, "no_such_database", "No such database")),
+    }
+}
+
+fn no_such_object() -> MockError {
+    MockError::new(StatusCode::NOT_FOUND, "no_such_object", "No such object")
+}
+
+fn content_type(req: &HttpRequest) -> String {
+    req.headers().get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_owned()
+}
+
+#[get("/databases")]
+async fn list_databases(req: HttpRequest, mock: Mock) -> MockResult {
+    authorise(&req, MockServer::USER_TOKEN)?;
+
+    let state = mock.lock().map_err(MockError::internal)?;
+    let databases = state.databases.iter().map(|(id, db)| json! {{ "id": id, "name": db.name }}).collect::<Vec<_>>();
+
+    Ok(ok(json! {{ "databases": databases, "next_cursor": null }}))
+}
+
+#[derive(Deserialize)]
+struct CreateOptions {
+    name: String,
+}
+
+#[put("/databases")]
+async fn create_database(req: HttpRequest, options: web::Query<CreateOptions>, mock: Mock) -> MockResult {
+    authorise(&req, MockServer::USER_TOKEN)?;
+
+    let mut state = mock.lock().map_err(MockError::internal)?;
+    state.created += 1;
+
+    let id = format!("db{}", state.created);
+    let db = MockDatabase::new(&options.name).map_err(MockError::internal)?;
+    state.databases.insert(id.clone(), db);
+
+    Ok(success(StatusCode::CREATED, json! {{ "id": id, "name": options.name }}))
+}
+
+#[delete("/databases/{id}")]
+async fn delete_database(req: HttpRequest, id: web::Path<String>, mock: Mock) -> MockResult {
+    authorise(&req, MockServer::USER_TOKEN)?;
+
+    match mock.lock().map_err(MockError::internal)?.databases.remove(id.as_str()) {
+        Some(_) => Ok(ok(json! {{}})),
+        None => Err(MockError::new(StatusCode::NOT_FOUND, "no_such_database", "No such database")),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListOptions {
+    #[serde(default)]
+    prefix: String,
+    delimiter: Option<String>,
+}
+
+#[get("/objects")]
+async fn list_objects(req: HttpRequest, options: web::Query<ListOptions>, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| {
+        let (mut keys, mut prefixes) = (vec![], vec![]);
+
+        for key in db.objects.keys().filter(|key| key.starts_with(&options.prefix)) {
+            let rest = &key[options.prefix.len()..];
+
+            match options.delimiter.as_deref().filter(|delimiter| !delimiter.is_empty()).and_then(|delimiter| rest.find(delimiter).map(|at| at + delimiter.len())) {
+                Some(end) => {
+                    let prefix = format!("{}{}", options.prefix, &rest[..end]);
+                    if prefixes.last() != Some(&prefix) {
+                        prefixes.push(prefix);
+                    }
+                },
+                None => keys.push(key.clone()),
+            }
+        }
+
+        Ok(ok(json! {{ "keys": keys, "common_prefixes": prefixes, "next_cursor": null }}))
+    })
+}
+
+#[derive(Deserialize)]
+struct PostOptions {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[post("/objects")]
+async fn post_object(req: HttpRequest, options: web::Query<PostOptions>, body: web::Bytes, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| {
+        let key = format!("{}{:016x}", options.prefix, rand::random::<u64>());
+        db.write(&key, &content_type(&req), &body).map_err(MockError::internal)?;
+
+        Ok(success(StatusCode::CREATED, json! {{ "object": key, "expires": null }}))
+    })
+}
+
+#[get("/objects/{key:.+}")]
+async fn get_object(req: HttpRequest, key: web::Path<String>, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| match db.read(&key).map_err(MockError::internal)? {
+        Some((data, content_type)) => Ok(HttpResponse::Ok().content_type(content_type).body(data)),
+        None => Err(no_such_object()),
+    })
+}
+
+#[put("/objects/{key:.+}")]
+async fn put_object(req: HttpRequest, key: web::Path<String>, body: web::Bytes, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| {
+        db.write(&key, &content_type(&req), &body).map_err(MockError::internal)?;
+        Ok(ok(json! {{ "object": key.as_str(), "expires": null }}))
+    })
+}
+
+#[delete("/objects/{key:.+}")]
+async fn delete_object(req: HttpRequest, key: web::Path<String>, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| match db.remove(&key).map_err(MockError::internal)? {
+        true => Ok(ok(json! {{ "object": key.as_str() }})),
+        false => Err(no_such_object()),
+    })
+}
+
+#[derive(Deserialize)]
+struct IndexDeclaration {
+    field: String,
+}
+
+#[put("/collections/{collection}/indexes/{name}")]
+async fn create_index(req: HttpRequest, path: web::Path<(String, String)>, declaration: web::Json<IndexDeclaration>, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| {
+        let (collection, name) = path.into_inner();
+        if !declaration.field.starts_with('/') {
+            return Err(MockError::new(StatusCode::BAD_REQUEST, "invalid_field", "Fields are named by JSON pointers, which start with '/'"));
+        }
+
+        let indexed = db.values(&collection, &declaration.field).map_err(MockError::internal)?.len();
+        db.indexes.insert((collection, name), declaration.field.clone());
+
+        Ok(ok(json! {{ "indexed": indexed }}))
+    })
+}
+
+#[derive(Deserialize)]
+struct IndexQuery {
+    value: Option<String>,
+}
+
+/// Looks up an exact value. Values are read as JSON where they can be, and as strings otherwise, as the server reads them.
+#[get("/collections/{collection}/indexes/{name}")]
+async fn query_index(req: HttpRequest, path: web::Path<(String, String)>, query: web::Query<IndexQuery>, mock: Mock) -> MockResult {
+    with_database(&req, &mock, |db| {
+        let (collection, name) = path.into_inner();
+        let Some(field) = db.indexes.get(&(collection.clone(), name)).cloned() else {
+            return Err(MockError::new(StatusCode::NOT_FOUND, "no_such_index", "No such index"));
+        };
+
+        let Some(ref value) = query.value else {
+            return Err(MockError::new(StatusCode::BAD_REQUEST, "not_supported", "The mock server only looks up exact values"));
+        };
+
+        let value = serde_json::from_str::<Value>(value).ok()
+            .filter(|value| !value.is_array() && !value.is_object())
+            .unwrap_or_else(|| Value::String(value.clone()));
+
+        let results = db.values(&collection, &field).map_err(MockError::internal)?.into_iter()
+            .filter(|(_, found)| *found == value)
+            .map(|(key, value)| json! {{ "key": key, "value": value }})
+            .collect::<Vec<_>>();
+
+        Ok(ok(json! {{ "results": results, "next_cursor": null }}))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DbModel;
+    use super::*;
+    use reqwest::blocking::Body;
+    use serde::Serialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, DbModel)]
+    #[db(collection = "users")]
+    struct User {
+        #[db(key)]
+        id: String,
+        #[db(index)]
+        email: String,
+    }
+
+    #[test]
+    pub fn test_clients_work_against_the_mock() -> Result<()> {
+        let mock = MockServer::start()?;
+        let (app, user) = (mock.app_client()?, mock.user_client()?);
+
+        let created = user.create_database("other", None)?;
+        assert_eq!(created["name"], "other");
+        assert_eq!(user.databases()?.len(), 2);
+
+        assert_eq!(app.upload(Some("notes/a"), Some("text/plain"), Body::from("Hello"))?, "notes/a");
+        assert_eq!(app.read_object("notes/a")?, Some(b"Hello".to_vec()));
+        assert_eq!(app.read_object("notes/b")?, None);
+
+        let ada = User { id: "ada".to_owned(), email: "ada@example.com".to_owned() };
+        ada.save(&app)?;
+        User { id: "alan".to_owned(), email: "alan@example.com".to_owned() }.save(&app)?;
+        User::create_indexes(&app)?;
+
+        assert_eq!(User::get(&app, "ada")?, Some(ada));
+        assert_eq!(User::find_by(&app, "by-email", &"alan@example.com")?.len(), 1);
+        assert!(User::delete(&app, "alan")?);
+        assert!(User::find_by(&app, "by-email", &"alan@example.com")?.is_empty());
+
+        let mut pages = vec![];
+        app.list_objects("", |page| pages.push(page.clone()))?;
+        app.list_objects("notes/", |page| pages.push(page.clone()))?;
+        assert_eq!(pages[0]["common_prefixes"], json!(["notes/", "users/"]));
+        assert_eq!(pages[1]["keys"], json!(["notes/a"]));
+
+        assert!(user.read_object("notes/a").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+//! A client for the server's HTTP API, shared by the binaries which talk to a running server. Each includes it with
+//! `#[path = "../sdk/mod.rs"] mod sdk;`, as binaries can't depend on the server's own crate.
+
+// Each binary only uses part of the client.
+#![allow(dead_code, unused_imports)]
+
+mod client;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock;
+pub mod model;
+pub mod retry;
+
+pub use client::Client;
+pub use model::DbModel;
+pub use sdk_derive::DbModel;
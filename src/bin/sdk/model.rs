@@ -0,0 +1,141 @@
+use super::client::Client;
+use libdb::error::{Error, Result};
+use reqwest::blocking::Body;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A secondary index over a field of a collection's documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Index {
+    pub name: &'static str,
+
+    /// The JSON pointer naming the field, such as `/email`.
+    pub field: &'static str,
+}
+
+/// A struct stored as JSON documents of a collection, each under the key `<collection>/<id>`. Derive it with `#[derive(DbModel)]`, which
+/// also generates the collection's indexes and schema from the struct's fields:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, DbModel)]
+/// #[db(collection = "users")]
+/// struct User {
+///     #[db(key)]
+///     id: String,
+///     #[db(index)]
+///     email: String,
+/// }
+///
+/// User::create_indexes(&client)?;
+/// let ada = User::get(&client, "ada")?;
+/// let found = User::find_by(&client, "by-email", &"ada@example.com")?;
+/// ```
+///
+/// Documents are ordinary objects, so the client's token must belong to an app, with a database chosen.
+pub trait DbModel: Serialize + DeserializeOwned {
+    const COLLECTION: &'static str;
+    const INDEXES: &'static [Index];
+
+    /// The ID the document is stored under.
+    fn id(&self) -> String;
+
+    /// A JSON Schema describing the documents.
+    fn schema() -> Value;
+
+    fn key(id: &str) -> String {
+        format!("{}/{}", Self::COLLECTION, id)
+    }
+
+    /// The document with the ID `id`, or `None` if there is none.
+    fn get(client: &Client, id: &str) -> Result<Option<Self>> {
+        client.read_object(&Self::key(id))?
+            .map(|document| serde_json::from_slice(&document).map_err(|err| Error::custom(format!("{} isn't a valid {}: {err}", Self::key(id), Self::COLLECTION))))
+            .transpose()
+    }
+
+    /// Stores the document under its ID, replacing whatever was there.
+    fn save(&self, client: &Client) -> Result<()> {
+        let document = serde_json::to_vec(self).map_err(|err| Error::custom(err.to_string()))?;
+        client.upload(Some(&Self::key(&self.id())), Some("application/json"), Body::from(document))?;
+
+        Ok(())
+    }
+
+    /// Deletes the document with the ID `id`, answering whether there was one.
+    fn delete(client: &Client, id: &str) -> Result<bool> {
+        client.delete_object(&Self::key(id))
+    }
+
+    /// The documents whose value in the index `index` is `value`, in order of key.
+    fn find_by(client: &Client, index: &str, value: &impl Serialize) -> Result<Vec<Self>> {
+        let value = serde_json::to_string(value).map_err(|err| Error::custom(err.to_string()))?;
+        let mut found = vec![];
+
+        for key in client.lookup(Self::COLLECTION, index, &value)? {
+            let Some(id) = key.strip_prefix(Self::COLLECTION).and_then(|key| key.strip_prefix('/')) else {
+                continue;
+            };
+
+            // A document deleted since the lookup is left out.
+            found.extend(Self::get(client, id)?);
+        }
+
+        Ok(found)
+    }
+
+    /// Declares every index the model has, building each over the documents already stored. Declaring them again rebuilds them.
+    fn create_indexes(client: &Client) -> Result<()> {
+        for index in Self::INDEXES {
+            client.create_index(Self::COLLECTION, index.name, index.field)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DbModel;
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, DbModel)]
+    #[db(collection = "people")]
+    struct Person {
+        #[db(key)]
+        id: u32,
+        #[db(index)]
+        email: String,
+        #[serde(rename = "years")]
+        #[db(index = "age")]
+        age: Option<u8>,
+        tags: Vec<String>,
+        #[serde(skip)]
+        cached: bool,
+    }
+
+    #[test]
+    pub fn test_models_derive_their_collection() {
+        let person = Person { id: 7, email: "ada@example.com".to_owned(), age: Some(36), tags: vec![], cached: false };
+
+        assert_eq!(Person::COLLECTION, "people");
+        assert_eq!(person.id(), "7");
+        assert_eq!(Person::key(&person.id()), "people/7");
+        assert_eq!(Person::INDEXES, [Index { name: "by-email", field: "/email" }, Index { name: "age", field: "/years" }]);
+
+        assert_eq!(Person::schema(), json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer" },
+                "email": { "type": "string" },
+                "years": { "anyOf": [{ "type": "integer" }, { "type": "null" }] },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["id", "email", "tags"],
+        }));
+
+        assert!(!person.cached);
+    }
+}
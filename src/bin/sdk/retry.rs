@@ -0,0 +1,82 @@
+use reqwest::blocking::Response;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+/// How requests to a server are retried when it is busy, unavailable or too slow to answer.
+///
+/// The longest a retry may wait doubles with each attempt, up to `max_delay`, and each retry waits a random part of that, so clients which
+/// failed together don't all retry together. A delay the server asks for with `Retry-After` is used instead, where given, unless it is longer
+/// than `max_delay`, in which case the request isn't retried at all.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times a request may be retried. Requests are only sent once if this is 0.
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The longest a retry may wait after `attempt` failed attempts besides the first.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay)
+    }
+
+    /// How long to wait before retrying after `attempt` failed attempts besides the first, given what the server asked for, if anything.
+    /// Returns `None` if the server asked for a longer wait than `max_delay`, so the request should be given up on instead.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            Some(retry_after) => (retry_after <= self.max_delay).then_some(retry_after),
+            None => Some(self.backoff(attempt).mul_f64(rand::random::<f64>())),
+        }
+    }
+}
+
+/// Whether a response means the server didn't handle the request, and might if it is sent again later.
+pub fn retryable(status: StatusCode) -> bool {
+    matches!(status, StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// The delay the server asked for, in seconds.
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    response.headers().get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// A random key identifying a request, which stays the same across its retries.
+pub fn idempotency_key() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_delays_grow_up_to_the_limit() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(1600));
+        assert_eq!(policy.backoff(40), policy.max_delay);
+
+        for attempt in 0..8 {
+            assert!(policy.delay(attempt, None).is_some_and(|delay| delay <= policy.backoff(attempt)));
+        }
+
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(5))), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(30))), None);
+    }
+}
@@ -0,0 +1,208 @@
+use actix_web::{get, web, Responder};
+use chrono::{DateTime, Utc};
+use libdb::{AllocOptions, FragmentID};
+use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
+use crate::auth::AuthenticatedUser;
+use crate::error::*;
+use crate::keys::KeyDirectory;
+use crate::pool::{DbPool, Store};
+use crate::response::{ApiError, ApiResponse};
+use crate::{DBIndex, DatabaseID};
+use crate::telemetry;
+
+/// How many changes are kept in the key directory before they are written out to a segment of their own.
+const SEGMENT_LEN: usize = 256;
+
+/// The most segments kept. The oldest is deleted as each new one is written, so roughly this many times [`SEGMENT_LEN`] changes can be
+/// caught up on.
+const MAX_SEGMENTS: usize = 256;
+
+/// The most changes returned by one request.
+const MAX_CHANGES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Expire,
+}
+
+/// Something which happened to an object.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// Counts up from 1 with each change to the database.
+    pub sequence: u64,
+    pub object: String,
+    pub action: Action,
+    pub timestamp: DateTime<Utc>,
+
+    /// Who made the change, such as `app:{id}` or `user:{id}`. Changes the server makes on its own, such as removing expired objects, have
+    /// none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+}
+
+/// A run of [`SEGMENT_LEN`] changes written out to a fragment of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Segment {
+    first: u64,
+    fragment: FragmentID,
+}
+
+/// The ordered record of every change to a store's objects, so other systems can follow along without reading the whole store again.
+///
+/// The newest changes are kept in the key directory, which makes recording a change part of the write which saves the directory. Once
+/// enough have gathered, they are moved out to a segment, and the oldest segments are deleted once there are too many.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChangeLog {
+    /// The sequence of the newest change, or 0 if there are none.
+    last: u64,
+    segments: Vec<Segment>,
+    pending: Vec<Change>,
+}
+
+impl ChangeLog {
+    pub fn is_empty(&self) -> bool {
+        self.last == 0
+    }
+
+    /// The sequence of the newest change, or 0 if there are none.
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// The sequence of the oldest change which can still be read.
+    fn oldest(&self) -> u64 {
+        self.segments.first().map_or_else(|| self.pending.first().map_or(self.last + 1, |change| change.sequence), |segment| segment.first)
+    }
+
+    fn push(&mut self, object: &str, action: Action, actor: Option<&str>, timestamp: DateTime<Utc>) {
+        self.last += 1;
+        self.pending.push(Change { sequence: self.last, object: object.to_owned(), action, timestamp, actor: actor.map(str::to_owned) });
+    }
+}
+
+/// Records a change to the object at `key` in the directory's changelog. Returns the fragments of segments which no longer fit, which should
+/// only be deleted once the directory has been saved.
+pub fn record(store: &mut Store, directory: &mut KeyDirectory, key: &str, action: Action, actor: Option<&str>) -> Result<Vec<FragmentID>> {
+    directory.changelog_mut().push(key, action, actor, Utc::now());
+
+    if directory.changelog_mut().pending.len() < SEGMENT_LEN {
+        return Ok(vec![]);
+    }
+
+    let fragment = directory.allocate_id();
+    let log = directory.changelog_mut();
+    let pending = std::mem::take(&mut log.pending);
+
+    store.write_fragment(AllocOptions::default().fragment(fragment), &serde_json::to_vec(&pending)?)?;
+    log.segments.push(Segment { first: pending[0].sequence, fragment });
+
+    let expired = log.segments.len().saturating_sub(MAX_SEGMENTS);
+    Ok(log.segments.drain(..expired).map(|segment| segment.fragment).collect())
+}
+
+/// Changes read from the changelog.
+#[derive(Debug, Serialize)]
+pub struct Changes {
+    pub changes: Vec<Change>,
+
+    /// Passed back as `since` to carry on after the last change returned.
+    pub next: u64,
+
+    /// Set if changes after `since` have already been deleted, so a follower must read the whole database again to catch up.
+    pub truncated: bool,
+}
+
+/// Reads up to `limit` changes made after the change `since`, oldest first.
+pub fn read(store: &mut Store, since: u64, limit: usize) -> Result<Changes> {
+    let directory = KeyDirectory::load(store)?;
+    let log = directory.changelog();
+    let mut changes = vec![];
+
+    // Segments are in order, so only those holding changes after `since` are read.
+    for (i, segment) in log.segments.iter().enumerate() {
+        let end = log.segments.get(i + 1).map_or_else(|| log.pending.first().map_or(log.last + 1, |change| change.sequence), |next| next.first);
+        if changes.len() >= limit {
+            break;
+        }
+
+        if end <= since + 1 {
+            continue;
+        }
+
+        let mut data = vec![];
+        store.open_fragment(segment.fragment)?.read_into(&mut data)?;
+        changes.extend(serde_json::from_slice::<Vec<Change>>(&data)?.into_iter().filter(|change| change.sequence > since));
+    }
+
+    changes.extend(log.pending.iter().filter(|change| change.sequence > since).cloned());
+    changes.truncate(limit);
+
+    Ok(Changes {
+        next: changes.last().map_or(since, |change| change.sequence),
+        truncated: since + 1 < log.oldest(),
+        changes,
+    })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ChangesOptions {
+    #[serde(default)]
+    pub since: u64,
+    pub limit: Option<usize>,
+}
+
+/// Lists the changes made to the database's objects after the change `since`, oldest first, so search indexers, caches and the like can keep
+/// up with it incrementally. Any member of the database may read them.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID"), ChangesOptions),
+    responses((status = 200, description = "The changes made since `since`, oldest first"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/changes")]
+pub async fn get_changes(id: web::Path<DatabaseID>, options: web::Query<ChangesOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let Some(db) = index.lock().await.databases.iter()
+        .find(|db| db.id == *id && (db.owner == user.id || db.rw.contains(&user.id) || db.ro.contains(&user.id)))
+        .cloned() else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let (since, limit) = (options.since, options.limit.unwrap_or(MAX_CHANGES).clamp(1, MAX_CHANGES));
+
+    let changes = telemetry::block(move || read(&mut store.blocking_lock(), since, limit))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_the_oldest_change_follows_the_pending_and_segmented_changes() {
+        let mut log = ChangeLog::default();
+        assert_eq!(log.oldest(), 1);
+
+        let now = Utc::now();
+        log.push("notes/a", Action::Create, Some("app:a1"), now);
+        log.push("notes/a", Action::Delete, None, now);
+        assert_eq!((log.oldest(), log.last), (1, 2));
+        assert_eq!(log.pending[1], Change { sequence: 2, object: "notes/a".to_owned(), action: Action::Delete, timestamp: now, actor: None });
+
+        // Once the oldest changes are moved out to segments, the oldest segment's first change is the oldest.
+        log.segments.push(Segment { first: 1, fragment: 10 });
+        log.pending.clear();
+        assert_eq!(log.oldest(), 1);
+        log.segments.clear();
+        assert_eq!(log.oldest(), 3);
+    }
+}
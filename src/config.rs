@@ -0,0 +1,910 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::Deserialize;
+use crate::error::*;
+use crate::access::AccessField;
+use crate::ids::IdScheme;
+use crate::{AppID, DatabaseID, OAuthSettings, UserID};
+
+#[derive(clap::Parser, Clone)]
+pub struct Args {
+    /// The address to listen on. Overrides `address` in the config file.
+    address: Option<SocketAddr>,
+
+    #[clap(long = "database")]
+    database_dir: Option<PathBuf>,
+
+    /// A TOML file to read the server configuration from. Any options given on the command line take precedence.
+    #[clap(long = "config")]
+    config: Option<PathBuf>,
+
+    #[clap(long = "workers")]
+    workers: Option<usize>,
+
+    #[clap(long = "log-level")]
+    log_level: Option<String>,
+
+    /// A PEM file containing the certificate chain to serve over HTTPS. Requires `--tls-key`.
+    #[clap(long = "tls-cert", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// A PEM file containing the private key for `--tls-cert`.
+    #[clap(long = "tls-key", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Check every store before accepting connections, and refuse to start if any is damaged.
+    #[clap(long = "verify-on-start")]
+    verify_on_start: bool,
+
+    /// Run as a follower, keeping a copy of every database of the primary server at this URL instead of serving requests.
+    #[clap(long = "replicate-from", requires = "replicate_token")]
+    replicate_from: Option<String>,
+
+    /// The token presented to the primary given by `--replicate-from`.
+    #[clap(long = "token", requires = "replicate_from")]
+    replicate_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    pub database_dir: PathBuf,
+    /// How many threads serve requests. Defaults to the number of CPUs.
+    pub workers: usize,
+    pub log_level: String,
+
+    /// The largest request body any endpoint will accept, in bytes.
+    pub max_body_size: usize,
+
+    /// How identifiers are made for new databases and objects.
+    pub id_scheme: IdScheme,
+
+    /// Users who may administer the whole server: manage users, change quotas and erase anyone's data. The `admins` of `users`, `quotas`
+    /// and `erasure` add users who may only do that much.
+    pub admins: Vec<UserID>,
+
+    pub tokens: TokenConfig,
+    pub query: QueryConfig,
+    pub documents: DocumentConfig,
+    pub rate_limit: RateLimitConfig,
+    pub search: SearchConfig,
+    pub stores: StoreConfig,
+    pub maintenance: MaintenanceConfig,
+    pub quotas: QuotaConfig,
+    pub metrics: MetricsConfig,
+    pub erasure: ErasureConfig,
+    pub users: UserAdminConfig,
+    pub alerts: AlertConfig,
+    pub replication: ReplicationConfig,
+    pub cors: CorsConfig,
+    pub s3: S3Config,
+
+    /// Serves HTTPS instead of plain HTTP when present.
+    pub tls: Option<TlsConfig>,
+
+    /// Logs every request to a file when present. This is separate from the audit log.
+    pub access_log: Option<AccessLogConfig>,
+
+    /// Exports a span for every request to an OpenTelemetry collector when present.
+    pub otlp: Option<OtlpConfig>,
+
+    /// When present, replaces the OAuth settings stored in the database index.
+    pub oauth: Option<OAuthSettings>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 2003)),
+            database_dir: PathBuf::new(),
+            workers: std::thread::available_parallelism().map_or(1, usize::from),
+            log_level: "info".to_owned(),
+            max_body_size: 16 * 1024 * 1024,
+            id_scheme: IdScheme::default(),
+            admins: vec![],
+            tokens: TokenConfig::default(),
+            query: QueryConfig::default(),
+            documents: DocumentConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            search: SearchConfig::default(),
+            stores: StoreConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            quotas: QuotaConfig::default(),
+            metrics: MetricsConfig::default(),
+            erasure: ErasureConfig::default(),
+            users: UserAdminConfig::default(),
+            alerts: AlertConfig::default(),
+            replication: ReplicationConfig::default(),
+            cors: CorsConfig::default(),
+            s3: S3Config::default(),
+            tls: None,
+            access_log: None,
+            otlp: None,
+            oauth: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TokenConfig {
+    /// How long an API token remains valid after it was issued, in seconds.
+    pub lifetime: u64,
+
+    /// If set, apps may mint delegation tokens for their users, which are signed with this key using HMAC-SHA256.
+    pub delegation_key: Option<String>,
+
+    /// The longest a delegation token may remain valid for, in seconds.
+    pub delegation_max_lifetime: u64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: 12 * 60 * 60,
+            delegation_key: None,
+            delegation_max_lifetime: 60 * 60,
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.lifetime)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QueryConfig {
+    /// How long a single query may run before it returns partial results, in milliseconds.
+    pub time_budget: u64,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            time_budget: 500,
+        }
+    }
+}
+
+impl QueryConfig {
+    pub fn time_budget(&self) -> Duration {
+        Duration::from_millis(self.time_budget)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DocumentConfig {
+    /// The largest document which may be written, in bytes.
+    pub max_size: usize,
+
+    /// How deeply objects and arrays may be nested within a document.
+    pub max_depth: usize,
+}
+
+impl Default for DocumentConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 4 * 1024 * 1024,
+            max_depth: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// How long opening a store may take before it is given up on, in milliseconds.
+    pub open_timeout: u64,
+
+    /// How many times in a row a store may fail to open before its database is quarantined.
+    pub max_open_failures: u32,
+
+    /// How many bytes must remain free on the disk holding a store. Writes which would leave less than this are refused.
+    pub min_free_space: u64,
+
+    /// Whether to open and check every store before accepting connections. The server refuses to start if any store is damaged.
+    pub verify_on_start: bool,
+
+    /// How many fragments of each store are read back while verifying it. The header and key directory are always checked.
+    pub verify_sample: usize,
+
+    /// How many pages of each open store are kept in memory. Setting this to 0 turns the cache off.
+    pub cache_pages: usize,
+
+    /// How far each write is taken towards the disk before it is answered.
+    pub durability: Durability,
+
+    /// The share of object reads counted towards each object's access statistics, from 0 to 1.
+    pub usage_sample_rate: f64,
+
+    /// How often the access statistics of open stores are written out, in seconds.
+    pub usage_persist_interval: u64,
+
+    /// How often expired objects are removed from open stores, in seconds. Expired objects are hidden as soon as they expire either way.
+    pub reap_interval: u64,
+
+    /// How long deleted objects stay in the trash, where they can be restored from, in seconds. They are purged by the next pass after.
+    pub trash_retention: u64,
+
+    /// How often open stores with unflushed changes are flushed, in seconds. 0 turns checkpoints off, so stores are only flushed as their
+    /// durability asks and when they are closed.
+    pub checkpoint_interval: u64,
+
+    /// Up to how many seconds are added at random to each wait between checkpoints, so servers started together don't all write at once.
+    pub checkpoint_jitter: u64,
+
+    /// Whether checkpoints also sync every open store to its disk.
+    pub checkpoint_fsync: bool,
+
+    /// How many bytes of recently read objects are kept in memory, across every database. Setting this to 0 turns the object cache off.
+    pub object_cache_size: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            open_timeout: 10_000,
+            max_open_failures: 3,
+            min_free_space: 64 * 1024 * 1024,
+            verify_on_start: false,
+            verify_sample: 0,
+            cache_pages: libdb::DEFAULT_CACHE_PAGES,
+            durability: Durability::default(),
+            usage_sample_rate: 0.1,
+            usage_persist_interval: 5 * 60,
+            reap_interval: 60,
+            trash_retention: 7 * 24 * 60 * 60,
+            checkpoint_interval: 30,
+            checkpoint_jitter: 5,
+            checkpoint_fsync: false,
+            object_cache_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// The config's name for [`libdb::Durability`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Writes may wait in the store's cache until it is flushed, such as when it is closed.
+    #[default]
+    Relaxed,
+
+    /// Writes reach the operating system before they are answered, so they survive the server crashing.
+    FlushOnCommit,
+
+    /// Writes are synced to the disk before they are answered, so they survive power loss.
+    FsyncOnCommit,
+}
+
+impl From<Durability> for libdb::Durability {
+    fn from(durability: Durability) -> Self {
+        match durability {
+            Durability::Relaxed => libdb::Durability::Relaxed,
+            Durability::FlushOnCommit => libdb::Durability::FlushOnCommit,
+            Durability::FsyncOnCommit => libdb::Durability::FsyncOnCommit,
+        }
+    }
+}
+
+impl StoreConfig {
+    pub fn open_timeout(&self) -> Duration {
+        Duration::from_millis(self.open_timeout)
+    }
+
+    pub fn usage_persist_interval(&self) -> Duration {
+        Duration::from_secs(self.usage_persist_interval.max(1))
+    }
+
+    pub fn reap_interval(&self) -> Duration {
+        Duration::from_secs(self.reap_interval.max(1))
+    }
+
+    /// How long to wait before the next checkpoint, or `None` if stores aren't checkpointed.
+    pub fn checkpoint_delay(&self) -> Option<Duration> {
+        let jitter = Duration::from_secs(self.checkpoint_jitter).mul_f64(rand::random());
+        (self.checkpoint_interval > 0).then(|| Duration::from_secs(self.checkpoint_interval) + jitter)
+    }
+
+    pub fn trash_retention(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.trash_retention.min(i64::MAX as u64 / 1000) as i64)
+    }
+}
+
+/// When each database's heavy background work may run at full speed: removing expired and trashed objects, checkpoints, scrubbing its
+/// fragments and backing it up. Databases without any windows, which is all of them unless some are configured, are never held back.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// The windows of every database without windows of its own, such as `["02:00-04:00"]`. Times are in UTC.
+    pub windows: Vec<MaintenanceWindow>,
+
+    /// Windows for individual databases, keyed by their ID, in place of `windows`.
+    pub databases: HashMap<DatabaseID, Vec<MaintenanceWindow>>,
+
+    /// Outside its windows, a database's background work only runs one pass in every `throttle`, and scrubs read one fragment in every
+    /// `throttle` of their sample. Backups wait for the next window.
+    pub throttle: u32,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            windows: vec![],
+            databases: HashMap::new(),
+            throttle: 10,
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    fn windows(&self, db: &DatabaseID) -> &[MaintenanceWindow] {
+        self.databases.get(db).unwrap_or(&self.windows)
+    }
+
+    /// Whether the database may be maintained at full speed at `now`.
+    pub fn in_window(&self, db: &DatabaseID, now: DateTime<Utc>) -> bool {
+        let windows = self.windows(db);
+        windows.is_empty() || windows.iter().any(|window| window.contains(now.time()))
+    }
+
+    /// Whether pass number `pass` of a background job should include the database.
+    pub fn due(&self, db: &DatabaseID, pass: u64, now: DateTime<Utc>) -> bool {
+        self.in_window(db, now) || pass.is_multiple_of(u64::from(self.throttle.max(1)))
+    }
+
+    /// How many of `sample` fragments a scrub of the database should read at `now`.
+    pub fn sample(&self, db: &DatabaseID, sample: usize, now: DateTime<Utc>) -> usize {
+        match self.in_window(db, now) {
+            true => sample,
+            false => sample.div_ceil(self.throttle.max(1) as usize),
+        }
+    }
+
+    /// How long until the database's next window opens, or `None` if it is in one at `now`.
+    pub fn until_window(&self, db: &DatabaseID, now: DateTime<Utc>) -> Option<Duration> {
+        if self.in_window(db, now) {
+            return None;
+        }
+
+        self.windows(db).iter()
+            .map(|window| (window.start - now.time()).num_seconds().rem_euclid(24 * 60 * 60))
+            .min()
+            .map(|seconds| Duration::from_secs(seconds as u64))
+    }
+}
+
+/// A time of day between `start` and `end`, written `HH:MM-HH:MM`, which runs past midnight if `end` comes first. A window whose start and
+/// end are the same lasts all day.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MaintenanceWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match self.start.cmp(&self.end) {
+            Ordering::Less => self.start <= time && time < self.end,
+            Ordering::Greater => self.start <= time || time < self.end,
+            Ordering::Equal => true,
+        }
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = String;
+
+    fn try_from(window: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || format!("Maintenance windows are written HH:MM-HH:MM, not {:?}", window);
+        let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        Ok(Self { start: time(start)?, end: time(end)? })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// The quota given to new databases, in bytes. New databases are unlimited if this isn't set.
+    pub default: Option<u64>,
+
+    /// Users who may change any database's quota, besides the server's `admins`.
+    pub admins: Vec<UserID>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Whether to serve `GET /metrics`.
+    pub enabled: bool,
+
+    /// If set, Prometheus must present this as a bearer token to scrape the metrics.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ErasureConfig {
+    /// Users who may erase any user's data, rather than only their own, besides the server's `admins`.
+    pub admins: Vec<UserID>,
+
+    /// If set, erasure reports are signed with this key, using HMAC-SHA256.
+    pub signing_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UserAdminConfig {
+    /// Users who may list every user, revoke their tokens and delete them, besides the server's `admins`.
+    pub admins: Vec<UserID>,
+}
+
+/// Which web pages may call the API from a browser. See [`crate::cors`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// The origins which may call the API, such as `https://app.example.com`, or `"*"` for any. Browsers are refused if there are none.
+    pub allowed_origins: Vec<String>,
+
+    /// The request headers browsers may send, besides the ones they always may.
+    pub allowed_headers: Vec<String>,
+
+    /// The response headers scripts may read, besides the ones they always may.
+    pub exposed_headers: Vec<String>,
+
+    /// Whether browsers may send cookies and client certificates along with requests. Never applies to origins matched by `"*"`.
+    pub allow_credentials: bool,
+
+    /// How long browsers may reuse the answer to a preflight request, in seconds.
+    pub max_age: u64,
+
+    /// Paths starting with any of these never answer cross-origin requests. The OAuth endpoints are only ever reached by navigating to them.
+    pub exclude: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_headers: ["Authorization", "Content-Type", "db", "If-Match", "If-None-Match", "Range", "X-Request-Id"].map(str::to_owned).to_vec(),
+            exposed_headers: ["ETag", "Content-Range", "Accept-Ranges", "Retry-After", "X-Request-Id"].map(str::to_owned).to_vec(),
+            allow_credentials: false,
+            max_age: 60 * 60,
+            exclude: ["/oauth", "/refresh"].map(str::to_owned).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// Where operational alerts are sent. Alerts are only logged if there are none.
+    pub channels: Vec<AlertChannel>,
+
+    /// How long to hold back repeats of an alert about the same problem, in seconds.
+    pub repeat_interval: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec![],
+            repeat_interval: 60 * 60,
+        }
+    }
+}
+
+impl AlertConfig {
+    pub fn repeat_interval(&self) -> Duration {
+        Duration::from_secs(self.repeat_interval)
+    }
+}
+
+/// The S3-compatible API under `/s3`. See [`crate::s3`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct S3Config {
+    /// The region clients must sign their requests for.
+    pub region: String,
+
+    /// The access keys clients may sign requests with. Apps' tokens work as bearer tokens without any.
+    pub credentials: Vec<S3Credentials>,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            region: "us-east-1".to_owned(),
+            credentials: vec![],
+        }
+    }
+}
+
+/// An access key for signing S3 requests. Signatures can only be checked with the secret itself, so unlike tokens it is kept as it is.
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+
+    /// The app requests signed with the key act as.
+    pub app: AppID,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// The token followers must present to replicate from this server. Replication is disabled unless it is set.
+    pub token: Option<String>,
+
+    /// The primary to follow. When set, the server keeps a copy of the primary's databases instead of serving requests.
+    pub replicate_from: Option<String>,
+
+    /// The token presented to the primary, matching its `replication.token`.
+    pub replicate_token: Option<String>,
+
+    /// How often a follower asks the primary for changes, in seconds.
+    pub interval: u64,
+
+    /// Roughly how many bytes of fragments the primary sends in one response. Larger changes take several.
+    pub batch_size: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            replicate_from: None,
+            replicate_token: None,
+            interval: 5,
+            batch_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl ReplicationConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertChannel {
+    /// POSTs each alert as JSON.
+    Webhook { url: String },
+    Email(EmailConfig),
+    /// Prints each alert as a line of JSON.
+    Stdout,
+}
+
+impl AlertChannel {
+    pub fn name(&self) -> &str {
+        match self {
+            AlertChannel::Webhook { url } => url,
+            AlertChannel::Email(email) => &email.server,
+            AlertChannel::Stdout => "stdout",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmailConfig {
+    /// The SMTP server to send through.
+    pub server: String,
+
+    /// Defaults to 587 with STARTTLS, or 25 without.
+    pub port: Option<u16>,
+
+    /// Whether to upgrade the connection with STARTTLS. Only turn this off for a relay on the same machine or network.
+    #[serde(default = "EmailConfig::default_starttls")]
+    pub starttls: bool,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+impl EmailConfig {
+    fn default_starttls() -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SearchConfig {
+    /// How many databases a single search may query at once.
+    pub concurrency: usize,
+
+    /// The most results a search may return.
+    pub max_results: usize,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            max_results: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// The limit applied to routes without one of their own.
+    #[serde(flatten)]
+    pub default: RateLimit,
+
+    /// Limits for individual routes, keyed by their path pattern, e.g. `/query`.
+    pub routes: HashMap<String, RateLimit>,
+}
+
+impl RateLimitConfig {
+    pub fn route(&self, route: &str) -> &RateLimit {
+        self.routes.get(route).unwrap_or(&self.default)
+    }
+
+    /// Refuses limits whose buckets would never refill, since clients could only be told to retry after forever.
+    fn validate(&self) -> Result<()> {
+        let mut routes = std::iter::once(("default", &self.default)).chain(self.routes.iter().map(|(route, limit)| (route.as_str(), limit)));
+
+        match routes.find(|(_, limit)| !(limit.rate > 0.0 && limit.rate.is_finite())) {
+            Some((route, _)) => Err(ManualError::InvalidRateLimit(route.to_owned()).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A token bucket holding `burst` requests, which refills at `rate` requests per second.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            rate: 10.0,
+            burst: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+
+    /// How often to check the certificate and key for changes, in seconds.
+    #[serde(default = "TlsConfig::default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+impl TlsConfig {
+    fn default_reload_interval() -> u64 {
+        60
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+
+    /// Fields left out of every entry.
+    #[serde(default)]
+    pub redact: Vec<AccessField>,
+
+    /// Logs only the network a client is in rather than its full address.
+    #[serde(default)]
+    pub truncate_ip: bool,
+
+    /// How large the log may grow, in bytes, before it is rotated.
+    #[serde(default = "AccessLogConfig::default_max_size")]
+    pub max_size: u64,
+
+    /// How many days rotated logs are kept for.
+    #[serde(default = "AccessLogConfig::default_retention_days")]
+    pub retention_days: u64,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("access.log"),
+            redact: vec![],
+            truncate_ip: false,
+            max_size: Self::default_max_size(),
+            retention_days: Self::default_retention_days(),
+        }
+    }
+}
+
+impl AccessLogConfig {
+    fn default_max_size() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn default_retention_days() -> u64 {
+        30
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OtlpConfig {
+    /// Where spans are sent, using OTLP over HTTP with protobuf bodies, such as `http://localhost:4318/v1/traces`.
+    pub endpoint: String,
+
+    /// How the server is named in the collector.
+    #[serde(default = "OtlpConfig::default_service_name")]
+    pub service_name: String,
+
+    /// Sent with every export, such as a collector's API key.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl OtlpConfig {
+    fn default_service_name() -> String {
+        "simple-database-server".to_owned()
+    }
+}
+
+impl ServerConfig {
+    /// Whether `user` may manage users, as one of the server's `admins` or `users.admins`.
+    pub fn is_user_admin(&self, user: &UserID) -> bool {
+        self.admins.contains(user) || self.users.admins.contains(user)
+    }
+
+    /// Whether `user` may change quotas, as one of the server's `admins` or `quotas.admins`.
+    pub fn is_quota_admin(&self, user: &UserID) -> bool {
+        self.admins.contains(user) || self.quotas.admins.contains(user)
+    }
+
+    /// Whether `user` may erase anyone's data, as one of the server's `admins` or `erasure.admins`.
+    pub fn is_erasure_admin(&self, user: &UserID) -> bool {
+        self.admins.contains(user) || self.erasure.admins.contains(user)
+    }
+
+    /// Reads the config file named by the arguments, if any, and applies the command line overrides on top of it.
+    pub fn load(args: Args) -> Result<Self> {
+        let mut config = match args.config {
+            Some(ref path) => toml::from_str(&std::fs::read_to_string(path)?)?,
+            None => Self::default(),
+        };
+
+        if let Some(address) = args.address {
+            config.address = address;
+        }
+
+        if let Some(database_dir) = args.database_dir {
+            config.database_dir = database_dir;
+        }
+
+        if let Some(workers) = args.workers {
+            config.workers = workers;
+        }
+
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+
+        if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert,
+                key,
+                reload_interval: config.tls.as_ref().map_or(TlsConfig::default_reload_interval(), |tls| tls.reload_interval),
+            });
+        }
+
+        if args.verify_on_start {
+            config.stores.verify_on_start = true;
+        }
+
+        if let (Some(primary), Some(token)) = (args.replicate_from, args.replicate_token) {
+            config.replication.replicate_from = Some(primary);
+            config.replication.replicate_token = Some(token);
+        }
+
+        if config.database_dir.as_os_str().is_empty() {
+            return Err(ManualError::MissingDatabaseDir.into());
+        }
+
+        config.rate_limit.validate()?;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    pub fn test_server_admins_hold_every_admin_role() -> Result<()> {
+        let config: ServerConfig = toml::from_str(r#"
+            admins = ["root"]
+
+            [quotas]
+            admins = ["accountant"]
+        "#)?;
+
+        let (root, accountant) = ("root".to_owned(), "accountant".to_owned());
+
+        assert!(config.is_user_admin(&root) && config.is_quota_admin(&root) && config.is_erasure_admin(&root));
+        assert!(config.is_quota_admin(&accountant));
+        assert!(!config.is_user_admin(&accountant) && !config.is_erasure_admin(&accountant));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_maintenance_windows_hold_back_background_work() -> Result<()> {
+        let config: ServerConfig = toml::from_str(r#"
+            [maintenance]
+            windows = ["02:00-04:00"]
+            throttle = 4
+
+            [maintenance.databases]
+            night = ["23:00-01:00"]
+            always = []
+        "#)?;
+
+        let maintenance = &config.maintenance;
+        let at = |hour: u32, minute: u32| Utc.with_ymd_and_hms(2026, 1, 1, hour, minute, 0).unwrap();
+        let (db, night, always) = ("db1".to_owned(), "night".to_owned(), "always".to_owned());
+
+        assert!(maintenance.in_window(&db, at(3, 0)) && !maintenance.in_window(&db, at(4, 0)));
+        assert!(maintenance.in_window(&night, at(0, 30)) && !maintenance.in_window(&night, at(3, 0)));
+        assert!(maintenance.in_window(&always, at(12, 0)));
+
+        assert!(maintenance.due(&db, 1, at(3, 0)));
+        assert!((0..8).filter(|pass| maintenance.due(&db, *pass, at(12, 0))).eq([0, 4]));
+        assert_eq!(maintenance.sample(&db, 10, at(12, 0)), 3);
+
+        assert_eq!(maintenance.until_window(&db, at(3, 0)), None);
+        assert_eq!(maintenance.until_window(&db, at(1, 30)), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(maintenance.until_window(&night, at(1, 0)), Some(Duration::from_secs(22 * 60 * 60)));
+
+        assert!(toml::from_str::<ServerConfig>("maintenance.windows = [\"2am-4am\"]").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rate_limits_must_refill() -> Result<()> {
+        let config: RateLimitConfig = toml::from_str(r#"
+            rate = 5
+
+            [routes."/query"]
+            rate = 0
+            burst = 10
+        "#)?;
+
+        assert!(matches!(config.validate().unwrap_err().inner(), global::Inner::ManualError(ManualError::InvalidRateLimit(route)) if route == "/query"));
+        assert!(RateLimitConfig::default().validate().is_ok());
+
+        Ok(())
+    }
+}
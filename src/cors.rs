@@ -0,0 +1,116 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use crate::config::{CorsConfig, ServerConfig};
+
+/// The methods the API is called with.
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+
+impl CorsConfig {
+    /// What to send as `Access-Control-Allow-Origin` to a request from `origin` for `path`, if the origin may call it at all.
+    pub fn allow_origin(&self, origin: &str, path: &str) -> Option<String> {
+        if self.exclude.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return None;
+        }
+
+        if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.to_owned())
+        } else if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*".to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Adds the headers which let the browser hand the response to the page.
+    fn apply(&self, headers: &mut HeaderMap, origin: &str) {
+        // Responses differ by origin, so caches mustn't hand one origin's response to another.
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+
+        if let Ok(origin) = HeaderValue::from_str(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+
+        // Browsers refuse credentials along with a wildcard origin anyway.
+        if self.allow_credentials && origin != "*" {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+}
+
+fn header_list(list: &[String]) -> Option<HeaderValue> {
+    HeaderValue::from_str(&list.join(", ")).ok().filter(|_| !list.is_empty())
+}
+
+/// Answers preflight requests from the origins in `cors.allowed_origins`, and marks the responses to their other requests as readable by
+/// them. Requests without an `Origin` header, such as those from other servers, are left as they are.
+pub async fn cors(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let config = req.app_data::<web::Data<ServerConfig>>().map(|config| config.cors.clone());
+    let origin = req.headers().get(header::ORIGIN).and_then(|origin| origin.to_str().ok()).map(ToOwned::to_owned);
+
+    let (Some(config), Some(origin)) = (config, origin) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let allowed = config.allow_origin(&origin, req.path());
+    let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+    if preflight {
+        // Refused preflights get no CORS headers, which is how the browser learns it may not make the request.
+        let Some(allowed) = allowed else {
+            return Ok(req.into_response(HttpResponse::Forbidden().finish()).map_into_right_body());
+        };
+
+        let mut response = HttpResponse::NoContent();
+        response.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, config.max_age.to_string()));
+
+        if let Some(headers) = header_list(&config.allowed_headers) {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, headers));
+        }
+
+        let mut response = response.finish();
+        config.apply(response.headers_mut(), &allowed);
+
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+
+    if let Some(allowed) = allowed {
+        config.apply(res.headers_mut(), &allowed);
+
+        if let Some(headers) = header_list(&config.exposed_headers) {
+            res.headers_mut().insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, headers);
+        }
+    }
+
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_only_listed_origins_are_allowed_outside_excluded_paths() {
+        let mut config = CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_owned()],
+            ..CorsConfig::default()
+        };
+
+        assert_eq!(config.allow_origin("https://app.example.com", "/objects/a").as_deref(), Some("https://app.example.com"));
+        assert_eq!(config.allow_origin("https://evil.example.com", "/objects/a"), None);
+        assert_eq!(config.allow_origin("https://app.example.com", "/oauth"), None);
+
+        config.allowed_origins.push("*".to_owned());
+        assert_eq!(config.allow_origin("https://evil.example.com", "/objects/a").as_deref(), Some("*"));
+        assert_eq!(config.allow_origin("https://app.example.com", "/objects/a").as_deref(), Some("https://app.example.com"));
+        assert_eq!(config.allow_origin("https://app.example.com", "/refresh"), None);
+
+        assert_eq!(CorsConfig::default().allow_origin("https://app.example.com", "/objects/a"), None);
+    }
+}
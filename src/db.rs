@@ -1,28 +1,998 @@
-use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
-use serde::Deserialize;
-use serde_json::json;
-use crate::app::ValidatedApp;
+use std::ops::Bound;
+use std::sync::Arc;
+use actix_web::{delete, get, patch, post, put, web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web::http::header::{ContentRange, ContentRangeSpec, ETag, Range, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE};
+use actix_web::mime;
+use actix_web::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use tokio::sync::Mutex;
+use crate::app::{Scope, ValidatedApp};
+use crate::format::Binary;
+use crate::config::{DocumentConfig, ServerConfig};
+use crate::error::{global, DatabaseError, DocumentError, ManualError};
+use crate::dictionary;
+use crate::keys::{check_prefix, KeyDirectory, ObjectKey, DEFAULT_CONTENT_TYPE, DELIMITER};
+use crate::pool::{DbPool, Store};
+use crate::document::{read_body, read_document, JSON_CONTENT_TYPE};
+use crate::secondary::{self, IndexValue};
+use crate::versions::{self, VersionPolicy};
+use crate::patch::Patch;
+use crate::query::{apply_patch, create_object, read_object, read_object_ranges, write_object, write_object_if, Precondition, QueryBudget};
+use crate::paging::PageOptions;
+use crate::delegation::ObjectCaller;
+use crate::trash;
+use crate::response::{ApiError, ApiResponse, Done};
+use crate::{AppID, DBIndex, DatabaseID};
+use crate::telemetry;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema, IntoParams)]
 pub struct DBCall {
+    /// The key of the object to query.
     pub object: String,
     pub query: String,
+    /// Resumes a query which previously returned partial results.
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct BatchOptions {
+    /// Validates every operation before running any, stops at the first one to fail, and undoes the writes made before it.
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BatchOperation {
+    #[serde(flatten)]
+    pub call: DBCall,
+    /// The document to store, for `write` operations.
+    pub data: Option<serde_json::Value>,
+}
+
+/// A batch operation which has been checked and is ready to run.
+enum Operation {
+    Read { key: ObjectKey, offset: u64 },
+    Write { key: ObjectKey, data: Vec<u8> },
+}
+
+/// What a query answers with, whether it was made on its own or as part of a batch.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum QueryResponse {
+    Read {
+        /// The object's contents, in base64 in JSON and as raw bytes otherwise.
+        results: Binary,
+        partial: bool,
+        cursor: Option<String>,
+    },
+    Written {
+        object: String,
+    },
+}
+
+impl QueryResponse {
+    fn read(result: crate::query::QueryResult<Vec<u8>>) -> Self {
+        QueryResponse::Read {
+            results: Binary(result.results),
+            partial: result.partial,
+            cursor: result.cursor,
+        }
+    }
+}
+
+/// The result of one operation in a batch.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchResult {
+    Done(ApiResponse<QueryResponse>),
+    Failed(ApiError),
+}
+
+#[derive(Serialize)]
+struct BatchResults {
+    completed: usize,
+    results: Vec<BatchResult>,
+
+    /// Set if an atomic batch failed, and the writes it made before the failure were undone.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    rolled_back: bool,
+}
+
+fn invalid_cursor() -> ApiError {
+    ApiError::bad_request("invalid_cursor", "Invalid cursor")
+}
+
+fn no_such_object() -> ApiError {
+    ApiError::not_found("no_such_object", "No such object")
+}
+
+fn unknown_query(name: &str) -> ApiError {
+    ApiError::bad_request("unknown_query", format!("'{}' is not a recognised query", name))
+}
+
+impl Operation {
+    fn parse(op: BatchOperation, limits: &DocumentConfig) -> Result<Self, ApiError> {
+        let key = ObjectKey::parse(op.call.object).map_err(|err| ApiError::from(&err))?;
+
+        match (op.call.query.as_str(), op.data) {
+            ("read", _) => match op.call.cursor.as_deref().unwrap_or("0").parse::<u64>() {
+                Ok(offset) => Ok(Operation::Read { key, offset }),
+                Err(_) => Err(invalid_cursor()),
+            },
+            ("write", Some(data)) => {
+                let data = serde_json::to_vec(&data).map_err(|err| ApiError::bad_request("invalid_document", err.to_string()))?;
+
+                if data.len() > limits.max_size {
+                    return Err(ApiError::from(&DocumentError::TooLarge { limit: limits.max_size }));
+                }
+
+                Ok(Operation::Write { key, data })
+            },
+            ("write", None) => Err(ApiError::bad_request("missing_data", "Write operations need data")),
+            (other, _) => Err(unknown_query(other)),
+        }
+    }
+
+    fn run(self, store: &mut Store, budget: QueryBudget, actor: &str) -> Result<ApiResponse<QueryResponse>, ApiError> {
+        match self {
+            Operation::Read { key, offset } => match read_object(store, &key, offset, budget).map_err(ApiError::internal)? {
+                Some(result) => Ok(ApiResponse::ok(QueryResponse::read(result))),
+                None => Err(no_such_object()),
+            },
+            Operation::Write { key, data } => write_object(store, &key, JSON_CONTENT_TYPE, &data, None, Some(actor))
+                .map(|_| ApiResponse::ok(QueryResponse::Written { object: key.to_string() }))
+                .map_err(write_failed),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListOptions {
+    /// Only list keys starting with this prefix.
+    #[serde(default)]
+    pub prefix: String,
+    /// Rolls keys containing the delimiter after the prefix up into common prefixes.
+    pub delimiter: Option<String>,
+    /// Only list objects of this type. May be a whole family of types, such as `image/*`.
+    pub content_type: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ObjectOptions {
+    /// Reads a superseded version of the object rather than its current contents.
+    pub version: Option<u64>,
+}
+
+impl From<&DatabaseError> for ApiError {
+    fn from(err: &DatabaseError) -> Self {
+        let status = err.status_code();
+
+        match err {
+            DatabaseError::MissingHeader => ApiError::new(status, "missing_header", "No db header"),
+            DatabaseError::NotFound => ApiError::new(status, "no_such_database", "No such database"),
+            DatabaseError::Quarantined => ApiError::new(status, "quarantined", "The database is quarantined because its store could not be opened. It must be repaired before it can be used again"),
+            DatabaseError::QuotaExceeded => ApiError::new(status, "quota_exceeded", "The write would take the database beyond its quota"),
+            DatabaseError::OutOfSpace => ApiError::new(status, "out_of_space", "The disk holding the database is full"),
+            DatabaseError::PreconditionFailed => ApiError::new(status, "precondition_failed", "The object doesn't match the If-Match or If-None-Match header, so it was not written"),
+            DatabaseError::OutOfScope => ApiError::new(status, "out_of_scope", "The token doesn't allow this"),
+            DatabaseError::StoreClosed => ApiError::new(status, "store_closed", "The database was closed for maintenance while the request waited for it. Nothing was written, so the request may be retried"),
+        }
+    }
+}
+
+impl ResponseError for DatabaseError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DatabaseError::MissingHeader => StatusCode::BAD_REQUEST,
+            DatabaseError::NotFound => StatusCode::NOT_FOUND,
+            DatabaseError::Quarantined => StatusCode::SERVICE_UNAVAILABLE,
+            DatabaseError::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            DatabaseError::OutOfSpace => StatusCode::INSUFFICIENT_STORAGE,
+            DatabaseError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            DatabaseError::OutOfScope => StatusCode::FORBIDDEN,
+            DatabaseError::StoreClosed => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiError::from(self).error_response()
+    }
+}
+
+/// Reports a failed write, telling a database which has run out of space apart from other failures.
+fn write_failed(err: global::Error) -> ApiError {
+    if err.is_quota_exceeded() {
+        return ApiError::from(&DatabaseError::QuotaExceeded);
+    }
+
+    if err.is_out_of_space() {
+        return ApiError::from(&DatabaseError::OutOfSpace);
+    }
+
+    if err.is_precondition_failed() {
+        return ApiError::from(&DatabaseError::PreconditionFailed);
+    }
+
+    if err.is_store_closed() {
+        return ApiError::from(&DatabaseError::StoreClosed);
+    }
+
+    ApiError::internal(err)
+}
+
+/// The conditions a write must meet, from its `If-Match` and `If-None-Match` headers.
+fn precondition(req: &HttpRequest) -> Precondition {
+    Precondition { if_match: req.get_header(), if_none_match: req.get_header() }
+}
+
+fn database_header(req: &HttpRequest) -> Option<&str> {
+    req.headers().get("db").and_then(|db| db.to_str().ok())
+}
+
+/// Counts a read of `key` towards the access statistics of `db`.
+async fn record_read(db: Option<&str>, pool: &DbPool, key: &ObjectKey) {
+    if let Some(db) = db {
+        pool.record_read(db, key).await;
+    }
+}
+
+/// Opens the store of the database named by the `db` header, provided the app has access to it and its token allows `scope`.
+async fn open_database(req: &HttpRequest, app: &ValidatedApp, scope: Scope, index: &DBIndex, pool: &DbPool) -> actix_web::Result<Arc<Mutex<Store>>> {
+    open_app_database(database_header(req), &app.id, scope, index, pool).await
+}
+
+/// Opens the database for a caller who may read, or write if `write` is set, the keys starting with `key`.
+async fn open_for(req: &HttpRequest, caller: &ObjectCaller, key: &str, write: bool, index: &DBIndex, pool: &DbPool) -> actix_web::Result<Arc<Mutex<Store>>> {
+    if !caller.allows(key, write) {
+        return Err(DatabaseError::OutOfScope.into());
+    }
+
+    let scope = if write { Scope::Write } else { Scope::Read };
+    open_app_database(caller.database(req), caller.app(), scope, index, pool).await
+}
+
+/// Delegation tokens can't do more than the app which minted them, so the app's scopes are looked up here rather than taken from the
+/// caller.
+async fn open_app_database(db: Option<&str>, app: &AppID, scope: Scope, index: &DBIndex, pool: &DbPool) -> actix_web::Result<Arc<Mutex<Store>>> {
+    let Some(db) = db else {
+        return Err(DatabaseError::MissingHeader.into());
+    };
+
+    let db = {
+        let index = index.lock().await;
+        let Some(db) = index.databases.iter().find(|i| i.id == db && i.apps.contains(app)).cloned() else {
+            return Err(DatabaseError::NotFound.into());
+        };
+
+        if !index.apps.iter().any(|i| i.id == *app && i.permits(&db.id, scope)) {
+            return Err(DatabaseError::OutOfScope.into());
+        }
+
+        db
+    };
+
+    pool.open(&db).await.map_err(|err| match err.inner() {
+        global::Inner::ManualError(ManualError::StoreQuarantined(_)) => DatabaseError::Quarantined.into(),
+        _ => ApiError::internal(err).into(),
+    })
 }
 
+#[utoipa::path(
+    tag = "queries",
+    params(("db" = String, Header, description = "The database to use"), DBCall),
+    request_body(content = Object, description = "The document to write, for `write` queries", content_type = "application/json"),
+    responses((status = 200, description = "The result of the query"), (status = 400, description = "Unrecognised query or invalid cursor"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
 #[post("/query")]
-pub async fn query(req: HttpRequest, query: web::Query<DBCall>, app: ValidatedApp, input: web::Bytes) -> actix_web::Result<impl Responder> {
-    let Some(Ok(db)) = req.headers().get("db")
-        .map(|v| v.to_str()) else {
-        return Ok(HttpResponse::BadRequest().json(json! {{
-            "success": false,
-            "error": "No db header"
-        }}));   
-    };
-    
-    
-    
+pub async fn query(req: HttpRequest, query: web::Query<DBCall>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let scope = if query.query == "write" { Scope::Write } else { Scope::Read };
+    let store = open_database(&req, &app, scope, &index, &pool).await?;
+    let key = ObjectKey::parse(query.object.as_str())?;
+
+    let budget = QueryBudget::new(config.query.time_budget());
+
+    match query.query.as_str() {
+        "read" => {
+            let Ok(offset) = query.cursor.as_deref().unwrap_or("0").parse::<u64>() else {
+                return Err(invalid_cursor().into());
+            };
+
+            record_read(database_header(&req), &pool, &key).await;
+
+            // Reads are run off the request thread. The budget keeps them from holding the store for too long.
+            let result = telemetry::block(move || read_object(&mut store.blocking_lock(), &key, offset, budget))
+                .await?
+                .map_err(ApiError::internal)?;
+
+            let Some(result) = result else {
+                return Err(no_such_object().into());
+            };
+
+            Ok(ApiResponse::ok(QueryResponse::read(result)))
+        },
+        "write" => {
+            let document = read_document(&req, payload, &config.documents).await?;
+
+            let (object, actor) = (key.to_string(), app.actor());
+            telemetry::block(move || write_object(&mut store.blocking_lock(), &key, JSON_CONTENT_TYPE, &document, None, Some(&actor)))
+                .await?
+                .map_err(write_failed)?;
+
+            Ok(ApiResponse::ok(QueryResponse::Written { object }))
+        },
+        query => Err(unknown_query(query).into()),
+    }
+}
+
+/// Runs a list of operations against one database in order, holding the store for the whole batch so no other request can interleave.
+///
+/// The body is a JSON array of operations, each taking the same fields as `/query` plus `data` for writes. Every operation gets a result
+/// of its own. With `?atomic=true`, the batch is rejected if any operation is invalid, and stops at the first operation which fails. The
+/// writes made before it are then undone, so either every operation in the batch lands or none does.
+#[utoipa::path(
+    tag = "queries",
+    params(("db" = String, Header, description = "The database to use"), BatchOptions),
+    request_body = Vec<BatchOperation>,
+    responses((status = 200, description = "The result of every operation that ran"), (status = 400, description = "An invalid operation, in an atomic batch"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[post("/query/batch")]
+pub async fn batch(req: HttpRequest, options: web::Query<BatchOptions>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Read, &index, &pool).await?;
+
+    // The whole batch is held to the document limits, which also bounds every document inside it.
+    let body = read_document(&req, payload, &config.documents).await?;
+    let operations = match serde_json::from_slice::<Vec<BatchOperation>>(&body) {
+        Ok(operations) => operations,
+        Err(err) => return Err(ApiError::bad_request("invalid_batch", err.to_string()).into()),
+    };
+
+    let operations = operations.into_iter()
+        .map(|op| Operation::parse(op, &config.documents))
+        .collect::<Vec<_>>();
+
+    if operations.iter().any(|op| matches!(op, Ok(Operation::Write { .. }))) && !app.permits(database_header(&req).unwrap_or_default(), Scope::Write) {
+        return Err(DatabaseError::OutOfScope.into());
+    }
+
+    if options.atomic && let Some(index) = operations.iter().position(Result::is_err) {
+        let Some(Err(error)) = operations.into_iter().nth(index) else {
+            unreachable!();
+        };
+
+        return Err(ApiError::bad_request("invalid_operation", "Invalid operation").with("operation", index).with("result", error).into());
+    }
+
+    let (atomic, actor) = (options.atomic, app.actor());
+    let budget = QueryBudget::new(config.query.time_budget());
+
+    let (results, rolled_back) = telemetry::block(move || {
+        let mut store = store.blocking_lock();
+        let savepoint = store.savepoint();
+        let mut results = vec![];
+
+        for op in operations {
+            let result = op.and_then(|op| op.run(&mut store, budget, &actor));
+            let failed = result.is_err();
+
+            results.push(match result {
+                Ok(result) => BatchResult::Done(result),
+                Err(err) => BatchResult::Failed(err),
+            });
+
+            // Nothing else can take the store until the batch is done, so nobody sees the writes which are undone.
+            if atomic && failed {
+                store.rollback(savepoint).and_then(|_| store.flush()).map_err(ApiError::internal)?;
+                return Ok((results, true));
+            }
+        }
+
+        Ok::<_, ApiError>((results, false))
+    }).await??;
+
+    let success = results.iter().all(|result| matches!(result, BatchResult::Done(_)));
+    Ok(ApiResponse::ok(BatchResults { completed: results.len(), results, rolled_back }).succeeded(success))
+}
+
+/// Reads the body of a request storing an object, along with its Content-Type. JSON bodies are held to the document limits, while anything
+/// else is read as-is.
+async fn read_object_body(req: &HttpRequest, payload: web::Payload, config: &ServerConfig) -> actix_web::Result<(String, web::Bytes)> {
+    let content_type = req.mime_type().map_err(|_| DocumentError::InvalidContentType)?;
+    let is_json = content_type.as_ref().is_some_and(|mime| mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON));
+
+    let data = if is_json {
+        read_document(req, payload, &config.documents).await?
+    } else {
+        read_body(req, payload, config.documents.max_size).await?
+    };
+
+    Ok((content_type.map_or(DEFAULT_CONTENT_TYPE.to_owned(), |mime| mime.to_string()), data))
+}
+
+#[derive(Deserialize)]
+pub struct WriteOptions {
+    /// How many seconds the object lives for. Objects written without one never expire, even if an earlier version did.
+    pub ttl: Option<u64>,
+}
+
+/// The object a request wrote, and when it expires.
+#[derive(Serialize)]
+struct Stored {
+    object: String,
+    expires: Option<DateTime<Utc>>,
+}
+
+/// The object a request changed or deleted.
+#[derive(Serialize)]
+struct Changed {
+    object: String,
+}
+
+/// When an object written with a time-to-live of `ttl` seconds expires. Lifetimes too long to represent never expire.
+fn expiry(ttl: Option<u64>) -> Option<DateTime<Utc>> {
+    ttl.and_then(|ttl| chrono::Duration::try_seconds(i64::try_from(ttl).ok()?))
+        .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+}
+
+/// Stores the request body as the object at `key`, along with its Content-Type, and responds with its new ETag. With `?ttl=`, the object
+/// expires after that many seconds.
+///
+/// `If-Match` only writes the object if it still has one of the given tags, so clients can make sure they aren't overwriting a change they
+/// haven't seen, and `If-None-Match: *` only writes it if it doesn't exist yet. Writes whose condition fails are answered with 412.
+#[utoipa::path(
+    tag = "objects",
+    params(("key" = String, Path, description = "The object's key, which may contain `/`"), ("db" = Option<String>, Header, description = "The database to use. Delegation tokens carry their own")),
+    request_body(content = Vec<u8>, description = "The object's contents, stored with the request's Content-Type", content_type = "*/*"),
+    responses((status = 200, description = "The object was stored"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it"), (status = 412, description = "The object doesn't match the If-Match or If-None-Match header"), (status = 413, description = "The write would take the database beyond its quota")),
+    security(("app" = [])),
+)]
+#[put("/objects/{key:.+}")]
+pub async fn put_object(req: HttpRequest, key: web::Path<String>, caller: ObjectCaller, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let key = ObjectKey::parse(key.into_inner())?;
+    let store = open_for(&req, &caller, &key, true, &index, &pool).await?;
+    let options = web::Query::<WriteOptions>::from_query(req.query_string())?;
+    let (content_type, data) = read_object_body(&req, payload, &config).await?;
+    let precondition = precondition(&req);
+    let expires = expiry(options.ttl);
+
+    let (object, actor) = (key.to_string(), caller.actor());
+    let tag = telemetry::block(move || write_object_if(&mut store.blocking_lock(), &key, &content_type, &data, expires, Some(&actor), &precondition))
+        .await?
+        .map_err(write_failed)?;
+
+    Ok(ApiResponse::ok(Stored { object, expires }).customize().insert_header(ETag(tag)))
+}
+
+/// Changes parts of the JSON document at `key` without uploading the whole of it again. The body is either a JSON Patch
+/// (`application/json-patch+json`), whose operations are applied in order and all fail together, or a JSON Merge Patch
+/// (`application/merge-patch+json`). The patch is applied while the store is held, so concurrent patches to different fields never undo
+/// each other. Patches honour `If-Match` and `If-None-Match` as writes do.
+#[utoipa::path(
+    tag = "objects",
+    params(("key" = String, Path, description = "The object's key, which may contain `/`"), ("db" = Option<String>, Header, description = "The database to use. Delegation tokens carry their own")),
+    request_body(content = Object, description = "A JSON Patch or JSON Merge Patch, chosen by Content-Type", content_type = "application/json-patch+json"),
+    responses((status = 200, description = "The patched object"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it"), (status = 412, description = "The object doesn't match the If-Match header")),
+    security(("app" = [])),
+)]
+#[patch("/objects/{key:.+}")]
+pub async fn patch_object(req: HttpRequest, key: web::Path<String>, caller: ObjectCaller, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let key = ObjectKey::parse(key.into_inner())?;
+    let store = open_for(&req, &caller, &key, true, &index, &pool).await?;
+
+    let content_type = req.mime_type().map_err(|_| DocumentError::InvalidContentType)?;
+    let body = read_document(&req, payload, &config.documents).await?;
+    let patch = Patch::parse(content_type.as_ref(), &body)?;
+
+    let object = key.to_string();
+    let limits = config.documents.clone();
+    let precondition = precondition(&req);
+    let actor = caller.actor();
+    let patched = telemetry::block(move || apply_patch(&mut store.blocking_lock(), &key, &patch, &limits, Some(&actor), &precondition))
+        .await?
+        .map_err(|err| match err.inner() {
+            global::Inner::PatchError(err) => ApiError::from(err),
+            global::Inner::DocumentError(err) => ApiError::from(err),
+            _ => write_failed(err),
+        })?;
+
+    let Some(tag) = patched else {
+        return Err(no_such_object().into());
+    };
+
+    Ok(ApiResponse::ok(Changed { object }).customize().insert_header(ETag(tag)))
+}
+
+/// Deletes the object at `key` by moving it into the database's trash, where its owners can restore it from until
+/// `stores.trash_retention` has passed. See [`crate::trash`].
+#[utoipa::path(
+    tag = "objects",
+    params(("key" = String, Path, description = "The object's key, which may contain `/`"), ("db" = Option<String>, Header, description = "The database to use. Delegation tokens carry their own")),
+    responses((status = 200, description = "The object was moved to the trash"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[delete("/objects/{key:.+}")]
+pub async fn delete_object(req: HttpRequest, key: web::Path<String>, caller: ObjectCaller, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let key = ObjectKey::parse(key.into_inner())?;
+    let store = open_for(&req, &caller, &key, true, &index, &pool).await?;
+
+    let (object, actor) = (key.to_string(), caller.actor());
+    let deleted = telemetry::block(move || trash::delete(&mut store.blocking_lock(), &key, Some(&actor)))
+        .await?
+        .map_err(write_failed)?;
+
+    if !deleted {
+        return Err(no_such_object().into());
+    }
+
+    Ok(ApiResponse::ok(Changed { object }))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CreateOptions {
+    /// Placed in front of the generated key, such as `photos/`.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// How many seconds the object lives for, if it should expire.
+    pub ttl: Option<u64>,
+}
+
+/// Stores the request body as a new object under a key made by the configured ID scheme, and responds with the key.
+#[utoipa::path(
+    tag = "objects",
+    params(("db" = String, Header, description = "The database to use"), CreateOptions),
+    request_body(content = Vec<u8>, description = "The object's contents", content_type = "*/*"),
+    responses((status = 201, description = "The object was stored under a new key"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it"), (status = 413, description = "The write would take the database beyond its quota")),
+    security(("app" = [])),
+)]
+#[post("/objects")]
+pub async fn post_object(req: HttpRequest, options: web::Query<CreateOptions>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Write, &index, &pool).await?;
+    let (content_type, data) = read_object_body(&req, payload, &config).await?;
+    let expires = expiry(options.ttl);
+
+    loop {
+        let id = config.id_scheme.generate().await.map_err(ApiError::internal)?;
+
+        let key = ObjectKey::parse(format!("{}{}", options.prefix, id))?;
+        let object = key.to_string();
+
+        let (store, content_type, data, actor) = (store.clone(), content_type.clone(), data.clone(), app.actor());
+        let created = telemetry::block(move || create_object(&mut store.blocking_lock(), &key, &content_type, &data, expires, Some(&actor)))
+            .await?
+            .map_err(write_failed)?;
+
+        if created.is_some() {
+            return Ok(ApiResponse::created(Stored { object, expires }));
+        }
+    }
+}
+
+/// The most ranges a single request may ask for. Requests for more are answered with the whole object instead.
+const MAX_RANGES: usize = 16;
+
+/// Responds with the contents of the object at `key`, using the Content-Type it was stored with, and its ETag.
+///
+/// `?version=` responds with one of the object's superseded versions instead, if its collection keeps history. Ranges of old versions
+/// aren't supported, so the whole version is returned.
+///
+/// A `Range` header limits the response to parts of the object. A single range is returned as is, while several are returned as
+/// `multipart/byteranges`. Range headers which can't be understood are ignored and the whole object is returned.
+#[utoipa::path(
+    tag = "objects",
+    params(("key" = String, Path, description = "The object's key, which may contain `/`"), ("db" = Option<String>, Header, description = "The database to use. Delegation tokens carry their own"), ObjectOptions),
+    responses((status = 200, description = "The object's contents"), (status = 206, description = "The requested ranges of the object"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[get("/objects/{key:.+}")]
+pub async fn get_object(req: HttpRequest, key: web::Path<String>, options: web::Query<ObjectOptions>, caller: ObjectCaller, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let key = ObjectKey::parse(key.into_inner())?;
+    let store = open_for(&req, &caller, &key, false, &index, &pool).await?;
+    record_read(caller.database(&req), &pool, &key).await;
+
+    if let Some(version) = options.version {
+        return get_object_version(store, key, version).await;
+    }
+
+    let ranges = match req.get_header::<Range>() {
+        Some(Range::Bytes(ranges)) if ranges.len() <= MAX_RANGES => ranges,
+        _ => return get_whole_object(store, caller.database(&req).unwrap_or_default().to_owned(), key, &pool).await,
+    };
+
+    let object = telemetry::block(move || read_object_ranges(&mut store.blocking_lock(), &key, &ranges))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    let Some(mut object) = object else {
+        return Err(no_such_object().into());
+    };
+
+    if object.parts.len() == 1 {
+        let (range, data) = object.parts.remove(0);
+
+        return Ok(HttpResponse::PartialContent()
+            .insert_header((CONTENT_TYPE, object.meta.content_type))
+            .insert_header(ETag(object.etag))
+            .insert_header(ContentRange(ContentRangeSpec::Bytes { range: Some(range), instance_length: Some(object.size) }))
+            .body(data));
+    }
+
+    if object.parts.is_empty() {
+        return Ok(HttpResponse::RangeNotSatisfiable()
+            .insert_header(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(object.size) }))
+            .json(ApiError::new(StatusCode::RANGE_NOT_SATISFIABLE, "range_not_satisfiable", "None of the requested ranges are within the object")));
+    }
+
+    let boundary = format!("{:016x}", rand::random::<u64>());
+    let mut body = vec![];
+
+    for (range, data) in object.parts {
+        let spec = ContentRangeSpec::Bytes { range: Some(range), instance_length: Some(object.size) };
+
+        body.extend_from_slice(format!("--{}\r\n{}: {}\r\n{}: {}\r\n\r\n", boundary, CONTENT_TYPE, object.meta.content_type, CONTENT_RANGE, spec).as_bytes());
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n");
+    }
+
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Ok(HttpResponse::PartialContent()
+        .insert_header((CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary)))
+        .insert_header(ETag(object.etag))
+        .body(body))
+}
+
+async fn get_object_version(store: Arc<Mutex<Store>>, key: ObjectKey, version: u64) -> actix_web::Result<HttpResponse> {
+    let object = telemetry::block(move || versions::read_version(&mut store.blocking_lock(), &key, version))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    let Some((meta, data)) = object else {
+        return Err(ApiError::not_found("no_such_version", "No such version").into());
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, meta.content_type))
+        .body(data))
+}
+
+#[derive(Serialize)]
+struct Metadata {
+    content_type: String,
+    stored_size: u64,
+    compressed: bool,
+    versions: usize,
+    reads: u64,
+    last_read: Option<DateTime<Utc>>,
+}
+
+/// Describes the object at `key` without reading it: its content type, how large it is, how it is stored, and how often and how recently it
+/// has been read. Read counts are estimated from a sample of reads, as configured by `stores.usage_sample_rate`.
+#[utoipa::path(
+    tag = "objects",
+    params(("key" = String, Path, description = "The object's key, which may contain `/`"), ("db" = String, Header, description = "The database to use")),
+    responses((status = 200, description = "The object's metadata"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[get("/metadata/{key:.+}")]
+pub async fn get_metadata(req: HttpRequest, key: web::Path<String>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Read, &index, &pool).await?;
+    let key = ObjectKey::parse(key.into_inner())?;
+    let access = match database_header(&req) {
+        Some(db) => pool.usage(db).await.and_then(|usage| usage.get(&key)),
+        None => None,
+    };
+
+    let object = telemetry::block(move || {
+        let mut store = store.blocking_lock();
+        let Some(meta) = KeyDirectory::load(&mut store)?.get(&key).cloned() else {
+            return Ok(None);
+        };
+
+        store.fragment_info(meta.id).map(|info| Some((meta, info))).map_err(global::Error::from)
+    })
+        .await?
+        .map_err(|err: global::Error| ApiError::internal(err))?;
+
+    let Some((meta, info)) = object else {
+        return Err(no_such_object().into());
+    };
+
+    Ok(ApiResponse::ok(Metadata {
+        content_type: meta.content_type,
+        stored_size: info.length,
+        compressed: meta.dictionary.is_some(),
+        versions: meta.history.len(),
+        reads: access.map_or(0, |access| access.reads),
+        last_read: access.map(|access| access.last_read),
+    }))
+}
+
+#[derive(Serialize)]
+struct History {
+    /// The number the current contents will have once they are superseded.
+    current: u64,
+    versions: Vec<Version>,
+}
+
+#[derive(Serialize)]
+struct Version {
+    version: u64,
+    size: u64,
+    delta: bool,
+}
+
+/// Lists the superseded versions of the object at `key` which are still kept, oldest first, along with the number the current contents
+/// would have once they are superseded too.
+#[utoipa::path(
+    tag = "objects",
+    params(("key" = String, Path, description = "The object's key, which may contain `/`"), ("db" = String, Header, description = "The database to use")),
+    responses((status = 200, description = "The object's superseded versions"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[get("/history/{key:.+}")]
+pub async fn get_history(req: HttpRequest, key: web::Path<String>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Read, &index, &pool).await?;
+    let key = ObjectKey::parse(key.into_inner())?;
+
+    let meta = telemetry::block(move || KeyDirectory::load(&mut store.blocking_lock()).map(|directory| directory.get(&key).cloned()))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    let Some(meta) = meta else {
+        return Err(no_such_object().into());
+    };
+
+    Ok(ApiResponse::ok(History {
+        current: meta.history.last().map_or(1, |version| version.number + 1),
+        versions: meta.history.iter().map(|version| Version { version: version.number, size: version.size, delta: version.delta }).collect(),
+    }))
+}
+
+/// Serves the whole of the object, through the pool's object cache.
+pub(crate) async fn get_whole_object(store: Arc<Mutex<Store>>, database: DatabaseID, key: ObjectKey, pool: &DbPool) -> actix_web::Result<HttpResponse> {
+    let cache = pool.object_cache();
+    let object = telemetry::block(move || cache.read(&database, &mut store.blocking_lock(), &key))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    let Some(object) = object else {
+        return Err(no_such_object().into());
+    };
+
     Ok(HttpResponse::Ok()
-        .json(json! {{
-            "success": true
-        }}))
-}
\ No newline at end of file
+        .insert_header((CONTENT_TYPE, object.meta.content_type))
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .insert_header(ETag(object.etag))
+        .body(object.data))
+}
+
+#[derive(Serialize)]
+struct Listing {
+    keys: Vec<String>,
+    common_prefixes: Vec<String>,
+    next_cursor: Option<String>,
+}
+
+/// Lists the objects in a database. Given `?prefix=a/b/&delimiter=/`, lists the objects directly inside `a/b/`, along with the folders
+/// below it as `common_prefixes`. Keys and prefixes are returned together a page at a time.
+#[utoipa::path(
+    tag = "objects",
+    params(("db" = Option<String>, Header, description = "The database to use. Delegation tokens carry their own"), ListOptions, PageOptions),
+    responses((status = 200, description = "A page of the objects"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[get("/objects")]
+pub async fn list_objects(req: HttpRequest, options: web::Query<ListOptions>, page: web::Query<PageOptions>, caller: ObjectCaller, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    check_prefix(&options.prefix)?;
+    let store = open_for(&req, &caller, &options.prefix, false, &index, &pool).await?;
+
+    let options = options.into_inner();
+    let listing = telemetry::block(move || KeyDirectory::load(&mut store.blocking_lock())
+            .map(|directory| directory.list(&options.prefix, options.delimiter.as_deref(), options.content_type.as_deref())))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    // Keys and common prefixes are paged through together, in the order they would appear in a flat listing.
+    let entries = listing.keys.into_iter().map(|key| (key, false))
+        .chain(listing.common_prefixes.into_iter().map(|prefix| (prefix, true)));
+
+    let entries = page.paginate(entries, |(entry, _)| entry.clone())?;
+    let (common_prefixes, keys): (Vec<_>, Vec<_>) = entries.items.into_iter().partition(|(_, is_prefix)| *is_prefix);
+
+    Ok(ApiResponse::ok(Listing {
+        keys: keys.into_iter().map(|(key, _)| key).collect(),
+        common_prefixes: common_prefixes.into_iter().map(|(prefix, _)| prefix).collect(),
+        next_cursor: entries.next_cursor,
+    }))
+}
+
+/// Checks that `collection` could name a collection.
+pub(crate) fn check_collection(collection: &str) -> actix_web::Result<()> {
+    ObjectKey::parse(collection)?;
+
+    if collection.contains(DELIMITER) {
+        return Err(ApiError::bad_request("invalid_collection", format!("Collections are the first level of a key, so they can't contain '{}'", DELIMITER)).into());
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Trained {
+    dictionary: dictionary::DictionaryReport,
+}
+
+/// Trains a compression dictionary for the objects under `{collection}/`, which objects written there afterwards are compressed against.
+/// Collections of many small, similar documents compress far better against a dictionary than each document would on its own.
+#[utoipa::path(
+    tag = "collections",
+    params(("collection" = String, Path, description = "The collection, the first level of its objects' keys"), ("db" = String, Header, description = "The database to use")),
+    responses((status = 200, description = "The dictionary was trained"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[post("/collections/{collection}/dictionary")]
+pub async fn train_dictionary(req: HttpRequest, collection: web::Path<String>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Admin, &index, &pool).await?;
+    let collection = collection.into_inner();
+    check_collection(&collection)?;
+
+    let report = telemetry::block(move || dictionary::train(&mut store.blocking_lock(), &collection))
+        .await?
+        .map_err(write_failed)?;
+
+    match report {
+        Some(report) => Ok(ApiResponse::ok(Trained { dictionary: report })),
+        None => Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "not_enough_samples",
+            format!("Training a dictionary takes at least {} similar objects in the collection", dictionary::MIN_SAMPLES)).into()),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct IndexDeclaration {
+    /// A JSON pointer to the field objects are indexed by, such as `/email`.
+    pub field: String,
+}
+
+/// Declares a secondary index named `{name}` over a field of the JSON documents under `{collection}/`, indexing the objects already there.
+/// Declaring an index again rebuilds it. From then on the index is kept up to date by every write to the collection.
+#[utoipa::path(
+    tag = "collections",
+    params(("collection" = String, Path, description = "The collection, the first level of its objects' keys"), ("name" = String, Path, description = "The index's name"), ("db" = String, Header, description = "The database to use")),
+    request_body = IndexDeclaration,
+    responses((status = 200, description = "The index was built"), (status = 400, description = "Invalid collection or field"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[put("/collections/{collection}/indexes/{name}")]
+pub async fn create_index(req: HttpRequest, path: web::Path<(String, String)>, declaration: web::Json<IndexDeclaration>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Admin, &index, &pool).await?;
+    let (collection, name) = path.into_inner();
+    check_collection(&collection)?;
+
+    let field = declaration.into_inner().field;
+    if !secondary::is_field(&field) {
+        return Err(ApiError::bad_request("invalid_field", "Fields are named by JSON pointers, which start with '/'").into());
+    }
+
+    let indexed = telemetry::block(move || secondary::create(&mut store.blocking_lock(), &collection, &name, &field))
+        .await?
+        .map_err(write_failed)?;
+
+    Ok(ApiResponse::ok(Indexed { indexed }))
+}
+
+#[derive(Serialize)]
+struct Indexed {
+    indexed: usize,
+}
+
+/// Removes the secondary index named `{name}` of `{collection}`.
+#[utoipa::path(
+    tag = "collections",
+    params(("collection" = String, Path, description = "The collection, the first level of its objects' keys"), ("name" = String, Path, description = "The index's name"), ("db" = String, Header, description = "The database to use")),
+    responses((status = 200, description = "The index was removed"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such index or database")),
+    security(("app" = [])),
+)]
+#[delete("/collections/{collection}/indexes/{name}")]
+pub async fn delete_index(req: HttpRequest, path: web::Path<(String, String)>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Admin, &index, &pool).await?;
+    let (collection, name) = path.into_inner();
+
+    let removed = telemetry::block(move || secondary::remove(&mut store.blocking_lock(), &collection, &name))
+        .await?
+        .map_err(write_failed)?;
+
+    match removed {
+        true => Ok(ApiResponse::ok(Done {})),
+        false => Err(no_such_index().into()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct IndexQuery {
+    /// Finds the objects whose field holds exactly this value.
+    pub value: Option<String>,
+
+    /// The smallest value to find, inclusive.
+    pub min: Option<String>,
+
+    /// The largest value to find, inclusive.
+    pub max: Option<String>,
+}
+
+/// Finds the objects of `{collection}` by their value in the index `{name}`, either an exact `?value=` or a range between `?min=` and
+/// `?max=`, either of which may be left open. Values are read as JSON where they can be, so `?value=42` finds the number and `?value="42"`
+/// the string. Matching objects are returned a page at a time in order of key, along with their values.
+#[utoipa::path(
+    tag = "collections",
+    params(("collection" = String, Path, description = "The collection, the first level of its objects' keys"), ("name" = String, Path, description = "The index's name"), ("db" = String, Header, description = "The database to use"), IndexQuery, PageOptions),
+    responses((status = 200, description = "A page of the matching objects"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such index or database")),
+    security(("app" = [])),
+)]
+#[get("/collections/{collection}/indexes/{name}")]
+pub async fn query_index(req: HttpRequest, path: web::Path<(String, String)>, lookup: web::Query<IndexQuery>, page: web::Query<PageOptions>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Read, &index, &pool).await?;
+    let (collection, name) = path.into_inner();
+
+    let lookup = lookup.into_inner();
+    let bound = |value: Option<String>| value.map_or(Bound::Unbounded, |value| Bound::Included(IndexValue::parse(&value)));
+    let (from, to) = match lookup.value {
+        Some(_) if lookup.min.is_some() || lookup.max.is_some() => return Err(ApiError::bad_request("invalid_lookup", "Either look up a value, or a range between min and max, but not both").into()),
+        Some(value) => (bound(Some(value.clone())), bound(Some(value))),
+        None => (bound(lookup.min), bound(lookup.max)),
+    };
+
+    let found = telemetry::block(move || secondary::lookup(&mut store.blocking_lock(), &collection, &name, from, to))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    let Some(found) = found else {
+        return Err(no_such_index().into());
+    };
+
+    let found = page.paginate(found, |entry| entry.key.clone())?;
+
+    Ok(ApiResponse::ok(IndexResults { results: found.items, next_cursor: found.next_cursor }))
+}
+
+fn no_such_index() -> ApiError {
+    ApiError::not_found("no_such_index", "No such index")
+}
+
+#[derive(Serialize)]
+struct IndexResults {
+    results: Vec<secondary::IndexEntry>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Versioning {
+    versioning: VersionPolicy,
+}
+
+/// Sets how `{collection}` keeps the history of its objects, given as `{"keep": 32, "snapshot_every": 16}`. Each time an object is
+/// written, its previous contents are kept as a delta against the new ones, or whole every `snapshot_every` versions, and the oldest
+/// versions beyond `keep` are deleted. Keeping no versions stops recording history.
+#[utoipa::path(
+    tag = "collections",
+    params(("collection" = String, Path, description = "The collection, the first level of its objects' keys"), ("db" = String, Header, description = "The database to use")),
+    request_body = VersionPolicy,
+    responses((status = 200, description = "The policy was set"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or the app isn't attached to it")),
+    security(("app" = [])),
+)]
+#[put("/collections/{collection}/versioning")]
+pub async fn set_versioning(req: HttpRequest, collection: web::Path<String>, policy: web::Json<VersionPolicy>, app: ValidatedApp, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let store = open_database(&req, &app, Scope::Admin, &index, &pool).await?;
+    let collection = collection.into_inner();
+    check_collection(&collection)?;
+
+    let policy = policy.into_inner();
+    telemetry::block(move || versions::set_policy(&mut store.blocking_lock(), &collection, policy))
+        .await?
+        .map_err(write_failed)?;
+
+    Ok(ApiResponse::ok(Versioning { versioning: policy }))
+}
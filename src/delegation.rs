@@ -0,0 +1,245 @@
+use actix_web::{post, web, FromRequest, HttpRequest, Responder};
+use actix_web::dev::Payload;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::app::{Scope, ValidatedApp};
+use crate::config::ServerConfig;
+use crate::error::{AppError, DatabaseError};
+use crate::keys::check_prefix;
+use crate::response::{ApiError, ApiResponse};
+use crate::telemetry;
+use crate::{AppID, DBIndex, DatabaseID};
+
+/// Delegation tokens start with this, which tells them apart from app tokens without having to look either up.
+pub const DELEGATION_PREFIX: &str = "dt.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DelegatedAccess {
+    Read,
+    ReadWrite,
+}
+
+/// What an app lets one of its users do, carried in a delegation token.
+///
+/// The claims are signed with the server's delegation key, so a token can be checked by its signature alone, without a lookup of its own.
+/// Whatever the token claims, the app must still be attached to the database, so detaching the app stops its tokens working too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delegation {
+    pub app: AppID,
+    pub db: DatabaseID,
+
+    /// Only objects whose keys start with this may be touched.
+    pub prefix: String,
+    pub access: DelegatedAccess,
+    pub expires: DateTime<Utc>,
+
+    /// Whoever the app minted the token for, in the app's own terms. The server doesn't read it, but it's signed with the rest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+impl Delegation {
+    /// Encodes the claims as a token, `dt.{claims}.{signature}`, with both parts in unpadded URL-safe base64.
+    pub fn sign(&self, key: &str) -> serde_json::Result<String> {
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(self)?);
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.as_bytes());
+        let tag = ring::hmac::sign(&key, claims.as_bytes());
+
+        Ok(format!("{}{}.{}", DELEGATION_PREFIX, claims, URL_SAFE_NO_PAD.encode(tag.as_ref())))
+    }
+
+    /// Reads the claims of a token made by [`Delegation::sign`] with the same key, if its signature holds and it hasn't expired by `now`.
+    pub fn verify(token: &str, key: &str, now: DateTime<Utc>) -> Result<Self, AppError> {
+        let (claims, tag) = token.strip_prefix(DELEGATION_PREFIX)
+            .and_then(|token| token.split_once('.'))
+            .ok_or(AppError::InvalidToken)?;
+
+        let tag = URL_SAFE_NO_PAD.decode(tag).map_err(|_| AppError::InvalidToken)?;
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.as_bytes());
+        ring::hmac::verify(&key, claims.as_bytes(), &tag).map_err(|_| AppError::InvalidToken)?;
+
+        let delegation = URL_SAFE_NO_PAD.decode(claims).ok()
+            .and_then(|claims| serde_json::from_slice::<Self>(&claims).ok())
+            .ok_or(AppError::InvalidToken)?;
+
+        match delegation.expires > now {
+            true => Ok(delegation),
+            false => Err(AppError::ExpiredToken),
+        }
+    }
+
+    /// Whether the token lets its holder read, or write if `write` is set, keys starting with `key`.
+    pub fn allows(&self, key: &str, write: bool) -> bool {
+        key.starts_with(&self.prefix) && (!write || self.access == DelegatedAccess::ReadWrite)
+    }
+}
+
+/// Whoever is calling one of the routes which accept delegation tokens: an app using its own token, or one of its users holding a
+/// delegation token it minted.
+pub enum ObjectCaller {
+    App(ValidatedApp),
+    Delegated(Delegation),
+}
+
+impl ObjectCaller {
+    pub fn app(&self) -> &AppID {
+        match self {
+            Self::App(app) => &app.id,
+            Self::Delegated(delegation) => &delegation.app,
+        }
+    }
+
+    /// How the caller is named in the changelog. Holders of delegation tokens are named by the app and the token's subject, if it has one.
+    pub fn actor(&self) -> String {
+        match self {
+            Self::App(app) => app.actor(),
+            Self::Delegated(Delegation { app, subject: Some(subject), .. }) => format!("app:{}/{}", app, subject),
+            Self::Delegated(delegation) => format!("app:{}", delegation.app),
+        }
+    }
+
+    /// The database being called on. Apps name it in the `db` header, while delegation tokens carry it.
+    pub fn database<'a>(&'a self, req: &'a HttpRequest) -> Option<&'a str> {
+        match self {
+            Self::App(_) => req.headers().get("db").and_then(|db| db.to_str().ok()),
+            Self::Delegated(delegation) => Some(&delegation.db),
+        }
+    }
+
+    /// Whether the caller may read, or write if `write` is set, keys starting with `key`. Apps may touch every object.
+    pub fn allows(&self, key: &str, write: bool) -> bool {
+        match self {
+            Self::App(_) => true,
+            Self::Delegated(delegation) => delegation.allows(key, write),
+        }
+    }
+}
+
+impl FromRequest for ObjectCaller {
+    type Error = AppError;
+    type Future = BoxFuture<'static, actix_web::Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let token = req.headers().get("Authorization")
+            .and_then(|token| token.to_str().ok())
+            .and_then(|token| token.strip_prefix("Bearer "))
+            .filter(|token| token.starts_with(DELEGATION_PREFIX));
+
+        let Some(token) = token else {
+            return ValidatedApp::from_request(req, payload).map(|app| app.map(Self::App)).boxed();
+        };
+
+        let key = req.app_data::<web::Data<ServerConfig>>().and_then(|config| config.tokens.delegation_key.clone());
+        let delegation = match key {
+            Some(key) => Delegation::verify(token, &key, Utc::now()).inspect(|delegation| telemetry::record_app(&delegation.app)).map(Self::Delegated),
+            None => Err(AppError::InvalidToken),
+        };
+
+        futures::future::ready(delegation).boxed()
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DelegationRequest {
+    #[serde(default)]
+    pub prefix: String,
+    pub access: DelegatedAccess,
+
+    /// How long the token remains valid for, in seconds. Defaults to, and is capped at, `tokens.delegation_max_lifetime`.
+    pub ttl: Option<u64>,
+    pub subject: Option<String>,
+}
+
+/// A new delegation token, which is only ever shown here.
+#[derive(Serialize)]
+struct Minted {
+    token: String,
+    expires: DateTime<Utc>,
+}
+
+/// Mints a token letting one of the app's users read, or also write, the objects under a prefix of the database named by the `db` header.
+/// Apps hand these out so browsers can talk to the server directly instead of through the app. Tokens can't be revoked one by one, which
+/// is why they are short-lived. The app's token must have the `read` scope, and `write` too for read-write tokens. Disabled unless
+/// `tokens.delegation_key` is set.
+#[utoipa::path(
+    tag = "delegation",
+    params(("db" = String, Header, description = "The database to use")),
+    request_body = DelegationRequest,
+    responses((status = 201, description = "The delegation token"), (status = 403, description = "The token doesn't allow this"), (status = 404, description = "No such database, or delegation tokens are not enabled")),
+    security(("app" = [])),
+)]
+#[post("/delegations")]
+pub async fn create_delegation(req: HttpRequest, request: web::Json<DelegationRequest>, app: ValidatedApp, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let Some(key) = config.tokens.delegation_key.as_deref() else {
+        return Err(ApiError::not_found("delegation_disabled", "Delegation tokens are not enabled on this server").into());
+    };
+
+    let request = request.into_inner();
+    check_prefix(&request.prefix)?;
+
+    let db = req.headers().get("db").and_then(|db| db.to_str().ok()).unwrap_or_default();
+    let attached = index.lock().await.databases.iter().any(|i| i.id == db && i.apps.contains(&app.id));
+
+    if !attached {
+        return Err(ApiError::no_such_database().into());
+    }
+
+    // Tokens are held to the app's scopes when they are used as well, but an app can't mint one it couldn't use itself.
+    if !app.permits(db, Scope::Read) || request.access == DelegatedAccess::ReadWrite && !app.permits(db, Scope::Write) {
+        return Err(DatabaseError::OutOfScope.into());
+    }
+
+    let max_lifetime = config.tokens.delegation_max_lifetime;
+    let lifetime = request.ttl.unwrap_or(max_lifetime).clamp(1, max_lifetime);
+
+    let delegation = Delegation {
+        app: app.id.clone(),
+        db: db.to_owned(),
+        prefix: request.prefix,
+        access: request.access,
+        expires: Utc::now() + chrono::Duration::seconds(lifetime as i64),
+        subject: request.subject,
+    };
+
+    let token = delegation.sign(key).map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::created(Minted { token, expires: delegation.expires }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_tokens_hold_their_claims_only_under_the_same_key() {
+        let now = Utc::now();
+        let delegation = Delegation {
+            app: "app".to_owned(),
+            db: "db".to_owned(),
+            prefix: "users/ada/".to_owned(),
+            access: DelegatedAccess::Read,
+            expires: now + chrono::Duration::minutes(5),
+            subject: Some("ada".to_owned()),
+        };
+
+        let token = delegation.sign("secret").unwrap();
+        assert_eq!(Delegation::verify(&token, "secret", now).unwrap(), delegation);
+        assert!(matches!(Delegation::verify(&token, "other", now), Err(AppError::InvalidToken)));
+        assert!(matches!(Delegation::verify(&token, "secret", now + chrono::Duration::minutes(5)), Err(AppError::ExpiredToken)));
+
+        // Claims can't be changed without the signature failing.
+        let (claims, tag) = token.strip_prefix(DELEGATION_PREFIX).unwrap().split_once('.').unwrap();
+        let mut forged = serde_json::from_slice::<Delegation>(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        forged.access = DelegatedAccess::ReadWrite;
+        let forged = format!("{}{}.{}", DELEGATION_PREFIX, URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap()), tag);
+        assert!(matches!(Delegation::verify(&forged, "secret", now), Err(AppError::InvalidToken)));
+
+        assert!(delegation.allows("users/ada/notes", false) && !delegation.allows("users/ada/notes", true) && !delegation.allows("users/bob", false));
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use crate::changelog::{self, Change};
+use crate::error::*;
+use crate::keys::KeyDirectory;
+use crate::pool::{DbPool, Store};
+use crate::webhooks::{self, Webhook};
+use crate::{DBIndex, DatabaseID};
+
+/// The name of the file each database keeps beside its store, recording the last change delivered to each of its webhooks.
+pub const CURSOR_FILE: &str = "webhooks.json";
+
+/// How often the changelogs of open stores are checked for changes to deliver.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most changes delivered to a webhook in one pass.
+const BATCH: usize = 100;
+
+/// The longest a failing webhook is left before it is tried again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How delivering a database's object changes to one of its webhooks is going.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeliveryStatus {
+    /// The sequence of the last change the webhook accepted.
+    pub delivered: u64,
+
+    /// How many changes are waiting to be delivered.
+    pub pending: u64,
+
+    /// How many times in a row delivery has failed.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    pub last_success: Option<DateTime<Utc>>,
+
+    /// When delivery will be tried again, if it is backing off after failing.
+    pub next_attempt: Option<DateTime<Utc>>,
+}
+
+/// Held while any database's cursors are read and written back, so registering a webhook doesn't race a pass which would write over it.
+static CURSORS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+static STATUS: LazyLock<Mutex<HashMap<(DatabaseID, String), DeliveryStatus>>> = LazyLock::new(Default::default);
+
+/// How delivery to the webhook is going, as of the last pass over its database.
+pub fn status(database: &DatabaseID, webhook: &str) -> DeliveryStatus {
+    STATUS.lock().unwrap().get(&(database.clone(), webhook.to_owned())).cloned().unwrap_or_default()
+}
+
+/// How long to wait after the `failures`th failure in a row: a second, doubling with each failure up to [`MAX_BACKOFF`].
+fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.saturating_sub(1).min(16)).min(MAX_BACKOFF)
+}
+
+/// A change to an object, as it is sent to webhooks.
+#[derive(Serialize)]
+struct ObjectEvent<'a> {
+    event: &'static str,
+    database: &'a DatabaseID,
+
+    #[serde(flatten)]
+    change: &'a Change,
+}
+
+fn read_cursors(root: &Path) -> BTreeMap<String, u64> {
+    std::fs::read(root.join(CURSOR_FILE)).ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_cursors(root: &Path, cursors: &BTreeMap<String, u64>) -> Result<()> {
+    let path = root.join(CURSOR_FILE);
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, serde_json::to_vec(cursors)?)?;
+
+    Ok(std::fs::rename(&partial, &path)?)
+}
+
+/// Starts a new webhook off after the newest change to the database, so it is told about every change from then on but none from before.
+pub async fn begin(pool: &DbPool, db: &crate::Database, webhook: &str) -> Result<()> {
+    let store = pool.open(db).await?;
+    let _cursors = CURSORS.lock().await;
+    let mut cursors = read_cursors(&db.root);
+    cursors.insert(webhook.to_owned(), last_change(&store).await?);
+
+    write_cursors(&db.root, &cursors)
+}
+
+async fn read_changes(store: &Arc<tokio::sync::Mutex<Store>>, since: u64) -> Result<changelog::Changes> {
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || changelog::read(&mut store.blocking_lock(), since, BATCH))
+        .await.map_err(|_| ManualError::StoreOpenFailed)?
+}
+
+/// The sequence of the newest change to the store.
+async fn last_change(store: &Arc<tokio::sync::Mutex<Store>>) -> Result<u64> {
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || Ok(KeyDirectory::load(&mut store.blocking_lock())?.changelog().last()))
+        .await.map_err(|_| ManualError::StoreOpenFailed)?
+}
+
+/// Delivers the changes made to a database's objects since the last pass to each of its webhooks which asked for them, in order. A webhook
+/// which fails is tried again from the same change once it has backed off, so every change reaches it at least once, in order.
+async fn deliver_database(client: &reqwest::Client, pool: &DbPool, db: &crate::Database, hooks: Vec<Webhook>) -> Result<()> {
+    // Changes are only made to open stores, and those made before the server last stopped are delivered once the store is opened again.
+    let Some(store) = pool.get_open(&db.id).await else {
+        return Ok(());
+    };
+
+    let _cursors = CURSORS.lock().await;
+    let mut cursors = read_cursors(&db.root);
+    let before = cursors.clone();
+    cursors.retain(|id, _| hooks.iter().any(|hook| hook.id == *id));
+
+    for hook in hooks {
+        let key = (db.id.clone(), hook.id.clone());
+        if STATUS.lock().unwrap().get(&key).and_then(|status| status.next_attempt).is_some_and(|next| next > Utc::now()) {
+            continue;
+        }
+
+        // Webhooks registered before they could be told about objects have no place yet, so they start from the newest change.
+        let since = match cursors.get(&hook.id) {
+            Some(since) => *since,
+            None => last_change(&store).await?,
+        };
+
+        let batch = read_changes(&store, since).await?;
+        if batch.truncated {
+            log::warn!("Changes to database {} were deleted before they could be delivered to webhook {}", db.id, hook.id);
+        }
+
+        let mut delivered = since;
+        let mut failure = None;
+
+        for change in batch.changes.iter() {
+            match webhooks::post(client, &hook, &ObjectEvent { event: "object", database: &db.id, change }).await {
+                Ok(()) => delivered = change.sequence,
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                },
+            }
+        }
+
+        let last = last_change(&store).await?;
+        cursors.insert(hook.id.clone(), delivered);
+
+        let mut statuses = STATUS.lock().unwrap();
+        let status = statuses.entry(key).or_default();
+        status.delivered = delivered;
+        status.pending = last.saturating_sub(delivered);
+
+        if delivered > since {
+            status.last_success = Some(Utc::now());
+        }
+
+        match failure {
+            Some(err) => {
+                status.failures += 1;
+                status.last_error = Some(match err.inner() {
+                    global::Inner::ReqwestError(err) => err.to_string(),
+                    inner => inner.to_string(),
+                });
+                status.next_attempt = Some(Utc::now() + backoff(status.failures));
+                log::warn!("Failed to deliver changes to database {} to {}: {:?}", db.id, hook.url, err);
+            },
+            None => {
+                status.failures = 0;
+                status.next_attempt = None;
+            },
+        }
+    }
+
+    if cursors != before {
+        write_cursors(&db.root, &cursors)?;
+    }
+
+    Ok(())
+}
+
+/// Starts delivering changes to objects to the webhooks which asked for them. Each database's changelog is the queue: a webhook's place in
+/// it is kept in [`CURSOR_FILE`], so nothing is lost if the server stops, and a change is only ever delivered after it has been saved.
+pub fn start(index: DBIndex, pool: DbPool) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(webhooks::TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let watched = index.lock().await.databases.iter()
+                .filter(|db| db.webhooks.iter().any(|hook| hook.objects))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for db in watched {
+                let hooks = db.webhooks.iter().filter(|hook| hook.objects).cloned().collect();
+                if let Err(err) = deliver_database(&client, &pool, &db, hooks).await {
+                    log::warn!("Failed to deliver changes to database {} to its webhooks: {:?}", db.id, err);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_failing_webhooks_back_off_exponentially_up_to_an_hour() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(12), Duration::from_secs(2048));
+        assert_eq!(backoff(13), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
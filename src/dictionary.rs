@@ -0,0 +1,130 @@
+use std::borrow::Cow;
+use std::io::Read;
+use libdb::AllocOptions;
+use libdb::FragmentID;
+use serde::Serialize;
+use crate::error::*;
+use crate::keys::{collection, KeyDirectory, ObjectKey, ObjectMeta, DELIMITER};
+use crate::pool::Store;
+
+/// The largest dictionary trained for a collection. Dictionaries much larger than the documents they compress gain little more.
+pub const MAX_DICTIONARY_SIZE: usize = 16 * 1024;
+
+/// The most objects a dictionary is trained from.
+pub const MAX_SAMPLES: usize = 1000;
+
+/// The fewest objects a dictionary can be trained from.
+pub const MIN_SAMPLES: usize = 8;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// What training a collection's dictionary produced.
+#[derive(Debug, Serialize)]
+pub struct DictionaryReport {
+    pub collection: String,
+    pub fragment: FragmentID,
+    pub samples: usize,
+    pub size: usize,
+}
+
+/// Trains a dictionary from samples of similar documents, or returns `None` if there are too few of them to learn anything from.
+pub fn train_from_samples(samples: &[Vec<u8>]) -> Option<Vec<u8>> {
+    if samples.len() < MIN_SAMPLES {
+        return None;
+    }
+
+    match zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE) {
+        Ok(dictionary) => Some(dictionary),
+        Err(err) => {
+            log::debug!("Couldn't train a dictionary from {} samples: {}", samples.len(), err);
+            None
+        },
+    }
+}
+
+pub fn compress(dictionary: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::Compressor::with_dictionary(COMPRESSION_LEVEL, dictionary)?.compress(data)?)
+}
+
+pub fn decompress(dictionary: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    zstd::stream::Decoder::with_dictionary(data, dictionary)?.read_to_end(&mut contents)?;
+
+    Ok(contents)
+}
+
+fn read_fragment(store: &mut Store, id: FragmentID) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    store.open_fragment(id)?.read_into(&mut data)?;
+
+    Ok(data)
+}
+
+/// Reads the contents of an object, decompressing them if they were compressed against a dictionary.
+pub fn read_contents(store: &mut Store, meta: &ObjectMeta) -> Result<Vec<u8>> {
+    let data = read_fragment(store, meta.id)?;
+
+    match meta.dictionary {
+        Some(dictionary) => decompress(&read_fragment(store, dictionary)?, &data),
+        None => Ok(data),
+    }
+}
+
+/// Compresses `data` against the dictionary of the collection `key` belongs to. The contents are left as they are if the collection has no
+/// dictionary, or if compressing them wouldn't make them any smaller. Returns the dictionary used, if any.
+pub fn encode<'a>(store: &mut Store, directory: &KeyDirectory, key: &ObjectKey, data: &'a [u8]) -> Result<(Cow<'a, [u8]>, Option<FragmentID>)> {
+    let Some(dictionary) = collection(key).and_then(|collection| directory.collection_dictionary(collection)) else {
+        return Ok((Cow::Borrowed(data), None));
+    };
+
+    let compressed = compress(&read_fragment(store, dictionary)?, data)?;
+
+    Ok(match compressed.len() < data.len() {
+        true => (Cow::Owned(compressed), Some(dictionary)),
+        false => (Cow::Borrowed(data), None),
+    })
+}
+
+/// Trains a new dictionary for `collection` from up to [`MAX_SAMPLES`] of its objects, which objects written to the collection from then on
+/// are compressed against. Objects written before keep their contents as they are until they are next written. Returns `None` if the
+/// collection doesn't have enough objects to train from.
+pub fn train(store: &mut Store, collection: &str) -> Result<Option<DictionaryReport>> {
+    let mut directory = KeyDirectory::load(store)?;
+    let prefix = format!("{}{}", collection, DELIMITER);
+
+    let objects = directory.objects(&prefix).take(MAX_SAMPLES).map(|(_, meta)| meta.clone()).collect::<Vec<_>>();
+    let samples = objects.iter().map(|meta| read_contents(store, meta)).collect::<Result<Vec<_>>>()?;
+
+    let Some(dictionary) = train_from_samples(&samples) else {
+        return Ok(None);
+    };
+
+    let fragment = directory.new_collection_dictionary(collection);
+    store.write_fragment(AllocOptions::default().fragment(fragment), &dictionary)?;
+    directory.save(store)?;
+    store.flush()?;
+
+    Ok(Some(DictionaryReport { collection: collection.to_owned(), fragment, samples: samples.len(), size: dictionary.len() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_dictionaries_shrink_small_similar_documents() {
+        let document = |i: usize| format!(r#"{{"name":"User {i}","email":"user{i}@example.com","role":"member","active":true,"joined":"2024-01-{:02}"}}"#, i % 28 + 1)
+            .into_bytes();
+        let samples = (0..200).map(document).collect::<Vec<_>>();
+
+        assert!(train_from_samples(&samples[..MIN_SAMPLES - 1]).is_none());
+        let dictionary = train_from_samples(&samples).unwrap();
+
+        let unseen = document(1234);
+        let plain = zstd::bulk::compress(&unseen, COMPRESSION_LEVEL).unwrap();
+        let compressed = compress(&dictionary, &unseen).unwrap();
+
+        assert!(compressed.len() * 2 < plain.len());
+        assert_eq!(decompress(&dictionary, &compressed).unwrap(), unseen);
+    }
+}
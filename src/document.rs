@@ -0,0 +1,168 @@
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use futures::StreamExt;
+use serde::de::IgnoredAny;
+use crate::config::DocumentConfig;
+use crate::error::DocumentError;
+use crate::format::Format;
+use crate::response::ApiError;
+
+impl ResponseError for DocumentError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DocumentError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            DocumentError::TooDeep { .. } | DocumentError::Invalid(..) => StatusCode::UNPROCESSABLE_ENTITY,
+            DocumentError::Interrupted | DocumentError::InvalidContentType => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiError::from(self).error_response()
+    }
+}
+
+impl From<&DocumentError> for ApiError {
+    fn from(err: &DocumentError) -> Self {
+        let status = err.status_code();
+        match err {
+            DocumentError::TooLarge { limit } => ApiError::new(status, "document_too_large", format!("Documents may be at most {} bytes", limit))
+                .with("limit", limit),
+            DocumentError::TooDeep { limit } => ApiError::new(status, "document_too_deep", format!("Documents may be nested at most {} levels deep", limit))
+                .with("limit", limit),
+            DocumentError::Invalid(err) => ApiError::new(status, "invalid_document", err),
+            DocumentError::Interrupted => ApiError::new(status, "upload_interrupted", "The request body could not be read"),
+            DocumentError::InvalidContentType => ApiError::new(status, "invalid_content_type", "The Content-Type header is not a valid MIME type"),
+        }
+    }
+}
+
+/// The content type of objects written as JSON documents.
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Reads a JSON document from the request body without ever holding more than `max_size` bytes of it.
+///
+/// The document is checked for size and nesting depth before it is parsed, and parsing only validates it rather than building a value tree, so
+/// neither a large nor a deeply nested body can exhaust memory. The raw bytes are returned for storage. MessagePack and CBOR bodies are
+/// decoded into JSON first, which does build a value tree, though of no more than `max_size` bytes.
+pub async fn read_document(req: &HttpRequest, payload: web::Payload, limits: &DocumentConfig) -> Result<web::Bytes, DocumentError> {
+    let body = match Format::of_body(req) {
+        Format::Json => read_body(req, payload, limits.max_size).await?,
+        format => transcode(format, &read_body(req, payload, limits.max_size).await?)?,
+    };
+    check_document(&body, limits)?;
+
+    Ok(body)
+}
+
+/// Turns a MessagePack or CBOR document into the JSON it is stored as.
+fn transcode(format: Format, document: &[u8]) -> Result<web::Bytes, DocumentError> {
+    let document = format.decode::<serde_json::Value>(document).map_err(DocumentError::Invalid)?;
+    serde_json::to_vec(&document).map(web::Bytes::from).map_err(|err| DocumentError::Invalid(err.to_string()))
+}
+
+/// Checks that `document` is valid JSON within the document limits, without building a value tree.
+pub fn check_document(document: &[u8], limits: &DocumentConfig) -> Result<(), DocumentError> {
+    if document.len() > limits.max_size {
+        return Err(DocumentError::TooLarge { limit: limits.max_size });
+    }
+
+    check_depth(document, limits.max_depth)?;
+
+    serde_json::from_slice::<IgnoredAny>(document)
+        .map_err(|err| DocumentError::Invalid(err.to_string()))?;
+
+    Ok(())
+}
+
+/// Reads the request body, giving up as soon as it exceeds `limit` bytes.
+pub async fn read_body(req: &HttpRequest, mut payload: web::Payload, limit: usize) -> Result<web::Bytes, DocumentError> {
+    let declared = req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+
+    // Reject oversized bodies before reading any of them if the client told us how large they are.
+    if declared.is_some_and(|len| len > limit) {
+        return Err(DocumentError::TooLarge { limit });
+    }
+
+    let mut body = web::BytesMut::with_capacity(declared.unwrap_or_default());
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| DocumentError::Interrupted)?;
+
+        if body.len() + chunk.len() > limit {
+            return Err(DocumentError::TooLarge { limit });
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    // A body which ends before the length the client declared was cut off, and mustn't be stored as if it were complete.
+    if declared.is_some_and(|len| len != body.len()) {
+        return Err(DocumentError::Interrupted);
+    }
+
+    Ok(body.freeze())
+}
+
+/// Scans the document for the deepest level of nested objects and arrays without parsing it.
+fn check_depth(document: &[u8], limit: usize) -> Result<(), DocumentError> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for byte in document {
+        match (in_string, escaped, byte) {
+            (true, true, _) => escaped = false,
+            (true, false, b'\\') => escaped = true,
+            (true, false, b'"') => in_string = false,
+            (true, false, _) => (),
+            (false, _, b'"') => in_string = true,
+            (false, _, b'{' | b'[') => {
+                depth += 1;
+
+                if depth > limit {
+                    return Err(DocumentError::TooDeep { limit });
+                }
+            },
+            (false, _, b'}' | b']') => depth = depth.saturating_sub(1),
+            (false, _, _) => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn too_deep(document: &str, limit: usize) -> bool {
+        matches!(check_depth(document.as_bytes(), limit), Err(DocumentError::TooDeep { .. }))
+    }
+
+    #[test]
+    pub fn test_depth_is_counted_outside_strings_only() {
+        // Exactly at the limit passes, and one level more doesn't.
+        assert!(!too_deep(r#"{"a":[{"b":1}]}"#, 3));
+        assert!(too_deep(r#"{"a":[{"b":[1]}]}"#, 3));
+        assert!(!too_deep("[]", 1));
+        assert!(too_deep("[[]]", 1));
+        assert!(!too_deep("1", 0));
+        assert!(too_deep("{}", 0));
+
+        // Brackets inside strings don't nest.
+        assert!(!too_deep(r#"{"a":"[[[{{{"}"#, 1));
+        assert!(!too_deep(r#"["]]]}}}",["x"]]"#, 2));
+
+        // An escaped quote doesn't end the string, so the brackets after it are still inside it.
+        assert!(!too_deep(r#"["a\"[[[{{{"]"#, 1));
+        assert!(too_deep(r#"["a\\",[[1]]]"#, 2));
+
+        // Depth keeps counting after siblings close.
+        assert!(!too_deep(r#"[[1],[2],[3]]"#, 2));
+        assert!(too_deep(r#"[[1],[2],[[3]]]"#, 2));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use actix_web::{get, post, web, HttpRequest, Responder};
+use serde::Serialize;
+use serde_json::Value;
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::db::check_collection;
+use crate::dictionary;
+use crate::document::{read_document, JSON_CONTENT_TYPE};
+use crate::error::*;
+use crate::keys::{KeyDirectory, ObjectKey, DELIMITER};
+use crate::paging::{DEFAULT_LIMIT, MAX_LIMIT};
+use crate::pool::{DbPool, Store};
+use crate::query::create_object;
+use crate::response::{ApiError, ApiResponse};
+use crate::secondary::IndexValue;
+use crate::{DBIndex, Database, DatabaseID};
+use crate::telemetry;
+
+/// How a filter compares a document's field to its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+/// Picks out documents by one of their fields, written in a query string as `field=op:value`, such as `age=gte:18`.
+///
+/// Fields are named by their path through the document, with levels separated by `.`, so `address.city` is the `city` of the `address`.
+/// Values are read as JSON where they can be, as by [`IndexValue::parse`]. Ordering comparisons only match values of the same type, and
+/// documents missing the field, or holding an array or object there, only match `ne`.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pointer: String,
+    comparison: Comparison,
+    value: IndexValue,
+}
+
+impl Filter {
+    pub fn parse(field: &str, filter: &str) -> std::result::Result<Self, String> {
+        let Some((comparison, value)) = filter.split_once(':') else {
+            return Err(format!("Filters on '{}' must look like 'eq:value'", field));
+        };
+
+        let comparison = match comparison {
+            "eq" => Comparison::Eq,
+            "ne" => Comparison::Ne,
+            "gt" => Comparison::Gt,
+            "gte" => Comparison::Gte,
+            "lt" => Comparison::Lt,
+            "lte" => Comparison::Lte,
+            comparison => return Err(format!("'{}' is not a comparison. Use one of eq, ne, gt, gte, lt or lte", comparison)),
+        };
+
+        Ok(Self { pointer: pointer(field), comparison, value: IndexValue::parse(value) })
+    }
+
+    pub fn matches(&self, document: &Value) -> bool {
+        let Some(found) = document.pointer(&self.pointer).and_then(IndexValue::from_json) else {
+            return self.comparison == Comparison::Ne;
+        };
+
+        match self.comparison {
+            Comparison::Eq => found == self.value,
+            Comparison::Ne => found != self.value,
+            Comparison::Gt => found.same_type(&self.value) && found > self.value,
+            Comparison::Gte => found.same_type(&self.value) && found >= self.value,
+            Comparison::Lt => found.same_type(&self.value) && found < self.value,
+            Comparison::Lte => found.same_type(&self.value) && found <= self.value,
+        }
+    }
+
+    /// The values an index would have to be searched between to find every matching document, unless the filter can't use an index.
+    fn bounds(&self) -> Option<(Bound<IndexValue>, Bound<IndexValue>)> {
+        let value = || self.value.clone();
+
+        match self.comparison {
+            Comparison::Eq => Some((Bound::Included(value()), Bound::Included(value()))),
+            Comparison::Ne => None,
+            Comparison::Gt => Some((Bound::Excluded(value()), Bound::Unbounded)),
+            Comparison::Gte => Some((Bound::Included(value()), Bound::Unbounded)),
+            Comparison::Lt => Some((Bound::Unbounded, Bound::Excluded(value()))),
+            Comparison::Lte => Some((Bound::Unbounded, Bound::Included(value()))),
+        }
+    }
+}
+
+/// Turns a field's path, such as `address.city`, into a JSON pointer.
+pub(crate) fn pointer(field: &str) -> String {
+    field.split('.')
+        .map(|level| format!("/{}", level.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// The order documents are returned in: by a field, then by key. Documents missing the field come last either way.
+#[derive(Debug, Clone)]
+pub struct Sort {
+    pointer: String,
+    descending: bool,
+}
+
+impl Sort {
+    /// Reads `field`, or `-field` to sort in descending order.
+    pub fn parse(sort: &str) -> Self {
+        match sort.strip_prefix('-') {
+            Some(field) => Self { pointer: pointer(field), descending: true },
+            None => Self { pointer: pointer(sort), descending: false },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FoundDocument {
+    /// The document's ID within its collection.
+    pub id: String,
+    pub document: Value,
+}
+
+/// Finds up to `limit` documents of `collection` matching every filter.
+///
+/// If a collection has a secondary index over a filtered field (see [`crate::secondary`]), only the documents the index finds are read.
+/// Otherwise every document in the collection is read and filtered on the server.
+pub fn find(store: &mut Store, collection: &str, filters: &[Filter], sort: Option<&Sort>, limit: usize) -> Result<Vec<FoundDocument>> {
+    let directory = KeyDirectory::load(store)?;
+    let prefix = format!("{}{}", collection, DELIMITER);
+
+    let indexed = filters.iter().find_map(|filter| directory.index_on(collection, &filter.pointer)
+        .zip(filter.bounds())
+        .map(|(index, (from, to))| index.range(from, to).map(|entry| entry.key).collect::<BTreeSet<_>>()));
+
+    let candidates = directory.objects(&prefix)
+        .filter(|(key, _)| indexed.as_ref().is_none_or(|indexed| indexed.contains(*key)))
+        .map(|(key, meta)| (key.to_owned(), meta.clone()))
+        .collect::<Vec<_>>();
+
+    let mut found = vec![];
+    for (key, meta) in candidates {
+        // Objects which aren't JSON are left out rather than failing the whole query.
+        let Ok(document) = serde_json::from_slice::<Value>(&dictionary::read_contents(store, &meta)?) else {
+            continue;
+        };
+
+        if filters.iter().all(|filter| filter.matches(&document)) {
+            found.push(FoundDocument { id: key[prefix.len()..].to_owned(), document });
+        }
+
+        // Without sorting, documents are found in order of key, so there's no need to look any further.
+        if sort.is_none() && found.len() == limit {
+            break;
+        }
+    }
+
+    if let Some(sort) = sort {
+        let value = |found: &FoundDocument| found.document.pointer(&sort.pointer).and_then(IndexValue::from_json);
+
+        found.sort_by(|a, b| match (value(a), value(b)) {
+            (Some(x), Some(y)) if sort.descending => y.cmp(&x),
+            (x, y) => x.is_none().cmp(&y.is_none()).then_with(|| x.cmp(&y)),
+        }.then_with(|| a.id.cmp(&b.id)));
+
+        found.truncate(limit);
+    }
+
+    Ok(found)
+}
+
+/// Finds a database the user may read, or write if `write` is set.
+pub(crate) async fn member_database(id: &DatabaseID, user: &AuthenticatedUser, index: &DBIndex, write: bool) -> Option<Database> {
+    index.lock().await.databases.iter()
+        .find(|db| db.id == *id && (db.owner == user.id || db.rw.contains(&user.id) || !write && db.ro.contains(&user.id)))
+        .cloned()
+}
+
+#[derive(Serialize)]
+struct Created {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct Found {
+    documents: Vec<FoundDocument>,
+}
+
+/// Stores the request body as a new document of `{name}`, under an ID made by the configured ID scheme, and responds with the ID. Documents
+/// are ordinary objects keyed `{name}/{id}`, so they can also be read and written through `/objects`. Any member who may write to the
+/// database may add documents.
+#[utoipa::path(
+    tag = "documents",
+    params(("id" = String, Path, description = "The database's ID"), ("name" = String, Path, description = "The collection")),
+    request_body(content = Object, description = "The document", content_type = "application/json"),
+    responses((status = 201, description = "The document was stored under a new key"), (status = 400, description = "The document doesn't match the collection's schema"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/collections/{name}/docs")]
+pub async fn create_document(req: HttpRequest, path: web::Path<(DatabaseID, String)>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let (id, collection) = path.into_inner();
+    check_collection(&collection)?;
+
+    let Some(db) = member_database(&id, &user, &index, true).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let document = read_document(&req, payload, &config.documents).await?;
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+
+    loop {
+        let document_id = config.id_scheme.generate().await.map_err(ApiError::internal)?;
+        let key = ObjectKey::parse(format!("{}{}{}", collection, DELIMITER, document_id))?;
+
+        let (store, document, actor) = (store.clone(), document.clone(), user.actor());
+        let created = telemetry::block(move || create_object(&mut store.blocking_lock(), &key, JSON_CONTENT_TYPE, &document, None, Some(&actor)))
+            .await?
+            .map_err(ApiError::internal)?;
+
+        if created.is_some() {
+            return Ok(ApiResponse::created(Created { id: document_id }));
+        }
+    }
+}
+
+/// Finds the documents of `{name}` matching every filter in the query string, such as `?status=eq:active&age=gte:18`. `sort=field` orders
+/// them by a field, or `sort=-field` in descending order, and `limit` caps how many are returned. Any member of the database may query it.
+#[utoipa::path(
+    tag = "documents",
+    params(("id" = String, Path, description = "The database's ID"), ("name" = String, Path, description = "The collection")),
+    responses((status = 200, description = "The documents whose fields match every query parameter"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/collections/{name}/docs")]
+pub async fn find_documents(path: web::Path<(DatabaseID, String)>, query: web::Query<Vec<(String, String)>>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let (id, collection) = path.into_inner();
+    check_collection(&collection)?;
+
+    let Some(db) = member_database(&id, &user, &index, false).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let (mut filters, mut sort, mut limit) = (vec![], None, DEFAULT_LIMIT);
+    for (field, value) in query.into_inner() {
+        let parsed = match field.as_str() {
+            "sort" => {
+                sort = Some(Sort::parse(&value));
+                Ok(())
+            },
+            "limit" => match value.parse::<usize>() {
+                Ok(value) => {
+                    limit = value.clamp(1, MAX_LIMIT);
+                    Ok(())
+                },
+                Err(_) => Err("The limit must be a number".to_owned()),
+            },
+            _ => Filter::parse(&field, &value).map(|filter| filters.push(filter)),
+        };
+
+        if let Err(error) = parsed {
+            return Err(ApiError::bad_request("invalid_query", error).into());
+        }
+    }
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let found = telemetry::block(move || find(&mut store.blocking_lock(), &collection, &filters, sort.as_ref(), limit))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Found { documents: found }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_filters_compare_fields_of_the_same_type() {
+        let document = json!({"name": "Ada", "age": 36, "address": {"city": "London"}, "tags": ["a"]});
+        let filter = |field: &str, filter: &str| Filter::parse(field, filter).unwrap().matches(&document);
+
+        assert!(filter("name", "eq:Ada") && filter("name", "eq:\"Ada\"") && !filter("name", "ne:Ada"));
+        assert!(filter("address.city", "eq:London") && filter("age", "gte:36") && filter("age", "lt:40.5"));
+        assert!(!filter("age", "gt:abc") && !filter("age", "lt:abc"));
+        assert!(filter("missing", "ne:1") && !filter("missing", "eq:null") && !filter("tags", "eq:a"));
+
+        assert!(Filter::parse("age", "36").is_err());
+        assert!(Filter::parse("age", "around:36").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::db::get_whole_object;
+use crate::index::{commit_change, DBIndexChange};
+use crate::keys::{check_prefix, ObjectKey};
+use crate::pool::DbPool;
+use crate::redact;
+use crate::response::{ApiError, ApiResponse, Done};
+use crate::{DBIndex, DatabaseID};
+
+/// The number of random bytes in an embed token.
+const TOKEN_BYTES: usize = 32;
+
+/// Grants anyone holding it read-only access to the objects under some prefixes of a database, so they can be embedded in pages elsewhere.
+///
+/// Embed tokens are passed in the query string, since pages embedding an object can't set headers. Anyone who can see such a page can see
+/// the token, so it should only cover objects which are meant to be public.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbedToken {
+    pub id: String,
+    pub token: String,
+
+    /// The token grants access to objects whose keys start with any of these.
+    pub prefixes: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+impl EmbedToken {
+    fn grants(&self, token: &str, key: &ObjectKey, now: DateTime<Utc>) -> bool {
+        self.token == token
+            && self.expiry.is_none_or(|expiry| expiry > now)
+            && self.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+/// A view of an [`EmbedToken`] which is safe to include in listings.
+#[derive(Debug, Serialize)]
+struct EmbedSummary<'a> {
+    id: &'a str,
+    #[serde(serialize_with = "redact::prefix")]
+    token: &'a str,
+    prefixes: &'a [String],
+    expiry: Option<DateTime<Utc>>,
+}
+
+impl<'a> From<&'a EmbedToken> for EmbedSummary<'a> {
+    fn from(embed: &'a EmbedToken) -> Self {
+        Self {
+            id: &embed.id,
+            token: &embed.token,
+            prefixes: &embed.prefixes,
+            expiry: embed.expiry,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateEmbedOptions {
+    prefixes: Vec<String>,
+
+    /// How long the token lasts, in seconds. Tokens last until they are revoked if this isn't given.
+    expires_in: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct RedeemOptions {
+    token: String,
+}
+
+/// Creates an embed token for the objects under the given prefixes. Only the database's owner may create them.
+///
+/// The token itself is only ever returned here. Listings show its first few characters.
+#[utoipa::path(
+    tag = "embeds",
+    params(("id" = String, Path, description = "The database's ID")),
+    request_body = CreateEmbedOptions,
+    responses((status = 201, description = "The embed token"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/embeds")]
+pub async fn create_embed(id: web::Path<DatabaseID>, options: web::Json<CreateEmbedOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let options = options.into_inner();
+
+    if options.prefixes.is_empty() {
+        return Err(ApiError::bad_request("missing_prefix", "Embed tokens need at least one prefix").into());
+    }
+
+    for prefix in options.prefixes.iter() {
+        check_prefix(prefix)?;
+    }
+
+    if !index.lock().await.databases.iter().any(|db| db.id == id && db.owner == user.id) {
+        return Err(ApiError::no_such_database().into());
+    }
+
+    let embed = EmbedToken {
+        id: config.id_scheme.generate().await.map_err(ApiError::internal)?,
+        token: URL_SAFE_NO_PAD.encode(crate::random_bytes(TOKEN_BYTES).await.map_err(ApiError::internal)?),
+        prefixes: options.prefixes,
+        expiry: options.expires_in.map(|secs| Utc::now() + chrono::Duration::seconds(secs.min(i64::MAX as u64) as i64)),
+    };
+
+    commit_change(DBIndexChange::AddEmbed { database: id, embed: embed.clone() }).await.map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::created(Created { embed }))
+}
+
+#[derive(Serialize)]
+struct Created {
+    embed: EmbedToken,
+}
+
+#[derive(Serialize)]
+struct Embeds<'a> {
+    embeds: Vec<EmbedSummary<'a>>,
+}
+
+/// Lists a database's embed tokens. Only the database's owner may see them.
+#[utoipa::path(
+    tag = "embeds",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "The database's embed tokens"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/embeds")]
+pub async fn list_embeds(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let index = index.lock().await;
+
+    let Some(db) = index.databases.iter().find(|db| db.id == *id && db.owner == user.id) else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    // Summaries borrow from the index, so the response is built before it is unlocked.
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(Embeds { embeds: db.embeds.iter().map(EmbedSummary::from).collect() })))
+}
+
+/// Revokes an embed token. Only the database's owner may revoke them.
+#[utoipa::path(
+    tag = "embeds",
+    params(("id" = String, Path, description = "The database's ID"), ("embed" = String, Path, description = "The embed token's ID")),
+    responses((status = 200, description = "The token was revoked"), (status = 404, description = "No such database or embed token")),
+    security(("user" = [])),
+)]
+#[delete("/databases/{id}/embeds/{embed}")]
+pub async fn revoke_embed(path: web::Path<(DatabaseID, String)>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let (id, embed) = path.into_inner();
+
+    let exists = index.lock().await.databases.iter()
+        .find(|db| db.id == id && db.owner == user.id)
+        .map(|db| db.embeds.iter().any(|existing| existing.id == embed));
+
+    match exists {
+        None => return Err(ApiError::no_such_database().into()),
+        Some(false) => return Err(ApiError::not_found("no_such_embed", "No such embed token").into()),
+        Some(true) => (),
+    }
+
+    commit_change(DBIndexChange::RevokeEmbed { database: id, embed }).await.map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Done {}))
+}
+
+/// Serves an object to anyone holding an embed token which covers it, given as `?token=`.
+///
+/// Objects the token doesn't cover are reported as missing, so the token doesn't reveal what else the database holds.
+#[utoipa::path(
+    tag = "embeds",
+    params(("id" = String, Path, description = "The database's ID"), ("key" = String, Path, description = "The object's key, which may contain `/`"), RedeemOptions),
+    responses((status = 200, description = "The object's contents"), (status = 404, description = "No such object, or the token doesn't cover it")),
+)]
+#[get("/embed/{id}/{key:.+}")]
+pub async fn get_embedded_object(path: web::Path<(DatabaseID, String)>, options: web::Query<RedeemOptions>, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let (id, key) = path.into_inner();
+    let key = ObjectKey::parse(key)?;
+
+    let db = index.lock().await.databases.iter()
+        .find(|db| db.id == id && db.embeds.iter().any(|embed| embed.grants(&options.token, &key, Utc::now())))
+        .cloned();
+
+    let Some(db) = db else {
+        return Err(ApiError::not_found("no_such_object", "No such object").into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    pool.record_read(&db.id, &key).await;
+
+    get_whole_object(store, db.id, key, &pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_embed_grants_only_its_prefixes() {
+        let now = Utc::now();
+        let embed = EmbedToken {
+            id: "e1".to_owned(),
+            token: "secret".to_owned(),
+            prefixes: vec!["site/".to_owned(), "img/logo".to_owned()],
+            expiry: Some(now + chrono::Duration::hours(1)),
+        };
+
+        let key = |key: &str| ObjectKey::parse(key).unwrap();
+
+        assert!(embed.grants("secret", &key("site/index.html"), now));
+        assert!(embed.grants("secret", &key("img/logo.png"), now));
+        assert!(!embed.grants("secret", &key("private/notes"), now));
+        assert!(!embed.grants("wrong", &key("site/index.html"), now));
+        assert!(!embed.grants("secret", &key("site/index.html"), now + chrono::Duration::hours(2)));
+    }
+}
@@ -0,0 +1,262 @@
+use std::path::PathBuf;
+use actix_web::{delete, web, Responder};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::index::{commit_change, DBIndexChange};
+use crate::pool::DbPool;
+use crate::response::{ApiError, ApiResponse};
+use crate::{AppID, DBIndex, DatabaseID, DatabaseIndex, UserID};
+use crate::telemetry;
+
+/// A record of everything erased on a user's behalf, which can be kept as proof once their data is gone.
+///
+/// The report names the user, so it is returned to whoever asked for the erasure rather than kept by the server.
+#[derive(Debug, Serialize)]
+pub struct ErasureReport {
+    pub id: String,
+    pub user: UserID,
+    pub requested_by: UserID,
+    pub erased_at: DateTime<Utc>,
+
+    /// The databases the user owned. Their stores were deleted along with them.
+    pub databases: Vec<DatabaseID>,
+
+    /// The databases the user was a member of, but didn't own. They were removed from each.
+    pub memberships: Vec<DatabaseID>,
+
+    /// The user's apps, whose tokens stop working with them.
+    pub apps: Vec<AppID>,
+
+    /// How many OAuth, API and app tokens were revoked.
+    pub tokens: usize,
+
+    /// Databases whose files couldn't be deleted, and must be removed by hand.
+    pub incomplete: Vec<DatabaseID>,
+
+    /// Records naming the user which the erasure left in place, and which must be removed wherever they are kept.
+    pub retained: Vec<Retained>,
+}
+
+/// A kind of record which an erasure doesn't reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Retained {
+    /// Audit entries are written to the server's log, which the server can't rewrite. Those naming the user age out with the log.
+    AuditLog,
+}
+
+impl ErasureReport {
+    /// Finds everything in the index which belongs to the user, or nothing if the index doesn't know them.
+    fn plan(index: &DatabaseIndex, user: &UserID) -> Option<Self> {
+        let owned = |owner: &UserID| owner == user;
+
+        let record = index.users.iter().find(|record| record.id == *user);
+        let apps = index.apps.iter().filter(|app| owned(&app.owner)).collect::<Vec<_>>();
+        let databases = index.databases.iter().filter(|db| owned(&db.owner)).map(|db| db.id.clone()).collect::<Vec<_>>();
+
+        if record.is_none() && apps.is_empty() && databases.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            id: String::new(),
+            user: user.clone(),
+            requested_by: UserID::new(),
+            erased_at: Utc::now(),
+            memberships: index.databases.iter()
+                .filter(|db| !owned(&db.owner) && (db.ro.contains(user) || db.rw.contains(user)))
+                .map(|db| db.id.clone())
+                .collect(),
+            tokens: record.map_or(0, |record| record.oauth.len() + record.api.len()) + apps.len(),
+            apps: apps.into_iter().map(|app| app.id.clone()).collect(),
+            databases,
+            incomplete: vec![],
+            retained: vec![Retained::AuditLog],
+        })
+    }
+
+    /// The report as it is signed: a compact JSON array of its fields, always in this order, with the values they have in the response.
+    /// Fields added to the report are appended, so signatures don't depend on how serde happens to order the keys of an object.
+    fn canonical(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&(
+            &self.id,
+            &self.user,
+            &self.requested_by,
+            &self.erased_at,
+            &self.databases,
+            &self.memberships,
+            &self.apps,
+            self.tokens,
+            &self.incomplete,
+            &self.retained,
+        ))
+    }
+
+    /// Signs the report with HMAC-SHA256, so it can later be shown to have come from this server unaltered.
+    fn sign(&self, key: &str) -> serde_json::Result<String> {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.as_bytes());
+        let tag = ring::hmac::sign(&key, &self.canonical()?);
+
+        Ok(base64::engine::general_purpose::STANDARD.encode(tag.as_ref()))
+    }
+}
+
+/// Removes a user from the index along with their apps and every database they own, and takes them off every other database's members.
+pub fn erase_user(index: &mut DatabaseIndex, user: &UserID) {
+    let apps = index.apps.iter().filter(|app| app.owner == *user).map(|app| app.id.clone()).collect::<Vec<_>>();
+
+    index.users.retain(|record| record.id != *user);
+    index.apps.retain(|app| app.owner != *user);
+    index.databases.retain(|db| db.owner != *user);
+
+    for db in index.databases.iter_mut() {
+        db.ro.retain(|member| member != user);
+        db.rw.retain(|member| member != user);
+        db.apps.retain(|app| !apps.contains(app));
+    }
+}
+
+/// Closes and deletes the stores of databases which have already left the index. Returns those whose files couldn't be deleted.
+pub async fn delete_stores(pool: &DbPool, roots: Vec<(DatabaseID, PathBuf)>) -> actix_web::Result<Vec<DatabaseID>> {
+    let mut incomplete = vec![];
+
+    for (db, root) in roots {
+        pool.evict(&db).await;
+
+        match telemetry::block(move || std::fs::remove_dir_all(root)).await? {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => {
+                log::error!("Failed to delete the files of database {}: {}", db, err);
+                incomplete.push(db);
+            },
+        }
+    }
+
+    Ok(incomplete)
+}
+
+#[derive(Serialize)]
+struct Erased {
+    report: ErasureReport,
+    signature: Option<String>,
+}
+
+/// Erases everything the server holds about a user: their tokens, apps, memberships, and the databases they own along with their stores.
+///
+/// Users may erase their own data, and the users listed in `admins` or `erasure.admins` may erase anyone's. The response carries a report of what was
+/// erased, signed with `erasure.signing_key` if one is configured.
+#[utoipa::path(
+    tag = "users",
+    params(("id" = String, Path, description = "The user's ID")),
+    responses((status = 200, description = "The signed erasure report"), (status = 403, description = "Only erasure admins may erase other users")),
+    security(("user" = [])),
+)]
+#[delete("/users/{id}/data")]
+pub async fn erase_user_data(id: web::Path<UserID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    if id != user.id && !config.is_erasure_admin(&user.id) {
+        return Err(ApiError::forbidden("not_an_admin", "Only the user or an administrator may erase a user's data").into());
+    }
+
+    let (report, roots) = {
+        let index = index.lock().await;
+
+        let Some(report) = ErasureReport::plan(&index, &id) else {
+            return Err(ApiError::not_found("no_such_user", "No such user").into());
+        };
+
+        let roots = index.databases.iter()
+            .filter(|db| report.databases.contains(&db.id))
+            .map(|db| (db.id.clone(), db.root.clone()))
+            .collect::<Vec<(DatabaseID, PathBuf)>>();
+
+        (report, roots)
+    };
+
+    let mut report = ErasureReport {
+        id: config.id_scheme.generate().await.map_err(ApiError::internal)?,
+        requested_by: user.id.clone(),
+        ..report
+    };
+
+    // The databases leave the index before their stores are closed, so nothing can open them again in between.
+    commit_change(DBIndexChange::EraseUser { user: id }).await.map_err(ApiError::internal)?;
+
+    report.incomplete = delete_stores(&pool, roots).await?;
+
+    // The audit log outlives the erasure, so it records the report rather than who was erased.
+    log::warn!(target: "audit", "Erasure {} requested by {}: {} databases, {} memberships, {} apps, {} tokens", report.id, report.requested_by,
+        report.databases.len(), report.memberships.len(), report.apps.len(), report.tokens);
+
+    let signature = config.erasure.signing_key.as_deref()
+        .map(|key| report.sign(key))
+        .transpose()
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Erased { report, signature }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::IndexFixture;
+
+    #[test]
+    pub fn test_erases_everything_belonging_to_the_user() {
+        let mut index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .app("a1")
+            .user("u2")
+            .database("db2")
+            .rw("u1")
+            .app("a2")
+            .build();
+        index.databases[1].apps.push("a1".to_owned());
+
+        let user = "u1".to_owned();
+        let report = ErasureReport::plan(&index, &user).unwrap();
+
+        assert_eq!(report.databases, vec!["db1".to_owned()]);
+        assert_eq!(report.memberships, vec!["db2".to_owned()]);
+        assert_eq!(report.apps, vec!["a1".to_owned()]);
+        assert_eq!(report.tokens, 2);
+
+        erase_user(&mut index, &user);
+
+        assert!(ErasureReport::plan(&index, &user).is_none());
+        assert_eq!(index.users.len(), 1);
+        assert_eq!(index.databases.len(), 1);
+        assert!(index.databases[0].rw.is_empty());
+        assert_eq!(index.databases[0].apps, vec!["a2".to_owned()]);
+        assert_eq!(index.apps.len(), 1);
+    }
+
+    #[test]
+    pub fn test_signature_covers_the_report() -> serde_json::Result<()> {
+        let index = IndexFixture::new().user("u1").build();
+        let mut report = ErasureReport::plan(&index, &"u1".to_owned()).unwrap();
+
+        let signature = report.sign("secret")?;
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"secret");
+        let tag = base64::engine::general_purpose::STANDARD.decode(&signature).unwrap();
+
+        assert!(ring::hmac::verify(&key, &report.canonical()?, &tag).is_ok());
+
+        report.tokens += 1;
+        assert!(ring::hmac::verify(&key, &report.canonical()?, &tag).is_err());
+
+        // What is signed is the same whatever order the fields are declared or serialised in.
+        report.id = "e1".to_owned();
+        report.requested_by = "u1".to_owned();
+        report.erased_at = DateTime::from_timestamp(0, 0).unwrap();
+        assert_eq!(report.canonical()?, br#"["e1","u1","u1","1970-01-01T00:00:00Z",[],[],[],2,[],["audit_log"]]"#);
+
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ macro_rules! multi_error {
             use backtrace::Backtrace;
 
             #[derive(Debug)]
+            #[allow(clippy::enum_variant_names)]
             pub enum Inner {
                 $($err($obj),)*
                 $($manual),*
@@ -28,6 +29,12 @@ macro_rules! multi_error {
                 }
             }
 
+            impl Error {
+                pub fn inner(&self) -> &Inner {
+                    &self.inner
+                }
+            }
+
             impl std::error::Error for Error {}
             impl std::fmt::Display for Error {
                 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { std::fmt::Debug::fmt(self, f) }
@@ -53,15 +60,76 @@ multi_error! { global();
     SerdeJsonError = serde_json::error::Error;
     ReqwestError = reqwest::Error;
     OsError = rand::rand_core::OsError;
-    IoError = std::io::Error
+    IoError = std::io::Error;
+    LibDbError = libdb::error::Error;
+    TomlError = toml::de::Error;
+    TlsError = rustls::Error;
+    OtlpError = opentelemetry_otlp::ExporterBuildError;
+    PemError = rustls::pki_types::pem::Error;
+    SmtpError = lettre::transport::smtp::Error;
+    EmailError = lettre::error::Error;
+    AddressError = lettre::address::AddressError;
+    DocumentError = crate::error::DocumentError;
+    PatchError = crate::error::PatchError
 }
 
 pub type Result<T> = ::std::result::Result<T, global::Error>;
+#[allow(unused_imports)]
 pub use global::Error;
 
+impl global::Error {
+    /// Whether a store was refused more space because it would have outgrown its database's quota.
+    pub fn is_quota_exceeded(&self) -> bool {
+        self.io_error_kind() == Some(std::io::ErrorKind::QuotaExceeded)
+    }
+
+    /// Whether a store was refused more space because the disk holding it is nearly full.
+    pub fn is_out_of_space(&self) -> bool {
+        self.io_error_kind() == Some(std::io::ErrorKind::StorageFull)
+    }
+
+    /// Whether a conditional write was refused because the object didn't meet its `If-Match` or `If-None-Match` header.
+    pub fn is_precondition_failed(&self) -> bool {
+        matches!(self.inner(), global::Inner::ManualError(ManualError::PreconditionFailed))
+    }
+
+    /// Whether a write was refused because the store was closed while the request waited for it, such as for a repair or restore.
+    pub fn is_store_closed(&self) -> bool {
+        match self.inner() {
+            global::Inner::LibDbError(err) => matches!(err.inner(), libdb::error::global::Inner::FragmentError(libdb::error::FragmentError::Closed)),
+            _ => false,
+        }
+    }
+
+    fn io_error_kind(&self) -> Option<std::io::ErrorKind> {
+        match self.inner() {
+            global::Inner::IoError(err) => Some(err.kind()),
+            global::Inner::LibDbError(err) => match err.inner() {
+                libdb::error::global::Inner::IoError(err) => Some(err.kind()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ManualError {
     AppStateMissing,
+    StoreOpenFailed,
+    StoreLocked(std::path::PathBuf),
+    IndexChangeDropped,
+    MissingDatabaseDir,
+    StoreOpenTimedOut,
+    StoreQuarantined(crate::DatabaseID),
+    UnsupportedIndexVersion(u32),
+    UnsupportedIndexFeature(String),
+    FragmentDamaged(libdb::FragmentID),
+    VerificationFailed(usize),
+    PreconditionFailed,
+    ReplicationPayloadInvalid(libdb::FragmentID),
+    MissingReplicationToken,
+    InvalidRateLimit(String),
 }
 
 impl std::error::Error for ManualError {}
@@ -87,6 +155,7 @@ impl std::fmt::Display for TokenError {
 }
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum AppError {
     MissingToken,
     InvalidToken,
@@ -99,4 +168,83 @@ impl std::fmt::Display for AppError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         std::fmt::Debug::fmt(self, f)
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone)]
+pub enum DocumentError {
+    TooLarge { limit: usize },
+    TooDeep { limit: usize },
+    Invalid(String),
+    Interrupted,
+    InvalidContentType,
+}
+
+impl std::error::Error for DocumentError {}
+impl std::fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PatchError {
+    UnsupportedContentType,
+    Invalid(String),
+    NotADocument,
+    NoSuchPath(String),
+    TestFailed(String),
+}
+
+impl std::error::Error for PatchError {}
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum KeyError {
+    Empty,
+    TooLong { limit: usize },
+    InvalidCharacter(char),
+    EmptySegment,
+    RelativeSegment,
+}
+
+impl std::error::Error for KeyError {}
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DatabaseError {
+    MissingHeader,
+    NotFound,
+    Quarantined,
+    QuotaExceeded,
+    OutOfSpace,
+    PreconditionFailed,
+    OutOfScope,
+    StoreClosed,
+}
+
+impl std::error::Error for DatabaseError {}
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PagingError {
+    InvalidCursor,
+}
+
+impl std::error::Error for PagingError {}
+impl std::fmt::Display for PagingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
@@ -0,0 +1,186 @@
+use actix_web::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use actix_web::middleware::Compress;
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpResponse, Responder};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+use crate::auth::AuthenticatedUser;
+use crate::db::check_collection;
+use crate::dictionary;
+use crate::documents::{member_database, pointer, FoundDocument};
+use crate::error::*;
+use crate::keys::{KeyDirectory, DELIMITER};
+use crate::pool::{DbPool, Store};
+use crate::response::ApiError;
+use crate::{DBIndex, DatabaseID};
+use crate::telemetry;
+
+/// How many documents are read from the store at a time.
+const PAGE: usize = 100;
+
+/// How exported documents are written.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// One `{"id": ..., "document": ...}` per line.
+    #[default]
+    Jsonl,
+
+    /// A header naming the columns, then one row per document: its ID, then the requested fields.
+    Csv,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ExportOptions {
+    /// The collection whose documents are exported.
+    pub collection: String,
+
+    #[serde(default)]
+    pub format: ExportFormat,
+
+    /// The fields written as CSV columns after the ID, separated by commas, such as `name,address.city`. Required for CSV.
+    pub fields: Option<String>,
+}
+
+/// Writes a document as a row of CSV: its ID, then each of the fields. Strings are written as they are, missing fields and nulls as empty
+/// cells, and anything else as JSON.
+fn csv_row(id: &str, document: &Value, pointers: &[String]) -> Result<Vec<u8>> {
+    let cells = pointers.iter().map(|pointer| match document.pointer(pointer) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+    });
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(std::iter::once(id.to_owned()).chain(cells)).map_err(std::io::Error::from)?;
+
+    writer.into_inner().map_err(|err| std::io::Error::other(err.to_string()).into())
+}
+
+/// Reads the documents of the collection after the key `after`, up to a page of them, in order of key, and writes them out. Returns the
+/// last key read, or `None` once the collection has been read to the end. Objects which aren't JSON are left out.
+fn read_page(store: &mut Store, prefix: &str, after: Option<&str>, csv: Option<&[String]>) -> Result<(Vec<u8>, Option<String>)> {
+    let directory = KeyDirectory::load(store)?;
+    let objects = match after {
+        Some(after) => directory.objects_after(prefix, after).take(PAGE).collect::<Vec<_>>(),
+        None => directory.objects(prefix).take(PAGE).collect(),
+    };
+
+    let mut out = vec![];
+    for (key, meta) in &objects {
+        let Ok(document) = serde_json::from_slice::<Value>(&dictionary::read_contents(store, meta)?) else {
+            continue;
+        };
+
+        let id = &key[prefix.len()..];
+        match csv {
+            Some(pointers) => out.extend(csv_row(id, &document, pointers)?),
+            None => {
+                serde_json::to_writer(&mut out, &FoundDocument { id: id.to_owned(), document })?;
+                out.push(b'\n');
+            },
+        }
+    }
+
+    let last = (objects.len() == PAGE).then(|| objects.last().map(|(key, _)| key.to_string())).flatten();
+    Ok((out, last))
+}
+
+/// Streams every document of a collection in order of key, as JSON lines or CSV, for loading into other tools.
+///
+/// Documents are read a page at a time, and the store is only held while each page is read, so a long export doesn't hold up writes. A
+/// document changed during the export is written as it was when its page was read. The response is compressed if the client's
+/// `Accept-Encoding` allows it, such as with `gzip`. Any member of the database may export it.
+#[utoipa::path(
+    tag = "documents",
+    params(("id" = String, Path, description = "The database's ID"), ExportOptions),
+    responses((status = 200, description = "The collection's documents, as JSON lines or CSV"), (status = 400, description = "An invalid collection, or CSV without fields"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/export", wrap = "Compress::default()")]
+pub async fn export_documents(id: web::Path<DatabaseID>, options: web::Query<ExportOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let options = options.into_inner();
+    check_collection(&options.collection)?;
+
+    let Some(db) = member_database(&id, &user, &index, false).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let (content_type, extension, header, csv) = match options.format {
+        ExportFormat::Jsonl => ("application/x-ndjson", "jsonl", vec![], None),
+        ExportFormat::Csv => {
+            let Some(fields) = options.fields.filter(|fields| !fields.is_empty()) else {
+                return Err(ApiError::bad_request("missing_fields", "CSV exports need the fields to write as columns").into());
+            };
+
+            let fields = fields.split(',').map(str::trim).collect::<Vec<_>>();
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer.write_record(std::iter::once("id").chain(fields.iter().copied())).map_err(ApiError::internal)?;
+
+            let header = writer.into_inner().map_err(ApiError::internal)?;
+            ("text/csv; charset=utf-8", "csv", header, Some(fields.into_iter().map(pointer).collect::<Vec<_>>()))
+        },
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let prefix = format!("{}{}", options.collection, DELIMITER);
+    let database = db.id.clone();
+
+    // Each step reads the page after the last key of the one before, until a page comes up short.
+    let pages = futures::stream::try_unfold(Some(None::<String>), move |after| {
+        let (store, prefix, csv, database) = (store.clone(), prefix.clone(), csv.clone(), database.clone());
+
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+
+            let page = telemetry::block(move || read_page(&mut store.blocking_lock(), &prefix, after.as_deref(), csv.as_deref()))
+                .await
+                .map_err(std::io::Error::other)?;
+
+            // Failing the body cuts the export short, so it can't be mistaken for a whole collection.
+            let (out, last) = page.map_err(|err| {
+                log::error!("Failed to export documents of database {}: {:?}", database, err);
+                std::io::Error::other(err.to_string())
+            })?;
+
+            Ok::<_, std::io::Error>(Some((Bytes::from(out), last.map(Some))))
+        }
+    });
+
+    let body = futures::stream::once(async move { Ok::<_, std::io::Error>(Bytes::from(header)) }).chain(pages);
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, content_type))
+        .insert_header((CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", options.collection, extension)))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::ObjectKey;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_documents_are_written_as_csv_rows_in_key_order() {
+        let document = json!({"name": "Ada, Countess", "age": 36, "address": {"city": "London"}, "tags": ["a"], "gone": null});
+        let pointers = ["name", "age", "address.city", "tags", "gone", "missing"].map(pointer);
+
+        let row = csv_row("p1", &document, &pointers).unwrap();
+        assert_eq!(String::from_utf8(row).unwrap(), "p1,\"Ada, Countess\",36,London,\"[\"\"a\"\"]\",,\n");
+
+        let mut directory = KeyDirectory::default();
+        for key in ["people/c", "people/a", "people/b", "pets/a"] {
+            directory.get_or_insert(&ObjectKey::parse(key).unwrap(), "application/json");
+        }
+
+        let after = |after: &str| directory.objects_after("people/", after).map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(after("people/a"), ["people/b", "people/c"]);
+        assert_eq!(after("people/a0"), ["people/b", "people/c"]);
+        assert!(after("people/c").is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+use chrono::{DateTime, TimeZone, Utc};
+use crate::{AppID, Application, Database, DatabaseID, DatabaseIndex, OAuthSettings, Token, User, UserID};
+
+/// Builds a [`DatabaseIndex`] in code rather than by hand-writing `index.json`, so the result always matches the real structures.
+///
+/// Everything the builder produces is deterministic: tokens are derived from the IDs they belong to and expire at a fixed date far in the
+/// future. Entries refer to whatever was added before them. Databases are owned by the most recently added user, and apps belong to the most
+/// recent user and are granted access to the most recent database. The server also starts from an empty fixture when the database directory
+/// doesn't have an index yet.
+///
+/// ```ignore
+/// let index = IndexFixture::new()
+///     .user("u1")
+///     .database("db1")
+///     .app("a1")
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct IndexFixture {
+    root: PathBuf,
+    index: DatabaseIndex,
+}
+
+impl Default for IndexFixture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Only the empty index is built outside tests.
+#[cfg_attr(not(test), allow(dead_code))]
+impl IndexFixture {
+    /// An index without any users, databases or apps, and with blank OAuth settings.
+    pub fn new() -> Self {
+        Self {
+            root: PathBuf::new(),
+            index: DatabaseIndex::default(),
+        }
+    }
+
+    /// The expiry given to every token the fixture creates.
+    pub fn expiry() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    fn token(owner: &str, kind: &str) -> Token {
+        Token {
+            token: format!("{}-{}", owner, kind),
+            refresh: format!("{}-{}-refresh", owner, kind),
+            expiry: Self::expiry(),
+            hint: None,
+        }
+    }
+
+    /// The directory databases added after this are placed in.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    pub fn oauth(mut self, settings: OAuthSettings) -> Self {
+        self.index.oauth_settings = settings;
+        self
+    }
+
+    /// Adds a user with an API token of `<id>-api`.
+    pub fn user(mut self, id: impl Into<UserID>) -> Self {
+        let id = id.into();
+
+        self.index.users.push(User {
+            oauth: vec![],
+            api: vec![Self::token(&id, "api")],
+            id,
+            last_active: None,
+            service: None,
+        });
+
+        self
+    }
+
+    /// Adds a database owned by the most recent user, stored in a directory named after it.
+    pub fn database(mut self, id: impl Into<DatabaseID>) -> Self {
+        let id = id.into();
+        let owner = self.index.users.last().map(|user| user.id.clone()).unwrap_or_default();
+
+        self.index.databases.push(Database {
+            name: id.clone(),
+            root: self.root.join(&id),
+            owner,
+            id,
+            ..Database::default()
+        });
+
+        self
+    }
+
+    /// Gives a user read-write access to the most recent database.
+    pub fn rw(mut self, user: impl Into<UserID>) -> Self {
+        if let Some(db) = self.index.databases.last_mut() {
+            db.rw.push(user.into());
+        }
+
+        self
+    }
+
+    /// Gives a user read-only access to the most recent database.
+    pub fn ro(mut self, user: impl Into<UserID>) -> Self {
+        if let Some(db) = self.index.databases.last_mut() {
+            db.ro.push(user.into());
+        }
+
+        self
+    }
+
+    /// Adds an app with a token of `<id>-token`, owned by the most recent user and granted access to the most recent database.
+    pub fn app(mut self, id: impl Into<AppID>) -> Self {
+        let id = id.into();
+        let owner = self.index.users.last().map(|user| user.id.clone()).unwrap_or_default();
+
+        if let Some(db) = self.index.databases.last_mut() {
+            db.apps.push(id.clone());
+        }
+
+        self.index.apps.push(Application {
+            name: id.clone(),
+            token: Self::token(&id, "token"),
+            owner,
+            id,
+            scopes: crate::app::Scope::all(),
+            databases: None,
+        });
+
+        self
+    }
+
+    pub fn build(self) -> DatabaseIndex {
+        self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_fixture_links_entries() {
+        let index = IndexFixture::new()
+            .root("/srv/db")
+            .user("u1")
+            .database("db1")
+            .app("a1")
+            .user("u2")
+            .database("db2")
+            .ro("u1")
+            .build();
+
+        assert_eq!(index.users[0].api[0].token, "u1-api");
+        assert_eq!(index.databases[0].owner, "u1");
+        assert_eq!(index.databases[0].root, PathBuf::from("/srv/db/db1"));
+        assert_eq!(index.databases[0].apps, vec!["a1".to_owned()]);
+        assert_eq!(index.apps[0].owner, "u1");
+        assert_eq!(index.apps[0].token.token, "a1-token");
+        assert_eq!(index.databases[1].owner, "u2");
+        assert_eq!(index.databases[1].ro, vec!["u1".to_owned()]);
+    }
+
+    #[test]
+    pub fn test_fixture_survives_serialisation() -> serde_json::Result<()> {
+        let index = IndexFixture::new()
+            .oauth(OAuthSettings { client_id: "client".to_owned(), ..OAuthSettings::default() })
+            .user("u1")
+            .database("db1")
+            .app("a1")
+            .build();
+
+        let json = serde_json::to_string(&index)?;
+        let read: DatabaseIndex = serde_json::from_str(&json)?;
+
+        assert_eq!(serde_json::to_string(&read)?, json);
+
+        Ok(())
+    }
+}
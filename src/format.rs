@@ -0,0 +1,170 @@
+use actix_web::body::{self, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, Accept, Header, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError};
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::response::ApiError;
+
+/// The content types the API reads and writes besides JSON.
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// How a request or response body is encoded. MessagePack and CBOR carry the same fields as JSON, but binary fields such as the contents
+/// of a read are sent as raw bytes rather than base64.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            MSGPACK_CONTENT_TYPE | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MessagePack),
+            CBOR_CONTENT_TYPE => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The format the client prefers its responses in, according to its `Accept` header. JSON is preferred unless the client ranks another
+    /// format higher.
+    pub fn accepted(req: &HttpRequest) -> Self {
+        Accept::parse(req).ok()
+            .and_then(|accept| accept.ranked().iter().find_map(|mime| Self::from_mime(mime.essence_str())))
+            .unwrap_or_default()
+    }
+
+    /// The format of the request body, according to its `Content-Type` header. Bodies of any other type are taken to be JSON.
+    pub fn of_body(req: &HttpRequest) -> Self {
+        req.headers().get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .and_then(|mime| Self::from_mime(mime.trim()))
+            .unwrap_or_default()
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => MSGPACK_CONTENT_TYPE,
+            Format::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Format::Cbor => {
+                let mut encoded = vec![];
+                ciborium::into_writer(value, &mut encoded).map_err(|err| err.to_string())?;
+                Ok(encoded)
+            },
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, String> {
+        let mut rest = body;
+        let value = match self {
+            Format::Json => return serde_json::from_slice(body).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::from_read(&mut rest).map_err(|err| format!("Invalid MessagePack: {err}"))?,
+            Format::Cbor => ciborium::from_reader(&mut rest).map_err(|err| format!("Invalid CBOR: {err}"))?,
+        };
+
+        // Neither decoder minds bytes left over after the first value, which would otherwise be silently dropped.
+        if !rest.is_empty() {
+            return Err(format!("{} bytes left over after the document", rest.len()));
+        }
+
+        Ok(value)
+    }
+
+    /// Finishes a response with `value` as its body, in this format.
+    pub fn respond<T: Serialize>(self, mut response: HttpResponseBuilder, value: &T) -> HttpResponse {
+        // The body depends on the Accept header, so caches mustn't hand one format to a client asking for another.
+        response.append_header((header::VARY, "Accept"));
+
+        match self.encode(value) {
+            Ok(body) => response.content_type(self.content_type()).body(body),
+            Err(err) => ApiError::internal(err).error_response(),
+        }
+    }
+}
+
+/// Bytes which are sent as base64 in JSON, and as they are in the binary formats.
+#[derive(Debug)]
+pub struct Binary(pub Vec<u8>);
+
+impl Serialize for Binary {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+/// Re-encodes error bodies in the format the client accepts. Successful responses are encoded by `ApiResponse` itself, but errors are
+/// turned into responses without sight of the request, so they are always written as JSON first.
+pub async fn negotiate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let format = Format::accepted(req.request());
+    let response = next.call(req).await?;
+
+    let is_json = response.headers().get(header::CONTENT_TYPE).is_some_and(|content_type| content_type == "application/json");
+    if format == Format::Json || response.status().is_success() || !is_json {
+        return Ok(response.map_into_left_body());
+    }
+
+    let (req, response) = response.into_parts();
+    let (mut response, body) = response.into_parts();
+
+    let body = body::to_bytes(body).await.map_err(|err| {
+        let err: Box<dyn std::error::Error> = err.into();
+        ApiError::internal(err)
+    })?;
+    let body = match serde_json::from_slice::<serde_json::Value>(&body).map_err(|err| err.to_string()).and_then(|value| format.encode(&value)) {
+        Ok(encoded) => {
+            response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+            encoded.into()
+        },
+        Err(_) => body,
+    };
+
+    Ok(ServiceResponse::new(req, response.set_body(body)).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    pub fn test_clients_get_the_format_they_rank_highest() {
+        let req = TestRequest::default().insert_header((header::ACCEPT, "application/json;q=0.5, application/msgpack")).to_http_request();
+        assert_eq!(Format::accepted(&req), Format::MessagePack);
+
+        let req = TestRequest::default().insert_header((header::ACCEPT, "*/*, application/cbor;q=0.9")).to_http_request();
+        assert_eq!(Format::accepted(&req), Format::Json);
+
+        let req = TestRequest::default().insert_header((header::CONTENT_TYPE, "application/cbor; charset=binary")).to_http_request();
+        assert_eq!(Format::of_body(&req), Format::Cbor);
+        assert_eq!(Format::accepted(&req), Format::Json);
+
+        for format in [Format::Json, Format::MessagePack, Format::Cbor] {
+            let value = serde_json::json! {{ "object": "a/b", "partial": false, "cursor": null }};
+            assert_eq!(format.decode::<serde_json::Value>(&format.encode(&value).unwrap()).unwrap(), value);
+        }
+
+        assert!(Format::MessagePack.decode::<serde_json::Value>(b"garbage").is_err());
+
+        assert_eq!(Format::Json.encode(&Binary(b"hi".to_vec())).unwrap(), b"\"aGk=\"");
+        assert_eq!(Format::MessagePack.encode(&Binary(b"hi".to_vec())).unwrap(), b"\xc4\x02hi");
+    }
+}
@@ -0,0 +1,183 @@
+use std::path::Path;
+use std::sync::OnceLock;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ring::hmac;
+use crate::error::*;
+use crate::redact::TOKEN_PREFIX_LEN;
+use crate::{DatabaseIndex, Token};
+
+/// The name of the file the key tokens are hashed with is kept in, beside the index. It is deliberately not part of the index, so a leaked
+/// index alone gives nothing away.
+pub const KEY_FILE: &str = "token.key";
+
+/// Hashed tokens start with this, which tells them apart from tokens stored before they were hashed.
+const HASH_PREFIX: &str = "h1:";
+
+/// The number of random bytes in a new key.
+const KEY_BYTES: usize = 32;
+
+static KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+/// Reads the key tokens are hashed with from the data directory, creating one if there is none yet.
+async fn load_key(dir: &Path) -> Result<Vec<u8>> {
+    let path = dir.join(KEY_FILE);
+
+    match tokio::fs::read(&path).await {
+        Ok(key) => Ok(key),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let key = crate::random_bytes(KEY_BYTES).await?;
+            write_key(&path, &key)?;
+            log::info!("Created a new token key in {}", path.display());
+            Ok(key)
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Loads the key tokens are hashed with from the data directory, creating one the first time the server starts.
+pub async fn init(dir: &Path) -> Result<()> {
+    let key = load_key(dir).await?;
+    let _ = KEY.set(hmac::Key::new(hmac::HMAC_SHA256, &key));
+    Ok(())
+}
+
+/// Reads the key from the data directory as it is served to followers, so the tokens in the index they copy still work once they are
+/// promoted.
+pub async fn read_key(dir: &Path) -> Result<Vec<u8>> {
+    Ok(tokio::fs::read(dir.join(KEY_FILE)).await?)
+}
+
+/// Keeps a copy of the primary's key in a follower's data directory, replacing whatever key was there. Returns whether it changed.
+pub fn replicate_key(dir: &Path, key: &[u8]) -> Result<bool> {
+    let path = dir.join(KEY_FILE);
+    if std::fs::read(&path).is_ok_and(|current| current == key) {
+        return Ok(false);
+    }
+
+    // The key is written in full beside the old one first, so a follower which stops in between is left with one key or the other.
+    let partial = path.with_extension("key.partial");
+    let _ = std::fs::remove_file(&partial);
+    write_key(&partial, key)?;
+    std::fs::rename(&partial, &path)?;
+
+    Ok(true)
+}
+
+fn write_key(path: &Path, key: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(key)?;
+
+    Ok(file.sync_all()?)
+}
+
+fn hash_with(key: &hmac::Key, token: &str) -> String {
+    format!("{}{}", HASH_PREFIX, URL_SAFE_NO_PAD.encode(hmac::sign(key, token.as_bytes()).as_ref()))
+}
+
+/// Hashes a token as it is stored in the index: the HMAC-SHA256 of the token keyed with the server's [`KEY_FILE`]. The key stands in for a
+/// salt shared by every token, which keeps looking a token up to a single hash.
+pub fn hash(token: &str) -> String {
+    hash_with(KEY.get().expect("The token key hasn't been loaded"), token)
+}
+
+/// Whether a stored token was made by [`hash`].
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with(HASH_PREFIX)
+}
+
+/// The unkeyed hex SHA-256 service account tokens were stored as before tokens were hashed with the server's key. Such a hash can't be
+/// rehashed without the token, so it is only replaced with [`hash`] once the token is next used.
+pub fn legacy_hash(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes()).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hash_all_with(key: &hmac::Key, index: &mut DatabaseIndex) -> usize {
+    let mut hashed = 0;
+    let mut hash = |token: &mut Token| {
+        if !is_hashed(&token.token) {
+            token.hint.get_or_insert_with(|| token.token.chars().take(TOKEN_PREFIX_LEN).collect());
+            token.token = hash_with(key, &token.token);
+            hashed += 1;
+        }
+
+        if !is_hashed(&token.refresh) {
+            token.refresh = hash_with(key, &token.refresh);
+            hashed += 1;
+        }
+    };
+
+    for user in index.users.iter_mut() {
+        user.api.iter_mut().for_each(&mut hash);
+    }
+
+    for app in index.apps.iter_mut() {
+        hash(&mut app.token);
+    }
+
+    hashed
+}
+
+/// Replaces every API, refresh and app token in the index which is still in plaintext with its hash, and returns how many there were. The
+/// first few characters of API and app tokens are kept as their hint.
+///
+/// Indexes from before tokens were hashed are migrated by this when they are loaded. New tokens reach the index in plaintext through its
+/// changes and are hashed before it is written, so they never reach the disk. Service account tokens are only ever stored hashed, and are
+/// left alone.
+pub fn hash_all(index: &mut DatabaseIndex) -> usize {
+    hash_all_with(KEY.get().expect("The token key hasn't been loaded"), index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::IndexFixture;
+
+    #[test]
+    pub fn test_plaintext_tokens_are_hashed_once() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"key");
+        let mut index = IndexFixture::new().user("u1").app("a1").build();
+
+        assert_eq!(hash_all_with(&key, &mut index), 4);
+        assert_eq!(index.users[0].api[0].token, hash_with(&key, "u1-api"));
+        assert_eq!(index.apps[0].token.token, hash_with(&key, "a1-token"));
+        assert_eq!(index.users[0].api[0].hint.as_deref(), Some("u1-api"));
+
+        // Hashes are left as they are, so migrating twice changes nothing.
+        assert_eq!(hash_all_with(&key, &mut index), 0);
+        assert_eq!(index.users[0].api[0].token, hash_with(&key, "u1-api"));
+        assert_eq!(index.users[0].api[0].hint.as_deref(), Some("u1-api"));
+
+        assert_ne!(hash_with(&key, "u1-api"), hash_with(&hmac::Key::new(hmac::HMAC_SHA256, b"other"), "u1-api"));
+    }
+
+    #[test]
+    pub fn test_tokens_still_validate_on_a_promoted_follower() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("token-key-test-{}", std::process::id()));
+        let (primary, follower) = (dir.join("primary"), dir.join("follower"));
+        std::fs::create_dir_all(&primary)?;
+        std::fs::create_dir_all(&follower)?;
+
+        actix_web::rt::System::new().block_on(async {
+            let key = load_key(&primary).await?;
+            let hash = hash_with(&hmac::Key::new(hmac::HMAC_SHA256, &key), "u1-api");
+
+            // A follower which started on its own has a key of its own, which the primary's replaces.
+            load_key(&follower).await?;
+            assert!(replicate_key(&follower, &read_key(&primary).await?)?);
+            assert!(!replicate_key(&follower, &key)?);
+
+            // Promoting the follower loads the key it copied, which hashes tokens just as the primary did.
+            let promoted = load_key(&follower).await?;
+            assert_eq!(hash_with(&hmac::Key::new(hmac::HMAC_SHA256, &promoted), "u1-api"), hash);
+
+            Ok::<_, Error>(())
+        })?;
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
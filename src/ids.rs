@@ -0,0 +1,93 @@
+use std::fmt::Write;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::Deserialize;
+use crate::error::*;
+use crate::random_bytes;
+
+/// The alphabet ULIDs are written in. It leaves out I, L, O and U so IDs can't be misread.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// How new identifiers are made. Every scheme only uses characters which are safe in URLs and object keys.
+///
+/// UUIDv7 and ULID begin with the time they were made, so they sort in order of creation. URL-safe base64 IDs are entirely random.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdScheme {
+    #[default]
+    UuidV7,
+    Ulid,
+    Base64Url,
+}
+
+impl IdScheme {
+    /// Makes a new identifier. It is up to the caller to check it isn't already in use.
+    pub async fn generate(&self) -> Result<String> {
+        let random = random_bytes(16).await?
+            .try_into()
+            .expect("Asked for 16 random bytes");
+
+        Ok(self.format(chrono::Utc::now().timestamp_millis() as u64, random))
+    }
+
+    /// Builds an identifier from a timestamp in milliseconds and 16 random bytes, of which the timestamp replaces the first six where used.
+    fn format(&self, millis: u64, random: [u8; 16]) -> String {
+        let timestamped = || {
+            let mut bytes = random;
+            bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+            bytes
+        };
+
+        match self {
+            IdScheme::UuidV7 => {
+                let mut bytes = timestamped();
+                bytes[6] = 0x70 | (bytes[6] & 0x0f);
+                bytes[8] = 0x80 | (bytes[8] & 0x3f);
+
+                bytes.iter().enumerate().fold(String::with_capacity(36), |mut id, (i, byte)| {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        id.push('-');
+                    }
+
+                    let _ = write!(id, "{:02x}", byte);
+                    id
+                })
+            },
+            IdScheme::Ulid => {
+                let value = u128::from_be_bytes(timestamped());
+
+                (0..26).rev()
+                    .map(|i| CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char)
+                    .collect()
+            },
+            IdScheme::Base64Url => URL_SAFE_NO_PAD.encode(random),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::ObjectKey;
+
+    #[test]
+    pub fn test_ids_are_valid_keys() {
+        for scheme in [IdScheme::UuidV7, IdScheme::Ulid, IdScheme::Base64Url] {
+            let id = scheme.format(1_700_000_000_000, [0xff; 16]);
+            assert!(ObjectKey::parse(&id).is_ok(), "{:?} made an invalid key: {}", scheme, id);
+        }
+
+        assert_eq!(IdScheme::UuidV7.format(0x0123_4567_89ab, [0; 16]), "01234567-89ab-7000-8000-000000000000");
+        assert_eq!(IdScheme::Ulid.format(0, [0; 16]).len(), 26);
+    }
+
+    #[test]
+    pub fn test_timestamped_ids_sort_by_time() {
+        for scheme in [IdScheme::UuidV7, IdScheme::Ulid] {
+            let earlier = scheme.format(1_700_000_000_000, [0xff; 16]);
+            let later = scheme.format(1_700_000_000_001, [0; 16]);
+
+            assert!(earlier < later, "{:?} IDs don't sort by time", scheme);
+        }
+    }
+}
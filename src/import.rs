@@ -0,0 +1,535 @@
+use std::sync::Arc;
+use actix_web::{post, web, Responder};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::Mutex;
+use utoipa::{IntoParams, ToSchema};
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::db::check_collection;
+use crate::document::{check_document, JSON_CONTENT_TYPE};
+use crate::documents::{member_database, pointer};
+use crate::error::*;
+use crate::keys::{ObjectKey, DELIMITER};
+use crate::pool::{DbPool, Store};
+use crate::query::{create_object, write_object};
+use crate::response::{ApiError, ApiResponse};
+use crate::{DBIndex, DatabaseID};
+use crate::telemetry;
+
+/// How many rows are written to the store at a time.
+const BATCH_ROWS: usize = 500;
+
+/// The most failed rows described in a report. Every failed row is counted either way.
+const MAX_ERRORS: usize = 100;
+
+/// How the rows of an import are written.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// One JSON document per line.
+    #[default]
+    Jsonl,
+
+    /// A header naming the columns, then one document per row, made by the mapping.
+    Csv,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ImportOptions {
+    /// The collection the documents are added to.
+    pub collection: String,
+
+    /// How the body is written.
+    #[serde(default)]
+    pub format: ImportFormat,
+
+    /// The field holding each document's ID, such as `sku` or `meta.id`. A document with the ID of an existing one replaces it. Without
+    /// this, every document is given a new ID by the configured ID scheme.
+    pub id: Option<String>,
+
+    /// How CSV columns become fields, as `column:field:type` separated by commas, such as `Name:name,Age:age:number`. The field and type
+    /// may be left out. Types are `auto`, which reads cells as JSON where it can, `string`, `number` and `boolean`. Columns which aren't
+    /// mentioned are left out. Without a mapping, every column becomes a field of the same name, of type `auto`.
+    pub mapping: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ColumnType {
+    Auto,
+    String,
+    Number,
+    Boolean,
+}
+
+/// One column of a mapping, before it is matched up with the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ColumnSpec {
+    column: String,
+    field: String,
+    kind: ColumnType,
+}
+
+/// Reads a mapping such as `Name:name,Age:age:number`.
+fn parse_mapping(spec: &str) -> std::result::Result<Vec<ColumnSpec>, String> {
+    spec.split(',').map(|column| {
+        let mut parts = column.split(':');
+        let name = parts.next().unwrap_or_default().trim();
+        let field = parts.next().map(str::trim).filter(|field| !field.is_empty()).unwrap_or(name);
+
+        let kind = match parts.next().map(str::trim) {
+            None | Some("auto") => ColumnType::Auto,
+            Some("string") => ColumnType::String,
+            Some("number") => ColumnType::Number,
+            Some("boolean") => ColumnType::Boolean,
+            Some(kind) => return Err(format!("'{}' is not a column type. Use one of auto, string, number or boolean", kind)),
+        };
+
+        if name.is_empty() || parts.next().is_some() {
+            return Err(format!("'{}' must look like 'column:field:type'", column));
+        }
+
+        Ok(ColumnSpec { column: name.to_owned(), field: field.to_owned(), kind })
+    }).collect()
+}
+
+/// A column of a mapping matched up with the header: where the column is in each row, and the path to its field.
+#[derive(Debug, Clone)]
+struct Column {
+    index: usize,
+    name: String,
+    path: Vec<String>,
+    kind: ColumnType,
+}
+
+/// Matches the mapping's columns with the header's, or maps every column of the header if there's no mapping.
+fn resolve(mapping: Option<&[ColumnSpec]>, header: &[String]) -> std::result::Result<Vec<Column>, String> {
+    let column = |index: usize, spec: &ColumnSpec| Column { index, name: spec.column.clone(), path: spec.field.split('.').map(str::to_owned).collect(), kind: spec.kind };
+
+    let Some(mapping) = mapping else {
+        return Ok(header.iter().enumerate()
+            .map(|(index, name)| column(index, &ColumnSpec { column: name.clone(), field: name.clone(), kind: ColumnType::Auto }))
+            .collect());
+    };
+
+    mapping.iter().map(|spec| match header.iter().position(|name| *name == spec.column) {
+        Some(index) => Ok(column(index, spec)),
+        None => Err(format!("The header has no column '{}'", spec.column)),
+    }).collect()
+}
+
+/// Builds a document from a row of cells. Empty cells are left out.
+fn csv_document(columns: &[Column], cells: &[String]) -> std::result::Result<Value, String> {
+    let mut document = Map::new();
+
+    for column in columns {
+        let cell = cells.get(column.index).map(String::as_str).unwrap_or_default();
+        if cell.is_empty() {
+            continue;
+        }
+
+        let value = match column.kind {
+            ColumnType::Auto => serde_json::from_str(cell).unwrap_or_else(|_| Value::String(cell.to_owned())),
+            ColumnType::String => Value::String(cell.to_owned()),
+            ColumnType::Number => cell.parse::<serde_json::Number>()
+                .map(Value::Number)
+                .map_err(|_| format!("'{}' in column '{}' is not a number", cell, column.name))?,
+            ColumnType::Boolean => cell.parse::<bool>()
+                .map(Value::Bool)
+                .map_err(|_| format!("'{}' in column '{}' is not true or false", cell, column.name))?,
+        };
+
+        let (last, parents) = column.path.split_last().unwrap_or((&column.name, &[]));
+        let mut object = &mut document;
+        for parent in parents {
+            let Value::Object(child) = object.entry(parent.clone()).or_insert_with(|| Value::Object(Map::new())) else {
+                return Err(format!("Column '{}' is mapped inside '{}', which another column already set", column.name, parent));
+            };
+
+            object = child;
+        }
+
+        if object.insert(last.clone(), value).is_some() {
+            return Err(format!("Column '{}' is mapped to a field which another column already set", column.name));
+        }
+    }
+
+    Ok(Value::Object(document))
+}
+
+/// Splits a body into rows as it arrives, ending each at a newline. In CSV, newlines within quoted cells don't end a row.
+///
+/// Rows longer than the document size limit aren't kept, so however long a row is, no more than the limit is ever held of it.
+struct Rows {
+    csv: bool,
+    quoted: bool,
+    row: Vec<u8>,
+    too_large: bool,
+    limit: usize,
+    count: usize,
+}
+
+/// A row, by its number in the body counting from 1, or `None` if it was too large to keep.
+type Row = (usize, Option<Vec<u8>>);
+
+impl Rows {
+    fn new(csv: bool, limit: usize) -> Self {
+        Self { csv, quoted: false, row: vec![], too_large: false, limit, count: 0 }
+    }
+
+    fn push(&mut self, chunk: &[u8], rows: &mut Vec<Row>) {
+        for byte in chunk {
+            if self.csv && *byte == b'"' {
+                self.quoted = !self.quoted;
+            }
+
+            if *byte == b'\n' && !self.quoted {
+                rows.extend(self.end());
+            } else if self.row.len() < self.limit {
+                self.row.push(*byte);
+            } else {
+                self.too_large = true;
+            }
+        }
+    }
+
+    /// Ends the current row, leaving out blank ones.
+    fn end(&mut self) -> Option<Row> {
+        self.count += 1;
+
+        let row = std::mem::take(&mut self.row);
+        let too_large = std::mem::replace(&mut self.too_large, false);
+
+        if too_large {
+            return Some((self.count, None));
+        }
+
+        let row = row.strip_suffix(b"\r").unwrap_or(&row);
+        (!row.trim_ascii().is_empty()).then(|| (self.count, Some(row.to_vec())))
+    }
+
+    /// The last row, if the body didn't end with a newline.
+    fn finish(mut self) -> Option<Row> {
+        self.end()
+    }
+}
+
+/// A row ready to be written.
+struct Valid {
+    row: usize,
+    key: ObjectKey,
+    data: Vec<u8>,
+
+    /// Whether the row named its own ID, and so replaces any document which already has it.
+    replaces: bool,
+}
+
+#[derive(Default, Serialize)]
+struct ImportReport {
+    imported: usize,
+    failed: usize,
+
+    /// The first row of the batch which couldn't be written, if the import stopped there. The batch was rolled back, so no row from this
+    /// one on was imported, while every row before it was unless it is counted as failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stopped_at: Option<usize>,
+
+    /// Why rows failed, for up to the first hundred of them.
+    errors: Vec<ApiError>,
+}
+
+impl ImportReport {
+    fn fail(&mut self, row: usize, error: ApiError) {
+        self.failed += 1;
+        if self.errors.len() < MAX_ERRORS {
+            self.errors.push(error.with("row", row));
+        }
+    }
+}
+
+/// How writing a batch turned out.
+enum Batch {
+    /// Every row was written, each listed with whether it was created.
+    Written(Vec<(usize, bool)>),
+
+    /// `row` couldn't be written, so the whole batch was rolled back.
+    RolledBack { row: usize, error: Error },
+}
+
+/// Writes every row of a batch with `write`, which returns whether it created the row's document, or none of them. If a row can't be
+/// written, the rows before it are rolled back. Only a failure to roll back is returned as an error.
+fn write_batch(store: &mut Store, rows: Vec<Valid>, mut write: impl FnMut(&mut Store, &Valid) -> Result<bool>) -> Result<Batch> {
+    let savepoint = store.savepoint();
+    let mut written = vec![];
+
+    for row in rows {
+        match write(store, &row) {
+            Ok(created) => written.push((row.row, created)),
+            Err(error) => {
+                // Nothing else can take the store until the batch is done, so nobody sees the rows which are undone.
+                store.rollback(savepoint)?;
+                store.flush()?;
+
+                return Ok(Batch::RolledBack { row: row.row, error });
+            },
+        }
+    }
+
+    Ok(Batch::Written(written))
+}
+
+/// Turns rows into documents and writes them a batch at a time.
+struct Importer<'a> {
+    store: Arc<Mutex<Store>>,
+    collection: String,
+    id: Option<String>,
+    mapping: Option<Vec<ColumnSpec>>,
+
+    /// The CSV header's columns, once the header has been read.
+    columns: Option<Vec<Column>>,
+    csv: bool,
+    config: &'a ServerConfig,
+    actor: String,
+    report: ImportReport,
+}
+
+impl Importer<'_> {
+    /// Reads a row as a document, returning it along with its ID if it has one.
+    fn document(&self, row: &[u8]) -> std::result::Result<(Vec<u8>, Option<String>), ApiError> {
+        let data = match &self.columns {
+            Some(columns) => {
+                let record = csv_record(row).map_err(|err| ApiError::bad_request("invalid_row", err))?;
+                let document = csv_document(columns, &record).map_err(|err| ApiError::bad_request("invalid_row", err))?;
+                serde_json::to_vec(&document).map_err(ApiError::internal)?
+            },
+            None => row.to_vec(),
+        };
+        check_document(&data, &self.config.documents).map_err(|err| ApiError::from(&err))?;
+
+        let Some(ref field) = self.id else {
+            return Ok((data, None));
+        };
+
+        let id = match serde_json::from_slice::<Value>(&data).map_err(ApiError::internal)?.pointer(&pointer(field)) {
+            Some(Value::String(id)) => id.clone(),
+            Some(Value::Number(id)) => id.to_string(),
+            _ => return Err(ApiError::bad_request("missing_id", format!("The document has no string or number '{}' to use as its ID", field))),
+        };
+
+        if id.is_empty() || id.contains(DELIMITER) {
+            return Err(ApiError::bad_request("invalid_id", format!("Document IDs can't be empty or contain '{}'", DELIMITER)));
+        }
+
+        Ok((data, Some(id)))
+    }
+
+    /// Writes a batch of rows, holding the store throughout so no other request sees part of the batch. Rows which can't be read are
+    /// reported and left out, and the rest are still written. A failure to write rolls the whole batch back and stops the import, which is
+    /// reported by returning `false`.
+    async fn import(&mut self, rows: Vec<Row>) -> actix_web::Result<bool> {
+        let Some(&(first, _)) = rows.first() else {
+            return Ok(true);
+        };
+
+        let mut valid = vec![];
+
+        for (row, data) in rows {
+            let Some(data) = data else {
+                self.report.fail(row, ApiError::from(&DocumentError::TooLarge { limit: self.config.documents.max_size }));
+                continue;
+            };
+
+            if self.csv && self.columns.is_none() {
+                let header = csv_record(&data).map_err(|err| ApiError::bad_request("invalid_header", err))?;
+                let columns = resolve(self.mapping.as_deref(), &header).map_err(|err| ApiError::bad_request("invalid_mapping", err))?;
+                self.columns = Some(columns);
+                continue;
+            }
+
+            let (data, id) = match self.document(&data) {
+                Ok(document) => document,
+                Err(error) => {
+                    self.report.fail(row, error);
+                    continue;
+                },
+            };
+
+            let replaces = id.is_some();
+            let id = match id {
+                Some(id) => id,
+                None => self.config.id_scheme.generate().await.map_err(ApiError::internal)?,
+            };
+
+            match ObjectKey::parse(format!("{}{}{}", self.collection, DELIMITER, id)) {
+                Ok(key) => valid.push(Valid { row, key, data, replaces }),
+                Err(err) => self.report.fail(row, ApiError::from(&err)),
+            }
+        }
+
+        let (store, actor) = (self.store.clone(), self.actor.clone());
+        let batch = telemetry::block(move || write_batch(&mut store.blocking_lock(), valid, |store, row| match row.replaces {
+            true => write_object(store, &row.key, JSON_CONTENT_TYPE, &row.data, None, Some(&actor)).map(|_| true),
+            false => create_object(store, &row.key, JSON_CONTENT_TYPE, &row.data, None, Some(&actor)).map(|created| created.is_some()),
+        }))
+            .await?
+            .map_err(ApiError::internal)?;
+
+        let written = match batch {
+            Batch::Written(written) => written,
+            Batch::RolledBack { row, error } => {
+                self.report.stopped_at = Some(first);
+                self.report.fail(row, ApiError::internal(error));
+                return Ok(false);
+            },
+        };
+
+        for (row, created) in written {
+            if created {
+                self.report.imported += 1;
+            } else {
+                // Only new IDs are created rather than written, and they are random enough that this should never happen.
+                self.report.fail(row, ApiError::conflict("document_exists", "A document with the ID made for this row already exists"));
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Reads a single CSV row into its cells.
+fn csv_record(row: &[u8]) -> std::result::Result<Vec<String>, String> {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(row);
+
+    match reader.records().next() {
+        Some(Ok(record)) => Ok(record.iter().map(str::to_owned).collect()),
+        Some(Err(err)) => Err(err.to_string()),
+        None => Ok(vec![]),
+    }
+}
+
+/// Adds the documents in the request body to a collection, reading them as they arrive rather than all at once.
+///
+/// The body holds one JSON document per line, or with `format=csv`, CSV whose first row names its columns, turned into documents by
+/// `mapping`. Rows are written 500 at a time, holding the store for each batch so no other request sees part of one. Rows which can't be
+/// read, or break the document limits, are reported by their number in the body and left out. A failure to write rolls its whole batch back
+/// and stops the import, though the batches before it stay written, and the report's `stopped_at` names the batch's first row. Any member
+/// who may write to the database may import into it.
+#[utoipa::path(
+    tag = "documents",
+    params(("id" = String, Path, description = "The database's ID"), ImportOptions),
+    request_body(content = String, description = "The documents, as JSON lines or CSV", content_type = "application/x-ndjson"),
+    responses((status = 200, description = "How many rows were imported, and why any failed"), (status = 400, description = "An invalid collection, mapping or CSV header"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/import")]
+pub async fn import_documents(id: web::Path<DatabaseID>, options: web::Query<ImportOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>, mut payload: web::Payload) -> actix_web::Result<impl Responder> {
+    let options = options.into_inner();
+    check_collection(&options.collection)?;
+
+    let Some(db) = member_database(&id, &user, &index, true).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let mapping = options.mapping.as_deref()
+        .map(parse_mapping)
+        .transpose()
+        .map_err(|err| ApiError::bad_request("invalid_mapping", err))?;
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let mut importer = Importer {
+        store,
+        collection: options.collection,
+        id: options.id,
+        mapping,
+        columns: None,
+        csv: options.format == ImportFormat::Csv,
+        config: &config,
+        actor: user.actor(),
+        report: ImportReport::default(),
+    };
+
+    let mut rows = Rows::new(importer.csv, config.documents.max_size);
+    let mut batch = vec![];
+
+    while let Some(chunk) = payload.next().await {
+        rows.push(&chunk.map_err(|_| DocumentError::Interrupted)?, &mut batch);
+
+        if batch.len() >= BATCH_ROWS && !importer.import(std::mem::take(&mut batch)).await? {
+            break;
+        }
+    }
+
+    if importer.report.stopped_at.is_none() {
+        batch.extend(rows.finish());
+        importer.import(batch).await?;
+    }
+
+    let report = importer.report;
+    let success = report.failed == 0;
+    Ok(ApiResponse::ok(report).succeeded(success))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_csv_rows_become_documents_by_the_mapping() {
+        let mut rows = Rows::new(true, 32);
+        let mut split = vec![];
+        rows.push(b"Name,Age,City\r\nAda,36,\"London,\nUK\"\n\n", &mut split);
+        rows.push(b"Bob,x,Paris\n", &mut split);
+        rows.push(&[b'a'; 40], &mut split);
+        rows.push(b"\nCy,", &mut split);
+        split.extend(rows.finish());
+
+        let numbers = split.iter().map(|(row, data)| (*row, data.is_some())).collect::<Vec<_>>();
+        assert_eq!(numbers, vec![(1, true), (2, true), (4, true), (5, false), (6, true)]);
+        assert_eq!(split[1].1.as_deref(), Some(&b"Ada,36,\"London,\nUK\""[..]));
+
+        let header = csv_record(split[0].1.as_ref().unwrap()).unwrap();
+        let mapping = parse_mapping("Name:name,Age:age:number,City:address.city").unwrap();
+        let columns = resolve(Some(&mapping), &header).unwrap();
+
+        let document = |row: usize| csv_document(&columns, &csv_record(split[row].1.as_ref().unwrap()).unwrap());
+        assert_eq!(document(1), Ok(json!({"name": "Ada", "age": 36, "address": {"city": "London,\nUK"}})));
+        assert!(document(2).is_err());
+        assert_eq!(document(4), Ok(json!({"name": "Cy"})));
+
+        // Without a mapping, every column is read as JSON where it can be.
+        let columns = resolve(None, &header).unwrap();
+        assert_eq!(csv_document(&columns, &["Ada".into(), "36".into(), "true".into()]), Ok(json!({"Name": "Ada", "Age": 36, "City": true})));
+
+        assert!(parse_mapping("Name:name:date").is_err());
+        assert!(parse_mapping("Name:name:string:extra").is_err());
+        assert!(resolve(Some(&parse_mapping("Missing").unwrap()), &header).is_err());
+        assert!(resolve(Some(&parse_mapping("Name:a,Age:a.b").unwrap()), &header).is_ok_and(|columns| csv_document(&columns, &["x".into(), "1".into()]).is_err()));
+    }
+
+    #[test]
+    pub fn test_a_batch_which_fails_is_rolled_back_whole() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("import-batch-test-{}.db", std::process::id()));
+        let mut store = Store::create(&path)?;
+        let valid = |row: usize| Valid { row, key: ObjectKey::parse(format!("people/{}", row)).unwrap(), data: b"{}".to_vec(), replaces: true };
+        let write = |store: &mut Store, row: &Valid| write_object(store, &row.key, JSON_CONTENT_TYPE, &row.data, None, None).map(|_| true);
+
+        assert!(matches!(write_batch(&mut store, vec![valid(1), valid(2)], write)?, Batch::Written(written) if written == vec![(1, true), (2, true)]));
+
+        let batch = write_batch(&mut store, vec![valid(3), valid(4), valid(5)], |store, row| match row.row {
+            5 => Err(ManualError::PreconditionFailed.into()),
+            _ => write(store, row),
+        })?;
+        assert!(matches!(batch, Batch::RolledBack { row: 5, .. }));
+
+        let directory = crate::keys::KeyDirectory::load(&mut store)?;
+        assert!(directory.get(&ObjectKey::parse("people/2").unwrap()).is_some());
+        assert!(directory.get(&ObjectKey::parse("people/3").unwrap()).is_none());
+
+        store.unlock(&path)?;
+        drop(store);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,9 +1,21 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
-use std::vec::IntoIter;
 use chrono::{DateTime, Utc};
+use fs2::FileExt;
 use tokio::sync::mpsc::Sender;
-use crate::{Args, DBIndex, Token, UserID, User};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::app::Scope;
+use crate::error::*;
+use crate::config::ServerConfig;
+use crate::embed::EmbedToken;
+use crate::resources::Access;
+use crate::webhooks::{self, Webhook};
+use crate::{AppID, Application, DBIndex, Database, DatabaseID, Quarantine, Token, UserID, User};
 
 pub enum DBIndexChange {
     UserLogin {
@@ -20,6 +32,36 @@ pub enum DBIndexChange {
     },
     RefreshUserToken { user: String, token: Token },
     Resync,
+    QuarantineDatabase { database: DatabaseID, reason: String },
+    ReleaseDatabase { database: DatabaseID },
+    /// Removes the user along with their apps and the databases they own. See [`crate::erasure::erase_user`].
+    EraseUser { user: UserID },
+    AddEmbed { database: DatabaseID, embed: EmbedToken },
+    RevokeEmbed { database: DatabaseID, embed: String },
+    SetQuota { database: DatabaseID, quota: Option<u64> },
+    RenameDatabase { database: DatabaseID, name: String },
+    /// Gives a user access to a database, replacing any they had. Takes it away if `access` is `None`.
+    SetMember { database: DatabaseID, user: UserID, access: Option<Access> },
+    DeleteDatabase { database: DatabaseID },
+    SetAppAccess { database: DatabaseID, app: AppID, granted: bool },
+    AddWebhook { database: DatabaseID, webhook: Webhook },
+    RemoveWebhook { database: DatabaseID, webhook: String },
+    /// Adds a database whose directory has already been created.
+    AddDatabase { database: Database },
+    AddApp { app: Application },
+    /// Replaces what the app's token may be used for.
+    SetAppScopes { app: AppID, scopes: BTreeSet<Scope>, databases: Option<BTreeSet<DatabaseID>> },
+    RecordActivity { user: UserID, at: DateTime<Utc> },
+    AddUser { user: User },
+    /// Replaces a service account's token hash from before tokens were keyed with its [`crate::hashing::hash`]. See
+    /// [`crate::hashing::legacy_hash`].
+    RehashServiceToken { user: UserID, token_hash: String },
+    /// Revokes every OAuth and API token the user holds, and a service account's token, without removing the user.
+    RevokeUserTokens { user: UserID },
+    /// Hands the databases and apps one user owns to another. See [`crate::users::transfer_ownership`].
+    TransferOwnership { from: UserID, to: UserID },
+    /// Persists every change queued before it, then stops accepting new changes.
+    Shutdown,
 }
 
 impl IntoIterator for DBIndexChange {
@@ -31,83 +73,259 @@ impl IntoIterator for DBIndexChange {
     }
 }
 
-static CHANGE_DB_INDEX: OnceLock<Sender<DBIndexChange>> = OnceLock::new();
+/// A group of changes which are applied together and persisted with a single write.
+struct ChangeBatch {
+    changes: Vec<DBIndexChange>,
+    ack: Option<oneshot::Sender<std::io::Result<()>>>,
+}
+
+static CHANGE_DB_INDEX: OnceLock<Sender<ChangeBatch>> = OnceLock::new();
+
+async fn send_batch(batch: ChangeBatch) -> bool {
+    let sent = CHANGE_DB_INDEX
+        .get()
+        .unwrap()
+        .send(batch)
+        .await
+        .is_ok();
+
+    if !sent {
+        log::error!("Index change was dropped because the server is shutting down");
+    }
+
+    sent
+}
 
+/// Queues changes to the index without waiting for them to be applied.
 pub async fn push_change(change: impl IntoIterator<Item = DBIndexChange>) {
-    for change in change {
-        CHANGE_DB_INDEX
-            .get()
-            .unwrap()
-            .send(change)
-            .await
-            .expect("Failed to send change");
+    send_batch(ChangeBatch {
+        changes: change.into_iter().collect(),
+        ack: None,
+    }).await;
+}
+
+/// Applies changes to the index and waits until they have been written to disk.
+/// Use this over [`push_change`] wherever a client must not be told about a change before it is durable, such as when tokens are invalidated.
+pub async fn commit_change(change: impl IntoIterator<Item = DBIndexChange>) -> Result<()> {
+    let (ack, persisted) = oneshot::channel();
+
+    if !send_batch(ChangeBatch {
+        changes: change.into_iter().collect(),
+        ack: Some(ack),
+    }).await {
+        return Err(ManualError::IndexChangeDropped.into());
     }
+
+    Ok(persisted.await.map_err(|_| ManualError::IndexChangeDropped)??)
 }
 
-pub fn handle_changes(args: Args, db: DBIndex) {
-    tokio::spawn(async move {
-        let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
-        CHANGE_DB_INDEX.set(sender).unwrap();
+/// Waits for all pending changes to be written to the index. No further changes are accepted afterwards.
+pub async fn shutdown(handler: JoinHandle<()>) {
+    push_change(DBIndexChange::Shutdown).await;
+
+    if let Err(err) = handler.await {
+        log::error!("Index change handler failed: {}", err);
+    }
+}
+
+/// The file beside `index.json` which is locked while the index is written. The index itself is replaced rather than written over, so the
+/// lock can't be held on it.
+pub const INDEX_LOCK_FILE: &str = "index.lock";
 
-        while let Some(change) = receiver.recv().await {
+/// Replaces `index.json` while holding an exclusive lock on [`INDEX_LOCK_FILE`]. Tools which read the index under a shared lock, such as
+/// `dbadmin backup-all`, hold changes back this way for as long as they need the index to stay as it is.
+///
+/// The new index is written in full and synced to disk beside the old one, and only then renamed over it, so a crash or a full disk part
+/// way through leaves the old index as it was. Once this returns, the new index survives a crash.
+pub async fn write_locked(path: PathBuf, data: String) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let lock = OpenOptions::new().write(true).create(true).truncate(false).open(path.with_file_name(INDEX_LOCK_FILE))?;
+        FileExt::lock_exclusive(&lock)?;
+
+        let partial = path.with_extension("json.partial");
+        let mut file = File::create(&partial)?;
+
+        // The index holds token hashes, so the new one is readable by no more than the old one was.
+        if let Ok(meta) = std::fs::metadata(&path) {
+            file.set_permissions(meta.permissions())?;
+        }
+
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&partial, &path)?;
+
+        // The rename is only durable once the directory holding the index is synced too.
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+
+        FileExt::unlock(&lock)
+    }).await?
+}
+
+pub fn handle_changes(config: ServerConfig, db: DBIndex) -> JoinHandle<()> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
+    CHANGE_DB_INDEX.set(sender).unwrap();
+
+    tokio::spawn(async move {
+        while let Some(batch) = receiver.recv().await {
             let mut db = db.lock().await;
+            let watched = webhooks::snapshot(&db);
 
-            match change {
-                DBIndexChange::UserLogin {
-                    oauth_token,
-                    oauth_refresh,
-                    oauth_expiry,
-                    user,
-                    api_token,
-                    refresh_token,
-                    expiry,
-                } =>
-                    if let Some(user) = db.users.iter_mut().find(|i| i.id.eq(&user)) {
-                        user.oauth.push(Token {
-                            token: oauth_token,
-                            refresh: oauth_refresh,
-                            expiry: oauth_expiry,
-                        });
-                        user.api.push(Token {
-                            token: api_token,
-                            refresh: refresh_token,
-                            expiry,
+            for change in batch.changes {
+                match change {
+                    DBIndexChange::UserLogin {
+                        oauth_token,
+                        oauth_refresh,
+                        oauth_expiry,
+                        user,
+                        api_token,
+                        refresh_token,
+                        expiry,
+                    } =>
+                        if let Some(user) = db.users.iter_mut().find(|i| i.id.eq(&user)) {
+                            user.oauth.push(Token::issued(oauth_token, oauth_refresh, oauth_expiry));
+                            user.api.push(Token::issued(api_token, refresh_token, expiry));
+                        } else {
+                            db.users.push(User {
+                                id: user.clone(),
+                                oauth: vec![Token::issued(oauth_token, oauth_refresh, oauth_expiry)],
+                                api: vec![Token::issued(api_token, refresh_token, expiry)],
+                                last_active: None,
+                                service: None,
+                            })
+                        },
+                    DBIndexChange::InvalidateUserToken { token } => for user in db.users
+                        .iter_mut() {
+
+                        user.api.retain(|i| !i.token.eq(&token.token));
+                    },
+                    DBIndexChange::RefreshUserToken { user: user_id, token } => if let Some(user) = db.users.iter_mut().find(|user| user.id == user_id) {
+                        user.api.push(token);
+                    },
+                    DBIndexChange::QuarantineDatabase { database, reason } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.quarantine = Some(Quarantine {
+                            since: Utc::now(),
+                            reason,
                         });
-                    } else {
-                        db.users.push(User {
-                            id: user.clone(),
-                            oauth: vec![Token {
-                                token: oauth_token,
-                                refresh: oauth_refresh,
-                                expiry: oauth_expiry,
-                            }],
-                            api: vec![Token {
-                                token: api_token,
-                                refresh: refresh_token,
-                                expiry,
-                            }],
-                        })
-                    },
-                DBIndexChange::InvalidateUserToken { token } => for user in db.users
-                    .iter_mut() {
-
-                    user.api.retain(|i| !i.token.eq(&token.token));
-                },
-                DBIndexChange::RefreshUserToken { user: user_id, token } => if let Some(user) = db.users.iter_mut().find(|user| user.id == user_id) {
-                    user.api.push(token);
-                },
-                DBIndexChange::Resync => ()
-            }
+                    },
+                    DBIndexChange::ReleaseDatabase { database } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.quarantine = None;
+                    },
+                    DBIndexChange::EraseUser { user } => crate::erasure::erase_user(&mut db, &user),
+                    DBIndexChange::AddEmbed { database, embed } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.embeds.push(embed);
+                    },
+                    DBIndexChange::RevokeEmbed { database, embed } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.embeds.retain(|existing| existing.id != embed);
+                    },
+                    DBIndexChange::SetQuota { database, quota } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.quota = quota;
+                    },
+                    DBIndexChange::RenameDatabase { database, name } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.name = name;
+                    },
+                    DBIndexChange::SetMember { database, user, access } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.rw.retain(|member| *member != user);
+                        db.ro.retain(|member| *member != user);
+
+                        match access {
+                            Some(Access::ReadWrite) => db.rw.push(user),
+                            Some(Access::ReadOnly) => db.ro.push(user),
+                            None => (),
+                        }
+                    },
+                    DBIndexChange::SetAppAccess { database, app, granted } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.apps.retain(|existing| *existing != app);
 
-            match serde_json::to_string_pretty(db.deref()) {
-                Ok(data) =>
-                    if let Err(err) = tokio::fs::write(args.database_dir.join("index.json"), data).await {
-                        log::error!("Failed to write database index: {}", err);
+                        if granted {
+                            db.apps.push(app);
+                        }
+                    },
+                    DBIndexChange::DeleteDatabase { database } => db.databases.retain(|db| db.id != database),
+                    DBIndexChange::AddWebhook { database, webhook } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.webhooks.push(webhook);
+                    },
+                    DBIndexChange::RemoveWebhook { database, webhook } => if let Some(db) = db.databases.iter_mut().find(|db| db.id == database) {
+                        db.webhooks.retain(|existing| existing.id != webhook);
+                    },
+                    DBIndexChange::AddDatabase { database } => db.databases.push(database),
+                    DBIndexChange::AddApp { app } => db.apps.push(app),
+                    DBIndexChange::SetAppScopes { app, scopes, databases } => if let Some(app) = db.apps.iter_mut().find(|i| i.id == app) {
+                        app.scopes = scopes;
+                        app.databases = databases;
+                    },
+                    DBIndexChange::RecordActivity { user, at } => if let Some(user) = db.users.iter_mut().find(|i| i.id == user) {
+                        user.last_active = Some(at);
+                    },
+                    DBIndexChange::RevokeUserTokens { user } => if let Some(user) = db.users.iter_mut().find(|i| i.id == user) {
+                        user.oauth.clear();
+                        user.api.clear();
+
+                        if let Some(ref mut service) = user.service {
+                            service.token_hash = None;
+                        }
+                    },
+                    DBIndexChange::RehashServiceToken { user, token_hash } => {
+                        // The token may have been revoked in the meantime, and a revoked token mustn't come back.
+                        let service = db.users.iter_mut().find(|i| i.id == user).and_then(|user| user.service.as_mut());
+                        if let Some(stored) = service.and_then(|service| service.token_hash.as_mut()).filter(|stored| !crate::hashing::is_hashed(stored)) {
+                            *stored = token_hash;
+                        }
                     },
-                Err(e) => {
-                    log::error!("Failed to write database index: {}", e);
+                    DBIndexChange::AddUser { user } => db.users.push(user),
+                    DBIndexChange::TransferOwnership { from, to } => crate::users::transfer_ownership(&mut db, &from, &to),
+                    DBIndexChange::Resync => (),
+                    DBIndexChange::Shutdown => receiver.close(),
                 }
             }
+
+            // Changes carry new tokens in plaintext, so they are hashed before the index is written.
+            crate::hashing::hash_all(&mut db);
+
+            let result = match crate::schema::write_index(db.deref()) {
+                Ok(data) => write_locked(config.database_dir.join("index.json"), data).await,
+                Err(e) => Err(e.into()),
+            };
+
+            if let Err(ref err) = result {
+                log::error!("Failed to write database index: {}", err);
+            }
+
+            webhooks::emit(webhooks::changes(&watched, &db));
+
+            if let Some(ack) = batch.ack {
+                let _ = ack.send(result);
+            }
         }
-    });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_index_is_replaced_whole() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("index-write-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("index.json");
+
+        actix_web::rt::System::new().block_on(async {
+            write_locked(path.clone(), "{\"version\": 3}".to_owned()).await?;
+            write_locked(path.clone(), "{}".to_owned()).await
+        })?;
+
+        assert_eq!(std::fs::read_to_string(&path)?, "{}");
+        assert!(dir.join(INDEX_LOCK_FILE).exists());
+        assert!(!path.with_extension("json.partial").exists());
+
+        // A reader holding the lock keeps the index as it is, even though the index itself is replaced.
+        let lock = File::open(dir.join(INDEX_LOCK_FILE))?;
+        FileExt::lock_shared(&lock)?;
+        assert!(FileExt::try_lock_exclusive(&File::open(dir.join(INDEX_LOCK_FILE))?).is_err());
+        FileExt::unlock(&lock)?;
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,577 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Read;
+use std::ops::Bound;
+use std::ops::Deref;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use libdb::error::FragmentError;
+use libdb::AllocOptions;
+use libdb::FragmentID;
+use serde::{Deserialize, Serialize};
+use crate::error::*;
+use crate::pool::Store;
+use crate::changelog::ChangeLog;
+use crate::secondary::SecondaryIndex;
+use crate::trash::TrashedObject;
+use crate::versions::{Version, VersionPolicy};
+use crate::response::ApiError;
+
+/// The longest key an object may have, in bytes.
+pub const MAX_KEY_LEN: usize = 1024;
+
+/// Separates the levels of a key's hierarchy, so `photos/2024/beach.jpg` lives in the `photos/2024/` folder.
+pub const DELIMITER: char = '/';
+
+/// The fragment holding the key directory of each store. Objects are given IDs after it.
+pub const DIRECTORY_FRAGMENT: FragmentID = 1;
+
+/// The name of an object within a database.
+///
+/// Keys are made of ASCII letters, digits and the characters `!-_.*'()`, with `/` separating levels of the hierarchy. Keys may be at most
+/// [`MAX_KEY_LEN`] bytes long, and every level must be non-empty and must not be `.` or `..`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct ObjectKey(String);
+
+impl ObjectKey {
+    pub fn parse(key: impl Into<String>) -> std::result::Result<Self, KeyError> {
+        let key = key.into();
+
+        check_characters(&key)?;
+
+        if key.is_empty() {
+            return Err(KeyError::Empty);
+        }
+
+        for segment in key.split(DELIMITER) {
+            if segment.is_empty() {
+                return Err(KeyError::EmptySegment);
+            }
+
+            if segment == "." || segment == ".." {
+                return Err(KeyError::RelativeSegment);
+            }
+        }
+
+        Ok(Self(key))
+    }
+}
+
+impl Deref for ObjectKey {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for ObjectKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '!' | '-' | '_' | '.' | '*' | '\'' | '(' | ')' | DELIMITER)
+}
+
+fn check_characters(key: &str) -> std::result::Result<(), KeyError> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(KeyError::TooLong { limit: MAX_KEY_LEN });
+    }
+
+    match key.chars().find(|c| !is_key_char(*c)) {
+        Some(c) => Err(KeyError::InvalidCharacter(c)),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether a content type matches a filter such as `text/plain` or `image/*`. Parameters such as `charset` are ignored.
+fn matches_content_type(content_type: &str, filter: &str) -> bool {
+    let essence = |mime: &str| mime.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let (content_type, filter) = (essence(content_type), essence(filter));
+
+    match filter.strip_suffix("/*") {
+        Some(family) => content_type.split('/').next() == Some(family),
+        None => content_type == filter,
+    }
+}
+
+/// The collection an object belongs to, which is the first level of its key. Objects at the top level belong to none.
+pub fn collection(key: &str) -> Option<&str> {
+    key.split_once(DELIMITER).map(|(collection, _)| collection)
+}
+
+/// Checks that a listing prefix could be the start of a valid key. Unlike keys, prefixes may be empty and may end in the delimiter.
+pub fn check_prefix(prefix: &str) -> std::result::Result<(), KeyError> {
+    check_characters(prefix)
+}
+
+impl ResponseError for KeyError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiError::from(self).error_response()
+    }
+}
+
+impl From<&KeyError> for ApiError {
+    fn from(err: &KeyError) -> Self {
+        ApiError::bad_request("invalid_key", match err {
+            KeyError::Empty => "Keys may not be empty".to_owned(),
+            KeyError::TooLong { limit } => format!("Keys may be at most {} bytes long", limit),
+            KeyError::InvalidCharacter(c) => format!("'{}' is not allowed in keys", c),
+            KeyError::EmptySegment => format!("Keys may not start or end with '{0}', or contain '{0}{0}'", DELIMITER),
+            KeyError::RelativeSegment => "Keys may not contain '.' or '..' as a level".to_owned(),
+        })
+    }
+}
+
+/// The content type given to objects written without one.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// What the directory knows about each object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectMeta {
+    /// The fragment holding the object's contents.
+    pub id: FragmentID,
+
+    /// The MIME type the object was written with.
+    pub content_type: String,
+
+    /// The fragment holding the dictionary the object's contents were compressed against, if they were compressed. See
+    /// [`crate::dictionary`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<FragmentID>,
+
+    /// The object's superseded versions, oldest first, if its collection keeps history. See [`crate::versions`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Version>,
+
+    /// When the object expires, if it was written with a time-to-live. Expired objects are treated as missing until [`crate::ttl`] removes
+    /// them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl ObjectMeta {
+    pub fn expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Every fragment holding the object's contents or history. Dictionaries are shared by the whole collection, so they aren't included.
+    pub fn fragments(&self) -> impl Iterator<Item = FragmentID> + '_ {
+        std::iter::once(self.id).chain(self.history.iter().map(|version| version.fragment))
+    }
+}
+
+/// Maps the keys of a store's objects to the fragments holding them, along with their metadata. Stored as JSON in [`DIRECTORY_FRAGMENT`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyDirectory {
+    next_id: FragmentID,
+
+    #[serde(deserialize_with = "deserialize_keys")]
+    keys: BTreeMap<String, ObjectMeta>,
+
+    /// The fragment holding the compression dictionary of each collection which has one.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dictionaries: BTreeMap<String, FragmentID>,
+
+    /// The secondary indexes of each collection which has any, by name. See [`crate::secondary`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    indexes: BTreeMap<String, BTreeMap<String, SecondaryIndex>>,
+
+    /// How each collection which keeps history does so.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    versioning: BTreeMap<String, VersionPolicy>,
+
+    /// Deleted objects which can still be restored, by key. See [`crate::trash`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    trash: BTreeMap<String, TrashedObject>,
+
+    /// Every change to the store's objects. See [`crate::changelog`].
+    #[serde(default, skip_serializing_if = "ChangeLog::is_empty")]
+    changelog: ChangeLog,
+}
+
+/// An entry of the directory as it may be stored. Directories written before objects had content types mapped each key straight to the
+/// fragment holding it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredMeta {
+    Fragment(FragmentID),
+    Meta(ObjectMeta),
+}
+
+impl From<StoredMeta> for ObjectMeta {
+    fn from(stored: StoredMeta) -> Self {
+        match stored {
+            StoredMeta::Fragment(id) => ObjectMeta {
+                id,
+                content_type: DEFAULT_CONTENT_TYPE.to_owned(),
+                dictionary: None,
+                history: vec![],
+                expires: None,
+            },
+            StoredMeta::Meta(meta) => meta,
+        }
+    }
+}
+
+fn deserialize_keys<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<BTreeMap<String, ObjectMeta>, D::Error> {
+    let keys = BTreeMap::<String, StoredMeta>::deserialize(deserializer)?;
+    Ok(keys.into_iter().map(|(key, stored)| (key, stored.into())).collect())
+}
+
+impl Default for KeyDirectory {
+    fn default() -> Self {
+        Self {
+            next_id: DIRECTORY_FRAGMENT + 1,
+            keys: BTreeMap::new(),
+            dictionaries: BTreeMap::new(),
+            indexes: BTreeMap::new(),
+            versioning: BTreeMap::new(),
+            trash: BTreeMap::new(),
+            changelog: ChangeLog::default(),
+        }
+    }
+}
+
+/// The objects and folders found directly under a prefix.
+#[derive(Debug, Default, Serialize)]
+pub struct Listing {
+    pub keys: Vec<String>,
+
+    /// Every distinct prefix ending at the first delimiter after the listing's prefix. Objects under these prefixes are not listed in `keys`.
+    pub common_prefixes: Vec<String>,
+}
+
+impl KeyDirectory {
+    /// Reads the store's directory, or returns an empty one if nothing has been written to the store yet.
+    pub fn load(store: &mut Store) -> Result<Self> {
+        let mut fragment = match store.open_fragment(DIRECTORY_FRAGMENT) {
+            Ok(fragment) => fragment,
+            Err(err) if matches!(err.inner(), libdb::error::global::Inner::FragmentError(FragmentError::NoFound(_))) => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut data = vec![];
+        fragment.read_to_end(&mut data)?;
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Writes the directory back to the store as the next sequence of its fragment.
+    pub fn save(&self, store: &mut Store) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+        store.write_fragment(AllocOptions::default().fragment(DIRECTORY_FRAGMENT), &data)?;
+
+        Ok(())
+    }
+
+    /// The object at `key`, unless there is none or it has expired.
+    pub fn get(&self, key: &ObjectKey) -> Option<&ObjectMeta> {
+        self.keys.get(&key.0).filter(|meta| !meta.expired_at(Utc::now()))
+    }
+
+    /// Whether there is an object at `key` which hasn't expired.
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.get(key).is_some_and(|meta| !meta.expired_at(Utc::now()))
+    }
+
+    /// Returns the fragment for the key, assigning it a new one if the key doesn't exist yet, and records its content type.
+    /// The flag is set if the directory changed.
+    pub fn get_or_insert(&mut self, key: &ObjectKey, content_type: &str) -> (FragmentID, bool) {
+        if let Some(meta) = self.keys.get_mut(&key.0) {
+            let changed = meta.content_type != content_type;
+            meta.content_type = content_type.to_owned();
+
+            return (meta.id, changed);
+        }
+
+        let id = self.allocate_id();
+        self.keys.insert(key.0.clone(), ObjectMeta {
+            id,
+            content_type: content_type.to_owned(),
+            dictionary: None,
+            history: vec![],
+            expires: None,
+        });
+
+        (id, true)
+    }
+
+    /// Records which dictionary the object at `key` was compressed against, if any. Returns whether the directory changed.
+    pub fn set_dictionary(&mut self, key: &ObjectKey, dictionary: Option<FragmentID>) -> bool {
+        match self.keys.get_mut(&key.0) {
+            Some(meta) if meta.dictionary != dictionary => {
+                meta.dictionary = dictionary;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// The fragment holding the compression dictionary of `collection`, if it has one.
+    pub fn collection_dictionary(&self, collection: &str) -> Option<FragmentID> {
+        self.dictionaries.get(collection).copied()
+    }
+
+    /// Sets aside a fragment for a new dictionary of `collection`. Objects compressed against the previous one keep using it.
+    pub fn new_collection_dictionary(&mut self, collection: &str) -> FragmentID {
+        let id = self.allocate_id();
+        self.dictionaries.insert(collection.to_owned(), id);
+
+        id
+    }
+
+    pub fn index(&self, collection: &str, name: &str) -> Option<&SecondaryIndex> {
+        self.indexes.get(collection).and_then(|indexes| indexes.get(name))
+    }
+
+    /// An index of `collection` over the field at `pointer`, if it has one.
+    pub fn index_on(&self, collection: &str, pointer: &str) -> Option<&SecondaryIndex> {
+        self.indexes.get(collection).and_then(|indexes| indexes.values().find(|index| index.field == pointer))
+    }
+
+    /// Every secondary index of every collection.
+    pub fn indexes(&self) -> impl Iterator<Item = &SecondaryIndex> {
+        self.indexes.values().flat_map(|indexes| indexes.values())
+    }
+
+    pub fn indexes_mut(&mut self, collection: &str) -> impl Iterator<Item = &mut SecondaryIndex> {
+        self.indexes.get_mut(collection).into_iter().flat_map(|indexes| indexes.values_mut())
+    }
+
+    /// Declares the index `name` of `collection`, replacing any index of the same name.
+    pub fn set_index(&mut self, collection: &str, name: &str, index: SecondaryIndex) {
+        self.indexes.entry(collection.to_owned()).or_default().insert(name.to_owned(), index);
+    }
+
+    /// Removes the index `name` of `collection`. Returns whether there was one.
+    pub fn remove_index(&mut self, collection: &str, name: &str) -> bool {
+        let Some(indexes) = self.indexes.get_mut(collection) else {
+            return false;
+        };
+
+        let removed = indexes.remove(name).is_some();
+        if indexes.is_empty() {
+            self.indexes.remove(collection);
+        }
+
+        removed
+    }
+
+    pub fn version_policy(&self, collection: &str) -> Option<VersionPolicy> {
+        self.versioning.get(collection).copied()
+    }
+
+    /// Sets how `collection` keeps history. A policy keeping no versions is the same as none.
+    pub fn set_version_policy(&mut self, collection: &str, policy: VersionPolicy) {
+        match policy.keep {
+            0 => self.versioning.remove(collection),
+            _ => self.versioning.insert(collection.to_owned(), policy),
+        };
+    }
+
+    /// Replaces the superseded versions recorded for the object at `key`.
+    pub fn set_history(&mut self, key: &ObjectKey, history: Vec<Version>) {
+        if let Some(meta) = self.keys.get_mut(&key.0) {
+            meta.history = history;
+        }
+    }
+
+    /// Sets when the object at `key` expires, or that it never does. Returns whether the directory changed.
+    pub fn set_expiry(&mut self, key: &ObjectKey, expires: Option<DateTime<Utc>>) -> bool {
+        match self.keys.get_mut(&key.0) {
+            Some(meta) if meta.expires != expires => {
+                meta.expires = expires;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Forgets the object at `key`, returning what was known about it. Its fragments are left for the caller to delete once the directory
+    /// has been saved.
+    pub fn remove(&mut self, key: &str) -> Option<ObjectMeta> {
+        self.keys.remove(key)
+    }
+
+    /// Moves the object at `key` into the trash, unless there is no such object. Returns the fragments of the object it displaces from the
+    /// trash, if one of the same key was already there. They should only be deleted once the directory has been saved.
+    pub fn discard(&mut self, key: &ObjectKey, deleted: DateTime<Utc>) -> Option<Vec<FragmentID>> {
+        let meta = self.get(key)?.clone();
+        self.keys.remove(&key.0);
+
+        let displaced = self.trash.insert(key.0.clone(), TrashedObject { meta, deleted });
+        Some(displaced.map_or_else(Vec::new, |trashed| trashed.meta.fragments().collect()))
+    }
+
+    /// The objects in the trash, in order of key.
+    pub fn trashed(&self) -> impl Iterator<Item = (&str, &TrashedObject)> {
+        self.trash.iter().map(|(key, trashed)| (key.as_str(), trashed))
+    }
+
+    /// Takes the object at `key` back out of the trash. The caller must make sure the key isn't taken first.
+    pub fn untrash(&mut self, key: &ObjectKey) -> Option<ObjectMeta> {
+        let trashed = self.trash.remove(&key.0)?;
+        self.keys.insert(key.0.clone(), trashed.meta.clone());
+
+        Some(trashed.meta)
+    }
+
+    /// Empties the trash of objects deleted before `before`, and returns the fragments they held.
+    pub fn purge_trash(&mut self, before: DateTime<Utc>) -> Vec<FragmentID> {
+        let (purged, kept) = std::mem::take(&mut self.trash).into_iter().partition::<BTreeMap<_, _>, _>(|(_, trashed)| trashed.deleted < before);
+        self.trash = kept;
+
+        purged.values().flat_map(|trashed| trashed.meta.fragments()).collect()
+    }
+
+    /// The keys of every object which had expired by `now`.
+    pub fn expired(&self, now: DateTime<Utc>) -> Vec<String> {
+        self.keys.iter()
+            .filter(|(_, meta)| meta.expired_at(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn changelog(&self) -> &ChangeLog {
+        &self.changelog
+    }
+
+    pub fn changelog_mut(&mut self) -> &mut ChangeLog {
+        &mut self.changelog
+    }
+
+    /// Hands out a fragment which no object, dictionary or version has had before.
+    pub fn allocate_id(&mut self) -> FragmentID {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        id
+    }
+
+    /// The objects whose keys start with `prefix`, in order of key. Expired objects are left out.
+    pub fn objects(&self, prefix: &str) -> impl Iterator<Item = (&str, &ObjectMeta)> {
+        self.objects_from(prefix, Bound::Included(prefix))
+    }
+
+    /// Like [`KeyDirectory::objects`], but only the objects after `after`, the last key listed so far, so a listing can carry on from where
+    /// it stopped even if that key has since been removed.
+    pub fn objects_after<'a, 'b>(&'a self, prefix: &'b str, after: &str) -> impl Iterator<Item = (&'a str, &'a ObjectMeta)> + use<'a, 'b> {
+        self.objects_from(prefix, Bound::Excluded(after))
+    }
+
+    fn objects_from<'a, 'b>(&'a self, prefix: &'b str, from: Bound<&str>) -> impl Iterator<Item = (&'a str, &'a ObjectMeta)> + use<'a, 'b> {
+        let now = Utc::now();
+
+        self.keys.range::<str, _>((from, Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .filter(move |(_, meta)| !meta.expired_at(now))
+            .map(|(key, meta)| (key.as_str(), meta))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.objects("").map(|(key, _)| key)
+    }
+
+    /// Lists the keys starting with `prefix`. If a delimiter is given, keys containing it after the prefix are rolled up into
+    /// `common_prefixes`, so each level of the hierarchy can be browsed like a folder.
+    ///
+    /// If `content_type` is given, only objects of that type are listed. It may name a whole family of types, such as `image/*`.
+    pub fn list(&self, prefix: &str, delimiter: Option<&str>, content_type: Option<&str>) -> Listing {
+        let mut listing = Listing::default();
+
+        let keys = self.objects(prefix)
+            .filter(|(_, meta)| content_type.is_none_or(|filter| matches_content_type(&meta.content_type, filter)))
+            .map(|(key, _)| key);
+
+        for key in keys {
+            let rest = &key[prefix.len()..];
+
+            match delimiter.filter(|delimiter| !delimiter.is_empty()).and_then(|delimiter| rest.find(delimiter).map(|i| i + delimiter.len())) {
+                Some(end) => {
+                    let common = &key[..prefix.len() + end];
+
+                    // Keys are sorted, so every key sharing a prefix is adjacent.
+                    if listing.common_prefixes.last().is_none_or(|last| last != common) {
+                        listing.common_prefixes.push(common.to_owned());
+                    }
+                },
+                None => listing.keys.push(key.to_owned()),
+            }
+        }
+
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_invalid_keys_and_prefixes_are_rejected() {
+        assert!(ObjectKey::parse("photos/2024/beach.jpg").is_ok());
+        assert!(ObjectKey::parse("a!-_.*'()b").is_ok());
+
+        assert!(matches!(ObjectKey::parse(""), Err(KeyError::Empty)));
+        assert!(matches!(ObjectKey::parse("a//b"), Err(KeyError::EmptySegment)));
+        assert!(matches!(ObjectKey::parse("/a"), Err(KeyError::EmptySegment)));
+        assert!(matches!(ObjectKey::parse("a/"), Err(KeyError::EmptySegment)));
+        assert!(matches!(ObjectKey::parse(".."), Err(KeyError::RelativeSegment)));
+        assert!(matches!(ObjectKey::parse("a/../b"), Err(KeyError::RelativeSegment)));
+        assert!(matches!(ObjectKey::parse("a/./b"), Err(KeyError::RelativeSegment)));
+        assert!(matches!(ObjectKey::parse("a\nb"), Err(KeyError::InvalidCharacter('\n'))));
+        assert!(matches!(ObjectKey::parse("a\u{0}b"), Err(KeyError::InvalidCharacter('\u{0}'))));
+        assert!(matches!(ObjectKey::parse("a b"), Err(KeyError::InvalidCharacter(' '))));
+
+        assert!(ObjectKey::parse("a".repeat(MAX_KEY_LEN)).is_ok());
+        assert!(matches!(ObjectKey::parse("a".repeat(MAX_KEY_LEN + 1)), Err(KeyError::TooLong { limit: MAX_KEY_LEN })));
+
+        // Prefixes may be empty and may end in the delimiter, but are held to the same characters and length.
+        assert!(check_prefix("").is_ok());
+        assert!(check_prefix("photos/").is_ok());
+        assert!(matches!(check_prefix("photos\t"), Err(KeyError::InvalidCharacter('\t'))));
+        assert!(matches!(check_prefix(&"a".repeat(MAX_KEY_LEN + 1)), Err(KeyError::TooLong { .. })));
+    }
+
+    #[test]
+    pub fn test_listings_group_keys_under_the_delimiter() {
+        let mut directory = KeyDirectory::default();
+        for key in ["photos/2024/beach.jpg", "photos/2024/sea.jpg", "photos/2025/snow.jpg", "photos/cover.jpg", "notes.txt"] {
+            directory.get_or_insert(&ObjectKey::parse(key).unwrap(), DEFAULT_CONTENT_TYPE);
+        }
+
+        let listing = directory.list("", Some("/"), None);
+        assert_eq!(listing.keys, vec!["notes.txt"]);
+        assert_eq!(listing.common_prefixes, vec!["photos/"]);
+
+        let listing = directory.list("photos/", Some("/"), None);
+        assert_eq!(listing.keys, vec!["photos/cover.jpg"]);
+        assert_eq!(listing.common_prefixes, vec!["photos/2024/", "photos/2025/"]);
+
+        // Without a delimiter, everything under the prefix is listed flat.
+        let listing = directory.list("photos/2024/", None, None);
+        assert_eq!(listing.keys, vec!["photos/2024/beach.jpg", "photos/2024/sea.jpg"]);
+        assert!(listing.common_prefixes.is_empty());
+
+        assert!(directory.list("videos/", Some("/"), None).keys.is_empty());
+    }
+
+    #[test]
+    pub fn test_directories_from_before_content_types_still_load() {
+        let directory = serde_json::from_str::<KeyDirectory>(r#"{"next_id": 4, "keys": {"a": 2, "b": {"id": 3, "content_type": "text/plain"}}}"#).unwrap();
+
+        let old = directory.get(&ObjectKey::parse("a").unwrap()).unwrap();
+        assert_eq!((old.id, old.content_type.as_str()), (2, DEFAULT_CONTENT_TYPE));
+
+        let new = directory.get(&ObjectKey::parse("b").unwrap()).unwrap();
+        assert_eq!((new.id, new.content_type.as_str()), (3, "text/plain"));
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(duration_constructors_lite)]
-
 mod resources;
 mod error;
 mod oauth;
@@ -7,58 +5,97 @@ mod index;
 mod db;
 mod auth;
 mod app;
+mod redact;
+mod pool;
+mod config;
+mod query;
+mod document;
+mod tls;
+mod keys;
+mod ratelimit;
+mod search;
+mod admin;
+mod fixture;
+mod schema;
+mod paging;
+mod ids;
+mod verify;
+mod metrics;
+mod access;
+mod erasure;
+mod backup;
+mod alerts;
+mod embed;
+mod quota;
+mod webhooks;
+mod acl;
+mod provision;
+mod dictionary;
+mod secondary;
+mod patch;
+mod documents;
+mod versions;
+mod usage;
+mod delegation;
+mod ttl;
+mod trash;
+mod replication;
+mod changelog;
+mod deliveries;
+mod users;
+mod hashing;
+mod cors;
+mod openapi;
+mod response;
+mod format;
+mod s3;
+mod telemetry;
+mod request_id;
+mod object_cache;
+mod import;
+mod export;
 
 use crate::error::*;
-use actix_web::dev::{Payload, Service, ServiceRequest};
-use actix_web::http::StatusCode;
-use actix_web::{middleware, web, HttpMessage};
+use crate::config::Args;
+use crate::config::ServerConfig;
+use crate::pool::DbPool;
+use crate::ratelimit::RateLimiter;
+use crate::access::AccessLog;
+use actix_web::middleware;
+use actix_web::web;
 use actix_web::App;
-use actix_web::Error;
-use actix_web::FromRequest;
-use actix_web::HttpRequest;
-use actix_web::HttpResponse;
 use actix_web::HttpServer;
 use base64::Engine;
 use chrono::DateTime;
 use chrono::Utc;
 use clap::Parser;
-use futures::future::{BoxFuture, LocalBoxFuture};
-use futures::FutureExt;
 use rand::TryRngCore;
 use serde::Deserialize;
 use serde::Serialize;
-use serde_json::json;
-use std::net::SocketAddr;
+use std::collections::BTreeSet;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::LazyLock;
-use std::sync::MutexGuard;
-use std::sync::OnceLock;
-use std::task::{Context, Poll};
-use actix_web::error::HttpError;
-use actix_web::http::header::HeaderValue;
-use futures::stream::LocalBoxStream;
-use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
-#[derive(clap::Parser, Clone)]
-struct Args {
-    #[clap(default_value = "0.0.0.0:2003")]
-    address: SocketAddr,
-
-    #[clap(long = "database")]
-    database_dir: PathBuf,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+/// The contents of `index.json`. It is read and written through [`schema`], which takes care of its version.
+///
+/// Fields added to this or any of the structures below must have a default, so indices written by older versions can still be read.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DatabaseIndex {
+    #[serde(default)]
     pub databases: Vec<Database>,
+    #[serde(default)]
     pub apps: Vec<Application>,
+    #[serde(default)]
     pub users: Vec<User>,
     pub oauth_settings: OAuthSettings,
 }
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Database {
     pub name: String,
     pub id: DatabaseID,
@@ -68,27 +105,60 @@ pub struct Database {
     pub pages: Vec<Page>,
     pub root: PathBuf,
     pub owner: UserID,
+    /// Set once the database's store has repeatedly failed to open. Quarantined databases aren't opened until they are repaired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine: Option<Quarantine>,
+    /// The most bytes the database's store may take up on disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    /// Tokens granting read-only access to parts of the database, for embedding its objects in other pages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embeds: Vec<embed::EmbedToken>,
+    /// URLs told whenever the database is shared, unshared, renamed or deleted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<webhooks::Webhook>,
+    /// The page size the database's store is created with, if not libdb's default. It can't change once the store exists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<u32>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quarantine {
+    pub since: DateTime<Utc>,
+    pub reason: String,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Page {
     pub name: String,
     pub content: PathBuf,
     pub type_hint: String,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Application {
     pub name: String,
     pub id: AppID,
     pub owner: UserID,
     pub token: Token,
+    /// What the app's token may be used for. Apps from before tokens had scopes may do everything.
+    #[serde(default = "app::Scope::all")]
+    pub scopes: BTreeSet<app::Scope>,
+    /// Limits the app to these of the databases it is attached to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub databases: Option<BTreeSet<DatabaseID>>,
 }
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct User {
     pub oauth: Vec<Token>,
     pub api: Vec<Token>,
     pub id: UserID,
+    /// When the user last presented one of their API tokens, to within [`auth::ACTIVITY_RESOLUTION`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_active: Option<DateTime<Utc>>,
+    /// Set if the user is a service account, which authenticates with a long-lived token instead of signing in through OAuth.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<users::ServiceAccount>,
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct OAuthSettings {
     pub client_id: String,
     pub client_secret: String,
@@ -116,69 +186,251 @@ impl Deref for DBIndex {
 impl DBIndex {
     pub async fn serialise(&self) -> serde_json::Result<String> {
         let db = self.lock().await;
-        serde_json::to_string_pretty(&db.deref())
+        schema::write_index(db.deref())
     }
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
-    let args = Args::parse();
-    let mut db = DatabaseIndex {
-        databases: vec![],
-        apps: vec![],
-        users: vec![],
-        oauth_settings: OAuthSettings {
-            client_id: "".to_string(),
-            client_secret: "".to_string(),
-            redirect: "".to_string(),
-            authorisation: "".to_string(),
-            token: "".to_string(),
-        },
-    };
+    let config = ServerConfig::load(Args::parse())?;
 
-    let index = args.database_dir.join("index.json");
+    let _telemetry = telemetry::init(&config)?;
+
+    if config.replication.replicate_from.is_some() {
+        return replication::follow(config).await;
+    }
+
+    let mut db = fixture::IndexFixture::new().root(&config.database_dir).build();
+
+    let index = config.database_dir.join("index.json");
 
     if !index.exists() {
-        tokio::fs::create_dir_all(&args.database_dir).await?;
-        tokio::fs::write(&index, serde_json::to_string_pretty(&db)?).await?;
+        tokio::fs::create_dir_all(&config.database_dir).await?;
+        index::write_locked(index.clone(), schema::write_index(&db)?).await?;
     } else {
-        let data = tokio::fs::read_to_string(&args.database_dir.join("index.json")).await?;
-        db = serde_json::from_str(&data)?;
+        let data = tokio::fs::read_to_string(&config.database_dir.join("index.json")).await?;
+        db = schema::read_index(&data)?;
+    }
+
+    hashing::init(&config.database_dir).await?;
+
+    let migrated = hashing::hash_all(&mut db);
+    if migrated > 0 {
+        index::write_locked(index.clone(), schema::write_index(&db)?).await?;
+        log::info!("Hashed {} tokens which were stored in plaintext", migrated);
     }
 
-    let oauth_settings = db.oauth_settings.clone();
+    let oauth_settings = config.oauth.clone().unwrap_or_else(|| db.oauth_settings.clone());
     let db = DBIndex(Arc::new(Mutex::new(db)));
-    index::handle_changes(args.clone(), db.clone());
+    let changes = index::handle_changes(config.clone(), db.clone());
+    let alerts = alerts::start(config.alerts.clone());
+    let webhooks = webhooks::start();
+    let pool = DbPool::new(config.stores.clone());
+
+    if config.stores.verify_on_start {
+        log::info!("Verifying stores before accepting connections");
+
+        if let Err(err) = verify::verify_stores(&pool, &db, config.stores.verify_sample, &config.maintenance).await {
+            pool.close_all().await;
+            index::shutdown(changes).await;
+            return Err(err);
+        }
+    }
+
+    let usage = pool.clone();
+    let mut persist_usage = tokio::time::interval(config.stores.usage_persist_interval());
+    tokio::spawn(async move {
+        loop {
+            persist_usage.tick().await;
+            usage.persist_usage().await;
+        }
+    });
+
+    let deliveries = deliveries::start(db.clone(), pool.clone());
 
-    let addr = args.address;
+    let reaper = pool.clone();
+    let maintenance = config.maintenance.clone();
+    let mut reap = tokio::time::interval(config.stores.reap_interval());
+    tokio::spawn(async move {
+        for pass in 0.. {
+            reap.tick().await;
+            reaper.reap(&maintenance, pass).await;
+        }
+    });
 
-    HttpServer::new(move || {
+    let checkpointer = pool.clone();
+    let stores = config.stores.clone();
+    let maintenance = config.maintenance.clone();
+    tokio::spawn(async move {
+        let mut pass = 0;
+        while let Some(delay) = stores.checkpoint_delay() {
+            tokio::time::sleep(delay).await;
+            checkpointer.checkpoint_all(&maintenance, pass).await;
+            pass += 1;
+        }
+    });
+
+    let addr = config.address;
+    let workers = config.workers;
+    let tls = config.tls.clone();
+    let stores = pool.clone();
+    let limiter = web::Data::new(RateLimiter::new(config.rate_limit.clone()));
+    let (access_log, access_writer) = match config.access_log.clone().map(AccessLog::start).transpose()? {
+        Some((log, writer)) => (Some(web::Data::new(log)), Some(writer)),
+        None => (None, None),
+    };
+
+    let api = openapi::ApiDoc::openapi();
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(oauth_settings.clone()))
             .app_data(web::Data::new(reqwest::Client::new()))
             .app_data(web::Data::new(db.clone()))
-            .app_data(web::Data::new(args.clone()))
+            .app_data(web::Data::new(config.clone()))
+            .app_data(web::JsonConfig::default().limit(config.max_body_size).error_handler(response::json_error))
+            .app_data(web::QueryConfig::default().error_handler(response::query_error))
+            .app_data(web::PayloadConfig::new(config.max_body_size))
+            .app_data(web::Data::new(stores.clone()))
+            .app_data(limiter.clone())
+            .configure(|cfg| if let Some(ref access_log) = access_log {
+                cfg.app_data(access_log.clone());
+            })
+            .wrap(middleware::from_fn(ratelimit::rate_limit))
+            .wrap(middleware::from_fn(cors::cors))
+            .wrap(middleware::from_fn(access::log_access))
+            .wrap(middleware::from_fn(format::negotiate))
+            .wrap(middleware::from_fn(telemetry::trace_request))
+            .wrap(middleware::from_fn(request_id::assign))
             .service(oauth::oauth)
             .service(oauth::refresh_token)
             .service(oauth::get_oauth_details)
             .service(resources::get_databases)
             .service(resources::create_database)
+            .service(resources::rename_database)
+            .service(resources::set_member)
+            .service(resources::delete_database)
+            .service(resources::get_stats)
+            .service(resources::checkpoint_database)
+            .service(acl::set_acl)
+            .service(provision::provision)
+            .service(resources::get_tokens)
+            .service(db::query)
+            .service(db::batch)
+            .service(db::list_objects)
+            .service(db::train_dictionary)
+            .service(db::create_index)
+            .service(db::delete_index)
+            .service(db::query_index)
+            .service(db::set_versioning)
+            .service(db::get_history)
+            .service(db::get_metadata)
+            .service(documents::create_document)
+            .service(import::import_documents)
+            .service(export::export_documents)
+            .service(documents::find_documents)
+            .service(db::get_object)
+            .service(db::put_object)
+            .service(db::patch_object)
+            .service(db::post_object)
+            .service(db::delete_object)
+            .service(trash::list_trash)
+            .service(trash::restore_object)
+            .service(changelog::get_changes)
+            .service(search::search)
+            .service(admin::repair_database)
+            .service(quota::get_quota)
+            .service(quota::set_quota)
+            .service(webhooks::create_webhook)
+            .service(webhooks::list_webhooks)
+            .service(webhooks::delete_webhook)
+            .service(backup::backup_database)
+            .service(backup::restore_database)
+            .service(embed::create_embed)
+            .service(embed::list_embeds)
+            .service(embed::revoke_embed)
+            .service(embed::get_embedded_object)
+            .service(delegation::create_delegation)
+            .service(metrics::get_metrics)
+            .service(replication::replicate_index)
+            .service(replication::replicate_key)
+            .service(replication::stream)
+            .service(erasure::erase_user_data)
+            .service(users::list_users)
+            .service(users::revoke_tokens)
+            .service(users::delete_user)
+            .service(users::create_service_account)
+            .service(s3::list_buckets)
+            .service(s3::head_bucket)
+            .service(s3::list_objects)
+            .service(s3::put_object)
+            .service(s3::get_object)
+            .service(s3::delete_object)
+            .service(SwaggerUi::new(openapi::SWAGGER_UI_PATH).url(openapi::OPENAPI_PATH, api.clone()))
     })
-        .workers(1)
-        .bind(addr)?
-        .run()
-        .await?;
+        .workers(workers)
+        .disable_signals();
+
+    let server = match tls {
+        Some(ref tls) => server.bind_rustls_0_23(addr, tls::server_config(tls)?)?,
+        None => server.bind(addr)?,
+    }
+        .run();
+
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutdown requested. No longer accepting connections.");
+        handle.stop(true).await;
+    });
+
+    server.await?;
+
+    // The server has stopped accepting connections and in-flight requests have completed by now.
+    log::info!("Shutting down");
+    index::shutdown(changes).await;
+    deliveries.abort();
+    pool.close_all().await;
+    webhooks::shutdown(webhooks).await;
+
+    if let Some(alerts) = alerts {
+        alerts::shutdown(alerts).await;
+    }
+
+    // The server has dropped its handles to the access log, so the writer finishes once it has written what was queued.
+    if let Some(writer) = access_writer {
+        let _ = writer.await;
+    }
 
     Ok(())
 }
 
+/// Resolves once the process receives either Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::error!("Failed to listen for SIGTERM: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
 pub async fn generate_token(len: usize) -> Result<String> {
+    Ok(base64::engine::general_purpose::STANDARD.encode(random_bytes(len).await?))
+}
+
+pub async fn random_bytes(len: usize) -> Result<Vec<u8>> {
     let mut rng = RNG.lock().await;
-    let mut token = vec![0; len];
-    rng.try_fill_bytes(&mut token)?;
-    Ok(base64::engine::general_purpose::STANDARD.encode(&token))
+    let mut bytes = vec![0; len];
+    rng.try_fill_bytes(&mut bytes)?;
+    Ok(bytes)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -186,4 +438,21 @@ pub struct Token {
     pub token: String,
     pub refresh: String,
     pub expiry: DateTime<Utc>,
+
+    /// The first few characters of the token, kept beside its hash so the user can still tell their tokens apart. Recorded when the token
+    /// is issued, or failing that when it is hashed, so tokens hashed before hints were kept have none. See [`redact::TOKEN_PREFIX_LEN`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Token {
+    /// A token as it is issued, still in plaintext, with its hint recorded from it.
+    pub fn issued(token: String, refresh: String, expiry: DateTime<Utc>) -> Self {
+        Self {
+            hint: Some(token.chars().take(redact::TOKEN_PREFIX_LEN).collect()),
+            token,
+            refresh,
+            expiry,
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use libdb::metrics::{Operation, StoreMetrics};
+use crate::config::ServerConfig;
+use crate::pool::{DbPool, STORE_FILE};
+use crate::{DBIndex, DatabaseID};
+
+/// The upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.00001, 0.000025, 0.0001, 0.00025, 0.001, 0.0025, 0.01, 0.025, 0.1, 0.25, 1.0, 2.5];
+
+#[derive(Default)]
+struct Histogram {
+    /// How many observations fell into each bucket, but none before it. Observations beyond the last bucket are only counted in `count`.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The latency of each kind of operation on a single database's store.
+#[derive(Default)]
+pub struct StoreLatency {
+    histograms: [Histogram; Operation::ALL.len()],
+}
+
+impl StoreMetrics for StoreLatency {
+    fn observe(&self, operation: Operation, elapsed: Duration) {
+        self.histograms[operation as usize].observe(elapsed);
+    }
+}
+
+/// The storage engine's latency histograms for every database, exported in Prometheus' text format.
+///
+/// A database's histograms outlive its store being closed and reopened, so they only ever grow, as Prometheus expects.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    stores: Arc<Mutex<BTreeMap<DatabaseID, Arc<StoreLatency>>>>,
+}
+
+impl Metrics {
+    /// The histograms to install in the database's store when it is opened.
+    pub fn store(&self, id: &DatabaseID) -> Arc<StoreLatency> {
+        self.stores.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(id.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Renders the latency histograms, followed by the size and state of each store in `stores`.
+    pub fn render(&self, stores: &BTreeMap<DatabaseID, StoreStatus>) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP libdb_operation_duration_seconds Time taken by operations on a database's store.");
+        let _ = writeln!(out, "# TYPE libdb_operation_duration_seconds histogram");
+
+        for (id, latency) in self.stores.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            for operation in Operation::ALL {
+                let histogram = &latency.histograms[operation as usize];
+                let labels = format!("database=\"{}\",operation=\"{}\"", escape_label(id), operation.name());
+
+                let mut cumulative = 0;
+                for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+                    cumulative += bucket.load(Ordering::Relaxed);
+                    let _ = writeln!(out, "libdb_operation_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative);
+                }
+
+                let count = histogram.count.load(Ordering::Relaxed);
+                let sum = Duration::from_nanos(histogram.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+
+                let _ = writeln!(out, "libdb_operation_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, count);
+                let _ = writeln!(out, "libdb_operation_duration_seconds_sum{{{}}} {}", labels, sum);
+                let _ = writeln!(out, "libdb_operation_duration_seconds_count{{{}}} {}", labels, count);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP libdb_store_size_bytes Size of a database's store on disk.");
+        let _ = writeln!(out, "# TYPE libdb_store_size_bytes gauge");
+
+        for (id, status) in stores.iter() {
+            if let Some(size) = status.size {
+                let _ = writeln!(out, "libdb_store_size_bytes{{database=\"{}\"}} {}", escape_label(id), size);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP libdb_store_open Whether the server has a database's store open.");
+        let _ = writeln!(out, "# TYPE libdb_store_open gauge");
+
+        for (id, status) in stores.iter() {
+            let _ = writeln!(out, "libdb_store_open{{database=\"{}\"}} {}", escape_label(id), status.open as u8);
+        }
+
+        out
+    }
+}
+
+/// What the metrics report about a database's store besides its latency.
+#[derive(Debug, Default)]
+pub struct StoreStatus {
+    /// The size of the store file, or nothing if it hasn't been created yet.
+    pub size: Option<u64>,
+    pub open: bool,
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics to Prometheus. Disabled unless `metrics.enabled` is set, and guarded by `metrics.token` if there is one.
+#[utoipa::path(
+    tag = "metrics",
+    responses((status = 200, description = "Metrics in the Prometheus text format"), (status = 404, description = "Metrics are not enabled")),
+)]
+#[get("/metrics")]
+pub async fn get_metrics(req: HttpRequest, config: web::Data<ServerConfig>, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> impl Responder {
+    if !config.metrics.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+
+    if let Some(ref token) = config.metrics.token {
+        let presented = req.headers().get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+
+        if presented != Some(token.as_str()) {
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let open = pool.open_stores().await;
+    let stores = index.lock().await.databases.iter()
+        .map(|db| (db.id.clone(), StoreStatus {
+            size: std::fs::metadata(db.root.join(STORE_FILE)).ok().map(|meta| meta.len()),
+            open: open.contains(&db.id),
+        }))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut rendered = pool.metrics().render(&stores);
+    pool.object_cache().render(&mut rendered);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        let store = metrics.store(&"db\"1".to_owned());
+
+        store.observe(Operation::Read, Duration::from_micros(50));
+        store.observe(Operation::Read, Duration::from_millis(5));
+        store.observe(Operation::Read, Duration::from_secs(10));
+
+        let stores = BTreeMap::from([("db\"1".to_owned(), StoreStatus { size: Some(8192), open: true })]);
+        let rendered = metrics.render(&stores);
+        let read = |suffix: &str| rendered.lines()
+            .find(|line| line.contains("operation=\"read\"") && line.contains(suffix))
+            .map(|line| line.rsplit(' ').next().unwrap().to_owned());
+
+        assert_eq!(read("le=\"0.0001\"").as_deref(), Some("1"));
+        assert_eq!(read("le=\"0.01\"").as_deref(), Some("2"));
+        assert_eq!(read("le=\"2.5\"").as_deref(), Some("2"));
+        assert_eq!(read("le=\"+Inf\"").as_deref(), Some("3"));
+        assert!(rendered.contains("database=\"db\\\"1\""));
+        assert!(rendered.contains("libdb_store_size_bytes{database=\"db\\\"1\"} 8192"));
+        assert!(rendered.contains("libdb_store_open{database=\"db\\\"1\"} 1"));
+    }
+}
@@ -1,5 +1,9 @@
+use crate::config::ServerConfig;
 use crate::generate_token;
-use crate::index::push_change;
+use crate::hashing;
+use crate::redact;
+use crate::response::{ApiError, ApiResponse};
+use crate::index::commit_change;
 use crate::index::DBIndexChange;
 use crate::DBIndex;
 use crate::OAuthSettings;
@@ -7,15 +11,18 @@ use crate::Token;
 use actix_web::get;
 use actix_web::post;
 use actix_web::web;
+use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
 use actix_web::Responder;
 use chrono::DateTime;
 use serde::Deserialize;
+use serde::Serialize;
 use serde_json::json;
+use utoipa::ToSchema;
 use std::time::Duration;
 use std::time::SystemTime;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 struct OAuthCode {
     code: String,
 }
@@ -28,8 +35,24 @@ struct OAuthResponse {
     user_id: String,
 }
 
+/// A new API token, along with the refresh token which replaces it.
+#[derive(Serialize)]
+struct Issued {
+    token: String,
+    refresh: String,
+    expires_in: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+#[utoipa::path(
+    tag = "oauth",
+    request_body = OAuthCode,
+    responses((status = 200, description = "An API token for the signed in user"), (status = 400, description = "The code was refused by the provider")),
+)]
 #[post("/oauth")]
-pub async fn oauth(index: web::Data<OAuthSettings>, body: web::Json<OAuthCode>, client: web::Data<reqwest::Client>) -> actix_web::Result<impl Responder> {
+pub async fn oauth(index: web::Data<OAuthSettings>, body: web::Json<OAuthCode>, client: web::Data<reqwest::Client>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
     let oauth_response: OAuthResponse = match client
         .post(&index.token)
         .json(&json! {{
@@ -44,114 +67,101 @@ pub async fn oauth(index: web::Data<OAuthSettings>, body: web::Json<OAuthCode>,
     {
         Ok(resp) => match resp.json().await {
             Ok(json) => json,
-            Err(err) => return Ok(HttpResponse::BadRequest().json(json! {{
-                "success": false,
-                "error": err.to_string()
-            }})),
+            Err(err) => return Err(ApiError::bad_request("oauth_failed", err.to_string()).into()),
         },
-        Err(err) =>
-            return Ok(HttpResponse::BadRequest().json(json! {{
-                "success": false,
-                "error": err.to_string()
-            }})),
+        Err(err) => return Err(ApiError::bad_request("oauth_failed", err.to_string()).into()),
     };
 
     let (token, refresh) = futures::future::join(generate_token(64), generate_token(128)).await;
 
-    let token = token.map_err(|err| {
-        actix_web::error::ErrorInternalServerError(json! {{
-            "success": false,
-            "error": err.to_string()
-        }})
-    })?;
-    let refresh = refresh.map_err(|err| {
-        actix_web::error::ErrorInternalServerError(json! {{
-            "success": false,
-            "error": err.to_string()
-        }})
-    })?;
+    let token = token.map_err(ApiError::internal)?;
+    let refresh = refresh.map_err(ApiError::internal)?;
 
-    push_change(DBIndexChange::UserLogin {
+    // The client will use the token straight away, so it must be known to the index before we hand it out.
+    commit_change(DBIndexChange::UserLogin {
         oauth_token: oauth_response.access_token.clone(),
         oauth_refresh: oauth_response.refresh_token.clone(),
         oauth_expiry: DateTime::from(SystemTime::now() + Duration::from_secs(oauth_response.expires_in)),
         user: oauth_response.user_id.clone(),
         api_token: token.clone(),
         refresh_token: refresh.clone(),
-        expiry: DateTime::from(SystemTime::now() + Duration::from_hours(12)),
+        expiry: DateTime::from(SystemTime::now() + config.tokens.lifetime()),
     })
-    .await;
-
-    Ok(HttpResponse::Ok().json(json! {{
-        "success": true,
-        "token": token,
-        "refresh": refresh,
-        "expires_in": 3600,
-        "user": oauth_response.user_id,
-    }}))
+    .await
+    .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Issued { token, refresh, expires_in: config.tokens.lifetime, user: Some(oauth_response.user_id) }))
+}
+
+/// The publicly visible subset of the [`OAuthSettings`]. Anything a client doesn't need to begin the authorisation flow is redacted.
+#[derive(Debug, Serialize)]
+struct OAuthDetails<'a> {
+    client_id: &'a str,
+    redirect: &'a str,
+    #[serde(serialize_with = "redact::secret")]
+    token: &'a str,
+    authorisation: &'a str,
 }
 
+#[utoipa::path(
+    tag = "oauth",
+    responses((status = 200, description = "Where to send users to sign in")),
+)]
 #[get("/oauth")]
 pub async fn get_oauth_details(settings: web::Data<OAuthSettings>) -> actix_web::Result<impl Responder> {
-    Ok(web::Json(json! {{
-        "client_id": settings.client_id,
-        "redirect": settings.redirect,
-        "token": settings.token,
-        "authorisation": settings.authorisation,
-    }}))
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(OAuthDetails {
+        client_id: &settings.client_id,
+        redirect: &settings.redirect,
+        token: &settings.token,
+        authorisation: &settings.authorisation,
+    })))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 struct RefreshTokenRequest {
     refresh: String,
 }
 
+#[utoipa::path(
+    tag = "oauth",
+    request_body = RefreshTokenRequest,
+    responses((status = 200, description = "A new API token"), (status = 401, description = "The refresh token is invalid or expired")),
+)]
 #[post("/refresh")]
-pub async fn refresh_token(body: web::Json<RefreshTokenRequest>, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
-    let index = index.lock().await;
+pub async fn refresh_token(body: web::Json<RefreshTokenRequest>, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let refresh = hashing::hash(&body.refresh);
     let Some((user, token)) = index
+        .lock()
+        .await
         .users
         .iter()
         .filter_map(|user| {
             user.api
                 .iter()
-                .find(|token| token.refresh == body.refresh)
-                .map(|token| (user, token.clone()))
+                .find(|token| token.refresh == refresh)
+                .map(|token| (user.id.clone(), token.clone()))
         })
         .next()
     else {
-        return Ok(HttpResponse::Unauthorized().json(json! {{
-            "success": false,
-            "error": "Invalid refresh token"
-        }}));
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid refresh token").into());
     };
 
     let (new_token, new_refresh) = match futures::future::join(generate_token(64), generate_token(128)).await {
         (Ok(token), Ok(refresh)) => (token, refresh),
-        _ =>
-            return Ok(HttpResponse::InternalServerError().json(json! {{
-                "success": false,
-                "error": "Failed to generate new token"
-            }})),
+        _ => return Err(ApiError::internal("Failed to generate new token").into()),
     };
 
-    push_change([
+    // The old token must be revoked for good before the client is told it has been replaced.
+    if let Err(err) = commit_change([
         DBIndexChange::InvalidateUserToken { token: token.clone() },
         DBIndexChange::RefreshUserToken {
-            user: user.id.clone(),
-            token: Token {
-                token: new_token.clone(),
-                refresh: new_refresh.clone(),
-                expiry: DateTime::from(SystemTime::now() + Duration::from_hours(12)),
-            },
+            user,
+            token: Token::issued(new_token.clone(), new_refresh.clone(), DateTime::from(SystemTime::now() + config.tokens.lifetime())),
         },
     ])
-    .await;
-
-    Ok(HttpResponse::Ok().json(json! {{
-        "success": true,
-        "token": new_token,
-        "refresh": new_refresh,
-        "expires_in": Duration::from_hours(12),
-    }}))
+    .await {
+        return Err(ApiError::internal(err).into());
+    }
+
+    Ok(ApiResponse::ok(Issued { token: new_token, refresh: new_refresh, expires_in: config.tokens.lifetime, user: None }))
 }
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use actix_web::http::header::EntityTag;
+use actix_web::web::Bytes;
+use libdb::FragmentID;
+use crate::changelog;
+use crate::dictionary;
+use crate::error::*;
+use crate::keys::{KeyDirectory, ObjectKey, ObjectMeta, DIRECTORY_FRAGMENT};
+use crate::pool::Store;
+use crate::query::etag;
+use crate::DatabaseID;
+
+/// The most changes read from a database's changelog to find the objects to evict. If more than this have been made since the cache last
+/// looked, everything it holds of the database is evicted instead.
+const MAX_CHANGES: usize = 1000;
+
+/// Which write of an object a cached copy was read from: the fragment holding it and that fragment's sequence. Rewriting an object always
+/// changes one or the other.
+pub type Version = (FragmentID, u64);
+
+/// An object as it was when it was read.
+#[derive(Debug, Clone)]
+pub struct CachedObject {
+    pub meta: ObjectMeta,
+    pub etag: EntityTag,
+    pub data: Bytes,
+}
+
+impl CachedObject {
+    /// Roughly how much memory the object takes up in the cache.
+    fn size(&self) -> u64 {
+        (self.data.len() + self.meta.content_type.len()) as u64
+    }
+}
+
+struct Entry {
+    version: Version,
+    object: CachedObject,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<(DatabaseID, String), Entry>,
+
+    /// The key of every entry by when it was last used, least recently first.
+    recency: BTreeMap<u64, (DatabaseID, String)>,
+    clock: u64,
+    size: u64,
+
+    /// The newest change in each database's changelog which the cache has accounted for.
+    seen: HashMap<DatabaseID, u64>,
+
+    /// Each database's key directory as the cache last parsed it, along with the sequence of its fragment it was read from.
+    directories: HashMap<DatabaseID, (u64, Arc<KeyDirectory>)>,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &(DatabaseID, String)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.size -= entry.object.size();
+        }
+    }
+}
+
+/// Keeps recently read objects in memory, so hot objects aren't read back from their store on every request.
+///
+/// Each copy is kept with the [`Version`] it was read from, and is only served to a reader which finds the object at that version, so a copy
+/// can never be served after the object has changed. Copies of changed objects are evicted as soon as the database's changelog shows the
+/// change, rather than waiting to be pushed out. Once the cache holds more than its budget, the least recently read copies are evicted.
+///
+/// The cache also keeps each database's key directory, parsed, until the directory is rewritten. A hit therefore reads nothing from the
+/// store, and only looks up the sequences of the directory and the object in the fragment table the store keeps in memory.
+pub struct ObjectCache {
+    budget: u64,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ObjectCache {
+    /// A cache holding up to `budget` bytes of objects. A budget of 0 turns it off.
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            lru: Default::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn lru(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The copy of the object at `version`, if the cache has one.
+    pub fn get(&self, database: &str, object: &str, version: Version) -> Option<CachedObject> {
+        let mut lru = self.lru();
+        let key = (database.to_owned(), object.to_owned());
+
+        let found = match lru.entries.get(&key) {
+            Some(entry) if entry.version == version => {
+                let used = lru.clock;
+                lru.clock += 1;
+
+                let entry = lru.entries.get_mut(&key)?;
+                let last_used = std::mem::replace(&mut entry.used, used);
+                let object = entry.object.clone();
+
+                lru.recency.remove(&last_used);
+                lru.recency.insert(used, key);
+
+                Some(object)
+            },
+            Some(_) => {
+                lru.remove(&key);
+                None
+            },
+            None => None,
+        };
+
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        found
+    }
+
+    /// Keeps a copy of the object as read at `version`, evicting the least recently read copies to stay within the budget. Objects larger
+    /// than a quarter of the budget aren't kept, so a single large object can't push everything else out.
+    pub fn insert(&self, database: &str, object: &str, version: Version, copy: CachedObject) {
+        if copy.size() > self.budget / 4 {
+            return;
+        }
+
+        let mut lru = self.lru();
+        let key = (database.to_owned(), object.to_owned());
+        lru.remove(&key);
+
+        let used = lru.clock;
+        lru.clock += 1;
+        lru.size += copy.size();
+        lru.recency.insert(used, key.clone());
+        lru.entries.insert(key, Entry { version, object: copy, used });
+
+        while lru.size > self.budget && let Some((_, oldest)) = lru.recency.first_key_value() {
+            let oldest = oldest.clone();
+            lru.remove(&oldest);
+        }
+    }
+
+    /// Evicts every object of the database, such as when its store is closed or replaced.
+    pub fn forget(&self, database: &str) {
+        let mut lru = self.lru();
+
+        let keys = lru.entries.keys().filter(|(db, _)| db == database).cloned().collect::<Vec<_>>();
+        for key in keys {
+            lru.remove(&key);
+        }
+
+        lru.seen.remove(database);
+        lru.directories.remove(database);
+    }
+
+    /// Evicts the objects which changed since the cache last looked at the database's changelog.
+    fn follow(&self, database: &str, store: &mut Store, directory: &KeyDirectory) -> Result<()> {
+        let last = directory.changelog().last();
+        let Some(seen) = self.lru().seen.insert(database.to_owned(), last) else {
+            // Nothing can have been cached before the cache first looked.
+            return Ok(());
+        };
+
+        if seen == last {
+            return Ok(());
+        }
+
+        let changes = changelog::read(store, seen, MAX_CHANGES)?;
+        if changes.truncated || changes.next < last {
+            self.forget(database);
+            self.lru().seen.insert(database.to_owned(), last);
+            return Ok(());
+        }
+
+        let mut lru = self.lru();
+        for change in changes.changes {
+            lru.remove(&(database.to_owned(), change.object));
+        }
+
+        Ok(())
+    }
+
+    /// The database's key directory. Every change to an object rewrites the directory, so it is only read back and parsed once its fragment
+    /// has a new sequence, and the objects which changed are evicted then.
+    fn directory(&self, database: &str, store: &mut Store) -> Result<Arc<KeyDirectory>> {
+        // Stores nothing was written to yet have no directory to keep.
+        let Ok(sequence) = store.fragment_info(DIRECTORY_FRAGMENT).map(|info| info.sequence) else {
+            return Ok(Arc::new(KeyDirectory::load(store)?));
+        };
+
+        if let Some((parsed, directory)) = self.lru().directories.get(database) && *parsed == sequence {
+            return Ok(directory.clone());
+        }
+
+        let directory = Arc::new(KeyDirectory::load(store)?);
+        self.follow(database, store, &directory)?;
+        self.lru().directories.insert(database.to_owned(), (sequence, directory.clone()));
+
+        Ok(directory)
+    }
+
+    /// Reads the whole of the object at `key`, from the cache if it holds the object's current version, or from the store otherwise. Returns
+    /// `None` if there is no such object.
+    pub fn read(&self, database: &str, store: &mut Store, key: &ObjectKey) -> Result<Option<CachedObject>> {
+        let directory = match self.budget > 0 {
+            true => self.directory(database, store)?,
+            false => Arc::new(KeyDirectory::load(store)?),
+        };
+
+        let Some(meta) = directory.get(key).cloned() else {
+            return Ok(None);
+        };
+
+        let version = (meta.id, store.fragment_info(meta.id)?.sequence);
+        if self.budget > 0 && let Some(object) = self.get(database, key, version) {
+            return Ok(Some(object));
+        }
+
+        let object = CachedObject {
+            etag: etag(store, meta.id)?,
+            data: Bytes::from(dictionary::read_contents(store, &meta)?),
+            meta,
+        };
+
+        if self.budget > 0 {
+            self.insert(database, key, version, object.clone());
+        }
+
+        Ok(Some(object))
+    }
+
+    /// Renders the cache's hit and miss counts and size in Prometheus' text format.
+    pub fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP object_cache_hits_total Object reads answered from the object cache.");
+        let _ = writeln!(out, "# TYPE object_cache_hits_total counter");
+        let _ = writeln!(out, "object_cache_hits_total {}", self.hits.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP object_cache_misses_total Object reads the object cache couldn't answer.");
+        let _ = writeln!(out, "# TYPE object_cache_misses_total counter");
+        let _ = writeln!(out, "object_cache_misses_total {}", self.misses.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP object_cache_bytes Bytes of objects held in the object cache.");
+        let _ = writeln!(out, "# TYPE object_cache_bytes gauge");
+        let _ = writeln!(out, "object_cache_bytes {}", self.lru().size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(data: &'static [u8]) -> CachedObject {
+        CachedObject {
+            meta: ObjectMeta { id: 1, content_type: String::new(), dictionary: None, history: vec![], expires: None },
+            etag: EntityTag::new_strong("1-1".to_owned()),
+            data: Bytes::from_static(data),
+        }
+    }
+
+    #[test]
+    pub fn test_only_current_copies_are_served_within_the_budget() {
+        let cache = ObjectCache::new(40);
+        let db = "db1".to_owned();
+
+        cache.insert(&db, "a", (1, 1), copy(b"0123456789"));
+        cache.insert(&db, "b", (2, 1), copy(b"0123456789"));
+        assert!(cache.get(&db, "a", (1, 1)).is_some());
+
+        // The object was rewritten since, so the copy is stale.
+        assert!(cache.get(&db, "b", (2, 2)).is_none());
+        assert!(cache.get(&db, "b", (2, 1)).is_none());
+
+        // Too large for a quarter of the budget.
+        cache.insert(&db, "c", (3, 1), copy(b"0123456789a"));
+        assert!(cache.get(&db, "c", (3, 1)).is_none());
+
+        // `e` pushes the cache over its budget, and `a` was read least recently.
+        cache.insert(&db, "b", (2, 2), copy(b"0123456789"));
+        cache.insert(&db, "c", (3, 1), copy(b"0123456789"));
+        assert!(cache.get(&db, "b", (2, 2)).is_some());
+        cache.insert(&db, "d", (4, 1), copy(b"0123456789"));
+        cache.insert(&db, "e", (5, 1), copy(b"0123456789"));
+        assert!(cache.get(&db, "a", (1, 1)).is_none());
+        assert!(cache.get(&db, "b", (2, 2)).is_some());
+        assert_eq!(cache.lru().size, 40);
+
+        cache.forget(&db);
+        assert!(cache.get(&db, "d", (4, 1)).is_none());
+        assert_eq!(cache.lru().size, 0);
+
+        let mut rendered = String::new();
+        cache.render(&mut rendered);
+        assert!(rendered.contains("object_cache_hits_total 3"));
+        assert!(rendered.contains("object_cache_misses_total 5"));
+    }
+
+    #[test]
+    pub fn test_directory_is_only_parsed_again_once_rewritten() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("object-cache-test-{}.db", std::process::id()));
+        let mut store = Store::create(&path)?;
+        let cache = ObjectCache::new(1024);
+        let key = ObjectKey::parse("notes/a").unwrap();
+        let directory = |cache: &ObjectCache| cache.lru().directories.get("db1").map(|(_, directory)| directory.clone()).unwrap();
+
+        crate::query::write_object(&mut store, &key, "text/plain", b"one", None, None)?;
+        assert_eq!(cache.read("db1", &mut store, &key)?.map(|object| object.data), Some(Bytes::from_static(b"one")));
+        let parsed = directory(&cache);
+
+        // A hit reuses the parsed directory.
+        assert!(cache.read("db1", &mut store, &key)?.is_some());
+        assert!(Arc::ptr_eq(&parsed, &directory(&cache)));
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 1);
+
+        crate::query::write_object(&mut store, &key, "text/plain", b"two", None, None)?;
+        assert_eq!(cache.read("db1", &mut store, &key)?.map(|object| object.data), Some(Bytes::from_static(b"two")));
+        assert!(!Arc::ptr_eq(&parsed, &directory(&cache)));
+
+        store.unlock(&path)?;
+        drop(store);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
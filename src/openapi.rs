@@ -0,0 +1,84 @@
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use crate::{acl, admin, backup, changelog, db, delegation, documents, embed, erasure, export, import, metrics, oauth, provision, quota, replication, resources,
+    s3, search, trash, users, webhooks};
+
+/// Where the OpenAPI document is served.
+pub const OPENAPI_PATH: &str = "/openapi.json";
+
+/// Where Swagger UI is served, showing the document at [`OPENAPI_PATH`].
+pub const SWAGGER_UI_PATH: &str = "/docs/{_:.*}";
+
+/// The API as an OpenAPI 3 document. Every route is described by the `#[utoipa::path]` attribute on its handler, and the request types
+/// derive their schemas, so the document follows the code. Every handler registered in `main` must be listed here.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Simple database server", description = "Stores objects in databases shared between users and the apps they attach."),
+    paths(
+        oauth::oauth, oauth::refresh_token, oauth::get_oauth_details,
+        resources::get_databases, resources::create_database, resources::rename_database, resources::set_member, resources::delete_database,
+        resources::get_stats, resources::checkpoint_database, resources::get_tokens,
+        acl::set_acl, provision::provision,
+        db::query, db::batch, db::list_objects, db::train_dictionary, db::create_index, db::delete_index, db::query_index, db::set_versioning,
+        db::get_history, db::get_metadata, db::get_object, db::put_object, db::patch_object, db::post_object, db::delete_object,
+        documents::create_document, documents::find_documents, import::import_documents, export::export_documents,
+        trash::list_trash, trash::restore_object,
+        changelog::get_changes, search::search, admin::repair_database,
+        quota::get_quota, quota::set_quota,
+        webhooks::create_webhook, webhooks::list_webhooks, webhooks::delete_webhook,
+        backup::backup_database, backup::restore_database,
+        embed::create_embed, embed::list_embeds, embed::revoke_embed, embed::get_embedded_object,
+        delegation::create_delegation, metrics::get_metrics, erasure::erase_user_data,
+        users::list_users, users::revoke_tokens, users::create_service_account, users::delete_user,
+        replication::replicate_index, replication::replicate_key, replication::stream,
+        s3::list_buckets, s3::head_bucket, s3::list_objects, s3::put_object, s3::get_object, s3::delete_object,
+    ),
+    modifiers(&SecuritySchemes),
+)]
+pub struct ApiDoc;
+
+/// Describes the tokens the API is called with, which the routes refer to by name.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let bearer = |description: &str| SecurityScheme::Http(HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some(description))
+            .build());
+
+        components.add_security_scheme("user", bearer("A user's API token, or a service account's token"));
+        components.add_security_scheme("app", bearer("An app's token, or a delegation token minted by the app where the route accepts them"));
+        components.add_security_scheme("replication", bearer("The primary's `replication.token`"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_every_registered_handler_is_documented() {
+        let openapi = ApiDoc::openapi();
+        let documented = openapi.paths.paths.values()
+            .flat_map(|item| [&item.get, &item.head, &item.put, &item.post, &item.patch, &item.delete])
+            .flatten()
+            .filter_map(|operation| operation.operation_id.clone())
+            .collect::<Vec<_>>();
+
+        let registered = include_str!("main.rs").lines()
+            .filter_map(|line| line.trim().strip_prefix(".service(")?.strip_suffix(')'))
+            .filter_map(|service| service.split_once("::").map(|(_, handler)| handler))
+            .filter(|handler| handler.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+            .collect::<Vec<_>>();
+
+        assert!(registered.len() > 50);
+        for handler in registered {
+            assert!(documented.iter().any(|operation| operation == handler), "{} isn't in the OpenAPI document", handler);
+        }
+
+        // Routes with regex segments are documented by their plain names.
+        assert!(openapi.paths.paths.contains_key("/objects/{key}"));
+    }
+}
@@ -0,0 +1,110 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::Deserialize;
+use utoipa::IntoParams;
+use crate::error::PagingError;
+use crate::response::ApiError;
+
+/// The number of items returned when the client doesn't ask for a specific amount.
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// The most items a single page may hold, however many the client asks for.
+pub const MAX_LIMIT: usize = 1000;
+
+/// Selects a page of a listing. Pass the `next_cursor` of one page back as `cursor` to get the page after it.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct PageOptions {
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+
+    /// Set if there are more items after this page.
+    pub next_cursor: Option<String>,
+}
+
+impl PageOptions {
+    /// Returns the page of `items` selected by these options, ordering items by `key`.
+    ///
+    /// Keys must be unique. Cursors refer to the last key on a page rather than to a position, so items added or removed between requests
+    /// never cause others to be skipped or repeated.
+    pub fn paginate<T>(&self, items: impl IntoIterator<Item = T>, key: impl Fn(&T) -> String) -> Result<Paged<T>, PagingError> {
+        let after = self.cursor.as_deref()
+            .map(|cursor| URL_SAFE_NO_PAD.decode(cursor)
+                .ok()
+                .and_then(|after| String::from_utf8(after).ok())
+                .ok_or(PagingError::InvalidCursor))
+            .transpose()?;
+
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+        let mut items = items.into_iter()
+            .map(|item| (key(&item), item))
+            .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
+            .collect::<Vec<_>>();
+
+        items.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let next_cursor = match items.len() > limit {
+            true => {
+                items.truncate(limit);
+                items.last().map(|(key, _)| URL_SAFE_NO_PAD.encode(key))
+            },
+            false => None,
+        };
+
+        Ok(Paged {
+            items: items.into_iter().map(|(_, item)| item).collect(),
+            next_cursor,
+        })
+    }
+}
+
+impl ResponseError for PagingError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PagingError::InvalidCursor => ApiError::bad_request("invalid_cursor", "The cursor was not returned by this listing"),
+        }.error_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(limit: usize, cursor: Option<String>) -> PageOptions {
+        PageOptions { limit: Some(limit), cursor }
+    }
+
+    #[test]
+    pub fn test_pages_cover_every_item_once() -> Result<(), PagingError> {
+        let items = ["d", "a", "c", "e", "b"];
+
+        let first = page(2, None).paginate(items, |item| item.to_string())?;
+        assert_eq!(first.items, ["a", "b"]);
+
+        let second = page(2, first.next_cursor).paginate(items, |item| item.to_string())?;
+        assert_eq!(second.items, ["c", "d"]);
+
+        // An item added before the cursor doesn't shift the rest of the listing.
+        let last = page(2, second.next_cursor).paginate(["aa", "d", "a", "c", "e", "b"], |item| item.to_string())?;
+        assert_eq!(last.items, ["e"]);
+        assert!(last.next_cursor.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rejects_invalid_cursor() {
+        assert!(page(2, Some("not a cursor!".to_owned())).paginate(["a"], |item| item.to_string()).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use actix_web::http::StatusCode;
+use actix_web::{mime, HttpResponse, ResponseError};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use crate::error::PatchError;
+use crate::response::ApiError;
+
+/// The content type of JSON Patch documents, as described by RFC 6902.
+pub const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// The content type of JSON Merge Patch documents, as described by RFC 7396.
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+
+impl ResponseError for PatchError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PatchError::UnsupportedContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            PatchError::Invalid(_) => StatusCode::BAD_REQUEST,
+            PatchError::TestFailed(_) => StatusCode::CONFLICT,
+            PatchError::NotADocument | PatchError::NoSuchPath(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ApiError::from(self).error_response()
+    }
+}
+
+impl From<&PatchError> for ApiError {
+    fn from(err: &PatchError) -> Self {
+        let status = err.status_code();
+        match err {
+            PatchError::UnsupportedContentType => ApiError::new(status, "unsupported_patch",
+                format!("Patches must be sent as {} or {}", JSON_PATCH_CONTENT_TYPE, MERGE_PATCH_CONTENT_TYPE)),
+            PatchError::Invalid(err) => ApiError::new(status, "invalid_patch", err),
+            PatchError::NotADocument => ApiError::new(status, "not_a_document", "Only objects holding JSON documents can be patched"),
+            PatchError::NoSuchPath(path) => ApiError::new(status, "no_such_path", format!("The document has nothing at '{}'", path))
+                .with("path", path),
+            PatchError::TestFailed(path) => ApiError::new(status, "test_failed", format!("The value at '{}' isn't the one the patch expected", path))
+                .with("path", path),
+        }
+    }
+}
+
+/// A single operation of a JSON Patch. Paths are JSON pointers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// A change to a stored JSON document.
+#[derive(Debug, Clone)]
+pub enum Patch {
+    /// Operations applied in order. If any of them fails, none of them are.
+    Json(Vec<PatchOperation>),
+
+    /// A document whose fields replace those of the stored one, with `null` removing a field.
+    Merge(Value),
+}
+
+impl Patch {
+    /// Reads a patch, telling its format by its content type.
+    pub fn parse(content_type: Option<&mime::Mime>, body: &[u8]) -> Result<Self, PatchError> {
+        let invalid = |err: serde_json::Error| PatchError::Invalid(err.to_string());
+
+        match content_type.map(|mime| mime.essence_str()) {
+            Some(JSON_PATCH_CONTENT_TYPE) => serde_json::from_slice(body).map(Self::Json).map_err(invalid),
+            Some(MERGE_PATCH_CONTENT_TYPE) => serde_json::from_slice(body).map(Self::Merge).map_err(invalid),
+            _ => Err(PatchError::UnsupportedContentType),
+        }
+    }
+
+    /// Applies the patch to `document`. The document may be left partly patched if this fails, so it should be thrown away.
+    pub fn apply(&self, document: &mut Value) -> Result<(), PatchError> {
+        match self {
+            Self::Json(operations) => operations.iter().try_for_each(|op| apply_operation(document, op)),
+            Self::Merge(patch) => {
+                merge(document, patch);
+                Ok(())
+            },
+        }
+    }
+}
+
+fn apply_operation(document: &mut Value, op: &PatchOperation) -> Result<(), PatchError> {
+    match op {
+        PatchOperation::Add { path, value } => add(document, path, value.clone()),
+        PatchOperation::Remove { path } => remove(document, path).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            *document.pointer_mut(path).ok_or_else(|| PatchError::NoSuchPath(path.clone()))? = value.clone();
+            Ok(())
+        },
+        PatchOperation::Move { from, path } => {
+            if path.strip_prefix(from.as_str()).is_some_and(|rest| rest.starts_with('/')) {
+                return Err(PatchError::Invalid(format!("'{}' can't be moved into itself", from)));
+            }
+
+            let value = remove(document, from)?;
+            add(document, path, value)
+        },
+        PatchOperation::Copy { from, path } => {
+            let value = document.pointer(from).cloned().ok_or_else(|| PatchError::NoSuchPath(from.clone()))?;
+            add(document, path, value)
+        },
+        PatchOperation::Test { path, value } => match document.pointer(path) {
+            Some(found) if found == value => Ok(()),
+            Some(_) => Err(PatchError::TestFailed(path.clone())),
+            None => Err(PatchError::NoSuchPath(path.clone())),
+        },
+    }
+}
+
+/// Splits a JSON pointer into the pointer to its parent and its last reference token, unescaped.
+fn split(path: &str) -> Result<(&str, String), PatchError> {
+    let Some((parent, last)) = path.rsplit_once('/') else {
+        return Err(PatchError::Invalid(format!("'{}' is not a JSON pointer", path)));
+    };
+
+    Ok((parent, last.replace("~1", "/").replace("~0", "~")))
+}
+
+/// Reads an array index, which may not have leading zeroes or a sign.
+fn index(token: &str, path: &str) -> Result<usize, PatchError> {
+    match token.len() > 1 && token.starts_with('0') || !token.bytes().all(|b| b.is_ascii_digit()) {
+        true => Err(PatchError::NoSuchPath(path.to_owned())),
+        false => token.parse().map_err(|_| PatchError::NoSuchPath(path.to_owned())),
+    }
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+
+    let (parent, token) = split(path)?;
+
+    match document.pointer_mut(parent) {
+        Some(Value::Object(fields)) => {
+            fields.insert(token, value);
+        },
+        Some(Value::Array(items)) if token == "-" => items.push(value),
+        Some(Value::Array(items)) => match index(&token, path)? {
+            i if i <= items.len() => items.insert(i, value),
+            _ => return Err(PatchError::NoSuchPath(path.to_owned())),
+        },
+        _ => return Err(PatchError::NoSuchPath(path.to_owned())),
+    }
+
+    Ok(())
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, PatchError> {
+    if path.is_empty() {
+        return Err(PatchError::Invalid("The whole document can't be removed".to_owned()));
+    }
+
+    let (parent, token) = split(path)?;
+
+    let removed = match document.pointer_mut(parent) {
+        Some(Value::Object(fields)) => fields.remove(&token),
+        Some(Value::Array(items)) => match index(&token, path)? {
+            i if i < items.len() => Some(items.remove(i)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    removed.ok_or_else(|| PatchError::NoSuchPath(path.to_owned()))
+}
+
+/// Merges `patch` into `target` as described by RFC 7396.
+pub fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    let Value::Object(fields) = target else {
+        unreachable!();
+    };
+
+    for (field, value) in patch {
+        match value {
+            Value::Null => {
+                fields.remove(field);
+            },
+            value => merge(fields.entry(field.clone()).or_insert(Value::Null), value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_json_patch_applies_every_operation_or_fails() {
+        let mut document = json!({"name": "Ada", "tags": ["a", "c"], "address": {"city": "London"}});
+        let patch = serde_json::from_value::<Vec<PatchOperation>>(json!([
+            {"op": "test", "path": "/name", "value": "Ada"},
+            {"op": "add", "path": "/tags/1", "value": "b"},
+            {"op": "add", "path": "/tags/-", "value": "d"},
+            {"op": "replace", "path": "/name", "value": "Ada Lovelace"},
+            {"op": "move", "from": "/address/city", "path": "/city"},
+            {"op": "copy", "from": "/city", "path": "/address/home~1town"},
+            {"op": "remove", "path": "/tags/0"},
+        ])).unwrap();
+
+        Patch::Json(patch).apply(&mut document).unwrap();
+        assert_eq!(document, json!({"name": "Ada Lovelace", "tags": ["b", "c", "d"], "city": "London", "address": {"home/town": "London"}}));
+
+        let failing = |patch: Value| Patch::Json(serde_json::from_value(patch).unwrap()).apply(&mut document.clone()).unwrap_err();
+        assert!(matches!(failing(json!([{"op": "test", "path": "/name", "value": "Ada"}])), PatchError::TestFailed(_)));
+        assert!(matches!(failing(json!([{"op": "remove", "path": "/missing"}])), PatchError::NoSuchPath(_)));
+        assert!(matches!(failing(json!([{"op": "add", "path": "/tags/01", "value": 0}])), PatchError::NoSuchPath(_)));
+        assert!(matches!(failing(json!([{"op": "move", "from": "/address", "path": "/address/inner"}])), PatchError::Invalid(_)));
+    }
+
+    #[test]
+    pub fn test_merge_patch_follows_rfc_7396() {
+        let mut document = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["example", "sample"]});
+        merge(&mut document, &json!({"title": "Hello!", "phoneNumber": "+01-123-456-7890", "author": {"familyName": null}, "tags": ["example"]}));
+
+        assert_eq!(document, json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["example"], "phoneNumber": "+01-123-456-7890"}));
+    }
+}
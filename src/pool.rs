@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use libdb::lock::LockMode;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::sync::OnceCell;
+use crate::alerts::{self, Alert};
+use crate::config::{MaintenanceConfig, StoreConfig};
+use crate::error::*;
+use crate::index::{push_change, DBIndexChange};
+use crate::metrics::Metrics;
+use crate::object_cache::ObjectCache;
+use crate::telemetry;
+use crate::usage::ObjectUsage;
+use crate::DatabaseID;
+
+/// The name of the libdb store inside each database's directory.
+pub const STORE_FILE: &str = "store.db";
+
+pub type Store = libdb::Database<File>;
+
+type Slot = Arc<OnceCell<OpenStore>>;
+
+struct OpenStore {
+    path: PathBuf,
+    store: Arc<Mutex<Store>>,
+    usage: Arc<ObjectUsage>,
+}
+
+/// Keeps track of every database store the server currently has open, so they can be flushed and unlocked together.
+///
+/// The pool is shared by every worker. Each store is opened once and guarded by its own lock, so requests against different databases never
+/// wait on each other.
+#[derive(Clone)]
+pub struct DbPool {
+    stores: Arc<Mutex<HashMap<DatabaseID, Slot>>>,
+
+    /// How many times in a row each database's store has failed to open.
+    failures: Arc<Mutex<HashMap<DatabaseID, u32>>>,
+
+    limits: StoreConfig,
+    metrics: Metrics,
+    object_cache: Arc<ObjectCache>,
+}
+
+impl DbPool {
+    pub fn new(limits: StoreConfig) -> Self {
+        Self {
+            stores: Default::default(),
+            failures: Default::default(),
+            object_cache: Arc::new(ObjectCache::new(limits.object_cache_size)),
+            limits,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// The latency of every store's operations, including stores which have since been closed.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The recently read objects of every database, shared between all of them.
+    pub fn object_cache(&self) -> Arc<ObjectCache> {
+        self.object_cache.clone()
+    }
+
+    /// Returns the open store for the database, opening and locking it first if necessary.
+    ///
+    /// If several requests want the same database before it is open, only one opens it while the rest wait for it to finish.
+    /// Quarantined databases are never opened. A database is quarantined once its store fails to open too many times in a row.
+    pub async fn open(&self, db: &crate::Database) -> Result<Arc<Mutex<Store>>> {
+        telemetry::record_database(&db.id);
+
+        if db.quarantine.is_some() || self.failures.lock().await.get(&db.id).is_some_and(|failures| *failures >= self.limits.max_open_failures) {
+            return Err(ManualError::StoreQuarantined(db.id.clone()).into());
+        }
+
+        let slot = self.stores.lock().await
+            .entry(db.id.clone())
+            .or_default()
+            .clone();
+
+        let (database, limits, latency) = (db.clone(), self.limits.clone(), self.metrics.store(&db.id));
+        let path = db.root.join(STORE_FILE);
+        let usage = Arc::new(ObjectUsage::load(&db.root, self.limits.usage_sample_rate));
+        let timeout = self.limits.open_timeout();
+        let store = slot.get_or_try_init(|| async move {
+            let open = {
+                let path = path.clone();
+                move || {
+                    let mut store = open_store(&path, database.page_size.unwrap_or(libdb::DEFAULT_PAGE_SIZE))?;
+                    store.set_cache_capacity(limits.cache_pages)?;
+                    store.set_durability(limits.durability.into());
+                    limit_growth(&mut store, &database, &limits);
+                    store.set_metrics(latency);
+                    Result::Ok(store)
+                }
+            };
+
+            let store = tokio::time::timeout(timeout, tokio::task::spawn_blocking(open))
+                .await
+                .map_err(|_| ManualError::StoreOpenTimedOut)?
+                .map_err(|_| ManualError::StoreOpenFailed)??;
+
+            Result::Ok(OpenStore {
+                path,
+                store: Arc::new(Mutex::new(store)),
+                usage,
+            })
+        }).await;
+
+        match store {
+            Ok(open) => {
+                self.failures.lock().await.remove(&db.id);
+                Ok(open.store.clone())
+            },
+            Err(err) => Err(self.record_failure(&db.id, err).await),
+        }
+    }
+
+    /// Counts a failed attempt at opening a store, quarantining its database once it has failed too often.
+    /// Failures which don't suggest the store is damaged, such as another process holding its lock, aren't counted.
+    async fn record_failure(&self, id: &DatabaseID, err: Error) -> Error {
+        if !matches!(err.inner(), global::Inner::LibDbError(_) | global::Inner::ManualError(ManualError::StoreOpenTimedOut)) {
+            return err;
+        }
+
+        let failures = {
+            let mut failures = self.failures.lock().await;
+            let count = failures.entry(id.clone()).or_default();
+            *count += 1;
+            *count
+        };
+
+        log::warn!("Failed to open database {} ({} of {} attempts): {:?}", id, failures, self.limits.max_open_failures, err);
+
+        if failures < self.limits.max_open_failures {
+            return err;
+        }
+
+        log::error!(target: "audit", "Quarantined database {} after {} failed attempts to open it: {:?}", id, failures, err);
+        alerts::raise(Alert::StoreQuarantined {
+            database: id.clone(),
+            reason: format!("{:?}", err.inner()),
+        });
+        push_change(DBIndexChange::QuarantineDatabase {
+            database: id.clone(),
+            reason: format!("{:?}", err.inner()),
+        }).await;
+
+        ManualError::StoreQuarantined(id.clone()).into()
+    }
+
+    /// Closes the database's store if it is open and forgets any failed attempts at opening it, so it is opened afresh next time.
+    ///
+    /// Requests which took hold of the store before it was evicted may still be waiting for it. Once it is closed, their writes fail instead
+    /// of landing in a file which is about to be moved or replaced, so nothing they were told was written is lost.
+    pub async fn evict(&self, id: &DatabaseID) {
+        self.failures.lock().await.remove(id);
+        self.object_cache.forget(id);
+
+        let Some(slot) = self.stores.lock().await.remove(id) else {
+            return;
+        };
+
+        if let Some(open) = slot.get() {
+            close_store(id, open).await;
+        }
+    }
+
+    /// The database's store, if it is already open. Unlike [`DbPool::open`], this never opens it.
+    pub async fn get_open(&self, id: &str) -> Option<Arc<Mutex<Store>>> {
+        let slot = self.stores.lock().await.get(id).cloned();
+        slot.as_ref().and_then(|slot| slot.get()).map(|open| open.store.clone())
+    }
+
+    /// How often the objects of the database have been read, if its store is open.
+    pub async fn usage(&self, id: &str) -> Option<Arc<ObjectUsage>> {
+        let slot = self.stores.lock().await.get(id).cloned();
+        slot.as_ref().and_then(|slot| slot.get()).map(|open| open.usage.clone())
+    }
+
+    /// Counts a read of the object at `key` towards the database's access statistics, if its store is open.
+    pub async fn record_read(&self, id: &str, key: &str) {
+        if let Some(usage) = self.usage(id).await {
+            usage.record_read(key);
+        }
+    }
+
+    /// Writes out the access statistics of every open store which has been read since they were last written.
+    pub async fn persist_usage(&self) {
+        let open = self.stores.lock().await.iter()
+            .filter_map(|(id, slot)| slot.get().map(|open| (id.clone(), open.usage.clone())))
+            .collect::<Vec<_>>();
+
+        for (id, usage) in open {
+            if let Err(err) = usage.persist() {
+                log::warn!("Failed to save the access statistics of database {}: {:?}", id, err);
+            }
+        }
+    }
+
+    /// The open stores which pass number `pass` of a background job should include, given their maintenance windows.
+    async fn due(&self, maintenance: &MaintenanceConfig, pass: u64) -> Vec<(DatabaseID, Arc<Mutex<Store>>)> {
+        let now = chrono::Utc::now();
+
+        self.stores.lock().await.iter()
+            .filter(|(id, _)| maintenance.due(id, pass, now))
+            .filter_map(|(id, slot)| slot.get().map(|open| (id.clone(), open.store.clone())))
+            .collect()
+    }
+
+    /// Removes the expired objects of every open store, and purges the objects which have been in its trash for longer than
+    /// `stores.trash_retention`. See [`crate::ttl`] and [`crate::trash`]. Stores outside their maintenance window are only cleaned up by
+    /// some passes.
+    pub async fn reap(&self, maintenance: &MaintenanceConfig, pass: u64) {
+        let open = self.due(maintenance, pass).await;
+
+        let retention = self.limits.trash_retention();
+        for (id, store) in open {
+            let reaped = tokio::task::spawn_blocking(move || {
+                let mut store = store.blocking_lock();
+                Ok::<_, Error>((crate::ttl::reap(&mut store)?, crate::trash::purge(&mut store, retention)?))
+            }).await;
+
+            match reaped {
+                Ok(Ok((0, 0))) => {},
+                Ok(Ok((expired, purged))) => log::debug!("Removed {} expired and {} purged objects from database {}", expired, purged, id),
+                Ok(Err(err)) => log::warn!("Failed to clean up database {}: {:?}", id, err),
+                Err(err) => log::warn!("Failed to clean up database {}: {:?}", id, err),
+            }
+        }
+    }
+
+    /// Flushes every open store which has changes that haven't been flushed, and syncs every open store to its disk if
+    /// `stores.checkpoint_fsync` is set. See [`checkpoint`]. Stores outside their maintenance window are only checkpointed by some passes.
+    pub async fn checkpoint_all(&self, maintenance: &MaintenanceConfig, pass: u64) {
+        let open = self.due(maintenance, pass).await;
+
+        let fsync = self.limits.checkpoint_fsync;
+        for (id, store) in open {
+            match tokio::task::spawn_blocking(move || checkpoint(&mut store.blocking_lock(), fsync)).await {
+                Ok(Ok(Checkpoint { flushed: false, synced: false })) => {},
+                Ok(Ok(done)) => log::debug!("Checkpointed database {} (flushed: {}, synced: {})", id, done.flushed, done.synced),
+                Ok(Err(err)) => log::warn!("Failed to checkpoint database {}: {:?}", id, err),
+                Err(err) => log::warn!("Failed to checkpoint database {}: {:?}", id, err),
+            }
+        }
+    }
+
+    /// Applies the database's current quota to its store, if the store is open. Stores which aren't open pick it up when they are opened.
+    pub async fn set_quota(&self, db: &crate::Database) {
+        let slot = self.stores.lock().await.get(&db.id).cloned();
+
+        if let Some(open) = slot.as_ref().and_then(|slot| slot.get()) {
+            limit_growth(&mut *open.store.lock().await, db, &self.limits);
+        }
+    }
+
+    /// The databases whose stores are currently open.
+    pub async fn open_stores(&self) -> Vec<DatabaseID> {
+        self.stores.lock().await.iter()
+            .filter(|(_, slot)| slot.initialized())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Flushes and unlocks every open store, then removes them from the pool.
+    pub async fn close_all(&self) {
+        for (id, slot) in self.stores.lock().await.drain() {
+            if let Some(open) = slot.get() {
+                close_store(&id, open).await;
+            }
+        }
+    }
+}
+
+async fn close_store(id: &DatabaseID, open: &OpenStore) {
+    if let Err(err) = open.usage.persist() {
+        log::warn!("Failed to save the access statistics of database {}: {:?}", id, err);
+    }
+
+    let mut store = open.store.lock().await;
+    if let Err(err) = store.flush() {
+        log::error!("Failed to flush database {}: {:?}", id, err);
+    }
+
+    // Closed while it is still held, so whoever takes it next can't write to it. See [`DbPool::evict`].
+    store.close();
+
+    if let Err(err) = unlock_store(&open.path, &store) {
+        log::error!("Failed to unlock database {}: {:?}", id, err);
+    }
+}
+
+/// What a [`checkpoint`] did to a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Checkpoint {
+    /// Whether the store had changes which weren't flushed, and now are.
+    pub flushed: bool,
+
+    /// Whether the store was synced to its disk.
+    pub synced: bool,
+}
+
+/// Flushes the store if it has changes which haven't been flushed, writing its header and fragment table. If `fsync`, the store is then
+/// synced to its disk whether or not it had anything to flush, since stores which flush on every commit never have.
+pub fn checkpoint(store: &mut Store, fsync: bool) -> Result<Checkpoint> {
+    let flushed = store.is_dirty();
+
+    match fsync {
+        true => store.sync()?,
+        false if flushed => store.flush()?,
+        false => {},
+    }
+
+    Ok(Checkpoint { flushed, synced: fsync })
+}
+
+/// Refuses to let the store grow beyond its database's quota, or to use up the last of the free space on its disk.
+/// Installing the limits again replaces those installed before.
+fn limit_growth(store: &mut Store, db: &crate::Database, limits: &StoreConfig) {
+    let size = AtomicU64::new(store.allocated_size());
+    let (id, root, quota, min_free_space) = (db.id.clone(), db.root.clone(), db.quota, limits.min_free_space);
+
+    store.on_grow(move |bytes| {
+        let grown = size.load(Ordering::Relaxed) + bytes;
+
+        if let Some(quota) = quota.filter(|quota| grown > *quota) {
+            alerts::raise(Alert::QuotaExceeded { database: id.clone(), quota });
+            return Err(std::io::Error::new(std::io::ErrorKind::QuotaExceeded, "database quota exceeded"));
+        }
+
+        if fs2::available_space(&root)? < bytes.saturating_add(min_free_space) {
+            alerts::raise(Alert::DiskFull { database: id.clone() });
+            return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "not enough free disk space"));
+        }
+
+        size.store(grown, Ordering::Relaxed);
+
+        Ok(())
+    });
+}
+
+/// Opens and locks the store at `path`, initialising it first with pages of `page_size` bytes if the file is new.
+///
+/// The server records itself in the store's lock file while it has the store open, so tools which find the store locked can say who holds it.
+pub fn open_store(path: &Path, page_size: u32) -> Result<Store> {
+    let store = Store::open_path_with_page_size(path, LockMode::Exclusive, page_size);
+
+    if let Err(ref err) = store && let libdb::error::global::Inner::LockError(locked) = err.inner() {
+        log::warn!("{}", locked);
+        return Err(ManualError::StoreLocked(PathBuf::from(path)).into());
+    }
+
+    Ok(store?)
+}
+
+/// Removes the store's lock file and unlocks it, so other processes may open it for writing.
+pub fn unlock_store(path: &Path, store: &Store) -> Result<()> {
+    Ok(store.unlock(path)?)
+}
@@ -0,0 +1,444 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::SystemTime;
+use actix_web::{post, web, Responder};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use tokio::sync::Mutex;
+use crate::acl::{Acl, AclChange};
+use crate::app::Scope;
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::index::{commit_change, DBIndexChange};
+use crate::response::{ApiError, ApiResponse};
+use crate::{generate_token, AppID, Application, DBIndex, Database, DatabaseID, DatabaseIndex, Token, UserID};
+
+/// Held while a manifest is planned and applied, so two manifests applied at once can't both create the same database.
+static PROVISIONING: Mutex<()> = Mutex::const_new(());
+
+/// The apps and databases a user wants to have, and who may use each database.
+///
+/// Apps and databases are matched to the ones the user already owns by name. Anything the user owns which the manifest leaves out is left
+/// alone.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    pub apps: Vec<AppManifest>,
+    pub databases: Vec<DatabaseManifest>,
+}
+
+/// An app along with what its token may be used for. Apps listed without scopes may do everything, on any database they are attached to.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AppManifest {
+    pub name: String,
+    #[serde(default = "Scope::all")]
+    pub scopes: BTreeSet<Scope>,
+
+    /// The names of the databases the app's token may be used on, either from the manifest or already owned by the user.
+    #[serde(default)]
+    pub databases: Option<BTreeSet<String>>,
+}
+
+/// A database along with its whole access list. Members and apps the manifest leaves out lose access to it.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseManifest {
+    pub name: String,
+    #[serde(default)]
+    pub rw: BTreeSet<UserID>,
+    #[serde(default)]
+    pub ro: BTreeSet<UserID>,
+
+    /// The names of the apps which may use the database, either from the manifest or already owned by the user.
+    #[serde(default)]
+    pub apps: BTreeSet<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+/// What became of one entry of a manifest.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Provisioned {
+    App {
+        name: String,
+        id: AppID,
+        outcome: Outcome,
+
+        /// Only given when the app is created, since it can't be looked up afterwards.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    Database {
+        name: String,
+        id: DatabaseID,
+        outcome: Outcome,
+        changes: Vec<AclChange>,
+    },
+}
+
+/// Apps and databases made up for the entries of a manifest which don't exist yet, by name.
+#[derive(Debug, Default)]
+pub struct Created {
+    pub apps: HashMap<String, Application>,
+    pub databases: HashMap<String, Database>,
+}
+
+/// The index changes which bring the index in line with a manifest, and what they do to each of its entries.
+pub struct Plan {
+    pub changes: Vec<DBIndexChange>,
+    pub resources: Vec<Provisioned>,
+}
+
+impl Manifest {
+    /// Checks that the manifest can be applied for `owner` without leaving anything half done.
+    pub fn validate(&self, index: &DatabaseIndex, owner: &UserID) -> std::result::Result<(), String> {
+        let mut apps = BTreeSet::new();
+        for app in self.apps.iter() {
+            if app.name.is_empty() {
+                return Err("Apps need a name".to_owned());
+            }
+
+            if !apps.insert(app.name.as_str()) {
+                return Err(format!("The app {} is listed more than once", app.name));
+            }
+
+            if index.apps.iter().filter(|existing| existing.owner == *owner && existing.name == app.name).count() > 1 {
+                return Err(format!("You own more than one app named {}", app.name));
+            }
+
+            if app.scopes.is_empty() {
+                return Err(format!("The app {} needs at least one scope", app.name));
+            }
+
+            if let Some(db) = app.databases.iter().flatten().find(|db| !self.databases.iter().any(|listed| listed.name == **db)
+                && !index.databases.iter().any(|existing| existing.owner == *owner && existing.name == **db)) {
+                return Err(format!("No such database: {}", db));
+            }
+        }
+
+        let mut databases = BTreeSet::new();
+        for db in self.databases.iter() {
+            if db.name.is_empty() {
+                return Err("Databases need a name".to_owned());
+            }
+
+            if !databases.insert(db.name.as_str()) {
+                return Err(format!("The database {} is listed more than once", db.name));
+            }
+
+            if index.databases.iter().filter(|existing| existing.owner == *owner && existing.name == db.name).count() > 1 {
+                return Err(format!("You own more than one database named {}", db.name));
+            }
+
+            if let Some(member) = db.rw.intersection(&db.ro).next() {
+                return Err(format!("{} can't be both a read-write and a read-only member of {}", member, db.name));
+            }
+
+            if db.rw.contains(owner) || db.ro.contains(owner) {
+                return Err("The owner of a database always has access to it".to_owned());
+            }
+
+            if let Some(member) = db.rw.iter().chain(db.ro.iter()).find(|member| !index.users.iter().any(|user| user.id == **member)) {
+                return Err(format!("No such user: {}", member));
+            }
+
+            if let Some(app) = db.apps.iter().find(|app| !apps.contains(app.as_str()) && !index.apps.iter().any(|existing| existing.owner == *owner && existing.name == **app)) {
+                return Err(format!("No such app: {}", app));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The names of the apps in the manifest which `owner` doesn't have yet.
+    pub fn missing_apps<'a>(&'a self, index: &'a DatabaseIndex, owner: &'a UserID) -> impl Iterator<Item = &'a str> + 'a {
+        self.apps.iter()
+            .filter(|app| !index.apps.iter().any(|existing| existing.owner == *owner && existing.name == app.name))
+            .map(|app| app.name.as_str())
+    }
+
+    /// The names of the databases in the manifest which `owner` doesn't have yet.
+    pub fn missing_databases<'a>(&'a self, index: &'a DatabaseIndex, owner: &'a UserID) -> impl Iterator<Item = &'a str> + 'a {
+        self.databases.iter()
+            .filter(|db| !index.databases.iter().any(|existing| existing.owner == *owner && existing.name == db.name))
+            .map(|db| db.name.as_str())
+    }
+
+    /// Works out the changes which make the index match the manifest, given a record for everything it is missing. Creating a database adds
+    /// it without any members, then grants it its access list like any other database.
+    pub fn plan(&self, index: &DatabaseIndex, owner: &UserID, mut created: Created) -> Plan {
+        let mut changes = vec![];
+        let mut resources = vec![];
+        let mut app_ids = HashMap::new();
+
+        let database_ids = index.databases.iter()
+            .filter(|db| db.owner == *owner)
+            .chain(created.databases.values())
+            .map(|db| (db.name.clone(), db.id.clone()))
+            .collect::<HashMap<_, _>>();
+
+        for manifest in self.apps.iter() {
+            let databases = manifest.databases.as_ref()
+                .map(|names| names.iter().filter_map(|name| database_ids.get(name).cloned()).collect::<BTreeSet<_>>());
+
+            match index.apps.iter().find(|app| app.owner == *owner && app.name == manifest.name) {
+                Some(app) => {
+                    let outcome = match app.scopes == manifest.scopes && app.databases == databases {
+                        true => Outcome::Unchanged,
+                        false => {
+                            changes.push(DBIndexChange::SetAppScopes { app: app.id.clone(), scopes: manifest.scopes.clone(), databases });
+                            Outcome::Updated
+                        },
+                    };
+
+                    app_ids.insert(app.name.clone(), app.id.clone());
+                    resources.push(Provisioned::App { name: app.name.clone(), id: app.id.clone(), outcome, token: None });
+                },
+                None => if let Some(mut app) = created.apps.remove(&manifest.name) {
+                    app.scopes = manifest.scopes.clone();
+                    app.databases = databases;
+                    app_ids.insert(app.name.clone(), app.id.clone());
+                    resources.push(Provisioned::App { name: app.name.clone(), id: app.id.clone(), outcome: Outcome::Created, token: Some(app.token.token.clone()) });
+                    changes.push(DBIndexChange::AddApp { app });
+                },
+            }
+        }
+
+        for app in index.apps.iter().filter(|app| app.owner == *owner) {
+            app_ids.entry(app.name.clone()).or_insert_with(|| app.id.clone());
+        }
+
+        for manifest in self.databases.iter() {
+            let acl = Acl {
+                rw: manifest.rw.clone(),
+                ro: manifest.ro.clone(),
+                apps: manifest.apps.iter().filter_map(|name| app_ids.get(name).cloned()).collect(),
+            };
+
+            let (db, outcome) = match index.databases.iter().find(|db| db.owner == *owner && db.name == manifest.name) {
+                Some(db) => (db.clone(), None),
+                None => match created.databases.remove(&manifest.name) {
+                    Some(db) => {
+                        changes.push(DBIndexChange::AddDatabase { database: db.clone() });
+                        (db, Some(Outcome::Created))
+                    },
+                    None => continue,
+                },
+            };
+
+            let diff = acl.diff(&db);
+            changes.extend(diff.iter().cloned().map(|change| change.into_index_change(&db.id)));
+
+            resources.push(Provisioned::Database {
+                name: db.name,
+                id: db.id,
+                outcome: outcome.unwrap_or(if diff.is_empty() { Outcome::Unchanged } else { Outcome::Updated }),
+                changes: diff,
+            });
+        }
+
+        Plan { changes, resources }
+    }
+}
+
+#[derive(Serialize)]
+struct Provision {
+    resources: Vec<Provisioned>,
+}
+
+/// Makes the caller's apps and databases match a manifest, and reports whether each entry was created, updated or already matched it.
+/// Applying the same manifest again changes nothing.
+///
+/// Every change is applied together, so nothing ever sees only part of a manifest. Tokens of newly created apps are only ever returned here.
+#[utoipa::path(
+    tag = "databases",
+    request_body = Manifest,
+    responses((status = 200, description = "What became of each entry of the manifest"), (status = 400, description = "The manifest can't be applied")),
+    security(("user" = [])),
+)]
+#[post("/provision")]
+pub async fn provision(manifest: web::Json<Manifest>, user: AuthenticatedUser, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let _provisioning = PROVISIONING.lock().await;
+
+    let plan = {
+        let index = index.lock().await;
+
+        if let Err(error) = manifest.validate(&index, &user.id) {
+            return Err(ApiError::bad_request("invalid_manifest", error).into());
+        }
+
+        let mut created = Created::default();
+
+        for name in manifest.missing_apps(&index, &user.id) {
+            let (token, refresh) = futures::future::join(generate_token(64), generate_token(128)).await;
+
+            created.apps.insert(name.to_owned(), Application {
+                name: name.to_owned(),
+                id: config.id_scheme.generate().await.map_err(ApiError::internal)?,
+                owner: user.id.clone(),
+                token: Token::issued(
+                    token.map_err(ApiError::internal)?,
+                    refresh.map_err(ApiError::internal)?,
+                    DateTime::from(SystemTime::now() + config.tokens.lifetime()),
+                ),
+                scopes: Scope::all(),
+                databases: None,
+            });
+        }
+
+        for name in manifest.missing_databases(&index, &user.id) {
+            let id = loop {
+                let id = config.id_scheme.generate().await.map_err(ApiError::internal)?;
+
+                if !index.databases.iter().any(|db| db.id == id) && !created.databases.values().any(|db| db.id == id) {
+                    break id;
+                }
+            };
+
+            let root = config.database_dir.join(&id);
+            tokio::fs::create_dir_all(&root).await?;
+
+            created.databases.insert(name.to_owned(), Database {
+                name: name.to_owned(),
+                id,
+                owner: user.id.clone(),
+                root,
+                quota: config.quotas.default,
+                ..Database::default()
+            });
+        }
+
+        manifest.plan(&index, &user.id, created)
+    };
+
+    if !plan.changes.is_empty() {
+        let changes = plan.changes.len();
+        commit_change(plan.changes).await.map_err(ApiError::internal)?;
+
+        log::warn!(target: "audit", "{} applied a provisioning manifest: {} changes", user, changes);
+    }
+
+    Ok(ApiResponse::ok(Provision { resources: plan.resources }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::fixture::IndexFixture;
+    use crate::resources::Access;
+
+    #[test]
+    pub fn test_plan_only_changes_what_differs() {
+        let index = IndexFixture::new()
+            .user("u2")
+            .user("u1")
+            .database("db1")
+            .app("a1")
+            .rw("u2")
+            .build();
+
+        let manifest: Manifest = serde_json::from_value(json! {{
+            "apps": [{ "name": "a1" }, { "name": "a2" }],
+            "databases": [
+                { "name": "db1", "rw": ["u2"], "apps": ["a1"] },
+                { "name": "db2", "ro": ["u2"], "apps": ["a2"] }
+            ]
+        }}).unwrap();
+
+        let owner = "u1".to_owned();
+        assert_eq!(manifest.validate(&index, &owner), Ok(()));
+        assert_eq!(manifest.missing_apps(&index, &owner).collect::<Vec<_>>(), vec!["a2"]);
+        assert_eq!(manifest.missing_databases(&index, &owner).collect::<Vec<_>>(), vec!["db2"]);
+
+        let mut created = Created::default();
+        created.apps.insert("a2".to_owned(), Application {
+            name: "a2".to_owned(),
+            id: "a2-id".to_owned(),
+            owner: owner.clone(),
+            token: index.apps[0].token.clone(),
+            scopes: Scope::all(),
+            databases: None,
+        });
+        created.databases.insert("db2".to_owned(), Database {
+            name: "db2".to_owned(),
+            id: "db2-id".to_owned(),
+            owner: owner.clone(),
+            ..Database::default()
+        });
+
+        let plan = manifest.plan(&index, &owner, created);
+
+        assert_eq!(plan.resources, vec![
+            Provisioned::App { name: "a1".to_owned(), id: "a1".to_owned(), outcome: Outcome::Unchanged, token: None },
+            Provisioned::App { name: "a2".to_owned(), id: "a2-id".to_owned(), outcome: Outcome::Created, token: Some("a1-token".to_owned()) },
+            Provisioned::Database { name: "db1".to_owned(), id: "db1".to_owned(), outcome: Outcome::Unchanged, changes: vec![] },
+            Provisioned::Database { name: "db2".to_owned(), id: "db2-id".to_owned(), outcome: Outcome::Created, changes: vec![
+                AclChange::Member { user: "u2".to_owned(), from: None, to: Some(Access::ReadOnly) },
+                AclChange::App { app: "a2-id".to_owned(), granted: true },
+            ] },
+        ]);
+
+        // Adding the app and the database, then granting db2 to u2 and a2.
+        assert_eq!(plan.changes.len(), 4);
+    }
+
+    #[test]
+    pub fn test_app_scopes_are_brought_in_line_with_the_manifest() {
+        let index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .app("a1")
+            .database("db2")
+            .build();
+
+        let owner = "u1".to_owned();
+        let manifest: Manifest = serde_json::from_value(json! {{
+            "apps": [{ "name": "a1", "scopes": ["read"], "databases": ["db1"] }]
+        }}).unwrap();
+
+        assert_eq!(manifest.validate(&index, &owner), Ok(()));
+        let plan = manifest.plan(&index, &owner, Created::default());
+        assert_eq!(plan.resources, vec![Provisioned::App { name: "a1".to_owned(), id: "a1".to_owned(), outcome: Outcome::Updated, token: None }]);
+
+        let Some(DBIndexChange::SetAppScopes { scopes, databases, .. }) = plan.changes.into_iter().next() else {
+            panic!("The app's scopes weren't changed");
+        };
+
+        let mut app = index.apps[0].clone();
+        assert!(app.permits("db1", Scope::Read) && app.permits("db2", Scope::Read));
+
+        (app.scopes, app.databases) = (scopes, databases);
+        assert!(app.permits("db1", Scope::Read) && !app.permits("db1", Scope::Write) && !app.permits("db2", Scope::Read));
+
+        let refused = |value: serde_json::Value| serde_json::from_value::<Manifest>(value).unwrap().validate(&index, &owner).is_err();
+        assert!(refused(json! {{ "apps": [{ "name": "a1", "scopes": [] }] }}));
+        assert!(refused(json! {{ "apps": [{ "name": "a1", "databases": ["db9"] }] }}));
+    }
+
+    #[test]
+    pub fn test_manifests_which_cant_be_applied_are_refused() {
+        let index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .database("db1")
+            .build();
+
+        let owner = "u1".to_owned();
+        let manifest = |value: serde_json::Value| serde_json::from_value::<Manifest>(value).unwrap();
+
+        assert!(manifest(json! {{ "databases": [{ "name": "db1" }] }}).validate(&index, &owner).is_err());
+        assert!(manifest(json! {{ "databases": [{ "name": "db2", "rw": ["u9"] }] }}).validate(&index, &owner).is_err());
+        assert!(manifest(json! {{ "databases": [{ "name": "db2", "apps": ["a9"] }] }}).validate(&index, &owner).is_err());
+        assert!(manifest(json! {{ "apps": [{ "name": "a1" }, { "name": "a1" }] }}).validate(&index, &owner).is_err());
+        assert!(serde_json::from_value::<Manifest>(json! {{ "collections": [] }}).is_err());
+    }
+}
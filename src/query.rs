@@ -0,0 +1,336 @@
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::time::Duration;
+use std::time::Instant;
+use actix_web::http::header::{ByteRangeSpec, EntityTag, IfMatch, IfNoneMatch};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use libdb::FragmentID;
+use libdb::sizing::SizeHistogram;
+use crate::changelog::{self, Action};
+use crate::config::DocumentConfig;
+use crate::dictionary;
+use crate::document::check_document;
+use crate::error::*;
+use crate::keys::{collection, KeyDirectory, ObjectKey, ObjectMeta, DELIMITER};
+use crate::patch::Patch;
+use crate::pool::Store;
+use crate::secondary;
+use crate::versions;
+
+/// The amount of data read from a fragment between budget checks.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// The most objects of a collection whose sizes decide how a new object in it is buffered.
+const SIZE_SAMPLES: usize = 1000;
+
+/// Bounds how long a single query may run for. Once exhausted, queries stop and report what they have gathered so far.
+#[derive(Debug, Copy, Clone)]
+pub struct QueryBudget {
+    deadline: Instant,
+}
+
+impl QueryBudget {
+    pub fn new(budget: Duration) -> Self {
+        Self { deadline: Instant::now() + budget }
+    }
+
+    pub fn exhausted(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+/// The outcome of a query. If `partial` is set, the query ran out of time and can be resumed by passing `cursor` back.
+#[derive(Debug, Serialize)]
+pub struct QueryResult<T> {
+    pub results: T,
+    pub partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// The conditions a write places on the current version of an object, taken from its `If-Match` and `If-None-Match` headers.
+#[derive(Debug, Clone, Default)]
+pub struct Precondition {
+    pub if_match: Option<IfMatch>,
+    pub if_none_match: Option<IfNoneMatch>,
+}
+
+impl Precondition {
+    /// Whether an object tagged `current`, or a missing one, meets the conditions. `If-Match` compares tags strongly and `If-None-Match`
+    /// weakly, as RFC 9110 asks.
+    pub fn holds(&self, current: Option<&EntityTag>) -> bool {
+        let if_match = match &self.if_match {
+            None => true,
+            Some(IfMatch::Any) => current.is_some(),
+            Some(IfMatch::Items(tags)) => current.is_some_and(|current| tags.iter().any(|tag| tag.strong_eq(current))),
+        };
+
+        let if_none_match = match &self.if_none_match {
+            None => true,
+            Some(IfNoneMatch::Any) => current.is_none(),
+            Some(IfNoneMatch::Items(tags)) => current.is_none_or(|current| !tags.iter().any(|tag| tag.weak_eq(current))),
+        };
+
+        if_match && if_none_match
+    }
+}
+
+/// Tags the current contents of the object stored in fragment `id`. Every write gives the fragment a new sequence, so the tag changes
+/// whenever the object does.
+pub fn etag(store: &Store, id: FragmentID) -> Result<EntityTag> {
+    let info = store.fragment_info(id)?;
+
+    Ok(EntityTag::new_strong(format!("{:x}-{:x}", id, info.sequence)))
+}
+
+/// Writes the object at `key` as [`write_object`] does, but only if it meets `precondition`, and returns its new tag. The store is held
+/// from the check until the write is done, so no other write can come between them.
+pub fn write_object_if(store: &mut Store, key: &ObjectKey, content_type: &str, data: &[u8], expires: Option<DateTime<Utc>>, actor: Option<&str>, precondition: &Precondition) -> Result<EntityTag> {
+    let current = match KeyDirectory::load(store)?.get(key) {
+        Some(meta) => Some(etag(store, meta.id)?),
+        None => None,
+    };
+
+    if !precondition.holds(current.as_ref()) {
+        return Err(ManualError::PreconditionFailed.into());
+    }
+
+    let id = write_object(store, key, content_type, data, expires, actor)?;
+    etag(store, id)
+}
+
+/// Replaces the contents of the object at `key` by writing them as the next sequence of its fragment, creating the object if needed. The
+/// object expires at `expires`, if given, and otherwise never does. The write is recorded in the changelog as made by `actor`.
+pub fn write_object(store: &mut Store, key: &ObjectKey, content_type: &str, data: &[u8], expires: Option<DateTime<Utc>>, actor: Option<&str>) -> Result<FragmentID> {
+    let mut directory = KeyDirectory::load(store)?;
+
+    // An expired object which hasn't been removed yet is replaced by a new one rather than written over, so none of its history survives.
+    let expired = directory.get(key).is_none().then(|| directory.remove(key)).flatten();
+    let previous = directory.get(key).cloned();
+    let (id, _) = directory.get_or_insert(key, content_type);
+    directory.set_expiry(key, expires);
+    secondary::reindex(&mut directory, key, data);
+
+    let superseded = match previous {
+        Some(ref previous) => versions::record(store, &mut directory, key, previous, data)?,
+        None => None,
+    };
+    let (data, dictionary) = dictionary::encode(store, &directory, key, data)?;
+    directory.set_dictionary(key, dictionary);
+
+    let action = if previous.is_some() { Action::Update } else { Action::Create };
+    let segments = changelog::record(store, &mut directory, key, action, actor)?;
+
+    // The object is written before the directory refers to it, so a failed write can't leave a key pointing nowhere. Indexes and the
+    // changelog are saved with the directory, so they change along with it. Every write is recorded, so the directory is always saved.
+    let options = collection_sizes(store, &directory, key).alloc_options().fragment(id);
+    store.write_fragment(options, &data)?;
+    directory.save(store)?;
+
+    // Versions which no longer fit in the object's history are only deleted once the directory has stopped referring to them.
+    let expired = expired.iter().flat_map(ObjectMeta::fragments);
+    for fragment in superseded.into_iter().flatten().chain(expired).chain(segments) {
+        store.delete_fragment(fragment)?;
+    }
+
+    store.flush()?;
+
+    Ok(id)
+}
+
+/// Applies `patch` to the JSON document stored at `key` and writes the result back with the same content type. The store is held
+/// throughout, so no other write can land between reading the document and writing it. The patched document is held to the same limits as
+/// uploaded ones. The document is only patched if it meets `precondition`. Returns its new tag, or `None` if there is no such object.
+pub fn apply_patch(store: &mut Store, key: &ObjectKey, patch: &Patch, limits: &DocumentConfig, actor: Option<&str>, precondition: &Precondition) -> Result<Option<EntityTag>> {
+    let Some((meta, data)) = read_whole_object(store, key)? else {
+        return Ok(None);
+    };
+
+    if !precondition.holds(Some(&etag(store, meta.id)?)) {
+        return Err(ManualError::PreconditionFailed.into());
+    }
+
+    let mut document = serde_json::from_slice::<serde_json::Value>(&data).map_err(|_| PatchError::NotADocument)?;
+    patch.apply(&mut document)?;
+
+    let data = serde_json::to_vec(&document)?;
+    check_document(&data, limits)?;
+
+    let id = write_object(store, key, &meta.content_type, &data, meta.expires, actor)?;
+    etag(store, id).map(Some)
+}
+
+/// How large the objects already stored in `key`'s collection are, so a new one can be buffered to suit them.
+fn collection_sizes(store: &Store, directory: &KeyDirectory, key: &ObjectKey) -> SizeHistogram {
+    let Some(collection) = collection(key) else {
+        return SizeHistogram::default();
+    };
+
+    directory.objects(&format!("{}{}", collection, DELIMITER))
+        .take(SIZE_SAMPLES)
+        .filter_map(|(_, meta)| store.fragment_info(meta.id).ok())
+        .map(|info| info.length)
+        .collect()
+}
+
+/// Creates the object at `key` unless there is one already. Returns `None` if the key is taken.
+pub fn create_object(store: &mut Store, key: &ObjectKey, content_type: &str, data: &[u8], expires: Option<DateTime<Utc>>, actor: Option<&str>) -> Result<Option<FragmentID>> {
+    if KeyDirectory::load(store)?.get(key).is_some() {
+        return Ok(None);
+    }
+
+    write_object(store, key, content_type, data, expires, actor).map(Some)
+}
+
+/// Reads the contents of the object at `key` starting at `offset`, stopping early once the budget has been used up.
+/// Returns `None` if there is no such object.
+pub fn read_object(store: &mut Store, key: &ObjectKey, offset: u64, budget: QueryBudget) -> Result<Option<QueryResult<Vec<u8>>>> {
+    let Some(meta) = KeyDirectory::load(store)?.get(key).cloned() else {
+        return Ok(None);
+    };
+
+    // Compressed objects can only be read whole.
+    if meta.dictionary.is_some() {
+        let contents = dictionary::read_contents(store, &meta)?;
+        let results = contents.get(offset as usize..).unwrap_or_default().to_vec();
+
+        return Ok(Some(QueryResult { results, partial: false, cursor: None }));
+    }
+
+    let mut fragment = store.open_fragment(meta.id)?;
+    fragment.seek(SeekFrom::Start(offset))?;
+
+    let mut results = vec![];
+    let mut chunk = vec![0u8; READ_CHUNK];
+
+    loop {
+        let len = fragment.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(Some(QueryResult { results, partial: false, cursor: None }));
+        }
+
+        results.extend_from_slice(&chunk[..len]);
+
+        if budget.exhausted() {
+            let cursor = offset + results.len() as u64;
+            return Ok(Some(QueryResult { results, partial: true, cursor: Some(cursor.to_string()) }));
+        }
+    }
+}
+
+/// Reads the whole of the object at `key` along with its metadata. Returns `None` if there is no such object.
+pub fn read_whole_object(store: &mut Store, key: &ObjectKey) -> Result<Option<(ObjectMeta, Vec<u8>)>> {
+    let Some(meta) = KeyDirectory::load(store)?.get(key).cloned() else {
+        return Ok(None);
+    };
+
+    let data = dictionary::read_contents(store, &meta)?;
+
+    Ok(Some((meta, data)))
+}
+
+/// An inclusive `(first, last)` byte range of an object, along with its contents.
+pub type RangePart = ((u64, u64), Vec<u8>);
+
+/// Parts of an object read by [`read_object_ranges`].
+#[derive(Debug)]
+pub struct ObjectRanges {
+    pub meta: ObjectMeta,
+    pub etag: EntityTag,
+
+    /// The size of the whole object.
+    pub size: u64,
+
+    /// Each satisfiable range as an inclusive `(first, last)` pair, along with its contents. Empty if none of the ranges could be satisfied.
+    pub parts: Vec<RangePart>,
+}
+
+/// Reads only the requested byte ranges of the object at `key`, seeking past everything else. Returns `None` if there is no such object.
+///
+/// Ranges which lie entirely beyond the end of the object are skipped.
+pub fn read_object_ranges(store: &mut Store, key: &ObjectKey, ranges: &[ByteRangeSpec]) -> Result<Option<ObjectRanges>> {
+    let Some(meta) = KeyDirectory::load(store)?.get(key).cloned() else {
+        return Ok(None);
+    };
+
+    let (size, parts) = match meta.dictionary {
+        Some(_) => {
+            let contents = dictionary::read_contents(store, &meta)?;
+            let size = contents.len() as u64;
+            (size, read_ranges(Cursor::new(contents), size, ranges)?)
+        },
+        None => {
+            let fragment = store.open_fragment(meta.id)?;
+            let size = fragment.size() as u64;
+            (size, read_ranges(fragment, size, ranges)?)
+        },
+    };
+
+    let etag = etag(store, meta.id)?;
+
+    Ok(Some(ObjectRanges { meta, etag, size, parts }))
+}
+
+fn read_ranges(mut source: impl Read + Seek, size: u64, ranges: &[ByteRangeSpec]) -> Result<Vec<RangePart>> {
+    let mut parts = vec![];
+
+    for (first, last) in ranges.iter().filter_map(|range| range.to_satisfiable_range(size)) {
+        let mut data = vec![0u8; (last - first + 1) as usize];
+
+        source.seek(SeekFrom::Start(first))?;
+        source.read_exact(&mut data)?;
+
+        parts.push(((first, last), data));
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_preconditions_compare_tags() {
+        let (current, other) = (EntityTag::new_strong("1-2".to_owned()), EntityTag::new_strong("1-1".to_owned()));
+        let precondition = |if_match, if_none_match| Precondition { if_match, if_none_match };
+
+        assert!(Precondition::default().holds(None) && Precondition::default().holds(Some(&current)));
+
+        let matching = precondition(Some(IfMatch::Items(vec![other.clone(), current.clone()])), None);
+        assert!(matching.holds(Some(&current)) && !matching.holds(Some(&EntityTag::new_weak("1-2".to_owned()))) && !matching.holds(None));
+        assert!(precondition(Some(IfMatch::Any), None).holds(Some(&current)) && !precondition(Some(IfMatch::Any), None).holds(None));
+
+        let creating = precondition(None, Some(IfNoneMatch::Any));
+        assert!(creating.holds(None) && !creating.holds(Some(&current)));
+        assert!(!precondition(None, Some(IfNoneMatch::Items(vec![current.clone()]))).holds(Some(&current)));
+        assert!(precondition(None, Some(IfNoneMatch::Items(vec![other]))).holds(Some(&current)));
+    }
+
+    #[test]
+    pub fn test_rolled_back_writes_leave_no_trace() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("query-rollback-test-{}.db", std::process::id()));
+        let mut store = Store::create(&path)?;
+        let key = |key: &str| ObjectKey::parse(key).unwrap();
+
+        write_object(&mut store, &key("notes/a"), "application/json", b"1", None, None)?;
+        let changes = KeyDirectory::load(&mut store)?.changelog().last();
+
+        let savepoint = store.savepoint();
+        write_object(&mut store, &key("notes/a"), "application/json", b"2", None, None)?;
+        write_object(&mut store, &key("notes/b"), "application/json", b"3", None, None)?;
+        store.rollback(savepoint)?;
+
+        assert_eq!(read_whole_object(&mut store, &key("notes/a"))?.map(|(_, data)| data), Some(b"1".to_vec()));
+        assert!(read_whole_object(&mut store, &key("notes/b"))?.is_none());
+        assert_eq!(KeyDirectory::load(&mut store)?.changelog().last(), changes);
+
+        store.unlock(&path)?;
+        drop(store);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
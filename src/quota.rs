@@ -0,0 +1,86 @@
+use actix_web::{get, put, web, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::index::{commit_change, DBIndexChange};
+use crate::pool::DbPool;
+use crate::response::{ApiError, ApiResponse};
+use crate::{DBIndex, DatabaseID};
+
+#[derive(Deserialize, ToSchema)]
+pub struct SetQuotaOptions {
+    /// The most bytes the database's store may allocate. Removes the quota if null.
+    quota: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    quota: Option<u64>,
+    allocated: u64,
+}
+
+#[derive(Serialize)]
+struct Quota {
+    quota: Option<u64>,
+}
+
+/// Reports a database's quota and how much of it its store has allocated. Space freed by deleted objects stays allocated until it is
+/// reused, so it still counts. Only the database's owner, `admins` and `quotas.admins` may see it.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "The database's quota and usage"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/quota")]
+pub async fn get_quota(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let admin = config.is_quota_admin(&user.id);
+
+    let Some(db) = index.lock().await.databases.iter()
+        .find(|db| db.id == *id && (admin || db.owner == user.id))
+        .cloned() else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let allocated = store.lock().await.allocated_size();
+
+    Ok(ApiResponse::ok(Usage { quota: db.quota, allocated }))
+}
+
+/// Sets or removes a database's quota. It applies to the next write, including writes to a store which is already open.
+///
+/// Lowering a quota below what the store has already allocated doesn't shrink the store. It only refuses writes which would grow it further.
+/// Only `admins` and `quotas.admins` may change quotas.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "The database's ID")),
+    request_body = SetQuotaOptions,
+    responses((status = 200, description = "The quota was set"), (status = 403, description = "Only quota admins may set quotas"), (status = 404, description = "No such database")),
+    security(("user" = [])),
+)]
+#[put("/admin/databases/{id}/quota")]
+pub async fn set_quota(id: web::Path<DatabaseID>, options: web::Json<SetQuotaOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    if !config.is_quota_admin(&user.id) {
+        return Err(ApiError::forbidden("not_an_admin", "Only quota admins may change quotas").into());
+    }
+
+    if !index.lock().await.databases.iter().any(|db| db.id == id) {
+        return Err(ApiError::no_such_database().into());
+    }
+
+    commit_change(DBIndexChange::SetQuota { database: id.clone(), quota: options.quota }).await
+        .map_err(ApiError::internal)?;
+
+    let db = index.lock().await.databases.iter().find(|db| db.id == id).cloned();
+    if let Some(db) = db {
+        pool.set_quota(&db).await;
+    }
+
+    log::warn!(target: "audit", "{} set the quota of database {} to {:?}", user, id, options.quota);
+
+    Ok(ApiResponse::ok(Quota { quota: options.quota }))
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{AUTHORIZATION, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use crate::config::{RateLimit, RateLimitConfig};
+use crate::response::ApiError;
+
+/// Once this many buckets exist, idle ones are discarded before adding another.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Takes a token from the bucket, or returns how many seconds it will be until one is available.
+    fn take(&mut self, limit: &RateLimit) -> Result<(), u64> {
+        self.refill(limit, Instant::now());
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / limit.rate).ceil() as u64)
+        }
+    }
+}
+
+/// Tracks a token bucket for every client and route, so one client making too many requests can't starve the others.
+///
+/// Clients are identified by the token they authenticate with, or by their address if they don't send one.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, route: &str, client: &str) -> Result<(), u64> {
+        let limit = self.config.route(route);
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+
+        let key = (route.to_owned(), client.to_owned());
+
+        if !buckets.contains_key(&key) && buckets.len() >= PRUNE_THRESHOLD {
+            let now = Instant::now();
+            buckets.retain(|(route, _), bucket| {
+                let limit = self.config.route(route);
+                bucket.refill(limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+        }
+
+        buckets.entry(key)
+            .or_insert_with(|| Bucket::full(limit))
+            .take(limit)
+    }
+}
+
+/// Rejects requests with `429 Too Many Requests` once their client has used up its allowance for the route.
+pub async fn rate_limit(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+    let client = match req.headers().get(AUTHORIZATION).and_then(|token| token.to_str().ok()) {
+        // Signed S3 requests carry a new signature each time, so they are told apart by the key they were signed with.
+        Some(token) => crate::s3::access_key(token).unwrap_or(token).to_owned(),
+        None => req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default(),
+    };
+
+    if let Err(retry_after) = limiter.check(&route, &client) {
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, retry_after.to_string()))
+            .json(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", format!("Too many requests. Try again in {} seconds", retry_after)));
+
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    Ok(next.call(req).await?.map_into_left_body())
+}
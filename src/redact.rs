@@ -0,0 +1,85 @@
+use chrono::DateTime;
+use chrono::Utc;
+use serde::ser::SerializeStruct;
+use serde::Serialize;
+use serde::Serializer;
+use crate::Token;
+
+/// The number of leading characters of a token which may be shown to a user to help them tell tokens apart.
+pub const TOKEN_PREFIX_LEN: usize = 6;
+
+const REDACTED: &str = "<redacted>";
+
+/// Replaces the value with a fixed marker. Use with `#[serde(serialize_with = "redact::secret")]`.
+pub fn secret<T: ?Sized, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+/// Emits only the first [`TOKEN_PREFIX_LEN`] characters of a token along with its length. Use with `#[serde(serialize_with = "redact::prefix")]`.
+pub fn prefix<T: AsRef<str> + ?Sized, S: Serializer>(token: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    let token = token.as_ref();
+
+    let mut prefix = serializer.serialize_struct("TokenPrefix", 2)?;
+    prefix.serialize_field("prefix", &token.chars().take(TOKEN_PREFIX_LEN).collect::<String>())?;
+    prefix.serialize_field("length", &token.len())?;
+    prefix.end()
+}
+
+/// A view of a [`Token`] which is safe to include in responses. The refresh token is never emitted.
+#[derive(Debug, Serialize)]
+pub struct TokenSummary<'a> {
+    pub token: TokenHint<'a>,
+    pub expiry: DateTime<Utc>,
+}
+
+/// What a user is shown of a token to recognise it by. The index only keeps tokens hashed, so this is the hint recorded when it was
+/// hashed, or `None` for tokens hashed before hints were kept.
+#[derive(Debug, Serialize)]
+pub struct TokenHint<'a> {
+    pub prefix: Option<&'a str>,
+}
+
+impl<'a> From<&'a Token> for TokenSummary<'a> {
+    fn from(token: &'a Token) -> Self {
+        Self {
+            token: TokenHint { prefix: token.hint.as_deref() },
+            expiry: token.expiry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Credentials<'a> {
+        #[serde(serialize_with = "secret")]
+        secret: &'a str,
+        #[serde(serialize_with = "prefix")]
+        token: &'a str,
+    }
+
+    #[test]
+    pub fn test_secrets_and_tokens_never_echo_more_than_the_prefix() {
+        let token = Token { token: "abcdefghijklmnop".to_owned(), refresh: "refresh-secret".to_owned(), expiry: Utc::now(), hint: None };
+
+        let credentials = serde_json::to_string(&Credentials { secret: "hunter2", token: &token.token }).unwrap();
+        assert_eq!(credentials, r#"{"secret":"<redacted>","token":{"prefix":"abcdef","length":16}}"#);
+
+        // Summaries show the hint recorded for the token, never the hash kept in its place.
+        let hashed = Token { token: "h1:wyuQ2b".to_owned(), refresh: "h1:refresh".to_owned(), expiry: Utc::now(), hint: Some("abcdef".to_owned()) };
+        let summary = serde_json::to_string(&TokenSummary::from(&hashed)).unwrap();
+        assert!(summary.contains(r#""token":{"prefix":"abcdef"}"#));
+        assert!(!summary.contains("h1:"));
+
+        let summary = serde_json::to_string(&TokenSummary::from(&token)).unwrap();
+        assert!(summary.contains(r#""token":{"prefix":null}"#));
+        assert!(!summary.contains("abcdef"));
+        assert!(!summary.contains("refresh-secret"));
+
+        // Tokens shorter than the prefix are shown whole, but never padded or repeated.
+        let short = serde_json::to_string(&Credentials { secret: "", token: "abc" }).unwrap();
+        assert_eq!(short, r#"{"secret":"<redacted>","token":{"prefix":"abc","length":3}}"#);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use libdb::{AllocOptions, FragmentID};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::config::{ReplicationConfig, ServerConfig};
+use crate::error::*;
+use crate::keys::DIRECTORY_FRAGMENT;
+use crate::pool::{DbPool, Store};
+use crate::response::ApiError;
+use crate::{hashing, index, schema, DBIndex, DatabaseID};
+use crate::telemetry;
+
+/// The name of the file a follower keeps beside each store, recording which sequence of each of the primary's fragments it holds.
+pub const REPLICA_FILE: &str = "replica.json";
+
+/// What a follower asks the primary for: the changes to a database since the sequences it already holds.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct StreamRequest {
+    pub db: DatabaseID,
+
+    /// The primary's sequence of every fragment the follower holds.
+    #[serde(default)]
+    pub known: BTreeMap<FragmentID, u64>,
+}
+
+/// A fragment which differs between the primary and a follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FragmentChange {
+    pub id: FragmentID,
+    pub sequence: u64,
+
+    /// The fragment's contents in base64, or `None` if the primary has deleted it.
+    #[serde(default)]
+    pub payload: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamBatch {
+    pub changes: Vec<FragmentChange>,
+
+    /// Set if there are more changes than fit in one batch. The follower asks again once it has applied these.
+    pub more: bool,
+}
+
+/// Works out what a follower holding `known` lacks of a store whose fragments are at `live`: the fragments it should delete, followed by
+/// those it should write. The key directory is always written last, so a follower never refers to objects it hasn't received yet.
+fn plan(live: &BTreeMap<FragmentID, u64>, known: &BTreeMap<FragmentID, u64>) -> Vec<(FragmentID, Option<u64>)> {
+    let deleted = known.keys()
+        .filter(|id| !live.contains_key(id))
+        .map(|id| (*id, None));
+
+    let (directory, changed) = live.iter()
+        .filter(|(id, sequence)| known.get(id) != Some(sequence))
+        .map(|(id, sequence)| (*id, Some(*sequence)))
+        .partition::<Vec<_>, _>(|(id, _)| *id == DIRECTORY_FRAGMENT);
+
+    deleted.chain(changed).chain(directory).collect()
+}
+
+/// Gathers up to about `batch_size` bytes of the changes a follower holding `known` lacks. Every batch is worked out afresh from the store
+/// as it is, so the last batch of a round leaves the follower with a copy of the store as it was at a single moment.
+fn read_batch(store: &mut Store, known: &BTreeMap<FragmentID, u64>, batch_size: u64) -> Result<StreamBatch> {
+    let live = store.fragments().map(|info| (info.id, info.sequence)).collect::<BTreeMap<_, _>>();
+    let planned = plan(&live, known);
+
+    let mut batch = StreamBatch { changes: vec![], more: false };
+    let mut size = 0;
+
+    for (id, sequence) in planned {
+        if size >= batch_size && !batch.changes.is_empty() {
+            batch.more = true;
+            break;
+        }
+
+        let Some(sequence) = sequence else {
+            batch.changes.push(FragmentChange { id, sequence: 0, payload: None });
+            continue;
+        };
+
+        let mut data = vec![];
+        store.open_fragment(id)?.read_into(&mut data)?;
+        size += data.len() as u64;
+
+        batch.changes.push(FragmentChange { id, sequence, payload: Some(STANDARD.encode(data)) });
+    }
+
+    Ok(batch)
+}
+
+/// Whether the request carries the token followers must present. Replication is disabled without one configured.
+fn authorised(req: &HttpRequest, config: &ReplicationConfig) -> Option<HttpResponse> {
+    let Some(ref token) = config.token else {
+        return Some(HttpResponse::NotFound().finish());
+    };
+
+    let presented = req.headers().get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+
+    (presented != Some(token.as_str())).then(|| HttpResponse::Unauthorized().finish())
+}
+
+/// Serves the database index to followers, so they know which databases to copy and can take over serving them.
+#[utoipa::path(
+    tag = "replication",
+    responses((status = 200, description = "The index, for followers"), (status = 401, description = "Missing or wrong replication token")),
+    security(("replication" = [])),
+)]
+#[get("/replication/index")]
+pub async fn replicate_index(req: HttpRequest, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if let Some(refused) = authorised(&req, &config.replication) {
+        return Ok(refused);
+    }
+
+    let index = index.serialise().await.map_err(ApiError::internal)?;
+
+    Ok(HttpResponse::Ok().content_type("application/json").body(index))
+}
+
+/// Serves the key tokens are hashed with to followers. The index only holds the hashes of tokens, so a follower promoted without the key
+/// would accept none of them.
+#[utoipa::path(
+    tag = "replication",
+    responses((status = 200, description = "The token key, for followers"), (status = 401, description = "Missing or wrong replication token")),
+    security(("replication" = [])),
+)]
+#[get("/replication/key")]
+pub async fn replicate_key(req: HttpRequest, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if let Some(refused) = authorised(&req, &config.replication) {
+        return Ok(refused);
+    }
+
+    let key = hashing::read_key(&config.database_dir).await.map_err(ApiError::internal)?;
+    Ok(HttpResponse::Ok().content_type("application/octet-stream").body(key))
+}
+
+/// Sends a follower the fragments of a database which it doesn't hold, or holds an older sequence of, along with those it should delete.
+/// Guarded by `replication.token`.
+#[utoipa::path(
+    tag = "replication",
+    request_body = StreamRequest,
+    responses((status = 200, description = "The fragments which differ from the follower's"), (status = 401, description = "Missing or wrong replication token")),
+    security(("replication" = [])),
+)]
+#[post("/replication/stream")]
+pub async fn stream(req: HttpRequest, request: web::Json<StreamRequest>, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if let Some(refused) = authorised(&req, &config.replication) {
+        return Ok(refused);
+    }
+
+    let request = request.into_inner();
+    let Some(db) = index.lock().await.databases.iter().find(|db| db.id == request.db).cloned() else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let batch_size = config.replication.batch_size;
+
+    let batch = telemetry::block(move || read_batch(&mut store.blocking_lock(), &request.known, batch_size))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    Ok(HttpResponse::Ok().json(batch))
+}
+
+fn read_known(root: &Path) -> BTreeMap<FragmentID, u64> {
+    std::fs::read(root.join(REPLICA_FILE)).ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_known(root: &Path, known: &BTreeMap<FragmentID, u64>) -> Result<()> {
+    let path = root.join(REPLICA_FILE);
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, serde_json::to_vec(known)?)?;
+
+    Ok(std::fs::rename(&partial, &path)?)
+}
+
+/// Writes a batch from the primary into the follower's copy of the store, and records the sequences it now holds.
+fn apply_batch(store: &mut Store, root: &Path, known: &mut BTreeMap<FragmentID, u64>, batch: StreamBatch) -> Result<()> {
+    for change in batch.changes {
+        match change.payload {
+            Some(payload) => {
+                let data = STANDARD.decode(payload).map_err(|_| ManualError::ReplicationPayloadInvalid(change.id))?;
+                store.write_fragment(AllocOptions::default().fragment(change.id), &data)?;
+                known.insert(change.id, change.sequence);
+            },
+            None => {
+                if store.fragment_info(change.id).is_ok() {
+                    store.delete_fragment(change.id)?;
+                }
+
+                known.remove(&change.id);
+            },
+        }
+    }
+
+    // The store is flushed before the sequences are recorded, so a follower which stops in between only asks for the same changes again.
+    store.flush()?;
+    write_known(root, known)
+}
+
+struct Follower {
+    client: reqwest::Client,
+    primary: String,
+    token: String,
+    dir: PathBuf,
+    pool: DbPool,
+}
+
+impl Follower {
+    async fn sync(&self) -> Result<()> {
+        let data = self.client.get(format!("{}/replication/index", self.primary))
+            .bearer_auth(&self.token)
+            .send().await?
+            .error_for_status()?
+            .text().await?;
+
+        let mut index = schema::read_index(&data)?;
+        for db in index.databases.iter_mut() {
+            let name = db.root.file_name().map_or_else(|| db.id.clone().into(), |name| name.to_owned());
+            db.root = self.dir.join(name);
+            db.quarantine = None;
+        }
+
+        let index_path = self.dir.join("index.json");
+        let previous = std::fs::read_to_string(&index_path).ok().and_then(|data| schema::read_index(&data).ok());
+
+        for db in index.databases.iter() {
+            tokio::fs::create_dir_all(&db.root).await?;
+            self.sync_database(db).await?;
+        }
+
+        // The key is in place before the index whose tokens were hashed with it.
+        let key = self.client.get(format!("{}/replication/key", self.primary))
+            .bearer_auth(&self.token)
+            .send().await?
+            .error_for_status()?
+            .bytes().await?;
+
+        if hashing::replicate_key(&self.dir, &key)? {
+            log::info!("Copied the primary's token key");
+        }
+
+        index::write_locked(index_path, schema::write_index(&index)?).await?;
+
+        // Databases deleted on the primary are deleted here too, once the index no longer lists them.
+        let current = index.databases.iter().map(|db| &db.id).collect::<BTreeSet<_>>();
+        for db in previous.into_iter().flat_map(|index| index.databases).filter(|db| !current.contains(&db.id)) {
+            self.pool.evict(&db.id).await;
+            log::info!("Database {} was deleted on the primary", db.id);
+
+            if let Err(err) = tokio::fs::remove_dir_all(&db.root).await {
+                log::warn!("Failed to delete the copy of database {}: {}", db.id, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn sync_database(&self, db: &crate::Database) -> Result<()> {
+        let store = self.pool.open(db).await?;
+        let mut known = read_known(&db.root);
+
+        loop {
+            let request = StreamRequest { db: db.id.clone(), known: known.clone() };
+            let batch = self.client.post(format!("{}/replication/stream", self.primary))
+                .bearer_auth(&self.token)
+                .json(&request)
+                .send().await?
+                .error_for_status()?
+                .json::<StreamBatch>().await?;
+
+            let (more, changes) = (batch.more, batch.changes.len());
+            let (store, root) = (store.clone(), db.root.clone());
+            known = tokio::task::spawn_blocking(move || {
+                apply_batch(&mut store.blocking_lock(), &root, &mut known, batch).map(|_| known)
+            }).await.map_err(|_| ManualError::StoreOpenFailed)??;
+
+            if changes > 0 {
+                log::debug!("Applied {} changes to database {}", changes, db.id);
+            }
+
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Runs the server as a follower of `replication.replicate_from` until it is asked to stop, copying the primary's index and the fragments
+/// of every store every `replication.interval` seconds. The follower serves nothing itself. It is a warm standby: to promote it, restart
+/// it without `--replicate-from`, and it serves the databases as they were at the last round it finished.
+pub async fn follow(config: ServerConfig) -> Result<()> {
+    let (Some(primary), Some(token)) = (config.replication.replicate_from.clone(), config.replication.replicate_token.clone()) else {
+        return Err(ManualError::MissingReplicationToken.into());
+    };
+
+    let follower = Follower {
+        client: reqwest::Client::new(),
+        primary: primary.trim_end_matches('/').to_owned(),
+        token,
+        dir: config.database_dir.clone(),
+        pool: DbPool::new(config.stores.clone()),
+    };
+
+    log::info!("Following {}", follower.primary);
+    tokio::fs::create_dir_all(&follower.dir).await?;
+
+    let mut interval = tokio::time::interval(config.replication.interval());
+    let stop = crate::shutdown_signal();
+    tokio::pin!(stop);
+
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = interval.tick() => if let Err(err) = follower.sync().await {
+                log::warn!("Failed to replicate from {}: {:?}", follower.primary, err);
+            },
+        }
+    }
+
+    log::info!("Shutting down");
+    follower.pool.close_all().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_plans_send_what_followers_lack_with_the_directory_last() {
+        let live = BTreeMap::from([(DIRECTORY_FRAGMENT, 9), (2, 1), (3, 4), (5, 1)]);
+        let known = BTreeMap::from([(DIRECTORY_FRAGMENT, 8), (2, 1), (3, 3), (4, 2)]);
+
+        assert_eq!(plan(&live, &known), [(4, None), (3, Some(4)), (5, Some(1)), (DIRECTORY_FRAGMENT, Some(9))]);
+        assert!(plan(&live, &live).is_empty());
+    }
+}
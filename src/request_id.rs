@@ -0,0 +1,95 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::error::InternalError;
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The longest request ID accepted from a client. Longer ones are replaced rather than cut short, so they can't be mistaken for another.
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Identifies a request in the server's log, its access log, its span and its response, so a failure a client reports can be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        Self(format!("{:016x}", rand::random::<u64>()))
+    }
+
+    /// The ID the client sent, if it is one the server is willing to repeat in its log and headers.
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let id = headers.get(REQUEST_ID_HEADER)?.to_str().ok()?;
+        let valid = !id.is_empty() && id.len() <= MAX_LENGTH
+            && id.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b':'));
+
+        valid.then(|| Self(id.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The ID of the request being served, if any. Only requests which passed through [`assign`] have one.
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Gives every request an ID: the one in its `X-Request-Id` header, or a new one if it has none. The ID is returned in the same header,
+/// and is what [`current`] gives while the request is served.
+pub async fn assign(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let id = RequestId::from_headers(req.headers()).unwrap_or_else(RequestId::generate);
+    req.extensions_mut().insert(id.clone());
+
+    let res = CURRENT.scope(id.clone(), next.call(req)).await;
+    let Ok(value) = HeaderValue::from_str(id.as_str()) else {
+        return res;
+    };
+
+    match res {
+        Ok(mut res) => {
+            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            Ok(res)
+        },
+        Err(err) => {
+            // Errors are turned into responses by actix after this returns, so the header is added to one made here instead. Their bodies
+            // already carry the ID, since errors record it when they are made.
+            let mut response = err.error_response();
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            Err(InternalError::from_response(err, response).into())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use crate::response::ApiError;
+
+    #[test]
+    pub fn test_only_sensible_ids_are_taken_from_clients() {
+        let id = |value: &str| RequestId::from_headers(TestRequest::default().insert_header((REQUEST_ID_HEADER, value)).to_http_request().headers());
+
+        assert_eq!(id("3f2a-b7:c_1.0"), Some(RequestId("3f2a-b7:c_1.0".to_owned())));
+        assert_eq!(id(""), None);
+        assert_eq!(id("has spaces"), None);
+        assert_eq!(id("line\"break"), None);
+        assert_eq!(id(&"a".repeat(MAX_LENGTH + 1)), None);
+
+        assert_eq!(RequestId::from_headers(TestRequest::default().to_http_request().headers()), None);
+        assert_eq!(RequestId::generate().as_str().len(), 16);
+
+        let error = CURRENT.sync_scope(RequestId("r1".to_owned()), ApiError::no_such_database);
+        assert_eq!(serde_json::to_value(&error).unwrap()["request_id"], "r1");
+
+        assert!(serde_json::to_value(ApiError::no_such_database()).unwrap().get("request_id").is_none());
+    }
+}
@@ -1,17 +1,24 @@
-use actix_web::{get, put, web, HttpResponse, Responder};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use crate::{generate_token, Args, DBIndex, Database, DatabaseID, DatabaseIndex};
+use utoipa::{IntoParams, ToSchema};
+use crate::{DBIndex, Database, DatabaseID, UserID};
+use crate::config::ServerConfig;
 use crate::auth::AuthenticatedUser;
-use crate::index::{handle_changes, push_change, DBIndexChange};
+use crate::redact::TokenSummary;
+use crate::index::{commit_change, push_change, DBIndexChange};
+use crate::pool::{checkpoint, DbPool};
+use crate::paging::PageOptions;
+use crate::response::{ApiError, ApiResponse, Done};
+use crate::telemetry;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 pub struct GetDatabasesOptions {
     membership: Option<Membership>,
     name: Option<String>,
 }
 
-#[derive(Copy, Clone, Deserialize)]
+#[derive(Copy, Clone, Deserialize, ToSchema)]
 pub enum Membership {
     Owner,
     ReadWrite,
@@ -19,6 +26,14 @@ pub enum Membership {
     Member
 }
 
+/// What a member of a database may do with it. The owner may always do anything.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DatabaseDescription {
     name: String,
@@ -29,9 +44,25 @@ pub struct DatabaseDescription {
     objects: u64
 }
 
+#[derive(Serialize)]
+struct DatabaseList {
+    databases: Vec<DatabaseDescription>,
+    next_cursor: Option<String>,
+    health: u32,
+    objects: Option<usize>,
+}
+
+/// Lists the databases the user belongs to, a page at a time, ordered by ID.
+///
 /// TODO: Get database health - Perform an index check to see how large it is and whether it's corrupt.
+#[utoipa::path(
+    tag = "databases",
+    params(GetDatabasesOptions, PageOptions),
+    responses((status = 200, description = "A page of the databases the user may use")),
+    security(("user" = [])),
+)]
 #[get("/databases")]
-pub async fn get_databases(query: web::Query<GetDatabasesOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> impl Responder {
+pub async fn get_databases(query: web::Query<GetDatabasesOptions>, page: web::Query<PageOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
     let databases = index.lock().await
         .databases
         .iter()
@@ -55,58 +86,291 @@ pub async fn get_databases(query: web::Query<GetDatabasesOptions>, user: Authent
         })
         .collect::<Vec<_>>();
 
-    web::Json(json! {{
-        "success": true,
-        "databases": databases,
-        "health": 1,
-        "objects": Option::<usize>::None
-    }})
+    let databases = page.paginate(databases, |db| db.id.clone())?;
+
+    Ok(ApiResponse::ok(DatabaseList { databases: databases.items, next_cursor: databases.next_cursor, health: 1, objects: None }))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
 pub struct CreateDBOptions {
     name: String,
     ro: Option<Vec<String>>,
     rw: Option<Vec<String>>,
+
+    /// The page size of the database's store. Small pages suit many small objects, and large pages suit large ones.
+    page_size: Option<u32>,
 }
 
+#[utoipa::path(
+    tag = "databases",
+    params(CreateDBOptions),
+    responses((status = 200, description = "The new database")),
+    security(("user" = [])),
+)]
 #[put("/databases")]
-pub async fn create_database(options: web::Query<CreateDBOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, args: web::Data<Args>) -> actix_web::Result<impl Responder> {
+pub async fn create_database(options: web::Query<CreateDBOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if options.page_size.is_some_and(|page_size| !libdb::valid_page_size(page_size)) {
+        return Err(ApiError::bad_request("invalid_page_size", format!("Page sizes must be powers of two from {} to {} bytes", libdb::MIN_PAGE_SIZE, libdb::MAX_PAGE_SIZE)).into());
+    }
+
     let mut index = index.lock().await;
-    let token = loop {
-        let token = match generate_token(16).await {
-            Ok(token) => token,
-            Err(err) => return Ok(HttpResponse::InternalServerError().json(json! {{
-                "success": false,
-                "error": err.to_string()
-            }}))
-        };
+    let id = loop {
+        let id = config.id_scheme.generate().await.map_err(ApiError::internal)?;
 
-        if !index.databases.iter().any(|db| db.id == token) {
-            break token;
+        if !index.databases.iter().any(|db| db.id == id) {
+            break id;
         }
     };
 
-    let db_dir = args.database_dir.join(&token);
+    let db_dir = config.database_dir.join(&id);
     tokio::fs::create_dir_all(&db_dir).await?;
 
     index.databases.push(Database {
-        id: token.clone(),
+        id: id.clone(),
         name: options.name.clone(),
         owner: user.id.clone(),
         rw: options.rw.clone().unwrap_or_default(),
         ro: options.ro.clone().unwrap_or_default(),
         apps: vec![],
         root: db_dir,
-        pages: vec![]
+        pages: vec![],
+        quarantine: None,
+        quota: config.quotas.default,
+        embeds: vec![],
+        webhooks: vec![],
+        page_size: options.page_size,
     });
 
     push_change(DBIndexChange::Resync).await;
 
-    Ok(HttpResponse::Created()
-        .json(json! {{
-            "success": true,
-            "id": token.clone(),
-            "name": options.name.clone()
-        }}))
-}
\ No newline at end of file
+    Ok(ApiResponse::created(Named { id, name: options.name.clone() }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RenameDBOptions {
+    name: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MemberOptions {
+    /// Takes the user's access away if null.
+    access: Option<Access>,
+}
+
+/// A database's ID alongside its name.
+#[derive(Serialize)]
+struct Named {
+    id: DatabaseID,
+    name: String,
+}
+
+/// Renames a database. Only its owner may rename it.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    request_body = RenameDBOptions,
+    responses((status = 200, description = "The database was renamed"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[patch("/databases/{id}")]
+pub async fn rename_database(id: web::Path<DatabaseID>, options: web::Json<RenameDBOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    if !index.lock().await.databases.iter().any(|db| db.id == id && db.owner == user.id) {
+        return Err(ApiError::no_such_database().into());
+    }
+
+    commit_change(DBIndexChange::RenameDatabase { database: id.clone(), name: options.name.clone() }).await
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Named { id, name: options.name.clone() }))
+}
+
+#[derive(Serialize)]
+struct Member {
+    user: UserID,
+    access: Option<Access>,
+}
+
+/// Gives a user access to a database, changes their access, or takes it away. Only the database's owner may change its members.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID"), ("user" = String, Path, description = "The member's ID")),
+    request_body = MemberOptions,
+    responses((status = 200, description = "The member's access was changed"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[patch("/databases/{id}/members/{user}")]
+pub async fn set_member(path: web::Path<(DatabaseID, UserID)>, options: web::Json<MemberOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let (id, member) = path.into_inner();
+
+    {
+        let index = index.lock().await;
+
+        let Some(db) = index.databases.iter().find(|db| db.id == id && db.owner == user.id) else {
+            return Err(ApiError::no_such_database().into());
+        };
+
+        if db.owner == member {
+            return Err(ApiError::bad_request("owner_is_member", "The owner of a database always has access to it").into());
+        }
+
+        if options.access.is_some() && !index.users.iter().any(|user| user.id == member) {
+            return Err(ApiError::not_found("no_such_user", "No such user").into());
+        }
+    }
+
+    commit_change(DBIndexChange::SetMember { database: id, user: member.clone(), access: options.access }).await
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Member { user: member, access: options.access }))
+}
+
+#[derive(Serialize)]
+struct Stats {
+    stats: StoreStats,
+}
+
+#[derive(Serialize)]
+struct StoreStats {
+    allocated_bytes: u64,
+    live_bytes: u64,
+    free_bytes: u64,
+    page_size: u32,
+    fragments: u64,
+    largest_fragment: Option<Fragment>,
+    last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize)]
+struct Fragment {
+    id: u64,
+    bytes: u64,
+}
+
+/// Reports how a database's store uses its space. Only the fragment table is consulted, so this is cheap even for large stores. Any member
+/// of the database may see it.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "How the database's store uses its space"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/stats")]
+pub async fn get_stats(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let Some(db) = index.lock().await.databases.iter()
+        .find(|db| db.id == *id && (db.owner == user.id || db.rw.contains(&user.id) || db.ro.contains(&user.id)))
+        .cloned() else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let store = store.lock().await;
+    let stats = store.stats();
+
+    Ok(ApiResponse::ok(Stats {
+        stats: StoreStats {
+            allocated_bytes: stats.allocated,
+            live_bytes: stats.live,
+            free_bytes: stats.free,
+            page_size: store.page_size(),
+            fragments: stats.fragments,
+            largest_fragment: stats.largest.map(|frag| Fragment { id: frag.id, bytes: frag.length }),
+            // Taken from the fragment table rather than the file, which only changes once cached pages are written back.
+            last_modified: stats.last_modified.map(chrono::DateTime::<chrono::Utc>::from),
+        },
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CheckpointOptions {
+    /// Whether to sync the store to its disk as well. Defaults to `stores.checkpoint_fsync`.
+    fsync: Option<bool>,
+}
+
+/// Flushes a database's store now rather than at the next periodic checkpoint, writing its header and fragment table, and syncs it to its
+/// disk if asked to. Any member who may write to the database may checkpoint it.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID"), CheckpointOptions),
+    responses((status = 200, description = "Whether the store was flushed and synced"), (status = 404, description = "No such database, or the caller may not write to it")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/checkpoint")]
+pub async fn checkpoint_database(id: web::Path<DatabaseID>, options: web::Query<CheckpointOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let Some(db) = index.lock().await.databases.iter()
+        .find(|db| db.id == *id && (db.owner == user.id || db.rw.contains(&user.id)))
+        .cloned() else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let fsync = options.fsync.unwrap_or(config.stores.checkpoint_fsync);
+
+    let done = telemetry::block(move || checkpoint(&mut store.blocking_lock(), fsync))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(done))
+}
+
+/// Deletes a database along with its store. Only its owner may delete it.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "The database was deleted"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[delete("/databases/{id}")]
+pub async fn delete_database(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let Some(root) = index.lock().await.databases.iter()
+        .find(|db| db.id == id && db.owner == user.id)
+        .map(|db| db.root.clone()) else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    // The database leaves the index before its store is closed, so nothing can open it again in between.
+    commit_change(DBIndexChange::DeleteDatabase { database: id.clone() }).await
+        .map_err(ApiError::internal)?;
+
+    pool.evict(&id).await;
+
+    log::warn!(target: "audit", "{} deleted database {}", user, id);
+
+    match telemetry::block(move || std::fs::remove_dir_all(root)).await? {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => log::error!("Failed to delete the files of database {}: {}", id, err),
+    }
+
+    Ok(ApiResponse::ok(Done {}))
+}
+
+#[derive(Serialize)]
+struct TokenList<'a> {
+    tokens: Vec<TokenSummary<'a>>,
+    next_cursor: Option<String>,
+}
+
+/// Lists the caller's API tokens a page at a time, ordered by expiry. Only token prefixes and expiry are reported.
+#[utoipa::path(
+    tag = "users",
+    params(PageOptions),
+    responses((status = 200, description = "A page of the user's API tokens")),
+    security(("user" = [])),
+)]
+#[get("/tokens")]
+pub async fn get_tokens(page: web::Query<PageOptions>, user: AuthenticatedUser) -> actix_web::Result<impl Responder> {
+    // Cursors are handed to clients, so tokens are told apart by a hash rather than by the token itself.
+    let tokens = page.paginate(user.api.iter(), |token| {
+        let mut hasher = DefaultHasher::new();
+        token.token.hash(&mut hasher);
+        format!("{}/{:016x}", token.expiry.to_rfc3339(), hasher.finish())
+    })?;
+
+    // Summaries borrow from the user, so the response is built before they go out of scope.
+    let tokens = TokenList { tokens: tokens.items.into_iter().map(TokenSummary::from).collect(), next_cursor: tokens.next_cursor };
+    Ok(HttpResponse::Ok().json(ApiResponse::ok(tokens)))
+}
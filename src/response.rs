@@ -0,0 +1,178 @@
+use std::fmt::Display;
+use actix_web::body::BoxBody;
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::format::Format;
+use crate::request_id;
+
+/// The body of a successful response: `"success": true` alongside the fields of `T`, which must serialise as a map. It is sent in whichever
+/// format the client's `Accept` header prefers.
+#[derive(Debug)]
+pub struct ApiResponse<T> {
+    status: StatusCode,
+    success: bool,
+    body: T,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    success: bool,
+
+    #[serde(flatten)]
+    body: &'a T,
+}
+
+impl<T: Serialize> ApiResponse<T> {
+    pub fn ok(body: T) -> Self {
+        Self { status: StatusCode::OK, success: true, body }
+    }
+
+    pub fn created(body: T) -> Self {
+        Self { status: StatusCode::CREATED, success: true, body }
+    }
+
+    /// Reports `"success": false` while still answering with the status of a successful response, for requests which were carried out but
+    /// whose parts didn't all succeed, such as batches.
+    pub fn succeeded(mut self, success: bool) -> Self {
+        self.success = success;
+        self
+    }
+}
+
+impl<T: Serialize> Serialize for ApiResponse<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Envelope { success: self.success, body: &self.body }.serialize(serializer)
+    }
+}
+
+impl<T: Serialize> Responder for ApiResponse<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        Format::accepted(req).respond(HttpResponse::build(self.status), &self)
+    }
+}
+
+/// A successful response with nothing to say besides `"success": true`.
+#[derive(Debug, Serialize)]
+pub struct Done {}
+
+/// The body of a failed response: `{"success": false, "error": "...", "message": "...", "request_id": "..."}`, where `error` is a code
+/// clients can match on and `message` explains it to people. `request_id` names the request in the server's log. Some errors carry
+/// details alongside, such as the limit a document broke.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    success: bool,
+    error: &'static str,
+    message: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+
+    #[serde(flatten)]
+    details: Map<String, Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        let request_id = request_id::current().map(|id| id.as_str().to_owned());
+        Self { status, success: false, error, message: message.into(), request_id, details: Map::new() }
+    }
+
+    pub fn bad_request(error: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, error, message)
+    }
+
+    pub fn forbidden(error: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, error, message)
+    }
+
+    pub fn not_found(error: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, error, message)
+    }
+
+    pub fn conflict(error: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, error, message)
+    }
+
+    /// A failure on the server's side, which the client can't do anything about.
+    pub fn internal(err: impl Display) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", err.to_string())
+    }
+
+    /// The most common error of all.
+    pub fn no_such_database() -> Self {
+        Self::not_found("no_such_database", "No such database")
+    }
+
+    /// Adds a detail alongside the error and its message.
+    pub fn with(mut self, key: &str, value: impl Serialize) -> Self {
+        self.details.insert(key.to_owned(), serde_json::to_value(value).unwrap_or_default());
+        self
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.error, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self)
+    }
+}
+
+/// Answers request bodies which can't be read as the JSON a route expects, in place of actix's plain text.
+pub fn json_error(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::new(err.status_code(), "invalid_body", err.to_string()).into()
+}
+
+/// Answers query strings which don't match what a route expects, in place of actix's plain text.
+pub fn query_error(err: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::new(err.status_code(), "invalid_query", err.to_string()).into()
+}
+
+impl From<crate::error::Error> for ApiError {
+    fn from(err: crate::error::Error) -> Self {
+        Self::internal(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_envelopes_put_success_beside_the_body() {
+        #[derive(Serialize)]
+        struct Renamed {
+            name: &'static str,
+        }
+
+        let response = serde_json::to_value(ApiResponse::ok(Renamed { name: "db1" })).unwrap();
+        assert_eq!(response, serde_json::json! {{ "success": true, "name": "db1" }});
+
+        let response = serde_json::to_value(ApiResponse::ok(Done {}).succeeded(false)).unwrap();
+        assert_eq!(response, serde_json::json! {{ "success": false }});
+
+        let error = ApiError::bad_request("document_too_large", "Documents may be at most 10 bytes").with("limit", 10);
+        assert_eq!(serde_json::to_value(&error).unwrap(), serde_json::json! {{
+            "success": false,
+            "error": "document_too_large",
+            "message": "Documents may be at most 10 bytes",
+            "limit": 10,
+        }});
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+    }
+}
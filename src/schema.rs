@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use crate::error::*;
+use crate::DatabaseIndex;
+
+/// The version of `index.json` this build writes.
+///
+/// Version 1 files predate the version field. Every field added since has a default, so they are read as they are and written back as the
+/// current version the next time the index changes. Version 3 indices hold tokens only as their [`crate::hashing::hash`], which builds
+/// reading version 2 would take for plaintext tokens.
+pub const INDEX_VERSION: u32 = 3;
+
+/// Marks an index in which at least one database is quarantined. A build which doesn't know about quarantine would open those databases anyway.
+pub const QUARANTINE_FEATURE: &str = "quarantine";
+
+/// The critical features this build understands.
+const SUPPORTED_FEATURES: &[&str] = &[QUARANTINE_FEATURE];
+
+/// The part of the index every build can read, whatever version wrote it.
+#[derive(Deserialize)]
+struct SchemaHeader {
+    #[serde(default = "legacy_version")]
+    version: u32,
+
+    /// Features the index relies on which a build must understand to use it safely. Unknown fields outside of this list are ignored.
+    #[serde(default)]
+    critical: Vec<String>,
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+#[derive(Serialize)]
+struct VersionedIndex<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    critical: Vec<&'static str>,
+    #[serde(flatten)]
+    index: &'a DatabaseIndex,
+}
+
+/// Reads an index written by this or any earlier version of the server.
+///
+/// Indices written by a newer version, or relying on a feature this build doesn't understand, are refused rather than risk them being
+/// misread and then overwritten.
+pub fn read_index(data: &str) -> Result<DatabaseIndex> {
+    let header: SchemaHeader = serde_json::from_str(data)?;
+
+    if header.version > INDEX_VERSION {
+        return Err(ManualError::UnsupportedIndexVersion(header.version).into());
+    }
+
+    if let Some(feature) = header.critical.into_iter().find(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str())) {
+        return Err(ManualError::UnsupportedIndexFeature(feature).into());
+    }
+
+    Ok(serde_json::from_str(data)?)
+}
+
+/// Serialises the index as the current version, listing whichever critical features it relies on.
+pub fn write_index(index: &DatabaseIndex) -> serde_json::Result<String> {
+    let mut critical = vec![];
+
+    if index.databases.iter().any(|db| db.quarantine.is_some()) {
+        critical.push(QUARANTINE_FEATURE);
+    }
+
+    serde_json::to_string_pretty(&VersionedIndex {
+        version: INDEX_VERSION,
+        critical,
+        index,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::IndexFixture;
+
+    #[test]
+    pub fn test_reads_unversioned_index() -> Result<()> {
+        let index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .build();
+
+        let mut legacy = serde_json::to_value(&index)?;
+        legacy["databases"][0].as_object_mut().unwrap().remove("pages");
+
+        let read = read_index(&legacy.to_string())?;
+        assert_eq!(read.databases[0].id, "db1");
+        assert!(read.databases[0].pages.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_refuses_newer_index() -> Result<()> {
+        let mut index = serde_json::from_str::<serde_json::Value>(&write_index(&IndexFixture::new().build())?)?;
+        index["version"] = (INDEX_VERSION + 1).into();
+
+        let err = read_index(&index.to_string()).unwrap_err();
+        assert!(matches!(err.inner(), global::Inner::ManualError(ManualError::UnsupportedIndexVersion(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_refuses_unknown_critical_feature() -> Result<()> {
+        let mut index = serde_json::from_str::<serde_json::Value>(&write_index(&IndexFixture::new().build())?)?;
+        index["critical"] = serde_json::json!(["time-travel"]);
+        index["time_travel"] = true.into();
+
+        let err = read_index(&index.to_string()).unwrap_err();
+        assert!(matches!(err.inner(), global::Inner::ManualError(ManualError::UnsupportedIndexFeature(feature)) if feature == "time-travel"));
+
+        index["critical"] = serde_json::json!([]);
+        read_index(&index.to_string())?;
+
+        Ok(())
+    }
+}
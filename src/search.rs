@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use actix_web::{get, web};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::keys::{KeyDirectory, DELIMITER};
+use crate::pool::DbPool;
+use crate::response::{ApiError, ApiResponse};
+use crate::{DBIndex, Database, DatabaseID};
+use crate::telemetry;
+
+/// The number of results returned when the client doesn't ask for a specific amount.
+const DEFAULT_LIMIT: usize = 20;
+
+#[derive(Deserialize, IntoParams)]
+pub struct SearchOptions {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    database: DatabaseID,
+    database_name: String,
+    key: String,
+    score: f64,
+
+    /// The indexed fields of the object whose values matched, as JSON pointers.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<String>,
+}
+
+/// A string an object holds in an indexed field, lower-cased.
+struct IndexedValue<'a> {
+    field: &'a str,
+    value: String,
+}
+
+/// Scores how well a key matches a term, or returns `None` if the term doesn't appear in it.
+///
+/// Terms naming a whole level of the key (or a file name without its extension) count for the most, followed by terms at the start of a level.
+fn score_key(key: &str, segments: &[&str], term: &str) -> Option<f64> {
+    if !key.contains(term) {
+        return None;
+    }
+
+    Some(if segments.iter().any(|segment| *segment == term || segment.split('.').next() == Some(term)) {
+        3.0
+    } else if segments.iter().any(|segment| segment.starts_with(term)) {
+        2.0
+    } else {
+        1.0
+    })
+}
+
+/// Scores how well the object's indexed values match a term, along with the fields it matched, or returns `None` if it appears in none of
+/// them. Whole words count for more than parts of them.
+fn score_values<'a>(values: &[IndexedValue<'a>], term: &str) -> Option<(f64, Vec<&'a str>)> {
+    let mut score = None::<f64>;
+    let mut fields = vec![];
+
+    for value in values.iter().filter(|value| value.value.contains(term)) {
+        let whole = value.value.split(|c: char| !c.is_alphanumeric()).any(|word| word == term);
+        score = Some(score.unwrap_or(0.0).max(if whole { 2.0 } else { 1.0 }));
+        fields.push(value.field);
+    }
+
+    score.map(|score| (score, fields))
+}
+
+/// Scores how well an object matches every one of the search terms, through its key or its indexed values, or returns `None` if any term
+/// appears in neither. A term found in the key counts for more. Shallower keys are preferred over deeper ones.
+fn score<'a>(key: &str, values: &[IndexedValue<'a>], terms: &[String]) -> Option<(f64, Vec<&'a str>)> {
+    let key = key.to_lowercase();
+    let segments = key.split(DELIMITER).collect::<Vec<_>>();
+
+    let mut score = 0.0;
+    let mut fields = vec![];
+
+    for term in terms {
+        score += match score_key(&key, &segments, term) {
+            Some(score) => score,
+            None => {
+                let (score, matched) = score_values(values, term)?;
+                fields.extend(matched);
+                score
+            },
+        };
+    }
+
+    fields.sort_unstable();
+    fields.dedup();
+
+    Some((score / (1.0 + 0.1 * segments.len() as f64), fields))
+}
+
+/// Searches the keys of a single database, and the strings its objects hold in the fields its secondary indexes cover.
+async fn search_database(db: Database, terms: &[String], pool: &DbPool) -> crate::error::Result<Vec<SearchResult>> {
+    let store = pool.open(&db).await?;
+    let terms = terms.to_vec();
+
+    let directory = telemetry::block(move || KeyDirectory::load(&mut store.blocking_lock()))
+        .await
+        .map_err(|_| crate::error::ManualError::StoreOpenFailed)??;
+
+    let mut indexed = HashMap::<&str, Vec<IndexedValue>>::new();
+    for index in directory.indexes() {
+        for (key, value) in index.strings() {
+            indexed.entry(key).or_default().push(IndexedValue { field: &index.field, value: value.to_lowercase() });
+        }
+    }
+
+    Ok(directory.keys()
+        .filter_map(|key| {
+            let values = indexed.get(key).map(Vec::as_slice).unwrap_or_default();
+
+            score(key, values, &terms).map(|(score, fields)| SearchResult {
+                database: db.id.clone(),
+                database_name: db.name.clone(),
+                key: key.to_owned(),
+                score,
+                fields: fields.into_iter().map(str::to_owned).collect(),
+            })
+        })
+        .collect())
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    results: Vec<SearchResult>,
+
+    /// The databases which couldn't be searched.
+    incomplete: Vec<DatabaseID>,
+}
+
+/// Searches every database the user can read, returning the best matches across all of them.
+///
+/// Objects are found by their keys, and by the strings they hold in any field a secondary index covers, so declaring an index over a field
+/// makes it searchable. Every term must appear in either. The contents of other fields aren't searched.
+///
+/// Databases are searched a few at a time, as configured by `search.concurrency`. Any database which couldn't be searched is reported under
+/// `incomplete` rather than failing the whole search.
+#[utoipa::path(
+    tag = "search",
+    params(SearchOptions),
+    responses((status = 200, description = "The objects matching the query, best first")),
+    security(("user" = [])),
+)]
+#[get("/search")]
+pub async fn search(options: web::Query<SearchOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> Result<ApiResponse<SearchResults>, ApiError> {
+    let terms = options.q
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+
+    if terms.is_empty() {
+        return Err(ApiError::bad_request("no_search_terms", "No search terms"));
+    }
+
+    let databases = index.lock().await
+        .databases
+        .iter()
+        .filter(|db| db.owner == user.id || db.rw.contains(&user.id) || db.ro.contains(&user.id))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let searches = futures::stream::iter(databases)
+        .map(|db| {
+            let (terms, pool) = (&terms, &pool);
+            async move {
+                let id = db.id.clone();
+                (id, search_database(db, terms, pool).await)
+            }
+        })
+        .buffer_unordered(config.search.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut results = vec![];
+    let mut incomplete = vec![];
+
+    for (id, search) in searches {
+        match search {
+            Ok(found) => results.extend(found),
+            Err(err) => {
+                log::warn!("Failed to search database {}: {:?}", id, err);
+                incomplete.push(id);
+            },
+        }
+    }
+
+    results.sort_by(|a, b| b.score.total_cmp(&a.score)
+        .then_with(|| a.database.cmp(&b.database))
+        .then_with(|| a.key.cmp(&b.key)));
+    results.truncate(options.limit.unwrap_or(DEFAULT_LIMIT).min(config.search.max_results));
+
+    Ok(ApiResponse::ok(SearchResults { results, incomplete }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_terms_match_keys_or_indexed_values() {
+        let terms = |q: &str| q.split_whitespace().map(str::to_owned).collect::<Vec<_>>();
+        let values = [IndexedValue { field: "/title", value: "a day at the beach".to_owned() }, IndexedValue { field: "/place", value: "brighton".to_owned() }];
+
+        assert_eq!(score("photos/beach.jpg", &[], &terms("beach")), Some((3.0 / 1.2, vec![])));
+        assert_eq!(score("photos/1.jpg", &values, &terms("beach")), Some((2.0 / 1.2, vec!["/title"])));
+        assert_eq!(score("photos/1.jpg", &values, &terms("photos bright")), Some((4.0 / 1.2, vec!["/place"])));
+        assert_eq!(score("photos/1.jpg", &values, &terms("beach paris")), None);
+        assert_eq!(score("photos/1.jpg", &[], &terms("beach")), None);
+    }
+}
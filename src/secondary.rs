@@ -0,0 +1,275 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use crate::dictionary;
+use crate::error::*;
+use crate::keys::{collection, KeyDirectory, ObjectKey, DELIMITER};
+use crate::pool::Store;
+
+/// A value objects can be looked up by. Values of different types are ordered nulls first, then booleans, numbers and strings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IndexValue {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+}
+
+impl IndexValue {
+    /// The value of a JSON field, or `None` if it holds an array or object, which aren't indexed.
+    pub fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(Self::Null),
+            Value::Bool(bool) => Some(Self::Bool(*bool)),
+            Value::Number(number) => Some(Self::Number(number.clone())),
+            Value::String(string) => Some(Self::String(string.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// Reads a value given in a query string. Anything which isn't a JSON scalar is taken as a string, so `?value=alice` and
+    /// `?value="alice"` are the same, while `?value="42"` looks for the string rather than the number.
+    pub fn parse(value: &str) -> Self {
+        serde_json::from_str(value).ok()
+            .and_then(|value| Self::from_json(&value))
+            .unwrap_or_else(|| Self::String(value.to_owned()))
+    }
+
+    /// Whether both values are of the same JSON type.
+    pub fn same_type(&self, other: &Self) -> bool {
+        self.rank() == other.rank()
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Null => 0,
+            Self::Bool(_) => 1,
+            Self::Number(_) => 2,
+            Self::String(_) => 3,
+        }
+    }
+}
+
+/// Numbers are compared by value however they were written, so `1` and `1.0` are the same.
+fn as_f64(number: &Number) -> f64 {
+    number.as_f64().unwrap_or(f64::NAN)
+}
+
+impl Ord for IndexValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::Number(a), Self::Number(b)) => as_f64(a).total_cmp(&as_f64(b)),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexValue {}
+
+/// Finds the JSON documents of a collection by the value of one of their fields.
+///
+/// Indexes are kept in the key directory, so they are saved along with every write which changes them and can never disagree with the
+/// objects they point to. Objects which aren't JSON, or whose field is missing or holds an array or object, are left out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecondaryIndex {
+    /// A JSON pointer to the indexed field, such as `/address/city`.
+    pub field: String,
+
+    /// Each indexed object's value alongside its key, in order of value.
+    entries: BTreeSet<(IndexValue, String)>,
+}
+
+/// One object found through an index.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexEntry {
+    pub key: String,
+    pub value: IndexValue,
+}
+
+impl SecondaryIndex {
+    pub fn new(field: impl Into<String>) -> Self {
+        Self { field: field.into(), entries: BTreeSet::new() }
+    }
+
+    /// Records the object at `key` under its field's value in `document`, replacing what was recorded for it before. Objects which are no
+    /// longer JSON are dropped from the index. Returns whether the index changed.
+    pub fn update(&mut self, key: &str, document: Option<&Value>) -> bool {
+        let value = document
+            .and_then(|document| document.pointer(&self.field))
+            .and_then(IndexValue::from_json);
+
+        let previous = self.entries.iter().find(|(_, indexed)| indexed == key).cloned();
+        if previous.as_ref().map(|(value, _)| value) == value.as_ref() {
+            return false;
+        }
+
+        if let Some(previous) = previous {
+            self.entries.remove(&previous);
+        }
+
+        if let Some(value) = value {
+            self.entries.insert((value, key.to_owned()));
+        }
+
+        true
+    }
+
+    /// The objects whose value lies within the bounds, in order of value and then of key.
+    pub fn range(&self, from: Bound<IndexValue>, to: Bound<IndexValue>) -> impl Iterator<Item = IndexEntry> + '_ {
+        // Every key sorts after the empty one, so a value's entries start at `(value, "")`.
+        let start = match &from {
+            Bound::Included(value) | Bound::Excluded(value) => Bound::Included((value.clone(), String::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        self.entries.range((start, Bound::Unbounded))
+            .skip_while(move |(value, _)| matches!(&from, Bound::Excluded(from) if value == from))
+            .take_while(move |(value, _)| match &to {
+                Bound::Included(to) => value <= to,
+                Bound::Excluded(to) => value < to,
+                Bound::Unbounded => true,
+            })
+            .map(|(value, key)| IndexEntry { key: key.clone(), value: value.clone() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The key of every object whose field holds a string, along with the string.
+    pub fn strings(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().filter_map(|(value, key)| match value {
+            IndexValue::String(string) => Some((key.as_str(), string.as_str())),
+            _ => None,
+        })
+    }
+}
+
+/// Checks that `field` is a JSON pointer, which names the whole document if empty or a field within it if it starts with `/`.
+pub fn is_field(field: &str) -> bool {
+    field.is_empty() || field.starts_with('/')
+}
+
+/// Declares the index `name` over `field` of the JSON documents under `{collection}/`, indexing the objects already there. An index of the
+/// same name is rebuilt. Returns the number of objects indexed.
+pub fn create(store: &mut Store, collection: &str, name: &str, field: &str) -> Result<usize> {
+    let mut directory = KeyDirectory::load(store)?;
+    let prefix = format!("{}{}", collection, DELIMITER);
+    let mut index = SecondaryIndex::new(field);
+
+    let objects = directory.objects(&prefix).map(|(key, meta)| (key.to_owned(), meta.clone())).collect::<Vec<_>>();
+    for (key, meta) in objects {
+        let document = serde_json::from_slice::<Value>(&dictionary::read_contents(store, &meta)?).ok();
+        index.update(&key, document.as_ref());
+    }
+
+    let indexed = index.len();
+    directory.set_index(collection, name, index);
+    directory.save(store)?;
+    store.flush()?;
+
+    Ok(indexed)
+}
+
+/// Removes the index `name` of `collection`. Returns whether there was one.
+pub fn remove(store: &mut Store, collection: &str, name: &str) -> Result<bool> {
+    let mut directory = KeyDirectory::load(store)?;
+    if !directory.remove_index(collection, name) {
+        return Ok(false);
+    }
+
+    directory.save(store)?;
+    store.flush()?;
+
+    Ok(true)
+}
+
+/// Looks up the objects of `collection` whose value in the index `name` lies within the bounds. Returns `None` if there is no such index.
+pub fn lookup(store: &mut Store, collection: &str, name: &str, from: Bound<IndexValue>, to: Bound<IndexValue>) -> Result<Option<Vec<IndexEntry>>> {
+    let directory = KeyDirectory::load(store)?;
+
+    Ok(directory.index(collection, name).map(|index| index.range(from, to).filter(|entry| directory.contains(&entry.key)).collect()))
+}
+
+/// Brings every index of the collection `key` belongs to up to date with the contents it is being written with. Returns whether any of
+/// them changed.
+pub fn reindex(directory: &mut KeyDirectory, key: &ObjectKey, data: &[u8]) -> bool {
+    let Some(collection) = collection(key) else {
+        return false;
+    };
+
+    let mut indexes = directory.indexes_mut(collection).peekable();
+    if indexes.peek().is_none() {
+        return false;
+    }
+
+    let document = serde_json::from_slice::<Value>(data).ok();
+
+    indexes.fold(false, |changed, index| index.update(key, document.as_ref()) | changed)
+}
+
+/// Drops the object at `key` from every index of its collection, as it is removed. Returns whether any of them changed.
+pub fn forget(directory: &mut KeyDirectory, key: &str) -> bool {
+    let Some(collection) = collection(key) else {
+        return false;
+    };
+
+    directory.indexes_mut(collection).fold(false, |changed, index| index.update(key, None) | changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_index_follows_writes_and_answers_ranges() {
+        let mut index = SecondaryIndex::new("/age");
+        let keys = ["users/ada", "users/bob", "users/cy", "users/dee"];
+        let ages = [json!({"age": 36}), json!({"age": 17}), json!({"age": 36}), json!({"name": "dee"})];
+
+        for (key, document) in keys.iter().zip(&ages) {
+            index.update(key, Some(document));
+        }
+
+        let found = |index: &SecondaryIndex, from, to| index.range(from, to).map(|entry| entry.key).collect::<Vec<_>>();
+        let age = |age: f64| IndexValue::parse(&age.to_string());
+
+        assert_eq!(found(&index, Bound::Included(age(36.0)), Bound::Included(age(36.0))), ["users/ada", "users/cy"]);
+        assert_eq!(found(&index, Bound::Included(age(18.0)), Bound::Unbounded), ["users/ada", "users/cy"]);
+        assert_eq!(found(&index, Bound::Excluded(age(17.0)), Bound::Excluded(age(36.0))), Vec::<String>::new());
+        assert_eq!(found(&index, Bound::Unbounded, Bound::Excluded(age(36.0))), ["users/bob"]);
+
+        // Rewriting an object moves it, and writing something else in its place drops it.
+        assert!(index.update("users/bob", Some(&json!({"age": 18}))));
+        assert!(!index.update("users/bob", Some(&json!({"age": 18}))));
+        assert!(index.update("users/ada", None));
+        assert_eq!(found(&index, Bound::Included(age(18.0)), Bound::Unbounded), ["users/bob", "users/cy"]);
+    }
+
+    #[test]
+    pub fn test_values_from_queries_match_json_fields() {
+        assert_eq!(IndexValue::parse("42"), IndexValue::from_json(&json!(42.0)).unwrap());
+        assert_eq!(IndexValue::parse("alice"), IndexValue::parse("\"alice\""));
+        assert_eq!(IndexValue::parse("\"42\""), IndexValue::String("42".to_owned()));
+        assert!(IndexValue::parse("true") < IndexValue::parse("0") && IndexValue::parse("9") < IndexValue::parse("\"1\""));
+        assert!(IndexValue::from_json(&json!([1, 2])).is_none());
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::IsTerminal;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::middleware::Next;
+use actix_web::web;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use crate::config::{OtlpConfig, ServerConfig};
+use crate::error::*;
+use crate::request_id::{self, RequestId};
+
+/// Keeps spans flowing to the collector, if there is one. Dropping it sends whatever spans are still waiting.
+pub struct Telemetry(Option<SdkTracerProvider>);
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(ref provider) = self.0 && let Err(err) = provider.shutdown() {
+            eprintln!("Failed to export the last spans: {err}");
+        }
+    }
+}
+
+/// The filter for the server's log, made of `log_level` with whatever `RUST_LOG` asks for on top.
+fn filter(log_level: &str, env: Option<&str>) -> EnvFilter {
+    let directives = match env {
+        Some(env) if !env.is_empty() => format!("{log_level},{env}"),
+        _ => log_level.to_owned(),
+    };
+
+    EnvFilter::builder().parse_lossy(directives)
+}
+
+fn exporter(config: &OtlpConfig) -> Result<SdkTracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .with_headers(config.headers.clone())
+        .build()?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build())
+}
+
+/// Starts writing the log to stderr, and exporting spans if `otlp` is configured. Records made through the `log` crate are logged as
+/// events of whichever span they were made in.
+pub fn init(config: &ServerConfig) -> Result<Telemetry> {
+    let provider = config.otlp.as_ref().map(exporter).transpose()?;
+    let otel = provider.as_ref().map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("simple-database-server")));
+
+    tracing_subscriber::registry()
+        .with(filter(&config.log_level, std::env::var("RUST_LOG").ok().as_deref()))
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr).with_ansi(std::io::stderr().is_terminal()))
+        .with(otel)
+        .init();
+
+    Ok(Telemetry(provider))
+}
+
+/// Names the user making the current request in its span.
+pub fn record_user(id: &str) {
+    tracing::Span::current().record("user", id);
+}
+
+/// Names the app making the current request in its span.
+pub fn record_app(id: &str) {
+    tracing::Span::current().record("app", id);
+}
+
+/// Names the database the current request opened in its span.
+pub fn record_database(id: &str) {
+    tracing::Span::current().record("database", id);
+}
+
+/// Runs `f` on the blocking thread pool like [`web::block`], but inside the current span, so what `libdb` logs while `f` runs is
+/// attributed to the request.
+pub async fn block<F, R>(f: F) -> std::result::Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = tracing::Span::current();
+    web::block(move || span.in_scope(f)).await
+}
+
+/// Runs every request in a span of its own, named by its [`RequestId`]. The caller and database are filled in once they are known, by [`record_user`],
+/// [`record_app`] and [`record_database`].
+pub async fn trace_request(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_owned());
+    let span = tracing::info_span!("request",
+        otel.name = format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        request_id = request_id::current().as_ref().map(RequestId::as_str),
+        method = %req.method(),
+        route,
+        user = Empty,
+        app = Empty,
+        database = Empty,
+        status = Empty,
+    );
+
+    let res = next.call(req).instrument(span.clone()).await;
+
+    let status = match res {
+        Ok(ref res) => res.status(),
+        Err(ref err) => err.as_response_error().status_code(),
+    };
+
+    span.record("status", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_rust_log_is_applied_over_the_log_level() {
+        assert_eq!(filter("info", None).to_string(), "info");
+        assert_eq!(filter("info", Some("")).to_string(), "info");
+        assert_eq!(filter("info,libdb=debug", Some("libdb=trace")).to_string(), "libdb=trace,info");
+    }
+}
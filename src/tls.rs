@@ -0,0 +1,88 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::server::ClientHello;
+use rustls::server::ResolvesServerCert;
+use rustls::sign::CertifiedKey;
+use crate::config::TlsConfig;
+use crate::error::*;
+
+/// Serves whichever certificate was most recently loaded from disk, so certificates can be renewed without restarting the server.
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+/// Builds the rustls configuration for the server and starts watching the certificate files for changes.
+pub fn server_config(tls: &TlsConfig) -> Result<rustls::ServerConfig> {
+    let resolver = Arc::new(ReloadingCertResolver {
+        current: RwLock::new(Arc::new(load_certified_key(&tls.cert, &tls.key)?)),
+    });
+
+    watch(tls.clone(), resolver.clone());
+
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    Ok(CertifiedKey::from_der(certs, key, &default_provider())?)
+}
+
+fn modified(tls: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(&tls.cert).and_then(|meta| meta.modified()).ok()?;
+    let key = std::fs::metadata(&tls.key).and_then(|meta| meta.modified()).ok()?;
+
+    Some((cert, key))
+}
+
+/// Periodically checks whether the certificate or key has changed on disk and swaps them in if so.
+/// If the new files can't be loaded, the previous certificate remains in use.
+fn watch(tls: TlsConfig, resolver: Arc<ReloadingCertResolver>) {
+    tokio::spawn(async move {
+        let mut last_modified = modified(&tls);
+        let mut interval = tokio::time::interval(tls.reload_interval());
+
+        loop {
+            interval.tick().await;
+
+            let modified = modified(&tls);
+            if modified == last_modified {
+                continue;
+            }
+
+            last_modified = modified;
+
+            match load_certified_key(&tls.cert, &tls.key) {
+                Ok(key) => match resolver.current.write() {
+                    Ok(mut current) => {
+                        *current = Arc::new(key);
+                        log::info!("Reloaded TLS certificate from {}", tls.cert.display());
+                    },
+                    Err(_) => log::error!("Failed to reload TLS certificate: the certificate lock is poisoned"),
+                },
+                Err(err) => log::error!("Failed to reload TLS certificate: {:?}", err),
+            }
+        }
+    });
+}
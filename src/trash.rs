@@ -0,0 +1,227 @@
+use actix_web::{get, post, web, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::auth::AuthenticatedUser;
+use crate::changelog::{self, Action};
+use crate::config::ServerConfig;
+use crate::dictionary;
+use crate::error::*;
+use crate::keys::{KeyDirectory, ObjectKey, ObjectMeta};
+use crate::pool::{DbPool, Store};
+use crate::response::{ApiError, ApiResponse};
+use crate::secondary;
+use crate::{DBIndex, DatabaseID};
+use crate::telemetry;
+
+/// A deleted object, kept whole so it can be restored until it is purged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedObject {
+    pub meta: ObjectMeta,
+    pub deleted: DateTime<Utc>,
+}
+
+/// What became of an attempt to restore an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restore {
+    Restored,
+
+    /// There is no object of that key in the trash.
+    NotTrashed,
+
+    /// Another object has been written at the key since, and restoring would replace it.
+    Occupied,
+}
+
+/// Deletes the object at `key` by moving it into the trash, where it stays restorable until it is purged. Returns whether there was such an
+/// object.
+///
+/// Deleting an object whose key is already in the trash replaces the trashed one, which is deleted for good.
+pub fn delete(store: &mut Store, key: &ObjectKey, actor: Option<&str>) -> Result<bool> {
+    let mut directory = KeyDirectory::load(store)?;
+    let Some(displaced) = directory.discard(key, Utc::now()) else {
+        return Ok(false);
+    };
+
+    secondary::forget(&mut directory, key);
+    let segments = changelog::record(store, &mut directory, key, Action::Delete, actor)?;
+    directory.save(store)?;
+
+    for fragment in displaced.into_iter().chain(segments) {
+        store.delete_fragment(fragment)?;
+    }
+
+    store.flush()?;
+
+    Ok(true)
+}
+
+/// Moves the object at `key` back out of the trash, with its contents, metadata and history as they were when it was deleted, and indexes
+/// it again. An expired object which hasn't been removed yet doesn't stop it.
+pub fn restore(store: &mut Store, key: &ObjectKey, actor: Option<&str>) -> Result<Restore> {
+    let mut directory = KeyDirectory::load(store)?;
+
+    if directory.get(key).is_some() {
+        return Ok(Restore::Occupied);
+    }
+
+    // Nothing is saved unless the object is restored, so the expired object is only removed if it is replaced.
+    let expired = directory.remove(key);
+    let Some(meta) = directory.untrash(key) else {
+        return Ok(Restore::NotTrashed);
+    };
+
+    let contents = dictionary::read_contents(store, &meta)?;
+    secondary::reindex(&mut directory, key, &contents);
+    let segments = changelog::record(store, &mut directory, key, Action::Restore, actor)?;
+    directory.save(store)?;
+
+    for fragment in expired.iter().flat_map(ObjectMeta::fragments).chain(segments) {
+        store.delete_fragment(fragment)?;
+    }
+
+    store.flush()?;
+
+    Ok(Restore::Restored)
+}
+
+/// Deletes the objects which have been in the trash for longer than `retention` for good, freeing their space. Returns how many there were.
+pub fn purge(store: &mut Store, retention: chrono::Duration) -> Result<usize> {
+    let mut directory = KeyDirectory::load(store)?;
+    let before = Utc::now() - retention;
+
+    let purged = directory.trashed().filter(|(_, trashed)| trashed.deleted < before).count();
+    if purged == 0 {
+        return Ok(0);
+    }
+
+    let fragments = directory.purge_trash(before);
+    directory.save(store)?;
+
+    for fragment in fragments {
+        store.delete_fragment(fragment)?;
+    }
+
+    store.flush()?;
+
+    Ok(purged)
+}
+
+/// Finds a database the user may read, or write if `write` is set.
+async fn member_database(id: &DatabaseID, user: &AuthenticatedUser, index: &DBIndex, write: bool) -> Option<crate::Database> {
+    index.lock().await.databases.iter()
+        .find(|db| db.id == *id && (db.owner == user.id || db.rw.contains(&user.id) || !write && db.ro.contains(&user.id)))
+        .cloned()
+}
+
+#[derive(Serialize)]
+struct TrashEntry {
+    object: String,
+    content_type: String,
+    size: Option<u64>,
+    deleted: DateTime<Utc>,
+    purge_after: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct Trash {
+    objects: Vec<TrashEntry>,
+}
+
+#[derive(Serialize)]
+struct Restored {
+    object: String,
+}
+
+/// Lists the database's deleted objects which can still be restored, along with when each was deleted and when it will be purged. Any
+/// member of the database may see them.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "The deleted objects which can be restored"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/trash")]
+pub async fn list_trash(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let Some(db) = member_database(&id, &user, &index, false).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let retention = config.stores.trash_retention();
+
+    let objects = telemetry::block(move || -> Result<Vec<TrashEntry>> {
+        let mut store = store.blocking_lock();
+        let directory = KeyDirectory::load(&mut store)?;
+
+        Ok(directory.trashed()
+            .map(|(key, trashed)| TrashEntry {
+                object: key.to_owned(),
+                content_type: trashed.meta.content_type.clone(),
+                size: store.fragment_info(trashed.meta.id).map(|info| info.length).ok(),
+                deleted: trashed.deleted,
+                purge_after: trashed.deleted + retention,
+            })
+            .collect())
+    }).await?.map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Trash { objects }))
+}
+
+/// Restores a deleted object from the database's trash. Fails with `409` if another object has been written at its key since. Any member
+/// who may write to the database may restore objects.
+#[utoipa::path(
+    tag = "databases",
+    params(("id" = String, Path, description = "The database's ID"), ("object" = String, Path, description = "The deleted object's key")),
+    responses((status = 200, description = "The object was restored"), (status = 404, description = "No such database, or no such object in the trash"), (status = 409, description = "Another object has been written at the key since")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/trash/{object:.+}/restore")]
+pub async fn restore_object(path: web::Path<(DatabaseID, String)>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>) -> actix_web::Result<impl Responder> {
+    let (id, object) = path.into_inner();
+    let key = ObjectKey::parse(object)?;
+
+    let Some(db) = member_database(&id, &user, &index, true).await else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let store = pool.open(&db).await.map_err(ApiError::internal)?;
+    let (object, actor) = (key.to_string(), user.actor());
+
+    let restored = telemetry::block(move || restore(&mut store.blocking_lock(), &key, Some(&actor)))
+        .await?
+        .map_err(ApiError::internal)?;
+
+    match restored {
+        Restore::Restored => Ok(ApiResponse::ok(Restored { object })),
+        Restore::NotTrashed => Err(ApiError::not_found("not_trashed", "No such object in the trash").into()),
+        Restore::Occupied => Err(ApiError::conflict("occupied", "Another object has been written at this key since it was deleted").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_trashed_objects_are_hidden_until_restored_or_purged() {
+        let mut directory = KeyDirectory::default();
+        let key = ObjectKey::parse("notes/todo").unwrap();
+        let now = Utc::now();
+
+        let first = directory.get_or_insert(&key, "text/plain").0;
+        assert_eq!(directory.discard(&key, now - chrono::Duration::days(2)), Some(vec![]));
+        assert!(directory.get(&key).is_none() && directory.discard(&key, now).is_none());
+
+        // Deleting the key again displaces the older object from the trash.
+        let second = directory.get_or_insert(&key, "text/plain").0;
+        assert_eq!(directory.discard(&key, now - chrono::Duration::hours(1)), Some(vec![first]));
+
+        assert_eq!(directory.untrash(&key).map(|meta| meta.id), Some(second));
+        assert_eq!(directory.get(&key).map(|meta| meta.id), Some(second));
+
+        directory.discard(&key, now - chrono::Duration::hours(1));
+        assert!(directory.purge_trash(now - chrono::Duration::days(1)).is_empty());
+        assert_eq!(directory.purge_trash(now), [second]);
+        assert_eq!(directory.trashed().count(), 0);
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+use crate::changelog::{self, Action};
+use crate::error::*;
+use crate::keys::KeyDirectory;
+use crate::pool::Store;
+use crate::secondary;
+
+/// Removes the objects of `directory` which had expired by `now`, along with their index entries, and returns their keys and the fragments
+/// they held.
+fn remove_expired(directory: &mut KeyDirectory, now: DateTime<Utc>) -> (Vec<String>, Vec<libdb::FragmentID>) {
+    let (expired, mut fragments) = (directory.expired(now), vec![]);
+
+    for key in expired.iter() {
+        secondary::forget(directory, key);
+
+        if let Some(meta) = directory.remove(key) {
+            fragments.extend(meta.fragments());
+        }
+    }
+
+    (expired, fragments)
+}
+
+/// Removes every object of the store whose time-to-live has run out, and returns how many there were.
+///
+/// Expired objects already read as missing, so removing them only frees their space. The directory stops referring to them before their
+/// fragments are deleted, so a removal which fails part-way never leaves a key pointing nowhere.
+pub fn reap(store: &mut Store) -> Result<usize> {
+    let mut directory = KeyDirectory::load(store)?;
+    let expired = directory.expired(Utc::now()).len();
+    if expired == 0 {
+        return Ok(0);
+    }
+
+    let (keys, mut fragments) = remove_expired(&mut directory, Utc::now());
+    for key in keys {
+        fragments.extend(changelog::record(store, &mut directory, &key, Action::Expire, None)?);
+    }
+
+    directory.save(store)?;
+
+    for fragment in fragments {
+        store.delete_fragment(fragment)?;
+    }
+
+    store.flush()?;
+
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::ObjectKey;
+    use crate::secondary::SecondaryIndex;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_expired_objects_are_hidden_and_then_removed() {
+        let mut directory = KeyDirectory::default();
+        let (stale, fresh) = (ObjectKey::parse("users/ada").unwrap(), ObjectKey::parse("users/bob").unwrap());
+        let now = Utc::now();
+
+        directory.set_index("users", "by-age", SecondaryIndex::new("/age"));
+        for key in [&stale, &fresh] {
+            directory.get_or_insert(key, "application/json");
+            secondary::reindex(&mut directory, key, &serde_json::to_vec(&json!({"age": 36})).unwrap());
+        }
+
+        directory.set_expiry(&stale, Some(now - chrono::Duration::seconds(1)));
+        directory.set_expiry(&fresh, Some(now + chrono::Duration::hours(1)));
+
+        assert!(directory.get(&stale).is_none() && directory.get(&fresh).is_some());
+        assert_eq!(directory.list("users/", None, None).keys, ["users/bob"]);
+
+        let stale_id = directory.get_or_insert(&stale, "application/json").0;
+        assert_eq!(remove_expired(&mut directory, now), (vec!["users/ada".to_owned()], vec![stale_id]));
+        assert_eq!(directory.expired(now + chrono::Duration::hours(1)), ["users/bob"]);
+        assert_eq!(directory.index("users", "by-age").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::error::*;
+
+/// The name of the file each database's access statistics are kept in, beside its store.
+pub const USAGE_FILE: &str = "usage.json";
+
+/// How often an object has been read, and when it was last read.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ObjectAccess {
+    /// An estimate of how many times the object has been read, made from the reads which were sampled.
+    pub reads: u64,
+    pub last_read: DateTime<Utc>,
+}
+
+/// Counts reads of a database's objects, so its owners can see what data is actually used.
+///
+/// Only a sample of reads is recorded, each standing in for the reads which weren't, so busy databases don't pay for counting every one.
+/// The counts are kept in memory and written to [`USAGE_FILE`] every so often and when the store is closed, so they never cost a write to
+/// the store itself. Counts of reads since the last time they were written are lost if the server stops abruptly.
+#[derive(Debug)]
+pub struct ObjectUsage {
+    path: PathBuf,
+    sample_rate: f64,
+    objects: Mutex<HashMap<String, ObjectAccess>>,
+
+    /// Set whenever a read has been recorded since the counts were last written.
+    dirty: AtomicBool,
+}
+
+impl ObjectUsage {
+    /// Picks up the counts kept in `root`, or starts afresh if there are none or they can't be read.
+    pub fn load(root: &Path, sample_rate: f64) -> Self {
+        let path = root.join(USAGE_FILE);
+        let objects = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                log::warn!("Ignoring unreadable access statistics in {}: {}", path.display(), err);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self { path, sample_rate: sample_rate.clamp(0.0, 1.0), objects: Mutex::new(objects), dirty: AtomicBool::new(false) }
+    }
+
+    /// Records a read of the object at `key`, if the read is sampled.
+    pub fn record_read(&self, key: &str) {
+        self.record_read_at(key, Utc::now(), rand::random::<f64>())
+    }
+
+    fn record_read_at(&self, key: &str, now: DateTime<Utc>, roll: f64) {
+        if roll >= self.sample_rate {
+            return;
+        }
+
+        let weight = (1.0 / self.sample_rate).round() as u64;
+        let mut objects = self.objects.lock().unwrap();
+
+        objects.entry(key.to_owned())
+            .and_modify(|access| {
+                access.reads += weight;
+                access.last_read = now;
+            })
+            .or_insert(ObjectAccess { reads: weight, last_read: now });
+
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, key: &str) -> Option<ObjectAccess> {
+        self.objects.lock().unwrap().get(key).copied()
+    }
+
+    /// Writes the counts out if any reads were recorded since they last were. The file is replaced whole, so it's never left half-written.
+    pub fn persist(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let data = serde_json::to_vec(&*self.objects.lock().unwrap())?;
+        let partial = self.path.with_extension("json.partial");
+
+        let written = std::fs::write(&partial, data).and_then(|_| std::fs::rename(&partial, &self.path));
+        if written.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+
+        Ok(written?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_sampled_reads_stand_in_for_the_rest() {
+        let usage = ObjectUsage::load(Path::new("/nonexistent"), 0.25);
+        let (earlier, later) = (DateTime::<Utc>::from_timestamp(1_000, 0).unwrap(), DateTime::<Utc>::from_timestamp(2_000, 0).unwrap());
+
+        usage.record_read_at("a", earlier, 0.1);
+        usage.record_read_at("a", later, 0.9);
+        usage.record_read_at("a", later, 0.2);
+        usage.record_read_at("b", later, 0.5);
+
+        assert_eq!(usage.get("a"), Some(ObjectAccess { reads: 8, last_read: later }));
+        assert_eq!(usage.get("b"), None);
+    }
+}
@@ -0,0 +1,334 @@
+use std::path::PathBuf;
+use actix_web::{delete, get, post, web, Responder};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::erasure::delete_stores;
+use crate::hashing;
+use crate::index::{commit_change, DBIndexChange};
+use crate::pool::DbPool;
+use crate::response::{ApiError, ApiResponse};
+use crate::{AppID, DBIndex, DatabaseID, DatabaseIndex, UserID};
+
+/// Service account tokens start with this, which tells them apart from API tokens without having to look either up.
+pub const SERVICE_PREFIX: &str = "sa.";
+
+/// The number of random bytes in a service account token.
+const TOKEN_BYTES: usize = 32;
+
+/// The credential of a user who is a service account, for headless services which can't sign in through OAuth.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceAccount {
+    pub name: String,
+
+    /// The account's token is only shown when the account is created. The index keeps its hash, made by [`crate::hashing::hash`]. Unset
+    /// once the token has been revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_hash: Option<String>,
+    pub created: DateTime<Utc>,
+    pub created_by: UserID,
+
+    /// Service account tokens never expire unless this is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+/// A user as administrators see them.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UserSummary {
+    pub id: UserID,
+
+    /// How many of the user's API tokens, or service account token, haven't expired yet.
+    pub tokens: usize,
+    pub oauth_tokens: usize,
+    pub databases: usize,
+    pub apps: usize,
+    pub last_active: Option<DateTime<Utc>>,
+
+    /// The name of the service account, if the user is one.
+    pub service_account: Option<String>,
+}
+
+impl UserSummary {
+    fn of(index: &DatabaseIndex, user: &crate::User, now: DateTime<Utc>) -> Self {
+        Self {
+            id: user.id.clone(),
+            tokens: user.api.iter().filter(|token| token.expiry > now).count()
+                + user.service.iter().filter(|service| service.token_hash.is_some() && service.expiry.is_none_or(|expiry| expiry > now)).count(),
+            oauth_tokens: user.oauth.len(),
+            databases: index.databases.iter().filter(|db| db.owner == user.id).count(),
+            apps: index.apps.iter().filter(|app| app.owner == user.id).count(),
+            last_active: user.last_active,
+            service_account: user.service.as_ref().map(|service| service.name.clone()),
+        }
+    }
+}
+
+/// Hands every database and app `from` owns to `to`. Where `to` was a member of one of the databases, ownership takes the place of their
+/// membership.
+pub fn transfer_ownership(index: &mut DatabaseIndex, from: &UserID, to: &UserID) {
+    for db in index.databases.iter_mut().filter(|db| db.owner == *from) {
+        db.owner = to.clone();
+        db.ro.retain(|member| member != to);
+        db.rw.retain(|member| member != to);
+    }
+
+    for app in index.apps.iter_mut().filter(|app| app.owner == *from) {
+        app.owner = to.clone();
+    }
+}
+
+fn forbidden() -> ApiError {
+    ApiError::forbidden("not_an_admin", "Only user admins may manage users")
+}
+
+fn no_such_user() -> ApiError {
+    ApiError::not_found("no_such_user", "No such user")
+}
+
+#[derive(Serialize)]
+struct Users {
+    users: Vec<UserSummary>,
+}
+
+/// Lists every user the server knows, with how many tokens, databases and apps they hold and when they were last active. Only
+/// `admins` and `users.admins` may see them.
+#[utoipa::path(
+    tag = "admin",
+    responses((status = 200, description = "Every user"), (status = 403, description = "Only user admins may manage users")),
+    security(("user" = [])),
+)]
+#[get("/admin/users")]
+pub async fn list_users(user: AuthenticatedUser, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if !config.is_user_admin(&user.id) {
+        return Err(forbidden().into());
+    }
+
+    let index = index.lock().await;
+    let now = Utc::now();
+
+    Ok(ApiResponse::ok(Users { users: index.users.iter().map(|user| UserSummary::of(&index, user, now)).collect() }))
+}
+
+#[derive(Serialize)]
+struct Revoked {
+    revoked: usize,
+}
+
+/// Revokes every OAuth and API token the user holds, signing them out everywhere. They can sign in again. A service account's token is
+/// revoked for good. Only `admins` and `users.admins` may revoke tokens.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "The user's ID")),
+    responses((status = 200, description = "The user's tokens were revoked"), (status = 403, description = "Only user admins may manage users"), (status = 404, description = "No such user")),
+    security(("user" = [])),
+)]
+#[post("/admin/users/{id}/revoke-tokens")]
+pub async fn revoke_tokens(id: web::Path<UserID>, user: AuthenticatedUser, index: web::Data<DBIndex>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if !config.is_user_admin(&user.id) {
+        return Err(forbidden().into());
+    }
+
+    let id = id.into_inner();
+    let Some(revoked) = index.lock().await.users.iter().find(|record| record.id == id).map(|record| {
+        record.oauth.len() + record.api.len() + record.service.as_ref().filter(|service| service.token_hash.is_some()).iter().count()
+    }) else {
+        return Err(no_such_user().into());
+    };
+
+    commit_change(DBIndexChange::RevokeUserTokens { user: id.clone() }).await.map_err(ApiError::internal)?;
+
+    log::warn!(target: "audit", "{} revoked the {} tokens of user {}", user, revoked, id);
+
+    Ok(ApiResponse::ok(Revoked { revoked }))
+}
+
+/// A new service account. Its token is only ever shown here.
+#[derive(Serialize)]
+struct CreatedAccount {
+    id: UserID,
+    token: String,
+    expiry: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateServiceAccountOptions {
+    pub name: String,
+
+    /// How long the account's token remains valid for, in seconds. It never expires if this isn't given.
+    pub expires_in: Option<u64>,
+}
+
+/// Creates a service account, a user for headless services which authenticates with a long-lived token in the `Authorization` header
+/// instead of signing in through OAuth. The token is only shown in the response. Databases are shared with service accounts like any other
+/// user, and the audit log names them as service accounts. Only `admins` and `users.admins` may create them.
+#[utoipa::path(
+    tag = "admin",
+    request_body = CreateServiceAccountOptions,
+    responses((status = 201, description = "The new account and its token"), (status = 403, description = "Only user admins may manage users")),
+    security(("user" = [])),
+)]
+#[post("/admin/service-accounts")]
+pub async fn create_service_account(options: web::Json<CreateServiceAccountOptions>, user: AuthenticatedUser, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if !config.is_user_admin(&user.id) {
+        return Err(forbidden().into());
+    }
+
+    let options = options.into_inner();
+    let token = format!("{}{}", SERVICE_PREFIX, URL_SAFE_NO_PAD.encode(crate::random_bytes(TOKEN_BYTES).await.map_err(ApiError::internal)?));
+
+    let account = crate::User {
+        id: config.id_scheme.generate().await.map_err(ApiError::internal)?,
+        service: Some(ServiceAccount {
+            name: options.name,
+            token_hash: Some(hashing::hash(&token)),
+            created: Utc::now(),
+            created_by: user.id.clone(),
+            expiry: options.expires_in.map(|secs| Utc::now() + chrono::Duration::seconds(secs.min(i64::MAX as u64 / 1000) as i64)),
+        }),
+        ..Default::default()
+    };
+
+    let (id, expiry) = (account.id.clone(), account.service.as_ref().and_then(|service| service.expiry));
+    commit_change(DBIndexChange::AddUser { user: account }).await.map_err(ApiError::internal)?;
+
+    log::warn!(target: "audit", "{} created service account {}", user, id);
+
+    Ok(ApiResponse::created(CreatedAccount { id, token, expiry }))
+}
+
+#[derive(Serialize)]
+struct Deleted {
+    user: UserID,
+    transferred_to: Option<UserID>,
+    databases: Vec<DatabaseID>,
+    apps: Vec<AppID>,
+
+    /// The databases whose files couldn't all be deleted.
+    incomplete: Vec<DatabaseID>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct DeleteUserOptions {
+    /// Hands the user's databases and apps to this user instead of deleting them.
+    pub transfer_to: Option<UserID>,
+}
+
+/// Deletes a user, revoking all their tokens and taking them off every database they are a member of. The databases and apps they own are
+/// handed to `transfer_to` if it is given, and otherwise deleted, stores and all. Only `admins` and `users.admins` may delete users.
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = String, Path, description = "The user's ID"), DeleteUserOptions),
+    responses((status = 200, description = "The user was deleted"), (status = 400, description = "Databases can only be transferred to another existing user"), (status = 403, description = "Only user admins may manage users"), (status = 404, description = "No such user")),
+    security(("user" = [])),
+)]
+#[delete("/admin/users/{id}")]
+pub async fn delete_user(id: web::Path<UserID>, options: web::Query<DeleteUserOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    if !config.is_user_admin(&user.id) {
+        return Err(forbidden().into());
+    }
+
+    let id = id.into_inner();
+    let transfer_to = options.into_inner().transfer_to;
+
+    let (databases, apps, roots) = {
+        let index = index.lock().await;
+
+        if !index.users.iter().any(|record| record.id == id) {
+            return Err(no_such_user().into());
+        }
+
+        if let Some(ref to) = transfer_to && (*to == id || !index.users.iter().any(|record| record.id == *to)) {
+            return Err(ApiError::bad_request("invalid_transfer", "Databases can only be transferred to another existing user").into());
+        }
+
+        let owned = index.databases.iter().filter(|db| db.owner == id);
+        let databases = owned.clone().map(|db| db.id.clone()).collect::<Vec<DatabaseID>>();
+        let apps = index.apps.iter().filter(|app| app.owner == id).map(|app| app.id.clone()).collect::<Vec<AppID>>();
+        let roots = match transfer_to {
+            Some(_) => vec![],
+            None => owned.map(|db| (db.id.clone(), db.root.clone())).collect::<Vec<(DatabaseID, PathBuf)>>(),
+        };
+
+        (databases, apps, roots)
+    };
+
+    let mut changes = vec![];
+    if let Some(ref to) = transfer_to {
+        changes.push(DBIndexChange::TransferOwnership { from: id.clone(), to: to.clone() });
+    }
+
+    // The databases leave the index before their stores are closed, so nothing can open them again in between.
+    changes.push(DBIndexChange::EraseUser { user: id.clone() });
+    commit_change(changes).await.map_err(ApiError::internal)?;
+
+    let incomplete = delete_stores(&pool, roots).await?;
+
+    match transfer_to {
+        Some(ref to) => log::warn!(target: "audit", "{} deleted user {}, transferring {} databases and {} apps to {}", user, id,
+            databases.len(), apps.len(), to),
+        None => log::warn!(target: "audit", "{} deleted user {} along with {} databases and {} apps", user, id, databases.len(), apps.len()),
+    }
+
+    Ok(ApiResponse::ok(Deleted { user: id, transferred_to: transfer_to, databases, apps, incomplete }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::IndexFixture;
+
+    #[test]
+    pub fn test_ownership_moves_to_the_new_owner_in_place_of_membership() {
+        let mut index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .rw("u2")
+            .app("a1")
+            .user("u2")
+            .database("db2")
+            .build();
+
+        let (u1, u2) = ("u1".to_owned(), "u2".to_owned());
+        let now = Utc::now();
+        assert_eq!((UserSummary::of(&index, &index.users[0], now).databases, UserSummary::of(&index, &index.users[0], now).apps), (1, 1));
+        assert_eq!(UserSummary::of(&index, &index.users[1], IndexFixture::expiry()).tokens, 0);
+
+        transfer_ownership(&mut index, &u1, &u2);
+        crate::erasure::erase_user(&mut index, &u1);
+
+        assert!(index.databases.iter().all(|db| db.owner == u2 && db.rw.is_empty()));
+        assert_eq!(index.apps[0].owner, u2);
+        assert_eq!(index.databases[0].apps, ["a1".to_owned()]);
+
+        let summary = UserSummary::of(&index, &index.users[0], now);
+        assert_eq!((summary.id, summary.tokens, summary.databases, summary.apps), (u2, 1, 2, 1));
+    }
+
+    #[test]
+    pub fn test_service_accounts_count_their_token_until_it_is_revoked() {
+        let index = IndexFixture::new().build();
+        let now = Utc::now();
+        let mut account = crate::User {
+            id: "s1".to_owned(),
+            service: Some(ServiceAccount {
+                name: "ci".to_owned(),
+                token_hash: Some("h1:hash".to_owned()),
+                created: now,
+                created_by: "u1".to_owned(),
+                expiry: None,
+            }),
+            ..Default::default()
+        };
+
+        let summary = UserSummary::of(&index, &account, now);
+        assert_eq!((summary.tokens, summary.service_account.as_deref()), (1, Some("ci")));
+
+        account.service.as_mut().unwrap().token_hash = None;
+        assert_eq!(UserSummary::of(&index, &account, now).tokens, 0);
+    }
+}
@@ -0,0 +1,75 @@
+use libdb::progress::Progress;
+use crate::config::MaintenanceConfig;
+use crate::error::*;
+use crate::keys::KeyDirectory;
+use crate::pool::{DbPool, Store, STORE_FILE};
+use crate::{DBIndex, DatabaseID};
+
+/// How many fragments are read between reports of a store's verification.
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Opens every database's store before the server accepts any connections, so a damaged store stops a deployment rather than failing
+/// requests once it is live.
+///
+/// Opening a store reads its header and fragment table, after which its key directory is read too. Space which was found allocated to no
+/// fragment when the store was opened is reported, though it has already been reclaimed by then. If `sample` is above zero, up to that many
+/// of each store's fragments, spread evenly across it, are read back as well. Stores outside their maintenance window only have a share of
+/// that many read, as `maintenance.throttle` says. Fragment hashes aren't recorded yet, so for now a sampled fragment passes if it lies
+/// within the store and can be read in full.
+///
+/// Stores are left open afterwards, so the first requests against them don't wait for them to open. Quarantined databases and databases
+/// which haven't been written to yet are skipped.
+pub async fn verify_stores(pool: &DbPool, index: &DBIndex, sample: usize, maintenance: &MaintenanceConfig) -> Result<()> {
+    let databases = index.lock().await.databases.clone();
+    let mut damaged = 0;
+
+    for db in databases.iter().filter(|db| db.quarantine.is_none() && db.root.join(STORE_FILE).exists()) {
+        let verified = match pool.open(db).await {
+            Ok(store) => {
+                let id = db.id.clone();
+                let sample = maintenance.sample(&id, sample, chrono::Utc::now());
+                tokio::task::spawn_blocking(move || verify_store(&mut store.blocking_lock(), &id, sample))
+                    .await
+                    .map_err(|_| ManualError::StoreOpenFailed)
+                    .map_err(Error::from)
+                    .and_then(|verified| verified)
+            },
+            Err(err) => Err(err),
+        };
+
+        match verified {
+            Ok(()) => log::info!("Verified database {}", db.id),
+            Err(err) => {
+                log::error!("Database {} failed verification: {:?}", db.id, err);
+                damaged += 1;
+            },
+        }
+    }
+
+    match damaged {
+        0 => Ok(()),
+        damaged => Err(ManualError::VerificationFailed(damaged).into()),
+    }
+}
+
+fn verify_store(store: &mut Store, id: &DatabaseID, sample: usize) -> Result<()> {
+    if store.reclaimed() > 0 {
+        log::warn!("Database {} had {} bytes allocated to no fragment, which were reclaimed", id, store.reclaimed());
+    }
+
+    KeyDirectory::load(store)?;
+
+    if sample == 0 {
+        return Ok(());
+    }
+
+    let id = id.clone();
+    let progress = Progress::new(move |step| if step.done > 0 && step.done.is_multiple_of(PROGRESS_INTERVAL) {
+        log::info!("Verifying database {}: {} of {} fragments read", id, step.done, step.total);
+    });
+
+    match store.scrub(sample, &progress)?.first() {
+        Some(damaged) => Err(ManualError::FragmentDamaged(*damaged).into()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+use libdb::AllocOptions;
+use libdb::FragmentID;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zstd::zstd_safe::{CCtx, CParameter, DCtx, DParameter};
+use crate::dictionary;
+use crate::error::*;
+use crate::keys::{collection, KeyDirectory, ObjectKey, ObjectMeta};
+use crate::pool::Store;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The largest window deltas are made with. Objects are held to the document size limit, so this is never the limiting factor.
+const MAX_WINDOW_LOG: u32 = 30;
+
+fn default_snapshot_interval() -> u64 {
+    16
+}
+
+/// How a collection keeps the history of its objects.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ToSchema)]
+pub struct VersionPolicy {
+    /// The most superseded versions kept of each object. Older ones are deleted as objects are written.
+    pub keep: usize,
+
+    /// Every this-many versions is stored whole rather than as a delta, which bounds how many deltas reading an old version takes.
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_every: u64,
+}
+
+/// A superseded version of an object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Version {
+    /// Counts up from 1 with each write, so versions keep their numbers as older ones are deleted.
+    pub number: u64,
+
+    /// The fragment holding the version, either whole or as a delta.
+    pub fragment: FragmentID,
+
+    /// The size of the version once rebuilt.
+    pub size: u64,
+
+    /// Whether the fragment holds a delta against the version written after this one rather than the whole version.
+    pub delta: bool,
+}
+
+fn zstd_error(code: usize) -> io::Error {
+    io::Error::other(zstd::zstd_safe::get_error_name(code))
+}
+
+/// A window large enough for zstd to refer back to anywhere in both the base and the data.
+fn window_log(base: &[u8], data: &[u8]) -> u32 {
+    (base.len() + data.len()).next_power_of_two().trailing_zeros().clamp(10, MAX_WINDOW_LOG)
+}
+
+/// Encodes `data` as a binary delta against `base`. zstd is handed `base` as a prefix to refer back to, so only the parts of `data` which
+/// differ from it take up space.
+pub fn encode_delta(base: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut context = CCtx::create();
+    context.set_parameter(CParameter::CompressionLevel(COMPRESSION_LEVEL)).map_err(zstd_error)?;
+    context.set_parameter(CParameter::WindowLog(window_log(base, data))).map_err(zstd_error)?;
+    context.set_parameter(CParameter::EnableLongDistanceMatching(true)).map_err(zstd_error)?;
+    context.ref_prefix(base).map_err(zstd_error)?;
+
+    let mut delta = Vec::with_capacity(zstd::zstd_safe::compress_bound(data.len()));
+    context.compress2(&mut delta, data).map_err(zstd_error)?;
+
+    Ok(delta)
+}
+
+/// Rebuilds `size` bytes of data from a delta made by [`encode_delta`] against the same `base`.
+pub fn decode_delta(base: &[u8], delta: &[u8], size: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size as usize);
+
+    let mut context = DCtx::create();
+    context.set_parameter(DParameter::WindowLogMax(MAX_WINDOW_LOG)).map_err(zstd_error)?;
+    context.ref_prefix(base).map_err(zstd_error)?;
+    context.decompress(&mut data, delta).map_err(zstd_error)?;
+
+    Ok(data)
+}
+
+fn read_fragment(store: &mut Store, id: FragmentID) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    store.open_fragment(id)?.read_into(&mut data)?;
+
+    Ok(data)
+}
+
+/// Keeps the contents the object described by `previous` is about to be replaced with `data` by as its newest superseded version, if its
+/// collection keeps history. The version is stored as a delta against `data`, or whole every [`VersionPolicy::snapshot_every`] versions.
+///
+/// Returns `None` if the history didn't change, and otherwise the fragments of the versions which no longer fit in it. They should only be
+/// deleted once the directory has been saved.
+pub fn record(store: &mut Store, directory: &mut KeyDirectory, key: &ObjectKey, previous: &ObjectMeta, data: &[u8]) -> Result<Option<Vec<FragmentID>>> {
+    let policy = collection(key).and_then(|collection| directory.version_policy(collection)).unwrap_or_default();
+
+    if policy.keep == 0 && previous.history.is_empty() {
+        return Ok(None);
+    }
+
+    let contents = dictionary::read_contents(store, previous)?;
+    if policy.keep > 0 && contents == data {
+        return Ok(None);
+    }
+
+    let number = previous.history.last().map_or(1, |version| version.number + 1);
+    let delta = !number.is_multiple_of(policy.snapshot_every.max(1));
+
+    let mut history = previous.history.clone();
+
+    // Without a policy there is nothing to keep, but the history can't stay either: its newest version is a delta against contents which
+    // are about to be replaced.
+    if policy.keep > 0 {
+        let (fragment, size) = (directory.allocate_id(), contents.len() as u64);
+        let stored = match delta {
+            true => encode_delta(data, &contents)?,
+            false => contents,
+        };
+
+        store.write_fragment(AllocOptions::default().fragment(fragment), &stored)?;
+        history.push(Version { number, fragment, size, delta });
+    }
+
+    let pruned = history.drain(..history.len().saturating_sub(policy.keep)).map(|version| version.fragment).collect();
+    directory.set_history(key, history);
+
+    Ok(Some(pruned))
+}
+
+/// Rebuilds version `number` of the object at `key`, starting from the nearest newer version stored whole, or the object's current
+/// contents, and applying deltas back to it. Returns `None` if there is no such object or version.
+pub fn read_version(store: &mut Store, key: &ObjectKey, number: u64) -> Result<Option<(ObjectMeta, Vec<u8>)>> {
+    let Some(meta) = KeyDirectory::load(store)?.get(key).cloned() else {
+        return Ok(None);
+    };
+
+    let Some(position) = meta.history.iter().position(|version| version.number == number) else {
+        return Ok(None);
+    };
+
+    let (start, mut contents) = match meta.history[position..].iter().position(|version| !version.delta) {
+        Some(offset) => (position + offset, read_fragment(store, meta.history[position + offset].fragment)?),
+        None => (meta.history.len(), dictionary::read_contents(store, &meta)?),
+    };
+
+    for version in meta.history[position..start].iter().rev() {
+        contents = decode_delta(&contents, &read_fragment(store, version.fragment)?, version.size)?;
+    }
+
+    Ok(Some((meta, contents)))
+}
+
+/// Sets how `collection` keeps the history of its objects. Keeping no versions stops recording history, and objects' existing history is
+/// deleted as they are next written.
+pub fn set_policy(store: &mut Store, collection: &str, policy: VersionPolicy) -> Result<()> {
+    let mut directory = KeyDirectory::load(store)?;
+    directory.set_version_policy(collection, policy);
+    directory.save(store)?;
+    store.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_deltas_of_similar_blobs_are_small() {
+        let base = (0..256 * 1024u32).flat_map(|i| (i.wrapping_mul(2654435761) >> 7).to_le_bytes()).collect::<Vec<_>>();
+        let mut edited = base.clone();
+        edited[1000..1010].copy_from_slice(b"0123456789");
+        edited.extend_from_slice(b"appended");
+
+        let delta = encode_delta(&edited, &base).unwrap();
+        assert!(delta.len() * 100 < base.len(), "a {} byte delta", delta.len());
+        assert_eq!(decode_delta(&edited, &delta, base.len() as u64).unwrap(), base);
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::OnceLock;
+use std::time::Duration;
+use actix_web::{delete, get, post, web, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use crate::auth::AuthenticatedUser;
+use crate::config::ServerConfig;
+use crate::deliveries;
+use crate::pool::DbPool;
+use crate::error::*;
+use crate::index::{commit_change, DBIndexChange};
+use crate::resources::Access;
+use crate::response::{ApiError, ApiResponse, Done};
+use crate::{DBIndex, DatabaseID, DatabaseIndex, UserID};
+
+/// How many deliveries may wait to be sent before new ones are dropped. Changing the index never waits on a webhook.
+const QUEUE_LENGTH: usize = 256;
+
+/// How long a webhook may take to respond before it is given up on.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A URL which is told about changes to a database.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+
+    /// Signs every event sent to the webhook, so it can tell they came from this server. See [`sign`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+
+    /// Whether the webhook is also told about changes to the database's objects. See [`crate::deliveries`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub objects: bool,
+}
+
+/// The header carrying an event's signature: `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the webhook's secret.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Signs `body` with the webhook's secret, as it is sent in [`SIGNATURE_HEADER`].
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = ring::hmac::sign(&key, body);
+
+    format!("sha256={}", tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+}
+
+/// Posts `body` to the webhook as JSON, signed if it has a secret.
+pub async fn post(client: &reqwest::Client, webhook: &Webhook, body: &impl Serialize) -> Result<()> {
+    let body = serde_json::to_vec(body)?;
+    let mut request = client.post(&webhook.url).header(reqwest::header::CONTENT_TYPE, "application/json");
+
+    if let Some(ref secret) = webhook.secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+    }
+
+    request.body(body).send().await?.error_for_status()?;
+
+    Ok(())
+}
+
+/// A change to a database itself, rather than to its objects.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    /// A user was given access to the database, or had their access changed.
+    Shared { user: UserID, access: Access },
+    Unshared { user: UserID },
+    Renamed { from: String, to: String },
+    /// The database was deleted, either by its owner or along with its owner's data.
+    Deleted,
+}
+
+/// An event as it is posted to every webhook.
+#[derive(Debug, Serialize)]
+struct EventBody<'a> {
+    time: DateTime<Utc>,
+    database: &'a DatabaseID,
+    #[serde(flatten)]
+    event: &'a LifecycleEvent,
+}
+
+/// An event along with the webhooks it is sent to.
+#[derive(Debug, PartialEq)]
+pub struct Delivery {
+    database: DatabaseID,
+    event: LifecycleEvent,
+    webhooks: Vec<Webhook>,
+}
+
+/// What is needed of a database to tell how it has changed.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    name: String,
+    ro: BTreeSet<UserID>,
+    rw: BTreeSet<UserID>,
+    webhooks: Vec<Webhook>,
+}
+
+/// Records the databases which have webhooks, before a batch of changes is applied to the index.
+pub fn snapshot(index: &DatabaseIndex) -> HashMap<DatabaseID, Snapshot> {
+    index.databases.iter()
+        .filter(|db| !db.webhooks.is_empty())
+        .map(|db| (db.id.clone(), Snapshot {
+            name: db.name.clone(),
+            ro: db.ro.iter().cloned().collect(),
+            rw: db.rw.iter().cloned().collect(),
+            webhooks: db.webhooks.clone(),
+        }))
+        .collect()
+}
+
+/// Works out the events to send by comparing the index with a snapshot taken before it changed.
+///
+/// Events are worked out from the index rather than from the changes made to it, so every way of changing a database is reported alike.
+pub fn changes(before: &HashMap<DatabaseID, Snapshot>, index: &DatabaseIndex) -> Vec<Delivery> {
+    let mut deliveries = vec![];
+
+    for (id, snapshot) in before.iter() {
+        let mut events = vec![];
+
+        match index.databases.iter().find(|db| db.id == *id) {
+            None => events.push(LifecycleEvent::Deleted),
+            Some(db) => {
+                if db.name != snapshot.name {
+                    events.push(LifecycleEvent::Renamed { from: snapshot.name.clone(), to: db.name.clone() });
+                }
+
+                for (users, previous, access) in [(&db.rw, &snapshot.rw, Access::ReadWrite), (&db.ro, &snapshot.ro, Access::ReadOnly)] {
+                    events.extend(users.iter()
+                        .filter(|user| !previous.contains(*user))
+                        .map(|user| LifecycleEvent::Shared { user: user.clone(), access }));
+                }
+
+                events.extend(snapshot.rw.union(&snapshot.ro)
+                    .filter(|user| !db.rw.contains(user) && !db.ro.contains(user))
+                    .map(|user| LifecycleEvent::Unshared { user: user.clone() }));
+            },
+        }
+
+        deliveries.extend(events.into_iter().map(|event| Delivery {
+            database: id.clone(),
+            event,
+            webhooks: snapshot.webhooks.clone(),
+        }));
+    }
+
+    deliveries
+}
+
+enum Dispatch {
+    Delivery(Delivery),
+    /// Sends every event emitted before it, then stops.
+    Shutdown,
+}
+
+static WEBHOOKS: OnceLock<Sender<Dispatch>> = OnceLock::new();
+
+/// Sends the events to their webhooks in the background.
+pub fn emit(deliveries: Vec<Delivery>) {
+    let Some(webhooks) = WEBHOOKS.get() else {
+        return;
+    };
+
+    for delivery in deliveries {
+        if webhooks.try_send(Dispatch::Delivery(delivery)).is_err() {
+            log::error!("Dropped a webhook event because too many are waiting to be sent");
+        }
+    }
+}
+
+/// Starts sending events to webhooks.
+pub fn start() -> JoinHandle<()> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(QUEUE_LENGTH);
+    let _ = WEBHOOKS.set(sender);
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        while let Some(dispatch) = receiver.recv().await {
+            let delivery = match dispatch {
+                Dispatch::Delivery(delivery) => delivery,
+                Dispatch::Shutdown => {
+                    receiver.close();
+                    continue;
+                },
+            };
+
+            let body = EventBody {
+                time: Utc::now(),
+                database: &delivery.database,
+                event: &delivery.event,
+            };
+
+            for webhook in delivery.webhooks.iter() {
+                if let Err(err) = post(&client, webhook, &body).await {
+                    log::warn!("Failed to send a webhook event for database {} to {}: {:?}", delivery.database, webhook.url, err);
+                }
+            }
+        }
+    })
+}
+
+/// Waits for every event emitted so far to be sent.
+pub async fn shutdown(handler: JoinHandle<()>) {
+    if let Some(webhooks) = WEBHOOKS.get() {
+        let _ = webhooks.send(Dispatch::Shutdown).await;
+    }
+
+    if let Err(err) = handler.await {
+        log::error!("Webhook handler failed: {}", err);
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateWebhookOptions {
+    url: String,
+
+    /// Signs every event sent to the webhook with this.
+    secret: Option<String>,
+
+    /// Also tell the webhook about every object created, updated, deleted, restored or expired.
+    #[serde(default)]
+    objects: bool,
+}
+
+/// Registers a URL to be told whenever the database is shared, unshared, renamed or deleted, and with `"objects": true`, whenever its
+/// objects change. Events are signed with `secret`, if given. Only the database's owner may register them.
+#[utoipa::path(
+    tag = "webhooks",
+    params(("id" = String, Path, description = "The database's ID")),
+    request_body = CreateWebhookOptions,
+    responses((status = 201, description = "The webhook was registered"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[post("/databases/{id}/webhooks")]
+pub async fn create_webhook(id: web::Path<DatabaseID>, options: web::Json<CreateWebhookOptions>, user: AuthenticatedUser, index: web::Data<DBIndex>, pool: web::Data<DbPool>, config: web::Data<ServerConfig>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    if !reqwest::Url::parse(&options.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+        return Err(ApiError::bad_request("invalid_url", "Webhooks need an HTTP or HTTPS URL").into());
+    }
+
+    let Some(db) = index.lock().await.databases.iter().find(|db| db.id == id && db.owner == user.id).cloned() else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    let options = options.into_inner();
+    let webhook = Webhook {
+        id: config.id_scheme.generate().await.map_err(ApiError::internal)?,
+        url: options.url,
+        secret: options.secret.filter(|secret| !secret.is_empty()),
+        objects: options.objects,
+    };
+
+    if webhook.objects {
+        deliveries::begin(&pool, &db, &webhook.id).await.map_err(ApiError::internal)?;
+    }
+
+    commit_change(DBIndexChange::AddWebhook { database: id.clone(), webhook: webhook.clone() }).await.map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::created(Created { webhook: describe(&id, &webhook) }))
+}
+
+/// A webhook as it is shown to its owner. Its secret is never shown.
+#[derive(Serialize)]
+struct WebhookDescription {
+    id: String,
+    url: String,
+    signed: bool,
+    objects: bool,
+    deliveries: Option<deliveries::DeliveryStatus>,
+}
+
+#[derive(Serialize)]
+struct Created {
+    webhook: WebhookDescription,
+}
+
+#[derive(Serialize)]
+struct Webhooks {
+    webhooks: Vec<WebhookDescription>,
+}
+
+fn describe(database: &DatabaseID, webhook: &Webhook) -> WebhookDescription {
+    WebhookDescription {
+        id: webhook.id.clone(),
+        url: webhook.url.clone(),
+        signed: webhook.secret.is_some(),
+        objects: webhook.objects,
+        deliveries: webhook.objects.then(|| deliveries::status(database, &webhook.id)),
+    }
+}
+
+/// Lists a database's webhooks, along with how delivering object changes to each is going. Only the database's owner may see them.
+#[utoipa::path(
+    tag = "webhooks",
+    params(("id" = String, Path, description = "The database's ID")),
+    responses((status = 200, description = "The database's webhooks and how delivery to each is going"), (status = 404, description = "No such database, or the caller may not use it")),
+    security(("user" = [])),
+)]
+#[get("/databases/{id}/webhooks")]
+pub async fn list_webhooks(id: web::Path<DatabaseID>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let index = index.lock().await;
+
+    let Some(db) = index.databases.iter().find(|db| db.id == *id && db.owner == user.id) else {
+        return Err(ApiError::no_such_database().into());
+    };
+
+    Ok(ApiResponse::ok(Webhooks { webhooks: db.webhooks.iter().map(|webhook| describe(&db.id, webhook)).collect() }))
+}
+
+/// Removes a webhook. Only the database's owner may remove them.
+#[utoipa::path(
+    tag = "webhooks",
+    params(("id" = String, Path, description = "The database's ID"), ("webhook" = String, Path, description = "The webhook's ID")),
+    responses((status = 200, description = "The webhook was removed"), (status = 404, description = "No such database or webhook")),
+    security(("user" = [])),
+)]
+#[delete("/databases/{id}/webhooks/{webhook}")]
+pub async fn delete_webhook(path: web::Path<(DatabaseID, String)>, user: AuthenticatedUser, index: web::Data<DBIndex>) -> actix_web::Result<impl Responder> {
+    let (id, webhook) = path.into_inner();
+
+    let exists = index.lock().await.databases.iter()
+        .find(|db| db.id == id && db.owner == user.id)
+        .map(|db| db.webhooks.iter().any(|existing| existing.id == webhook));
+
+    match exists {
+        None => return Err(ApiError::no_such_database().into()),
+        Some(false) => return Err(ApiError::not_found("no_such_webhook", "No such webhook").into()),
+        Some(true) => (),
+    }
+
+    commit_change(DBIndexChange::RemoveWebhook { database: id, webhook }).await.map_err(ApiError::internal)?;
+
+    Ok(ApiResponse::ok(Done {}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::IndexFixture;
+
+    #[test]
+    pub fn test_changes_are_worked_out_from_the_index() {
+        let mut index = IndexFixture::new()
+            .user("u1")
+            .database("db1")
+            .rw("u2")
+            .ro("u3")
+            .database("db2")
+            .database("db3")
+            .build();
+
+        for db in index.databases.iter_mut().take(2) {
+            db.webhooks.push(Webhook { id: "w1".to_owned(), url: "http://localhost/hook".to_owned(), secret: None, objects: false });
+        }
+
+        let before = snapshot(&index);
+        assert_eq!(before.len(), 2);
+
+        index.databases[0].name = "renamed".to_owned();
+        index.databases[0].rw.retain(|user| user != "u2");
+        index.databases[0].ro.push("u2".to_owned());
+        index.databases[0].ro.retain(|user| user != "u3");
+        index.databases.retain(|db| db.id != "db2" && db.id != "db3");
+
+        let mut events = changes(&before, &index).into_iter()
+            .map(|delivery| (delivery.database, delivery.event))
+            .collect::<Vec<_>>();
+        events.sort_by_key(|(database, _)| database.clone());
+
+        assert_eq!(events, vec![
+            ("db1".to_owned(), LifecycleEvent::Renamed { from: "db1".to_owned(), to: "renamed".to_owned() }),
+            ("db1".to_owned(), LifecycleEvent::Shared { user: "u2".to_owned(), access: Access::ReadOnly }),
+            ("db1".to_owned(), LifecycleEvent::Unshared { user: "u3".to_owned() }),
+            ("db2".to_owned(), LifecycleEvent::Deleted),
+        ]);
+    }
+
+    #[test]
+    pub fn test_events_are_signed_with_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(sign("Jefe", b"what do ya want for nothing?"), "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+}